@@ -2,6 +2,7 @@ use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use clap::{Args, Subcommand};
 use ipnet::Ipv4Net;
+use uuid::Uuid;
 
 #[derive(Subcommand, Debug)]
 pub enum AgentCommands {
@@ -11,6 +12,47 @@ pub enum AgentCommands {
     Init(Box<InitOptions>),
     #[command(about = "Run the wg-quickrs agent")]
     Run,
+    #[command(about = "Inspect or upgrade the persisted Router Mode state")]
+    State {
+        #[command(subcommand)]
+        target: StateCommands,
+    },
+    #[command(about = "Print the ip rule, ip route, iptables and sysctl changes a Router Mode change would make on this host, without making them")]
+    Router {
+        #[command(subcommand)]
+        target: RouterCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StateCommands {
+    #[command(about = "Check that the Router Mode state file can be loaded by this release, without changing it")]
+    Check,
+    #[command(about = "Upgrade the Router Mode state file to the current format (the previous file is kept as a backup)")]
+    Migrate,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RouterCommands {
+    #[command(about = "Plan a switch between Host and Router Mode, or a LAN CIDR update while in Router Mode")]
+    Mode {
+        #[arg(help = "Target mode", value_parser = ["host", "router"])]
+        mode: String,
+        #[arg(long, help = "LAN CIDR(s), comma-separated (required for Router Mode)", value_name = "192.168.1.0/24")]
+        lan_cidr: Option<String>,
+    },
+    #[command(about = "Plan setting the exit node for LAN and WireGuard internet traffic")]
+    ExitNode {
+        #[arg(help = "Exit node peer UUID (00000000-0000-0000-0000-000000000000 for the local breakout)")]
+        peer: Uuid,
+    },
+    #[command(about = "Plan allowing or denying a peer access to the LAN")]
+    LanAccess {
+        #[arg(help = "Peer UUID")]
+        peer: Uuid,
+        #[arg(help = "Whether the peer may reach the LAN", action = clap::ArgAction::Set)]
+        allowed: bool,
+    },
 }
 
 #[derive(Debug, Args)]
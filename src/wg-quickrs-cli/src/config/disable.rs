@@ -36,6 +36,8 @@ pub enum DisableAgentWebCommands {
     Https,
     #[command(about = "Disable password authentication for web server")]
     Password,
+    #[command(about = "Disable the Prometheus /metrics endpoint")]
+    Metrics,
 }
 
 #[derive(Subcommand, Debug)]
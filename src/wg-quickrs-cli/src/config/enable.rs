@@ -36,6 +36,8 @@ pub enum EnableAgentWebCommands {
     Https,
     #[command(about = "Enable password authentication for web server")]
     Password,
+    #[command(about = "Enable the Prometheus /metrics endpoint")]
+    Metrics,
 }
 
 #[derive(Subcommand, Debug)]
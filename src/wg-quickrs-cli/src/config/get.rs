@@ -33,6 +33,19 @@ pub enum GetAgentCommands {
         #[command(subcommand)]
         target: Option<GetAgentFirewallCommands>,
     },
+    #[command(about = "Get router mode configuration")]
+    Router {
+        #[command(subcommand)]
+        target: Option<GetAgentRouterCommands>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GetAgentRouterCommands {
+    #[command(about = "Get router mode (host or router)")]
+    Mode,
+    #[command(about = "Get exit node health monitor and failover tuning")]
+    Failover,
 }
 
 #[derive(Subcommand, Debug)]
@@ -22,6 +22,20 @@ pub enum ResetAgentCommands {
         #[command(subcommand)]
         target: ResetAgentWebCommands,
     },
+    #[command(about = "Reset router mode configuration")]
+    Router {
+        #[command(subcommand)]
+        target: ResetAgentRouterCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ResetAgentRouterCommands {
+    #[command(about = "Reset exit node health monitor and failover tuning to the defaults")]
+    Failover {
+        #[arg(long, help = "Only remove the overrides of this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
 }
 
 #[derive(Subcommand, Debug)]
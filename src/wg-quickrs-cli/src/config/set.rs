@@ -34,6 +34,11 @@ pub enum SetAgentCommands {
         #[command(subcommand)]
         target: SetAgentFirewallCommands,
     },
+    #[command(about = "Set router mode configuration")]
+    Router {
+        #[command(subcommand)]
+        target: SetAgentRouterCommands,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -106,6 +111,94 @@ pub enum SetAgentFirewallCommands {
     },
 }
 
+#[derive(Subcommand, Debug)]
+pub enum SetAgentRouterCommands {
+    #[command(about = "Set exit node health monitor and failover tuning")]
+    Failover {
+        #[command(subcommand)]
+        target: SetAgentRouterFailoverCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SetAgentRouterFailoverCommands {
+    #[command(about = "Set the number of consecutive failed pings before an exit node is marked offline")]
+    ConsecutiveFailures {
+        #[arg(help = "Number of failed pings (1-100)")]
+        value: u32,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set how long the primary exit node must be back online before failing back")]
+    FailbackStabilitySecs {
+        #[arg(help = "Seconds (0-86400)")]
+        value: u64,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set how long after startup failover is held off while tunnels come up")]
+    StartupGracePeriodSecs {
+        #[arg(help = "Seconds (0-3600)")]
+        value: u64,
+    },
+    #[command(about = "Set how often exit nodes are pinged")]
+    HealthMonitorIntervalSecs {
+        #[arg(help = "Seconds (1-3600)")]
+        value: u64,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set the number of pings packet loss and jitter are calculated over")]
+    PingHistorySize {
+        #[arg(help = "Number of pings (1-3600)")]
+        value: usize,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set the packet loss above which an exit node is degraded (SLA)")]
+    MaxPacketLossPercent {
+        #[arg(help = "Percent (0-100)")]
+        value: f64,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set the average latency above which an exit node is degraded (SLA)")]
+    MaxLatencyMs {
+        #[arg(help = "Milliseconds (1-60000)")]
+        value: u64,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set the jitter above which an exit node is degraded (SLA)")]
+    MaxJitterMs {
+        #[arg(help = "Milliseconds (1-60000)")]
+        value: u64,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set how long an SLA threshold must be exceeded before an exit node is degraded")]
+    SlaHoldSecs {
+        #[arg(help = "Seconds (0-3600)")]
+        value: u64,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set how an exit node is checked for liveness")]
+    Liveness {
+        #[arg(help = "ping, handshake (recent handshake and received data) or hybrid (either)")]
+        value: String,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+    #[command(about = "Set the handshake age up to which an exit node is alive (handshake liveness)")]
+    MaxHandshakeAgeSecs {
+        #[arg(help = "Seconds (120-3600)")]
+        value: u64,
+        #[arg(long, help = "Only set it for this exit node (peer UUID)")]
+        exit_node: Option<Uuid>,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 pub enum SetNetworkCommands {
     #[command(about = "Set network name")]
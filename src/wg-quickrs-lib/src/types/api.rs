@@ -119,7 +119,7 @@ impl From<&AddedPeer> for Peer {
     fn from(added_peer: &AddedPeer) -> Self {
         // If private_key is not provided, generate a new one
         // This allows users to bring their own keys or have one auto-generated
-        let private_key = added_peer.private_key.clone().unwrap_or_else(|| wg_generate_key());
+        let private_key = added_peer.private_key.unwrap_or_else(wg_generate_key);
         
         Peer {
             name: added_peer.name.clone(),
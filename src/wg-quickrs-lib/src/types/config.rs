@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::path::PathBuf;
+use uuid::Uuid;
 use crate::macros::*;
 use crate::types::misc::WireGuardLibError;
 use crate::types::network::{Network, NetworkWDigest};
@@ -62,6 +64,12 @@ pub struct Agent {
     pub firewall: AgentFirewall,
     #[serde(default)]
     pub router: AgentRouter,
+    #[serde(default)]
+    pub notifications: AgentNotifications,
+    #[serde(default)]
+    pub telemetry: AgentTelemetry,
+    #[serde(default)]
+    pub usage: AgentUsage,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -70,6 +78,37 @@ pub struct AgentWeb {
     pub http: AgentWebHttp,
     pub https: AgentWebHttps,
     pub password: Password,
+    #[serde(default)]
+    pub metrics: AgentWebMetrics,
+}
+
+// Prometheus /metrics endpoint (off unless enabled)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AgentWebMetrics {
+    pub enabled: bool,
+    #[serde(default)]
+    pub auth: MetricsAuth,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>, // bearer token for auth: token
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsAuth {
+    #[default]
+    Api,   // Same as the rest of the API (a login token when the web password is enabled)
+    Token, // A static bearer token, for scrapers that cannot log in
+    None,  // No authentication
+}
+
+impl std::fmt::Display for MetricsAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricsAuth::Api => write!(f, "api"),
+            MetricsAuth::Token => write!(f, "token"),
+            MetricsAuth::None => write!(f, "none"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -111,6 +150,8 @@ pub struct AgentRouter {
     pub mode: String, // "host" or "router"
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub lan_cidr: Option<String>, // e.g., "192.168.1.0/24"
+    #[serde(default)]
+    pub failover: AgentRouterFailover,
 }
 
 fn default_router_mode() -> String {
@@ -122,7 +163,391 @@ impl Default for AgentRouter {
         AgentRouter {
             mode: "host".to_string(),
             lan_cidr: None,
+            failover: AgentRouterFailover::default(),
+        }
+    }
+}
+
+// Health monitoring and Smart Gateway failover tuning
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AgentRouterFailover {
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32, // failed pings in a row before an exit node is offline
+    #[serde(default = "default_failback_stability_secs")]
+    pub failback_stability_secs: u64, // seconds the primary must be online before failing back
+    #[serde(default = "default_startup_grace_period_secs")]
+    pub startup_grace_period_secs: u64, // seconds after startup without failover
+    #[serde(default = "default_health_monitor_interval_secs")]
+    pub health_monitor_interval_secs: u64, // seconds between pings
+    #[serde(default = "default_ping_history_size")]
+    pub ping_history_size: usize, // pings kept for packet loss and jitter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_packet_loss_percent: Option<f64>, // SLA: packet loss above this degrades an exit node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_latency_ms: Option<u64>, // SLA: average latency above this degrades an exit node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_jitter_ms: Option<u64>, // SLA: jitter above this degrades an exit node
+    #[serde(default = "default_sla_hold_secs")]
+    pub sla_hold_secs: u64, // seconds an SLA threshold must be exceeded before degrading
+    #[serde(default)]
+    pub liveness: Liveness, // what a successful probe of an exit node means
+    #[serde(default = "default_max_handshake_age_secs")]
+    pub max_handshake_age_secs: u64, // handshake (and received data) age up to which a peer is alive
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub exit_nodes: BTreeMap<Uuid, ExitNodeFailover>, // per exit node overrides
+}
+
+// Per exit node overrides of AgentRouterFailover (the startup grace period is global)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ExitNodeFailover {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consecutive_failures: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failback_stability_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_monitor_interval_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ping_history_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_packet_loss_percent: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_latency_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_jitter_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sla_hold_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness: Option<Liveness>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_handshake_age_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probes: Vec<HealthProbe>, // internet probes sent through this exit node (per exit node only)
+}
+
+// How the health monitor decides an exit node is alive
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Liveness {
+    // The peer answers a ping through the tunnel
+    #[default]
+    Ping,
+    // The last handshake and the last received data are both younger than max_handshake_age_secs
+    // (for peers that drop ICMP; the peer is not pinged and reports no latency)
+    Handshake,
+    // Either of the two
+    Hybrid,
+}
+
+impl std::fmt::Display for Liveness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Liveness::Ping => write!(f, "ping"),
+            Liveness::Handshake => write!(f, "handshake"),
+            Liveness::Hybrid => write!(f, "hybrid"),
+        }
+    }
+}
+
+// Health probe beyond the tunnel, sent through one exit node to a public IPv4 target
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum HealthProbe {
+    // GET of an http:// URL with an IPv4 host, succeeds on a 2xx/3xx status
+    Http {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host: Option<String>, // Host header (defaults to the URL's address)
+    },
+    // A-record query to a DNS server, succeeds on any answer (NOERROR or NXDOMAIN)
+    Dns { server: Ipv4Addr, name: String },
+    // TCP connect, succeeds once the handshake completes
+    Tcp { address: SocketAddrV4 },
+}
+
+impl HealthProbe {
+    // Address the probe is sent to (None for an unparsable URL)
+    pub fn target(&self) -> Option<Ipv4Addr> {
+        match self {
+            HealthProbe::Http { url, .. } => parse_http_probe_url(url).map(|(address, _)| *address.ip()),
+            HealthProbe::Dns { server, .. } => Some(*server),
+            HealthProbe::Tcp { address } => Some(*address.ip()),
+        }
+    }
+}
+
+impl std::fmt::Display for HealthProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthProbe::Http { url, .. } => write!(f, "http {}", url),
+            HealthProbe::Dns { server, name } => write!(f, "dns {} @{}", name, server),
+            HealthProbe::Tcp { address } => write!(f, "tcp {}", address),
+        }
+    }
+}
+
+// Address and path of an http:// URL whose host is an IPv4 address (port 80 unless given)
+pub fn parse_http_probe_url(url: &str) -> Option<(SocketAddrV4, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = match authority.split_once(':') {
+        Some((host, port)) => SocketAddrV4::new(host.parse().ok()?, port.parse().ok()?),
+        None => SocketAddrV4::new(authority.parse().ok()?, 80),
+    };
+    Some((address, path.to_string()))
+}
+
+// WireGuard transfer/handshake sampling (GET /api/telemetry, the dashboard graphs, /metrics)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AgentTelemetry {
+    #[serde(default = "default_telemetry_always_on")]
+    pub always_on: bool, // false: only sample while the web UI or a live dashboard is using the samples
+    #[serde(default = "default_telemetry_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_telemetry_window")]
+    pub window: usize, // samples kept
+}
+
+impl Default for AgentTelemetry {
+    fn default() -> Self {
+        AgentTelemetry {
+            always_on: default_telemetry_always_on(),
+            interval_ms: default_telemetry_interval_ms(),
+            window: default_telemetry_window(),
+        }
+    }
+}
+
+fn default_telemetry_always_on() -> bool {
+    true
+}
+
+fn default_telemetry_interval_ms() -> u64 {
+    1000
+}
+
+fn default_telemetry_window() -> usize {
+    300
+}
+
+// Traffic quotas per peer (usage itself is always accounted, see GET /api/usage)
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AgentUsage {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub quotas: BTreeMap<Uuid, UsageQuota>,
+}
+
+// Received plus sent bytes per UTC day and calendar month
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UsageQuota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_bytes: Option<u64>,
+    #[serde(default)]
+    pub action: QuotaAction,
+}
+
+// What happens to a peer over its quota until the period ends
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaAction {
+    // Journal a quota_exceeded event (and notify)
+    #[default]
+    Warn,
+    // Also stop using the peer as an exit node (it counts as degraded for failover)
+    BlockExit,
+    // Also remove the peer from the WireGuard interface
+    Disconnect,
+}
+
+impl std::fmt::Display for QuotaAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaAction::Warn => write!(f, "warn"),
+            QuotaAction::BlockExit => write!(f, "block_exit"),
+            QuotaAction::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+// Notifications about gateway state changes (exit node status, failovers, switches)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AgentNotifications {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<NotificationSink>,
+    #[serde(default = "default_notification_retries")]
+    pub max_retries: u32, // retries of a failed delivery, with exponential backoff
+}
+
+impl Default for AgentNotifications {
+    fn default() -> Self {
+        AgentNotifications {
+            sinks: Vec::new(),
+            max_retries: default_notification_retries(),
+        }
+    }
+}
+
+fn default_notification_retries() -> u32 {
+    5
+}
+
+// Where notifications are sent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotificationSink {
+    // The event as JSON; with a secret, the body is signed (HMAC-SHA256)
+    Webhook {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secret: Option<String>,
+    },
+    // A plain-text mail
+    Smtp {
+        host: String,
+        #[serde(default = "default_smtp_port")]
+        port: u16,
+        #[serde(default)]
+        security: SmtpSecurity,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    // A push to an ntfy topic URL (https://ntfy.sh/<topic>)
+    Ntfy {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    // A push to a Gotify server with an application token
+    Gotify {
+        url: String,
+        token: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Plain connection upgraded with STARTTLS (port 587)
+    #[default]
+    Starttls,
+    // TLS from the start (port 465)
+    Tls,
+    // No encryption (a local relay)
+    None,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+// Parts of an http:// or https:// URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String, // with the query, "/" when empty
+}
+
+pub fn parse_http_url(url: &str) -> Option<HttpUrl> {
+    let (https, rest) = match url.split_once("://")? {
+        ("http", rest) => (false, rest),
+        ("https", rest) => (true, rest),
+        _ => return None,
+    };
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+        Some(i) => (&rest[..i], rest[i..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, if https { 443 } else { 80 }),
+    };
+    if host.is_empty() || host.contains(['@', ' ', '[']) {
+        return None;
+    }
+    Some(HttpUrl { https, host: host.to_string(), port, path })
+}
+
+fn default_consecutive_failures() -> u32 {
+    3
+}
+
+fn default_failback_stability_secs() -> u64 {
+    60
+}
+
+fn default_startup_grace_period_secs() -> u64 {
+    30
+}
+
+fn default_health_monitor_interval_secs() -> u64 {
+    1
+}
+
+fn default_ping_history_size() -> usize {
+    60
+}
+
+fn default_sla_hold_secs() -> u64 {
+    10
+}
+
+fn default_max_handshake_age_secs() -> u64 {
+    150
+}
+
+impl Default for AgentRouterFailover {
+    fn default() -> Self {
+        AgentRouterFailover {
+            consecutive_failures: default_consecutive_failures(),
+            failback_stability_secs: default_failback_stability_secs(),
+            startup_grace_period_secs: default_startup_grace_period_secs(),
+            health_monitor_interval_secs: default_health_monitor_interval_secs(),
+            ping_history_size: default_ping_history_size(),
+            max_packet_loss_percent: None,
+            max_latency_ms: None,
+            max_jitter_ms: None,
+            sla_hold_secs: default_sla_hold_secs(),
+            liveness: Liveness::default(),
+            max_handshake_age_secs: default_max_handshake_age_secs(),
+            exit_nodes: BTreeMap::new(),
         }
     }
 }
 
+impl AgentRouterFailover {
+    // Settings in effect for one exit node (its overrides applied, without the override table)
+    pub fn for_exit_node(&self, peer_id: &Uuid) -> AgentRouterFailover {
+        let overrides = self.exit_nodes.get(peer_id).cloned().unwrap_or_default();
+        AgentRouterFailover {
+            consecutive_failures: overrides.consecutive_failures.unwrap_or(self.consecutive_failures),
+            failback_stability_secs: overrides.failback_stability_secs.unwrap_or(self.failback_stability_secs),
+            startup_grace_period_secs: self.startup_grace_period_secs,
+            health_monitor_interval_secs: overrides.health_monitor_interval_secs.unwrap_or(self.health_monitor_interval_secs),
+            ping_history_size: overrides.ping_history_size.unwrap_or(self.ping_history_size),
+            max_packet_loss_percent: overrides.max_packet_loss_percent.or(self.max_packet_loss_percent),
+            max_latency_ms: overrides.max_latency_ms.or(self.max_latency_ms),
+            max_jitter_ms: overrides.max_jitter_ms.or(self.max_jitter_ms),
+            sla_hold_secs: overrides.sla_hold_secs.unwrap_or(self.sla_hold_secs),
+            liveness: overrides.liveness.unwrap_or(self.liveness),
+            max_handshake_age_secs: overrides.max_handshake_age_secs.unwrap_or(self.max_handshake_age_secs),
+            exit_nodes: BTreeMap::new(),
+        }
+    }
+
+    // Internet probes configured for one exit node
+    pub fn probes_for(&self, peer_id: &Uuid) -> &[HealthProbe] {
+        self.exit_nodes.get(peer_id).map(|overrides| overrides.probes.as_slice()).unwrap_or_default()
+    }
+}
+
@@ -1,6 +1,10 @@
 #![cfg(not(target_arch = "wasm32"))]
 use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
+use crate::types::config::{
+    parse_http_probe_url, parse_http_url, AgentNotifications, AgentRouterFailover, AgentTelemetry, AgentUsage, AgentWebMetrics,
+    HealthProbe, Liveness, MetricsAuth, NotificationSink, SmtpSecurity,
+};
 use crate::validation::error::{ValidationError, ValidationResult};
 use crate::validation::helpers;
 
@@ -68,3 +72,268 @@ pub fn validate_fw_utility(fw_utility: &Path) -> ValidationResult<PathBuf> {
     ))
 }
 
+pub fn validate_consecutive_failures(value: u32) -> ValidationResult<u32> {
+    if !(1..=100).contains(&value) {
+        return Err(ValidationError::InvalidConsecutiveFailures());
+    }
+    Ok(value)
+}
+
+pub fn validate_failback_stability_secs(value: u64) -> ValidationResult<u64> {
+    if value > 86400 {
+        return Err(ValidationError::InvalidFailbackStability());
+    }
+    Ok(value)
+}
+
+pub fn validate_startup_grace_period_secs(value: u64) -> ValidationResult<u64> {
+    if value > 3600 {
+        return Err(ValidationError::InvalidStartupGracePeriod());
+    }
+    Ok(value)
+}
+
+pub fn validate_health_monitor_interval_secs(value: u64) -> ValidationResult<u64> {
+    if !(1..=3600).contains(&value) {
+        return Err(ValidationError::InvalidHealthMonitorInterval());
+    }
+    Ok(value)
+}
+
+pub fn validate_ping_history_size(value: usize) -> ValidationResult<usize> {
+    if !(1..=3600).contains(&value) {
+        return Err(ValidationError::InvalidPingHistorySize());
+    }
+    Ok(value)
+}
+
+pub fn validate_max_packet_loss_percent(value: f64) -> ValidationResult<f64> {
+    if !(0.0..=100.0).contains(&value) {
+        return Err(ValidationError::InvalidMaxPacketLoss());
+    }
+    Ok(value)
+}
+
+pub fn validate_max_latency_ms(value: u64) -> ValidationResult<u64> {
+    if !(1..=60000).contains(&value) {
+        return Err(ValidationError::InvalidMaxLatency());
+    }
+    Ok(value)
+}
+
+pub fn validate_max_jitter_ms(value: u64) -> ValidationResult<u64> {
+    if !(1..=60000).contains(&value) {
+        return Err(ValidationError::InvalidMaxJitter());
+    }
+    Ok(value)
+}
+
+pub fn validate_sla_hold_secs(value: u64) -> ValidationResult<u64> {
+    if value > 3600 {
+        return Err(ValidationError::InvalidSlaHold());
+    }
+    Ok(value)
+}
+
+pub fn parse_and_validate_liveness(value: &str) -> ValidationResult<Liveness> {
+    match value {
+        "ping" => Ok(Liveness::Ping),
+        "handshake" => Ok(Liveness::Handshake),
+        "hybrid" => Ok(Liveness::Hybrid),
+        _ => Err(ValidationError::InvalidLiveness()),
+    }
+}
+
+// WireGuard renews a handshake every 2 minutes while traffic flows, so anything shorter would flap
+pub fn validate_max_handshake_age_secs(value: u64) -> ValidationResult<u64> {
+    if !(120..=3600).contains(&value) {
+        return Err(ValidationError::InvalidMaxHandshakeAge());
+    }
+    Ok(value)
+}
+
+// Validate the SLA thresholds that are set (unset thresholds are not checked)
+fn validate_sla_thresholds(
+    max_packet_loss_percent: Option<f64>,
+    max_latency_ms: Option<u64>,
+    max_jitter_ms: Option<u64>,
+) -> ValidationResult<()> {
+    if let Some(value) = max_packet_loss_percent {
+        validate_max_packet_loss_percent(value)?;
+    }
+    if let Some(value) = max_latency_ms {
+        validate_max_latency_ms(value)?;
+    }
+    if let Some(value) = max_jitter_ms {
+        validate_max_jitter_ms(value)?;
+    }
+    Ok(())
+}
+
+// Validate an internet probe: a unicast IPv4 target and a well-formed URL, name or port
+pub fn validate_health_probe(probe: &HealthProbe) -> ValidationResult<()> {
+    let invalid = |reason: &str| Err(ValidationError::InvalidHealthProbe(format!("{} ({})", probe, reason)));
+    match probe {
+        HealthProbe::Http { url, host } => {
+            let Some((address, _)) = parse_http_probe_url(url) else {
+                return invalid("url must be http://<ipv4>[:port][/path]");
+            };
+            if address.port() == 0 {
+                return invalid("port cannot be 0");
+            }
+            if host.as_ref().is_some_and(|host| host.is_empty() || host.contains(char::is_whitespace)) {
+                return invalid("host header is invalid");
+            }
+        }
+        HealthProbe::Dns { name, .. } => {
+            let name = name.trim_end_matches('.');
+            let valid = !name.is_empty() && name.len() <= 253 && name.split('.').all(|label| {
+                !label.is_empty() && label.len() <= 63 && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+            if !valid {
+                return invalid("name is not a valid domain name");
+            }
+        }
+        HealthProbe::Tcp { address } => {
+            if address.port() == 0 {
+                return invalid("port cannot be 0");
+            }
+        }
+    }
+    match probe.target() {
+        Some(target) if !(target.is_unspecified() || target.is_broadcast() || target.is_multicast() || target.is_loopback()) => Ok(()),
+        _ => invalid("target must be a unicast IPv4 address"),
+    }
+}
+
+// Validate the failover settings and every per exit node override
+pub fn validate_router_failover(failover: &AgentRouterFailover) -> ValidationResult<()> {
+    validate_consecutive_failures(failover.consecutive_failures)?;
+    validate_failback_stability_secs(failover.failback_stability_secs)?;
+    validate_startup_grace_period_secs(failover.startup_grace_period_secs)?;
+    validate_health_monitor_interval_secs(failover.health_monitor_interval_secs)?;
+    validate_ping_history_size(failover.ping_history_size)?;
+    validate_sla_thresholds(failover.max_packet_loss_percent, failover.max_latency_ms, failover.max_jitter_ms)?;
+    validate_sla_hold_secs(failover.sla_hold_secs)?;
+    validate_max_handshake_age_secs(failover.max_handshake_age_secs)?;
+    for overrides in failover.exit_nodes.values() {
+        if let Some(value) = overrides.consecutive_failures {
+            validate_consecutive_failures(value)?;
+        }
+        if let Some(value) = overrides.failback_stability_secs {
+            validate_failback_stability_secs(value)?;
+        }
+        if let Some(value) = overrides.health_monitor_interval_secs {
+            validate_health_monitor_interval_secs(value)?;
+        }
+        if let Some(value) = overrides.ping_history_size {
+            validate_ping_history_size(value)?;
+        }
+        validate_sla_thresholds(overrides.max_packet_loss_percent, overrides.max_latency_ms, overrides.max_jitter_ms)?;
+        if let Some(value) = overrides.sla_hold_secs {
+            validate_sla_hold_secs(value)?;
+        }
+        if let Some(value) = overrides.max_handshake_age_secs {
+            validate_max_handshake_age_secs(value)?;
+        }
+        for probe in &overrides.probes {
+            validate_health_probe(probe)?;
+        }
+    }
+    // WireGuard routes a probe target through exactly one peer, so tunnels cannot share one
+    // (the local breakout, the nil UUID, goes out of the uplink and can)
+    let mut targets = std::collections::HashMap::new();
+    for (peer_id, overrides) in failover.exit_nodes.iter().filter(|(peer_id, _)| !peer_id.is_nil()) {
+        for target in overrides.probes.iter().filter_map(HealthProbe::target) {
+            if targets.insert(target, peer_id).is_some_and(|other| other != peer_id) {
+                return Err(ValidationError::InvalidHealthProbe(format!("{} is probed through more than one exit node", target)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// At least the web UI's 21 samples are kept; a day of 1s samples at most
+pub fn validate_telemetry(telemetry: &AgentTelemetry) -> ValidationResult<()> {
+    if !(100..=60_000).contains(&telemetry.interval_ms) {
+        return Err(ValidationError::InvalidTelemetryInterval());
+    }
+    if !(21..=86_400).contains(&telemetry.window) {
+        return Err(ValidationError::InvalidTelemetryWindow());
+    }
+    Ok(())
+}
+
+// Each quota needs a daily or monthly limit, and a limit of 0 would cut the peer off right away
+pub fn validate_usage(usage: &AgentUsage) -> ValidationResult<()> {
+    for quota in usage.quotas.values() {
+        let limits = [quota.daily_bytes, quota.monthly_bytes];
+        if limits.iter().all(Option::is_none) || limits.contains(&Some(0)) {
+            return Err(ValidationError::InvalidUsageQuota());
+        }
+    }
+    Ok(())
+}
+
+// A static token must be long enough not to be guessed, and fit in an Authorization header
+pub fn validate_web_metrics(metrics: &AgentWebMetrics) -> ValidationResult<()> {
+    match (&metrics.auth, &metrics.token) {
+        (MetricsAuth::Token, None) => Err(ValidationError::InvalidMetricsToken()),
+        (_, Some(token)) if token.len() < 16 || !token.chars().all(|c| c.is_ascii_graphic()) => {
+            Err(ValidationError::InvalidMetricsToken())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Sinks need a usable URL or mail setup; max_retries is capped so a dead sink gives up within the hour
+pub fn validate_notifications(notifications: &AgentNotifications) -> ValidationResult<()> {
+    if notifications.max_retries > 10 {
+        return Err(ValidationError::InvalidNotificationSink("max_retries must be 0-10".to_string()));
+    }
+    for sink in &notifications.sinks {
+        validate_notification_sink(sink)?;
+    }
+    Ok(())
+}
+
+pub fn validate_notification_sink(sink: &NotificationSink) -> ValidationResult<()> {
+    let invalid = |reason: String| Err(ValidationError::InvalidNotificationSink(reason));
+    match sink {
+        NotificationSink::Webhook { url, .. } | NotificationSink::Ntfy { url, .. } | NotificationSink::Gotify { url, .. } => {
+            if parse_http_url(url).is_none() {
+                return invalid(format!("{} is not an http:// or https:// URL", url));
+            }
+            if let NotificationSink::Gotify { token, .. } = sink
+                && token.is_empty() {
+                    return invalid("gotify needs an application token".to_string());
+                }
+            if let NotificationSink::Webhook { secret: Some(secret), .. } = sink
+                && secret.is_empty() {
+                    return invalid("webhook secret is empty".to_string());
+                }
+        }
+        NotificationSink::Smtp { host, port, security, username, password, from, to } => {
+            if host.is_empty() || *port == 0 {
+                return invalid("smtp needs a host and port".to_string());
+            }
+            if username.is_some() != password.is_some() {
+                return invalid("smtp username and password go together".to_string());
+            }
+            // AUTH PLAIN sends the password as is
+            if username.is_some() && *security == SmtpSecurity::None {
+                return invalid("smtp credentials need security tls or starttls".to_string());
+            }
+            if to.is_empty() {
+                return invalid("smtp needs at least one recipient".to_string());
+            }
+            // Addresses end up in SMTP commands and headers
+            for address in std::iter::once(from).chain(to) {
+                if !address.contains('@') || address.contains(|c: char| c.is_whitespace() || "<>".contains(c)) {
+                    return invalid(format!("{} is not a mail address", address));
+                }
+            }
+        }
+    }
+    Ok(())
+}
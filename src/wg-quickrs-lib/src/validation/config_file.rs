@@ -23,6 +23,9 @@ pub fn validate_config_file(config_file: &mut ConfigFile, config_folder_path: &P
             ConfigFileValidationError::Validation("agent.web.https.tls_key".to_string(), e)
         })?;
     }
+    validate_web_metrics(&config_file.agent.web.metrics).map_err(|e| {
+        ConfigFileValidationError::Validation("agent.web.metrics".to_string(), e)
+    })?;
     if config_file.agent.firewall.enabled {
         validate_fw_utility(&config_file.agent.firewall.utility).map_err(|e| {
             ConfigFileValidationError::Validation("agent.firewall.utility".to_string(), e)
@@ -32,6 +35,19 @@ pub fn validate_config_file(config_file: &mut ConfigFile, config_folder_path: &P
         })?;
     }
 
+    validate_router_failover(&config_file.agent.router.failover).map_err(|e| {
+        ConfigFileValidationError::Validation("agent.router.failover".to_string(), e)
+    })?;
+    validate_telemetry(&config_file.agent.telemetry).map_err(|e| {
+        ConfigFileValidationError::Validation("agent.telemetry".to_string(), e)
+    })?;
+    validate_usage(&config_file.agent.usage).map_err(|e| {
+        ConfigFileValidationError::Validation("agent.usage".to_string(), e)
+    })?;
+    validate_notifications(&config_file.agent.notifications).map_err(|e| {
+        ConfigFileValidationError::Validation("agent.notifications".to_string(), e)
+    })?;
+
     // Validate Network
     parse_and_validate_network_name(&config_file.network.name).map_err(|e| {
         ConfigFileValidationError::Validation("network.name".to_string(), e)
@@ -55,5 +55,39 @@ pub enum ValidationError {
     InvalidPersistentKeepalivePeriod(),
     #[error("allowed_ips is not in CIDR format")]
     InvalidAllowedIPs(),
+    #[error("consecutive_failures is invalid (1-100)")]
+    InvalidConsecutiveFailures(),
+    #[error("failback_stability_secs is invalid (0-86400)")]
+    InvalidFailbackStability(),
+    #[error("startup_grace_period_secs is invalid (0-3600)")]
+    InvalidStartupGracePeriod(),
+    #[error("health_monitor_interval_secs is invalid (1-3600)")]
+    InvalidHealthMonitorInterval(),
+    #[error("ping_history_size is invalid (1-3600)")]
+    InvalidPingHistorySize(),
+    #[error("max_packet_loss_percent is invalid (0-100)")]
+    InvalidMaxPacketLoss(),
+    #[error("max_latency_ms is invalid (1-60000)")]
+    InvalidMaxLatency(),
+    #[error("max_jitter_ms is invalid (1-60000)")]
+    InvalidMaxJitter(),
+    #[error("sla_hold_secs is invalid (0-3600)")]
+    InvalidSlaHold(),
+    #[error("liveness is invalid (ping, handshake or hybrid)")]
+    InvalidLiveness(),
+    #[error("max_handshake_age_secs is invalid (120-3600)")]
+    InvalidMaxHandshakeAge(),
+    #[error("telemetry interval is invalid (valid range: 100-60000 ms)")]
+    InvalidTelemetryInterval(),
+    #[error("telemetry window is invalid (valid range: 21-86400 samples)")]
+    InvalidTelemetryWindow(),
+    #[error("usage quota is invalid (daily_bytes and/or monthly_bytes, at least 1)")]
+    InvalidUsageQuota(),
+    #[error("metrics token is invalid (at least 16 printable ASCII characters, required for token auth)")]
+    InvalidMetricsToken(),
+    #[error("notification sink is invalid: {0}")]
+    InvalidNotificationSink(String),
+    #[error("health probe is invalid: {0}")]
+    InvalidHealthProbe(String),
 }
 pub type ValidationResult<T> = Result<T, ValidationError>;
@@ -5,6 +5,10 @@ use uuid::Uuid;
 use wg_quickrs_lib::validation::network::*;
 use wg_quickrs_lib::validation::agent::*;
 use wg_quickrs_lib::validation::error::*;
+use wg_quickrs_lib::types::config::{
+    parse_http_url, AgentNotifications, AgentRouterFailover, AgentTelemetry, AgentUsage, AgentWebMetrics, ExitNodeFailover, HealthProbe,
+    Liveness, MetricsAuth, NotificationSink, QuotaAction, SmtpSecurity, UsageQuota,
+};
 use wg_quickrs_lib::types::network::*;
 
 
@@ -63,6 +67,181 @@ fn test_validate_port() {
     );
 }
 
+#[test]
+fn test_validate_router_failover() {
+    ok!(validate_router_failover(&AgentRouterFailover::default()));
+    ok!(validate_consecutive_failures(1));
+    is_err!(validate_consecutive_failures(0), ValidationError::InvalidConsecutiveFailures());
+    ok!(validate_failback_stability_secs(0));
+    is_err!(validate_failback_stability_secs(86401), ValidationError::InvalidFailbackStability());
+    is_err!(validate_startup_grace_period_secs(3601), ValidationError::InvalidStartupGracePeriod());
+    is_err!(validate_health_monitor_interval_secs(0), ValidationError::InvalidHealthMonitorInterval());
+    is_err!(validate_ping_history_size(0), ValidationError::InvalidPingHistorySize());
+
+    // Overrides are validated like the defaults and only replace what they set
+    let exit_node = Uuid::new_v4();
+    let mut failover = AgentRouterFailover::default();
+    failover.exit_nodes.insert(exit_node, ExitNodeFailover { ping_history_size: Some(0), ..Default::default() });
+    is_err!(validate_router_failover(&failover), ValidationError::InvalidPingHistorySize());
+    failover.exit_nodes.insert(exit_node, ExitNodeFailover { consecutive_failures: Some(5), ..Default::default() });
+    ok!(validate_router_failover(&failover));
+    let effective = failover.for_exit_node(&exit_node);
+    assert_eq!(effective.consecutive_failures, 5);
+    assert_eq!(effective.ping_history_size, failover.ping_history_size);
+    assert_eq!(failover.for_exit_node(&Uuid::new_v4()).consecutive_failures, 3);
+}
+
+#[test]
+fn test_validate_router_sla() {
+    ok!(validate_max_packet_loss_percent(10.0));
+    is_err!(validate_max_packet_loss_percent(100.5), ValidationError::InvalidMaxPacketLoss());
+    is_err!(validate_max_packet_loss_percent(f64::NAN), ValidationError::InvalidMaxPacketLoss());
+    is_err!(validate_max_latency_ms(0), ValidationError::InvalidMaxLatency());
+    is_err!(validate_max_jitter_ms(60001), ValidationError::InvalidMaxJitter());
+    is_err!(validate_sla_hold_secs(3601), ValidationError::InvalidSlaHold());
+
+    // Thresholds are off unless set; an exit node can set its own
+    let exit_node = Uuid::new_v4();
+    let mut failover = AgentRouterFailover { max_latency_ms: Some(300), ..Default::default() };
+    failover.exit_nodes.insert(exit_node, ExitNodeFailover { max_packet_loss_percent: Some(-1.0), ..Default::default() });
+    is_err!(validate_router_failover(&failover), ValidationError::InvalidMaxPacketLoss());
+    failover.exit_nodes.insert(exit_node, ExitNodeFailover { max_packet_loss_percent: Some(10.0), ..Default::default() });
+    ok!(validate_router_failover(&failover));
+    let effective = failover.for_exit_node(&exit_node);
+    assert_eq!((effective.max_packet_loss_percent, effective.max_latency_ms, effective.max_jitter_ms), (Some(10.0), Some(300), None));
+    assert_eq!(failover.for_exit_node(&Uuid::new_v4()).max_packet_loss_percent, None);
+}
+
+#[test]
+fn test_validate_router_liveness() {
+    assert_eq!(parse_and_validate_liveness("hybrid"), Ok(Liveness::Hybrid));
+    is_err!(parse_and_validate_liveness("arp"), ValidationError::InvalidLiveness());
+    ok!(validate_max_handshake_age_secs(150));
+    is_err!(validate_max_handshake_age_secs(60), ValidationError::InvalidMaxHandshakeAge());
+
+    // Ping stays the default; an exit node that drops ICMP can use its handshake instead
+    let exit_node = Uuid::new_v4();
+    let mut failover = AgentRouterFailover::default();
+    failover.exit_nodes.insert(exit_node, ExitNodeFailover { liveness: Some(Liveness::Handshake), ..Default::default() });
+    ok!(validate_router_failover(&failover));
+    assert_eq!(failover.for_exit_node(&exit_node).liveness, Liveness::Handshake);
+    assert_eq!(failover.for_exit_node(&Uuid::new_v4()).liveness, Liveness::Ping);
+    failover.exit_nodes.insert(exit_node, ExitNodeFailover { max_handshake_age_secs: Some(5000), ..Default::default() });
+    is_err!(validate_router_failover(&failover), ValidationError::InvalidMaxHandshakeAge());
+}
+
+#[test]
+fn test_validate_notifications() {
+    let url = parse_http_url("https://ntfy.example.com:8443/alerts?priority=4").unwrap();
+    assert_eq!((url.https, url.host.as_str(), url.port, url.path.as_str()), (true, "ntfy.example.com", 8443, "/alerts?priority=4"));
+    assert_eq!(parse_http_url("http://10.0.0.1?x=1").unwrap().path, "/?x=1");
+    assert!(parse_http_url("ftp://example.com/").is_none());
+
+    let webhook = |url: &str| NotificationSink::Webhook { url: url.to_string(), secret: Some("s3cret".to_string()) };
+    let smtp = |to: &str| NotificationSink::Smtp {
+        host: "mail.example.com".to_string(),
+        port: 587,
+        security: SmtpSecurity::Starttls,
+        username: None,
+        password: None,
+        from: "router@example.com".to_string(),
+        to: vec![to.to_string()],
+    };
+    let mut notifications = AgentNotifications {
+        sinks: vec![webhook("https://hooks.example.com/wg"), smtp("ops@example.com")],
+        ..Default::default()
+    };
+    ok!(validate_notifications(&notifications));
+    notifications.max_retries = 11;
+    assert!(matches!(validate_notifications(&notifications), Err(ValidationError::InvalidNotificationSink(_))));
+    assert!(matches!(validate_notification_sink(&webhook("hooks.example.com")), Err(ValidationError::InvalidNotificationSink(_))));
+    assert!(matches!(validate_notification_sink(&smtp("ops@example.com>\r\nRCPT TO:<x@y")), Err(ValidationError::InvalidNotificationSink(_))));
+    assert!(matches!(
+        validate_notification_sink(&NotificationSink::Gotify { url: "https://push.example.com".to_string(), token: String::new() }),
+        Err(ValidationError::InvalidNotificationSink(_))
+    ));
+
+    // Credentials only over an encrypted connection
+    let smtp_auth = |security: SmtpSecurity| NotificationSink::Smtp {
+        host: "mail.example.com".to_string(),
+        port: 25,
+        security,
+        username: Some("router".to_string()),
+        password: Some("s3cret".to_string()),
+        from: "router@example.com".to_string(),
+        to: vec!["ops@example.com".to_string()],
+    };
+    ok!(validate_notification_sink(&smtp_auth(SmtpSecurity::Starttls)));
+    ok!(validate_notification_sink(&smtp_auth(SmtpSecurity::Tls)));
+    assert!(matches!(validate_notification_sink(&smtp_auth(SmtpSecurity::None)), Err(ValidationError::InvalidNotificationSink(_))));
+}
+
+#[test]
+fn test_validate_web_metrics() {
+    let mut metrics = AgentWebMetrics { enabled: true, ..Default::default() };
+    ok!(validate_web_metrics(&metrics));
+    metrics.auth = MetricsAuth::Token;
+    assert!(matches!(validate_web_metrics(&metrics), Err(ValidationError::InvalidMetricsToken())));
+    metrics.token = Some("short".to_string());
+    assert!(matches!(validate_web_metrics(&metrics), Err(ValidationError::InvalidMetricsToken())));
+    metrics.token = Some("prometheus-scrape-token".to_string());
+    ok!(validate_web_metrics(&metrics));
+}
+
+#[test]
+fn test_validate_telemetry() {
+    let mut telemetry = AgentTelemetry::default();
+    ok!(validate_telemetry(&telemetry));
+    telemetry.interval_ms = 50;
+    assert!(matches!(validate_telemetry(&telemetry), Err(ValidationError::InvalidTelemetryInterval())));
+    telemetry.interval_ms = 500;
+    telemetry.window = 20;
+    assert!(matches!(validate_telemetry(&telemetry), Err(ValidationError::InvalidTelemetryWindow())));
+}
+
+#[test]
+fn test_validate_usage() {
+    let mut usage = AgentUsage::default();
+    ok!(validate_usage(&usage));
+    let peer_id = Uuid::new_v4();
+    usage.quotas.insert(peer_id, UsageQuota { action: QuotaAction::Disconnect, ..Default::default() });
+    assert!(matches!(validate_usage(&usage), Err(ValidationError::InvalidUsageQuota())));
+    usage.quotas.get_mut(&peer_id).unwrap().monthly_bytes = Some(0);
+    assert!(matches!(validate_usage(&usage), Err(ValidationError::InvalidUsageQuota())));
+    usage.quotas.get_mut(&peer_id).unwrap().monthly_bytes = Some(20_000_000_000);
+    ok!(validate_usage(&usage));
+}
+
+#[test]
+fn test_validate_health_probes() {
+    let http = |url: &str| HealthProbe::Http { url: url.to_string(), host: None };
+    ok!(validate_health_probe(&http("http://1.1.1.1")));
+    ok!(validate_health_probe(&http("http://1.1.1.1:8080/generate_204")));
+    assert!(matches!(validate_health_probe(&http("https://1.1.1.1/")), Err(ValidationError::InvalidHealthProbe(_))));
+    assert!(matches!(validate_health_probe(&http("http://example.com/")), Err(ValidationError::InvalidHealthProbe(_))));
+    ok!(validate_health_probe(&HealthProbe::Dns { server: Ipv4Addr::new(9, 9, 9, 9), name: "example.com".to_string() }));
+    assert!(matches!(
+        validate_health_probe(&HealthProbe::Dns { server: Ipv4Addr::new(9, 9, 9, 9), name: "bad name".to_string() }),
+        Err(ValidationError::InvalidHealthProbe(_))
+    ));
+    assert!(matches!(
+        validate_health_probe(&HealthProbe::Tcp { address: "0.0.0.0:443".parse().unwrap() }),
+        Err(ValidationError::InvalidHealthProbe(_))
+    ));
+
+    // A target can only be probed through one tunnel, but the local breakout may reuse it
+    let tcp = HealthProbe::Tcp { address: "1.1.1.1:443".parse().unwrap() };
+    let mut failover = AgentRouterFailover::default();
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    failover.exit_nodes.insert(a, ExitNodeFailover { probes: vec![tcp.clone(), http("http://1.1.1.1/")], ..Default::default() });
+    failover.exit_nodes.insert(Uuid::nil(), ExitNodeFailover { probes: vec![tcp.clone()], ..Default::default() });
+    ok!(validate_router_failover(&failover));
+    assert_eq!(failover.probes_for(&a).len(), 2);
+    assert!(failover.probes_for(&b).is_empty());
+    failover.exit_nodes.insert(b, ExitNodeFailover { probes: vec![tcp], ..Default::default() });
+    assert!(matches!(validate_router_failover(&failover), Err(ValidationError::InvalidHealthProbe(_))));
+}
+
 // Network Fields
 
 #[test]
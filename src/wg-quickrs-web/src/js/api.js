@@ -129,6 +129,13 @@ export default class API {
         });
     }
 
+    async get_peer_route_status() {
+        return this.call({
+            method: 'get',
+            path: '/api/mode/peer-route-status',
+        });
+    }
+
     async update_peer_route_status(prefix, active_peer_id, backup_peer_ids, timeout = 30000) {
         // Create AbortController for timeout
         const controller = new AbortController();
@@ -207,6 +214,170 @@ export default class API {
         });
     }
 
+    async get_local_breakout() {
+        return this.call({
+            method: 'get',
+            path: '/api/router-mode/local-breakout',
+        });
+    }
+
+    async set_local_breakout(data) {
+        return this.call({
+            method: 'post',
+            path: '/api/router-mode/local-breakout',
+            body: data
+        });
+    }
+
+    async get_failover_tuning() {
+        return this.call({
+            method: 'get',
+            path: '/api/router-mode/failover',
+        });
+    }
+
+    async set_failover_tuning(data) {
+        return this.call({
+            method: 'post',
+            path: '/api/router-mode/failover',
+            body: data
+        });
+    }
+
+    async get_load_balance() {
+        return this.call({
+            method: 'get',
+            path: '/api/router-mode/load-balance',
+        });
+    }
+
+    async set_load_balance(data) {
+        return this.call({
+            method: 'post',
+            path: '/api/router-mode/load-balance',
+            body: data
+        });
+    }
+
+    async get_client_exits() {
+        return this.call({
+            method: 'get',
+            path: '/api/router-mode/client-exits',
+        });
+    }
+
+    async set_client_exits(data) {
+        return this.call({
+            method: 'post',
+            path: '/api/router-mode/client-exits',
+            body: data
+        });
+    }
+
+    async get_domain_routes() {
+        return this.call({
+            method: 'get',
+            path: '/api/router-mode/domain-routes',
+        });
+    }
+
+    async set_domain_routes(data) {
+        return this.call({
+            method: 'post',
+            path: '/api/router-mode/domain-routes',
+            body: data
+        });
+    }
+
+    async get_gateway_groups() {
+        return this.call({
+            method: 'get',
+            path: '/api/router-mode/gateway-groups',
+        });
+    }
+
+    async set_gateway_groups(data) {
+        return this.call({
+            method: 'post',
+            path: '/api/router-mode/gateway-groups',
+            body: data
+        });
+    }
+
+    async get_health_history(peer, {from, to, step} = {}) {
+        const params = new URLSearchParams({peer});
+        if (from !== undefined) params.set('from', from);
+        if (to !== undefined) params.set('to', to);
+        if (step !== undefined) params.set('step', step);
+        return this.call({
+            method: 'get',
+            path: `/api/health/history?${params}`,
+        });
+    }
+
+    async get_events(filter = {}) {
+        const params = new URLSearchParams();
+        for (const [key, value] of Object.entries(filter)) {
+            if (value !== undefined && value !== null) params.set(key, value);
+        }
+        return this.call({
+            method: 'get',
+            path: `/api/events?${params}`,
+        });
+    }
+
+    async post_notifications_test() {
+        return this.call({
+            method: 'post',
+            path: '/api/notifications/test',
+        });
+    }
+
+    // Live updates from /api/stream (server-sent events, read with fetch so the token can be sent).
+    // onMessage(type, data) runs for each update, onClose(error) once the stream ends.
+    // Returns a function that closes the stream.
+    subscribe(onMessage, onClose) {
+        const controller = new AbortController();
+        const headers = {};
+        if (this.token !== '') {
+            headers["Authorization"] = `Bearer ${this.token}`;
+        }
+        (async () => {
+            const res = await fetch(`${import.meta.env.VITE_API_FETCH_URL_PREFIX}/api/stream`, {
+                headers: headers,
+                signal: controller.signal,
+            });
+            if (res.status === 401) {
+                this.does_need_auth = true;
+            }
+            if (!res.ok || !res.body) {
+                throw new Error(`Server returned ${res.status} ${res.statusText}`);
+            }
+            const reader = res.body.getReader();
+            const decoder = new TextDecoder();
+            let buffer = '';
+            for (;;) {
+                const {value, done} = await reader.read();
+                if (done) break;
+                buffer += decoder.decode(value, {stream: true});
+                let end;
+                while ((end = buffer.indexOf('\n\n')) !== -1) {
+                    const block = buffer.slice(0, end);
+                    buffer = buffer.slice(end + 2);
+                    let type = 'message';
+                    let data = '';
+                    for (const line of block.split('\n')) {
+                        if (line.startsWith('event: ')) type = line.slice(7);
+                        else if (line.startsWith('data: ')) data += line.slice(6);
+                    }
+                    // Lines starting with ':' are keep-alives
+                    if (data) onMessage(type, JSON.parse(data));
+                }
+            }
+        })().then(() => onClose(null), err => onClose(controller.signal.aborted ? null : err));
+        return () => controller.abort();
+    }
+
     async restore_routing_table() {
         return this.call({
             method: 'post',
@@ -728,6 +728,7 @@ pub fn initialize_agent(init_opts: &InitOptions) -> Result<(), AgentInitError> {
                     enabled: agent_web_password_enabled,
                     hash: agent_web_password_hash,
                 },
+                metrics: AgentWebMetrics::default(),
             },
             vpn: AgentVpn {
                 enabled: agent_vpn_enabled,
@@ -739,6 +740,9 @@ pub fn initialize_agent(init_opts: &InitOptions) -> Result<(), AgentInitError> {
                 gateway: agent_firewall_gateway,
             },
             router: wg_quickrs_lib::types::config::AgentRouter::default(),
+            notifications: wg_quickrs_lib::types::config::AgentNotifications::default(),
+            telemetry: wg_quickrs_lib::types::config::AgentTelemetry::default(),
+            usage: wg_quickrs_lib::types::config::AgentUsage::default(),
         },
         network: Network {
             name: network_name.to_string(),
@@ -1,2 +1,4 @@
 pub mod init;
-pub mod run;
\ No newline at end of file
+pub mod router;
+pub mod run;
+pub mod state;
@@ -0,0 +1,66 @@
+use crate::conf::{self, util::ConfUtilError};
+use crate::mode::change_plan::ChangePlan;
+use crate::mode::mode::{self as mode_switch, ModeError, SystemMode};
+use crate::mode::routing_pbr::{self, PolicyRoutingError};
+use thiserror::Error;
+use uuid::Uuid;
+use wg_quickrs_cli::agent::RouterCommands;
+
+#[derive(Error, Debug)]
+pub enum AgentRouterError {
+    #[error("configuration error: {0}")]
+    Conf(#[from] ConfUtilError),
+    #[error("{0}")]
+    Mode(#[from] ModeError),
+    #[error("{0}")]
+    Routing(#[from] PolicyRoutingError),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+// These commands only plan. Applying a change belongs to the running agent, which holds the
+// routing lock, the health monitor and the confirm timer; a second process changing the kernel
+// and the state file behind its back would race all three.
+pub fn handle_router_command(target: &RouterCommands) -> Result<(), AgentRouterError> {
+    let plan = match target {
+        RouterCommands::Mode { mode, lan_cidr } => mode_switch::plan_switch_mode(SystemMode::from(mode.as_str()), lan_cidr.clone())?,
+        RouterCommands::ExitNode { peer } => plan_exit_node(peer)?,
+        RouterCommands::LanAccess { peer, allowed } => plan_lan_access(peer, *allowed)?,
+    };
+    print_plan(&plan);
+    Ok(())
+}
+
+// One command line per change, so the output can be reviewed as is
+fn print_plan(plan: &ChangePlan) {
+    println!("# {}: {} change(s)", plan.operation, plan.changes.len());
+    if !plan.complete {
+        println!("# incomplete: the change may make more changes than listed (see the notes)");
+    }
+    for change in &plan.changes {
+        println!("{}", change.command);
+    }
+    for note in &plan.notes {
+        println!("# note: {}", note);
+    }
+    println!("# nothing was changed: apply the change through the running agent (web UI or API)");
+}
+
+fn plan_exit_node(peer_id: &Uuid) -> Result<ChangePlan, AgentRouterError> {
+    let config = conf::util::get_config()?;
+    Ok(routing_pbr::plan_set_exit_node(peer_id, &config.network)?)
+}
+
+fn plan_lan_access(peer_id: &Uuid, allowed: bool) -> Result<ChangePlan, AgentRouterError> {
+    let config = conf::util::get_config()?;
+    if config.agent.router.mode.as_str() != "router" {
+        return Err(AgentRouterError::Invalid("LAN access control is only available in Router Mode".to_string()));
+    }
+    if *peer_id == config.network.this_peer {
+        return Err(AgentRouterError::Invalid("Cannot change LAN access for the router itself".to_string()));
+    }
+    if !config.network.peers.contains_key(peer_id) {
+        return Err(AgentRouterError::Invalid(format!("Peer {} not found", peer_id)));
+    }
+    Ok(routing_pbr::plan_set_peer_lan_access(peer_id, allowed, &config.network)?)
+}
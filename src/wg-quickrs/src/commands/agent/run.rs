@@ -55,6 +55,27 @@ pub async fn run_agent() -> Result<(), AgentRunError> {
             }
         });
         
+        // Converge routing and firewall state to the mode state and config, on change and periodically
+        tokio::spawn(async {
+            if let Err(e) = mode::reconcile::start_reconciler().await {
+                log::error!("Reconciler error: {}", e);
+            }
+        });
+        
+        // Roll back mode switches and LAN CIDR changes that are not confirmed in time
+        tokio::spawn(async {
+            if let Err(e) = mode::confirm::start_rollback_timer().await {
+                log::error!("Rollback timer error: {}", e);
+            }
+        });
+        
+        // Keep domain-based split tunneling routes in line with DNS
+        tokio::spawn(async {
+            if let Err(e) = mode::routing_pbr::start_domain_route_resolver().await {
+                log::error!("Domain route resolver error: {}", e);
+            }
+        });
+        
         let web_future = server::run_web_server(cfg);
         let vpn_future = wireguard::cmd::run_vpn_server(cfg);
     try_join!(web_future, vpn_future)?;
@@ -0,0 +1,48 @@
+use crate::mode::persist::{self, PersistenceError, MODE_STATE_VERSION};
+use thiserror::Error;
+use wg_quickrs_cli::agent::StateCommands;
+
+#[derive(Error, Debug)]
+pub enum AgentStateError {
+    #[error("router mode state error: {0}")]
+    Persistence(#[from] PersistenceError),
+}
+
+pub fn handle_state_command(target: &StateCommands) -> Result<(), AgentStateError> {
+    match target {
+        StateCommands::Check => check_state(),
+        StateCommands::Migrate => migrate_state(),
+    }
+}
+
+// Load and migrate the state file in memory and summarize it
+fn check_state() -> Result<(), AgentStateError> {
+    let Some((state, stored_version)) = persist::check_mode_state()? else {
+        log::info!("No Router Mode state file found (Host Mode)");
+        return Ok(());
+    };
+    if stored_version < MODE_STATE_VERSION {
+        log::info!("Router Mode state is in format version {} and will be migrated to {} on the next load \
+            (or with `wg-quickrs agent state migrate`)", stored_version, MODE_STATE_VERSION);
+    } else {
+        log::info!("Router Mode state is in the current format version {}", MODE_STATE_VERSION);
+    }
+    log::info!("mode: {:?}, LAN CIDR: {}, exit node tables: {}, gateway groups: {} (active: {}), client exits: {}, domain routes: {}",
+        state.last_mode,
+        state.lan_cidr.as_deref().unwrap_or("none"),
+        state.peer_table_ids.len(),
+        state.gateway_groups.len(),
+        state.active_gateway_group.as_deref().unwrap_or("none"),
+        state.client_exits.len(),
+        state.domain_routes.len());
+    Ok(())
+}
+
+fn migrate_state() -> Result<(), AgentStateError> {
+    match persist::migrate_mode_state()? {
+        None => log::info!("No Router Mode state file found, nothing to migrate"),
+        Some(MODE_STATE_VERSION) => log::info!("Router Mode state is already in the current format version {}", MODE_STATE_VERSION),
+        Some(_) => {} // logged by the migration
+    }
+    Ok(())
+}
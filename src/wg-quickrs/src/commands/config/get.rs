@@ -174,6 +174,8 @@ impl_config_getter!(get_agent_web_https, agent.web.https, yaml);
 impl_config_getter!(get_agent_web_password, agent.web.password, yaml);
 impl_config_getter!(get_agent_vpn, agent.vpn, yaml);
 impl_config_getter!(get_agent_firewall, agent.firewall, yaml);
+impl_config_getter!(get_agent_router, agent.router, yaml);
+impl_config_getter!(get_agent_router_failover, agent.router.failover, yaml);
 
 // Agent individual field getters
 impl_config_getter!(get_agent_web_address, agent.web.address);
@@ -190,6 +192,7 @@ impl_config_getter!(get_agent_vpn_port, agent.vpn.port);
 impl_config_getter!(get_agent_firewall_enabled, agent.firewall.enabled);
 impl_config_getter!(get_agent_firewall_utility, agent.firewall.utility, display);
 impl_config_getter!(get_agent_firewall_gateway, agent.firewall.gateway);
+impl_config_getter!(get_agent_router_mode, agent.router.mode);
 
 // Network struct getter
 impl_config_getter!(get_network, network, yaml);
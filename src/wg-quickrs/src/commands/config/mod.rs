@@ -98,6 +98,7 @@ pub fn handle_config_command(target: &ConfigCommands) -> Result<(), ConfigComman
                     EnableAgentWebCommands::Http => toggle_agent_web_http(true),
                     EnableAgentWebCommands::Https => toggle_agent_web_https(true),
                     EnableAgentWebCommands::Password => toggle_agent_web_password(true),
+                    EnableAgentWebCommands::Metrics => toggle_agent_web_metrics(true),
                 },
                 EnableAgentCommands::Vpn => toggle_agent_vpn(true),
                 EnableAgentCommands::Firewall => toggle_agent_firewall(true),
@@ -128,6 +129,7 @@ pub fn handle_config_command(target: &ConfigCommands) -> Result<(), ConfigComman
                     DisableAgentWebCommands::Http => toggle_agent_web_http(false),
                     DisableAgentWebCommands::Https => toggle_agent_web_https(false),
                     DisableAgentWebCommands::Password => toggle_agent_web_password(false),
+                    DisableAgentWebCommands::Metrics => toggle_agent_web_metrics(false),
                 },
                 DisableAgentCommands::Vpn => toggle_agent_vpn(false),
                 DisableAgentCommands::Firewall => toggle_agent_firewall(false),
@@ -172,6 +174,21 @@ pub fn handle_config_command(target: &ConfigCommands) -> Result<(), ConfigComman
                     SetAgentFirewallCommands::Utility { value } => set_agent_firewall_utility(value),
                     SetAgentFirewallCommands::Gateway { value } => set_agent_firewall_gateway(value),
                 },
+                SetAgentCommands::Router { target } => match target {
+                    SetAgentRouterCommands::Failover { target } => match target {
+                        SetAgentRouterFailoverCommands::ConsecutiveFailures { value, exit_node } => set_agent_router_failover_consecutive_failures(exit_node, *value),
+                        SetAgentRouterFailoverCommands::FailbackStabilitySecs { value, exit_node } => set_agent_router_failover_failback_stability_secs(exit_node, *value),
+                        SetAgentRouterFailoverCommands::StartupGracePeriodSecs { value } => set_agent_router_failover_startup_grace_period_secs(*value),
+                        SetAgentRouterFailoverCommands::HealthMonitorIntervalSecs { value, exit_node } => set_agent_router_failover_health_monitor_interval_secs(exit_node, *value),
+                        SetAgentRouterFailoverCommands::PingHistorySize { value, exit_node } => set_agent_router_failover_ping_history_size(exit_node, *value),
+                        SetAgentRouterFailoverCommands::MaxPacketLossPercent { value, exit_node } => set_agent_router_failover_max_packet_loss_percent(exit_node, *value),
+                        SetAgentRouterFailoverCommands::MaxLatencyMs { value, exit_node } => set_agent_router_failover_max_latency_ms(exit_node, *value),
+                        SetAgentRouterFailoverCommands::MaxJitterMs { value, exit_node } => set_agent_router_failover_max_jitter_ms(exit_node, *value),
+                        SetAgentRouterFailoverCommands::SlaHoldSecs { value, exit_node } => set_agent_router_failover_sla_hold_secs(exit_node, *value),
+                        SetAgentRouterFailoverCommands::Liveness { value, exit_node } => set_agent_router_failover_liveness(exit_node, value.clone()),
+                        SetAgentRouterFailoverCommands::MaxHandshakeAgeSecs { value, exit_node } => set_agent_router_failover_max_handshake_age_secs(exit_node, *value),
+                    },
+                },
             },
             SetCommands::Network { target } => match target {
                 SetNetworkCommands::Name { name } => set_network_name(name.clone()),
@@ -210,6 +227,9 @@ pub fn handle_config_command(target: &ConfigCommands) -> Result<(), ConfigComman
                         reset_web_password(password)
                     },
                 },
+                ResetAgentCommands::Router { target } => match target {
+                    ResetAgentRouterCommands::Failover { exit_node } => reset_agent_router_failover(exit_node),
+                },
             },
             ResetCommands::Network { target } => match target {
                 ResetNetworkCommands::Peer { id, target } => match target {
@@ -268,6 +288,13 @@ pub fn handle_config_command(target: &ConfigCommands) -> Result<(), ConfigComman
                             GetAgentFirewallCommands::Gateway => get_agent_firewall_gateway(),
                         },
                     },
+                    GetAgentCommands::Router { target } => match target {
+                        None => get_agent_router(),
+                        Some(router_cmd) => match router_cmd {
+                            GetAgentRouterCommands::Mode => get_agent_router_mode(),
+                            GetAgentRouterCommands::Failover => get_agent_router_failover(),
+                        },
+                    },
                 },
             },
             GetCommands::Network { target } => match target {
@@ -4,6 +4,7 @@ use uuid::Uuid;
 use crate::commands::config::{parse_connection_id, ConfigCommandError};
 use crate::commands::helpers;
 use crate::conf;
+use wg_quickrs_lib::types::config::AgentRouterFailover;
 
 pub fn reset_web_password(reset_web_password_opts: &Option<String>) -> Result<(), ConfigCommandError> {
     // get the wireguard config a file path
@@ -33,6 +34,23 @@ pub fn reset_web_password(reset_web_password_opts: &Option<String>) -> Result<()
     Ok(())
 }
 
+/// Reset failover tuning to the defaults, or remove the overrides of one exit node
+pub fn reset_agent_router_failover(exit_node: &Option<Uuid>) -> Result<(), ConfigCommandError> {
+    let mut config = conf::util::get_config()?;
+    match exit_node {
+        None => {
+            config.agent.router.failover = AgentRouterFailover::default();
+            log::info!("Reset failover tuning to the defaults");
+        }
+        Some(id) => {
+            config.agent.router.failover.exit_nodes.remove(id);
+            log::info!("Removed failover tuning overrides of exit node {}", id);
+        }
+    }
+    conf::util::set_config(&mut config)?;
+    Ok(())
+}
+
 
 /// Reset peer private key (generates new WireGuard key)
 pub fn reset_peer_private_key(id: &Uuid) -> Result<(), ConfigCommandError> {
@@ -8,7 +8,12 @@ use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::str::FromStr;
 use uuid::Uuid;
-use wg_quickrs_lib::validation::agent::{parse_and_validate_fw_gateway, validate_fw_utility, validate_tls_file};
+use wg_quickrs_lib::validation::agent::{
+    parse_and_validate_fw_gateway, parse_and_validate_liveness, validate_consecutive_failures, validate_failback_stability_secs,
+    validate_fw_utility, validate_health_monitor_interval_secs, validate_max_handshake_age_secs,
+    validate_max_jitter_ms, validate_max_latency_ms, validate_max_packet_loss_percent, validate_ping_history_size,
+    validate_sla_hold_secs, validate_startup_grace_period_secs, validate_tls_file,
+};
 use wg_quickrs_lib::validation::error::ValidationError;
 use crate::WG_QUICKRS_CONFIG_FOLDER;
 
@@ -135,6 +140,135 @@ impl_setter!(
     transform: |gateway: &str| parse_and_validate_fw_gateway(gateway)
 );
 
+// ============================================================================
+// Agent Router Failover Configuration Functions
+// ============================================================================
+
+/// Macro for implementing failover tuning setters (global, or as an override for one exit node)
+/// `optional` is for settings that are off unless set (the SLA thresholds)
+macro_rules! impl_failover_setter {
+    ($fn_name:ident, $value_type:ty, $field:ident, $field_name:expr, $validator:expr) => {
+        impl_failover_setter!($fn_name, $value_type, $field, $field_name, $validator, |value| value);
+    };
+    ($fn_name:ident, $value_type:ty, $field:ident, $field_name:expr, $validator:expr, optional) => {
+        impl_failover_setter!($fn_name, $value_type, $field, $field_name, $validator, Some);
+    };
+    ($fn_name:ident, $value_type:ty, $field:ident, $field_name:expr, $validator:expr, $global:expr) => {
+        pub fn $fn_name(exit_node: &Option<Uuid>, value: $value_type) -> Result<(), ConfigCommandError> {
+            let mut config = conf::util::get_config()?;
+            let value = $validator(value)?;
+            match exit_node {
+                None => {
+                    log::info!("Setting {} to {}", $field_name, value);
+                    config.agent.router.failover.$field = $global(value);
+                }
+                Some(id) => {
+                    // The nil UUID is the local breakout (direct via the uplink)
+                    if !id.is_nil() && !config.network.peers.contains_key(id) {
+                        return Err(ConfigCommandError::PeerNotFound(*id));
+                    }
+                    log::info!("Setting {} for exit node {} to {}", $field_name, id, value);
+                    config.agent.router.failover.exit_nodes.entry(*id).or_default().$field = Some(value);
+                }
+            }
+            conf::util::set_config(&mut config)?;
+            Ok(())
+        }
+    };
+}
+
+impl_failover_setter!(
+    set_agent_router_failover_consecutive_failures,
+    u32,
+    consecutive_failures,
+    "failover consecutive failures",
+    validate_consecutive_failures
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_failback_stability_secs,
+    u64,
+    failback_stability_secs,
+    "failover fail-back stability",
+    validate_failback_stability_secs
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_health_monitor_interval_secs,
+    u64,
+    health_monitor_interval_secs,
+    "health monitor interval",
+    validate_health_monitor_interval_secs
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_ping_history_size,
+    usize,
+    ping_history_size,
+    "ping history size",
+    validate_ping_history_size
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_max_packet_loss_percent,
+    f64,
+    max_packet_loss_percent,
+    "SLA max packet loss",
+    validate_max_packet_loss_percent,
+    optional
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_max_latency_ms,
+    u64,
+    max_latency_ms,
+    "SLA max latency",
+    validate_max_latency_ms,
+    optional
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_max_jitter_ms,
+    u64,
+    max_jitter_ms,
+    "SLA max jitter",
+    validate_max_jitter_ms,
+    optional
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_sla_hold_secs,
+    u64,
+    sla_hold_secs,
+    "SLA hold time",
+    validate_sla_hold_secs
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_liveness,
+    String,
+    liveness,
+    "liveness",
+    |value: String| parse_and_validate_liveness(&value)
+);
+
+impl_failover_setter!(
+    set_agent_router_failover_max_handshake_age_secs,
+    u64,
+    max_handshake_age_secs,
+    "max handshake age",
+    validate_max_handshake_age_secs
+);
+
+/// Set the startup grace period (global only)
+pub fn set_agent_router_failover_startup_grace_period_secs(value: u64) -> Result<(), ConfigCommandError> {
+    let mut config = conf::util::get_config()?;
+    config.agent.router.failover.startup_grace_period_secs = validate_startup_grace_period_secs(value)?;
+    log::info!("Setting failover startup grace period to {}", value);
+    conf::util::set_config(&mut config)?;
+    Ok(())
+}
+
 /// Set network name
 pub fn set_network_name(name: String) -> Result<(), ConfigCommandError> {
     let mut config = conf::util::get_config()?;
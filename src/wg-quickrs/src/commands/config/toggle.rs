@@ -1,6 +1,6 @@
 use argon2::PasswordHash;
 use uuid::Uuid;
-use wg_quickrs_lib::validation::agent::{validate_fw_utility, validate_tls_file};
+use wg_quickrs_lib::validation::agent::{validate_fw_utility, validate_tls_file, validate_web_metrics};
 use crate::conf;
 use crate::commands::config::{parse_connection_id, ConfigCommandError};
 use crate::WG_QUICKRS_CONFIG_FOLDER;
@@ -144,6 +144,16 @@ impl_toggle!(
     }
 );
 
+impl_toggle!(
+    toggle_agent_web_metrics,
+    agent.web.metrics =>
+    |c: &wg_quickrs_lib::types::config::Config| format!("Prometheus /metrics endpoint (auth={})...", c.agent.web.metrics.auth),
+    validate: |c: &wg_quickrs_lib::types::config::Config| -> Result<(), ConfigCommandError> {
+        validate_web_metrics(&c.agent.web.metrics)?;
+        Ok(())
+    }
+);
+
 impl_toggle!(
     toggle_agent_vpn,
     agent.vpn =>
@@ -89,12 +89,12 @@ pub(crate) fn patch_network_config(body: web::Bytes) -> Result<HttpResponse, Htt
                         })?;
                     }
                     if let Some(address) = &peer_details.address {
-                        old_address_opt = Some(peer_config.address.clone());
+                        old_address_opt = Some(peer_config.address);
                         network_copy.peers.retain(|id, _| id != peer_id);
                         peer_config.address = validate_peer_address(address, &network_copy).map_err(|e| {
                             HttpResponse::BadRequest().body(format!("changed_fields.peers.{}.address: {}", peer_id, e))
                         })?;
-                        new_address_opt = Some(peer_config.address.clone());
+                        new_address_opt = Some(peer_config.address);
                     }
                     if let Some(endpoint) = &peer_details.endpoint {
                         peer_config.endpoint = validate_peer_endpoint(endpoint).map_err(|e| {
@@ -214,8 +214,8 @@ pub(crate) fn patch_network_config(body: web::Bytes) -> Result<HttpResponse, Htt
                     
                     // STEP 4: Update routes for both peers if Router Mode is active and allowed_ips changed
                     // Check mode directly from config we already have (avoid deadlock)
-                    if SystemMode::from(c.agent.router.mode.as_str()) == SystemMode::Router {
-                        if connection_details.allowed_ips_a_to_b.is_some() || connection_details.allowed_ips_b_to_a.is_some() {
+                    if SystemMode::from(c.agent.router.mode.as_str()) == SystemMode::Router
+                        && (connection_details.allowed_ips_a_to_b.is_some() || connection_details.allowed_ips_b_to_a.is_some()) {
                             let wg_interface = &c.network_w_digest.network.name;
                             
                             // Update routes for peer A (skip if host peer)
@@ -236,7 +236,6 @@ pub(crate) fn patch_network_config(body: web::Bytes) -> Result<HttpResponse, Htt
                                 log::debug!("Skipping routing table update for host peer {} in connection", connection_id.b);
                             }
                         }
-                    }
                 } else {
                     return Err(HttpResponse::NotFound().body(format!("connection '{}' does not exist", connection_id)));
                 }
@@ -386,6 +385,11 @@ pub(crate) fn patch_network_config(body: web::Bytes) -> Result<HttpResponse, Htt
                                     log::info!("[STEP4] Successfully installed PBR rules for peer {}", peer_id);
                                 }
                                 
+                                // LAN access rule for this peer, moving the peers after it
+                                if let Err(e) = routing_pbr::sync_peer_lan_access_rules(&c.network_w_digest.network) {
+                                    log::warn!("[STEP4] Failed to update LAN access rules for peer {}: {}", peer_id, e);
+                                }
+                                
                                 // If this peer has default route and no exit node is set, set it as exit node
                                 if (routes.contains(&"0.0.0.0/0".to_string()) || routes.contains(&"default".to_string())) 
                                     && routing_pbr::get_exit_node().unwrap_or(None).is_none() {
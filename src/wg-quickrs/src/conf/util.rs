@@ -1,8 +1,11 @@
 use crate::{WG_QUICKRS_CONFIG_FILE, WG_QUICKRS_CONFIG_FOLDER};
-use crate::wireguard::cmd::{get_telemetry, status_tunnel};
-use wg_quickrs_lib::types::config::{Config, ConfigFile, ConfigWNetworkDigest};
+use crate::wireguard::cmd::status_tunnel;
+use crate::wireguard::telemetry;
+use crate::web::stream::StreamMessage;
+use wg_quickrs_lib::types::config::{AgentNotifications, AgentRouterFailover, AgentUsage, Config, ConfigFile, ConfigWNetworkDigest};
 use wg_quickrs_lib::types::api::{Summary};
 use wg_quickrs_lib::types::misc::{WireGuardStatus};
+use wg_quickrs_lib::validation::agent::{validate_notifications, validate_router_failover, validate_usage};
 use wg_quickrs_lib::validation::config_file::{validate_config_file, ConfigFileValidationError};
 use wg_quickrs_lib::validation::error::ValidationError;
 use wg_quickrs_lib::macros::wg_quickrs_version;
@@ -10,7 +13,8 @@ use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
-use std::sync::{RwLock, OnceLock};
+use std::sync::{Mutex, RwLock, OnceLock};
+use std::time::SystemTime;
 use chrono::Utc;
 use thiserror::Error;
 use semver::Version;
@@ -53,15 +57,18 @@ fn set_or_init_config_w_digest(config_w_network_digest: ConfigWNetworkDigest) ->
             .map_err(|_| ConfUtilError::MutexSetFailed());
     }
 
-    mut_opt
+    let mut config = mut_opt
         .unwrap()
         .write()
-        .map(|mut c| {
-            c.agent = config_w_network_digest.agent;
-            c.network_w_digest = config_w_network_digest.network_w_digest;
-            Ok(())
-        })
-        .map_err(|e| ConfUtilError::MutexLockFailed(e.to_string()))?
+        .map_err(|e| ConfUtilError::MutexLockFailed(e.to_string()))?;
+    // Live dashboards refetch the network when its digest changes, and the routing state follows it
+    if config.network_w_digest.digest != config_w_network_digest.network_w_digest.digest {
+        crate::web::stream::publish(StreamMessage::Digest { digest: config_w_network_digest.network_w_digest.digest.clone() });
+        crate::mode::reconcile::request();
+    }
+    config.agent = config_w_network_digest.agent;
+    config.network_w_digest = config_w_network_digest.network_w_digest;
+    Ok(())
 }
 
 pub(crate) fn get_config() -> Result<Config, ConfUtilError> {
@@ -69,6 +76,11 @@ pub(crate) fn get_config() -> Result<Config, ConfUtilError> {
     Ok(config_w_digest.to_config())
 }
 
+// Digest of the network section, as reported by /api/network/summary
+pub(crate) fn get_network_digest() -> Result<String, ConfUtilError> {
+    Ok(get_config_w_digest()?.network_w_digest.digest)
+}
+
 fn get_config_w_digest() -> Result<ConfigWNetworkDigest, ConfUtilError> {
     let mut_opt = CONFIG_W_NETWORK_DIGEST.get();
     if let Some(m) = mut_opt {
@@ -100,18 +112,93 @@ fn get_config_w_digest() -> Result<ConfigWNetworkDigest, ConfUtilError> {
     Ok(config_w_digest)
 }
 
+// Modification time of conf.yml when agent.router.failover was last read from it
+static ROUTER_FAILOVER_MTIME: Mutex<Option<SystemTime>> = Mutex::new(None);
+// Same for agent.notifications
+static NOTIFICATIONS_MTIME: Mutex<Option<SystemTime>> = Mutex::new(None);
+// Same for agent.usage
+static USAGE_MTIME: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+// Parse conf.yml again if it changed since `last_mtime` (None when unchanged)
+fn reread_config_file_if_changed(last_mtime: &Mutex<Option<SystemTime>>) -> Result<Option<ConfigFile>, ConfUtilError> {
+    let config_file_path = WG_QUICKRS_CONFIG_FILE.get().unwrap();
+    let mtime = fs::metadata(config_file_path)
+        .and_then(|m| m.modified())
+        .map_err(|e| ConfUtilError::Read(config_file_path.clone(), e))?;
+    {
+        let mut last_mtime = last_mtime.lock()
+            .map_err(|e| ConfUtilError::MutexLockFailed(e.to_string()))?;
+        if *last_mtime == Some(mtime) {
+            return Ok(None);
+        }
+        *last_mtime = Some(mtime);
+    }
+
+    let config_str = fs::read_to_string(config_file_path)
+        .map_err(|e| ConfUtilError::Read(config_file_path.clone(), e))?;
+    Ok(Some(serde_yml::from_str(&config_str).map_err(ConfUtilError::Parse)?))
+}
+
+// Re-read agent.router.failover from conf.yml if the file changed since the last call,
+// so edits made by the CLI or by hand reach the running agent without a restart.
+// Returns None when the file is unchanged; an invalid section is reported once and not applied.
+pub(crate) fn reload_router_failover() -> Result<Option<AgentRouterFailover>, ConfUtilError> {
+    let Some(config_file) = reread_config_file_if_changed(&ROUTER_FAILOVER_MTIME)? else {
+        return Ok(None);
+    };
+    let failover = config_file.agent.router.failover;
+    validate_router_failover(&failover)?;
+
+    if let Some(m) = CONFIG_W_NETWORK_DIGEST.get() {
+        m.write()
+            .map_err(|e| ConfUtilError::MutexLockFailed(e.to_string()))?
+            .agent.router.failover = failover.clone();
+    }
+    Ok(Some(failover))
+}
+
+// Re-read agent.notifications from conf.yml if the file changed since the last call (like reload_router_failover)
+pub(crate) fn reload_notifications() -> Result<Option<AgentNotifications>, ConfUtilError> {
+    let Some(config_file) = reread_config_file_if_changed(&NOTIFICATIONS_MTIME)? else {
+        return Ok(None);
+    };
+    let notifications = config_file.agent.notifications;
+    validate_notifications(&notifications)?;
+
+    if let Some(m) = CONFIG_W_NETWORK_DIGEST.get() {
+        m.write()
+            .map_err(|e| ConfUtilError::MutexLockFailed(e.to_string()))?
+            .agent.notifications = notifications.clone();
+    }
+    Ok(Some(notifications))
+}
+
+// Re-read agent.usage from conf.yml if the file changed since the last call (like reload_router_failover)
+pub(crate) fn reload_usage() -> Result<Option<AgentUsage>, ConfUtilError> {
+    let Some(config_file) = reread_config_file_if_changed(&USAGE_MTIME)? else {
+        return Ok(None);
+    };
+    let usage = config_file.agent.usage;
+    validate_usage(&usage)?;
+
+    if let Some(m) = CONFIG_W_NETWORK_DIGEST.get() {
+        m.write()
+            .map_err(|e| ConfUtilError::MutexLockFailed(e.to_string()))?
+            .agent.usage = usage.clone();
+    }
+    Ok(Some(usage))
+}
+
 pub(crate) fn get_summary() -> Result<Summary, ConfUtilError> {
     let config_w_digest = get_config_w_digest()?;
     let status = status_tunnel().unwrap_or_else(|e| {
         log::error!("{e}");
         WireGuardStatus::UNKNOWN
     });
-    // let telemetry = None;
+    // The web UI polls this for its graphs, which keeps on-demand sampling going
+    telemetry::note_demand();
     let telemetry = if status == WireGuardStatus::UP {
-        get_telemetry().unwrap_or_else(|e| {
-            log::error!("{e}");
-            None
-        })
+        Some(telemetry::recent())
     } else {
         None
     };
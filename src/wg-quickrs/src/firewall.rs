@@ -3,12 +3,21 @@
 //
 // Responsibilities:
 // - STEP 2: Configure firewall rules for NAT/MASQUERADE and forwarding
+// - Tag every rule it adds (-m comment --comment wg-quickrs), so stale ones can be told from an admin's rules
+// - Hold the process-wide firewall backend (iptables commands, simulated kernel in tests)
 
 use crate::helpers::{shell_cmd, parse_lan_cidrs};
 use crate::conf::util::get_config;
+use crate::mode::backend::routing_backend;
+use ipnet::Ipv4Net;
+use once_cell::sync::Lazy;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 use thiserror::Error;
+use wg_quickrs_lib::types::network::Network;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum FirewallError {
     #[error("Firewall utility error: {0}")]
     UtilityError(String),
@@ -20,6 +29,91 @@ pub enum FirewallError {
     ConfigError(String),
 }
 
+// Comment on every rule wg-quickrs adds; rules without it are never deleted as stale
+const RULE_COMMENT: &str = "wg-quickrs";
+
+// Chains wg-quickrs adds rules to (where the reconciler looks for stale ones)
+pub const MANAGED_CHAINS: [(&str, &str); 5] = [
+    ("nat", "POSTROUTING"),
+    ("filter", "FORWARD"),
+    ("mangle", "FORWARD"),
+    ("mangle", "POSTROUTING"),
+    ("mangle", "PREROUTING"),
+];
+
+// The seam between firewall logic and iptables
+// A rule is a table, a chain and a rule spec (the arguments after `iptables -t <table> -A <chain>`)
+pub trait FirewallBackend: Send + Sync {
+    // True if iptables can be used at all
+    fn available(&self) -> bool;
+
+    // True if the rule is in the chain (iptables -C)
+    fn exists(&self, table: &str, chain: &str, spec: &[String]) -> bool;
+
+    // Append the rule to the chain
+    fn append(&self, table: &str, chain: &str, spec: &[String]) -> Result<(), FirewallError>;
+
+    // Delete the first matching rule; true if the rule was there and is gone now
+    fn delete(&self, table: &str, chain: &str, spec: &[String]) -> bool;
+
+    // Rule specs of a chain as `iptables -S` prints them (without the leading `-A <chain>`)
+    fn list(&self, table: &str, chain: &str) -> Result<Vec<Vec<String>>, FirewallError>;
+}
+
+struct IptablesBackend;
+
+impl IptablesBackend {
+    fn command<'a>(table: &'a str, op: &'a str, chain: &'a str, spec: &'a [String]) -> Vec<&'a str> {
+        let mut command = vec!["iptables", "-t", table, op, chain];
+        command.extend(spec.iter().map(String::as_str));
+        command
+    }
+}
+
+impl FirewallBackend for IptablesBackend {
+    fn available(&self) -> bool {
+        shell_cmd(&["iptables", "--version"]).is_ok()
+    }
+
+    fn exists(&self, table: &str, chain: &str, spec: &[String]) -> bool {
+        shell_cmd(&Self::command(table, "-C", chain, spec)).is_ok()
+    }
+
+    fn append(&self, table: &str, chain: &str, spec: &[String]) -> Result<(), FirewallError> {
+        shell_cmd(&Self::command(table, "-A", chain, spec))
+            .map(|_| ())
+            .map_err(|e| FirewallError::UtilityError(e.to_string()))
+    }
+
+    fn delete(&self, table: &str, chain: &str, spec: &[String]) -> bool {
+        shell_cmd(&Self::command(table, "-D", chain, spec)).is_ok()
+    }
+
+    fn list(&self, table: &str, chain: &str) -> Result<Vec<Vec<String>>, FirewallError> {
+        let output = shell_cmd(&["iptables", "-t", table, "-S", chain])
+            .map_err(|e| FirewallError::UtilityError(format!("Failed to list {} {}: {}", table, chain, e)))?;
+        let prefix = format!("-A {} ", chain);
+        Ok(String::from_utf8_lossy(&output.stdout).lines()
+            .filter_map(|line| line.strip_prefix(&prefix))
+            .map(|spec| spec.split_whitespace().map(str::to_string).collect())
+            .collect())
+    }
+}
+
+static FIREWALL_BACKEND: Lazy<RwLock<Arc<dyn FirewallBackend>>> =
+    Lazy::new(|| RwLock::new(Arc::new(IptablesBackend)));
+
+// Get the process-wide firewall backend
+pub fn firewall_backend() -> Arc<dyn FirewallBackend> {
+    FIREWALL_BACKEND.read().unwrap().clone()
+}
+
+// Swap the process-wide firewall backend (tests install a simulated kernel)
+#[cfg(test)]
+pub fn set_firewall_backend(backend: Arc<dyn FirewallBackend>) {
+    *FIREWALL_BACKEND.write().unwrap() = backend;
+}
+
 // Enable Router Mode firewall rules
 // Adds NAT/MASQUERADE and forwarding rules for LAN -> WireGuard interface
 // Supports multiple comma-separated CIDRs (e.g., "192.168.1.0/24,10.0.0.0/8")
@@ -56,179 +150,24 @@ pub fn enable_router_mode_firewall(lan_cidr: &str) -> Result<(), FirewallError>
     log::info!("LAN interface: {}, WireGuard interface: {}", lan_interface, wg_interface);
     
     // Check if iptables is available
-    if shell_cmd(&["iptables", "--version"]).is_err() {
+    if !firewall_backend().available() {
         return Err(FirewallError::UtilityError("iptables not available".to_string()));
     }
     
-    // Add NAT/MASQUERADE rules for each CIDR
-    for cidr in &cidrs {
-        let masq_cmd = &[
-            "iptables", "-t", "nat", "-C", "POSTROUTING",
-            "-s", cidr,
-            "-o", wg_interface,
-            "-j", "MASQUERADE"
-        ];
-        
-        // Check if rule already exists
-        let rule_exists = shell_cmd(masq_cmd).is_ok();
-        
-        if !rule_exists {
-            let add_masq_cmd = &[
-                "iptables", "-t", "nat", "-A", "POSTROUTING",
-                "-s", cidr,
-                "-o", wg_interface,
-                "-j", "MASQUERADE"
-            ];
-            
-            if let Err(e) = shell_cmd(add_masq_cmd) {
-                return Err(FirewallError::NatRuleError(format!("Failed to add MASQUERADE rule for {}: {}", cidr, e)));
-            }
-            log::info!("Added NAT/MASQUERADE rule: {} -> {}", cidr, wg_interface);
-        } else {
-            log::debug!("NAT/MASQUERADE rule already exists for {}", cidr);
-        }
-    }
-    
-    // Add NAT/MASQUERADE rule for WireGuard peers: NAT traffic from WireGuard subnet going out WireGuard interface
-    // This allows WireGuard peers to use the exit node for internet traffic
+    // Rules are checked before being added, so enabling twice is safe
     let wg_subnet = config.network.subnet.to_string();
-    let wg_peer_masq_cmd = &[
-        "iptables", "-t", "nat", "-C", "POSTROUTING",
-        "-s", &wg_subnet,
-        "-o", wg_interface,
-        "-j", "MASQUERADE"
-    ];
-    
-    let wg_peer_rule_exists = shell_cmd(wg_peer_masq_cmd).is_ok();
-    
-    if !wg_peer_rule_exists {
-        let add_wg_peer_masq_cmd = &[
-            "iptables", "-t", "nat", "-A", "POSTROUTING",
-            "-s", &wg_subnet,
-            "-o", wg_interface,
-            "-j", "MASQUERADE"
-        ];
-        
-        if let Err(e) = shell_cmd(add_wg_peer_masq_cmd) {
-            log::warn!("Failed to add MASQUERADE rule for WireGuard peers ({} -> {}): {} (continuing anyway)", wg_subnet, wg_interface, e);
-        } else {
-            log::info!("Added NAT/MASQUERADE rule for WireGuard peers: {} -> {}", wg_subnet, wg_interface);
-        }
-    } else {
-        log::debug!("NAT/MASQUERADE rule for WireGuard peers already exists");
-    }
-    
-    // Add forwarding rules: Allow traffic from LAN to WireGuard
-    let fwd_in_cmd = &[
-        "iptables", "-C", "FORWARD",
-        "-i", &lan_interface,
-        "-o", wg_interface,
-        "-j", "ACCEPT"
-    ];
-    
-    if shell_cmd(fwd_in_cmd).is_err() {
-        let add_fwd_in_cmd = &[
-            "iptables", "-A", "FORWARD",
-            "-i", &lan_interface,
-            "-o", wg_interface,
-            "-j", "ACCEPT"
-        ];
-        
-        if let Err(e) = shell_cmd(add_fwd_in_cmd) {
-            return Err(FirewallError::ForwardingRuleError(format!("Failed to add forwarding rule (LAN->WG): {}", e)));
-        }
-        log::info!("Added forwarding rule: {} -> {}", lan_interface, wg_interface);
-    }
-    
-    // Add forwarding rules: Allow return traffic from WireGuard to LAN
-    let fwd_out_cmd = &[
-        "iptables", "-C", "FORWARD",
-        "-i", wg_interface,
-        "-o", &lan_interface,
-        "-j", "ACCEPT"
-    ];
-    
-    if shell_cmd(fwd_out_cmd).is_err() {
-        let add_fwd_out_cmd = &[
-            "iptables", "-A", "FORWARD",
-            "-i", wg_interface,
-            "-o", &lan_interface,
-            "-j", "ACCEPT"
-        ];
-        
-        if let Err(e) = shell_cmd(add_fwd_out_cmd) {
-            return Err(FirewallError::ForwardingRuleError(format!("Failed to add forwarding rule (WG->LAN): {}", e)));
-        }
-        log::info!("Added forwarding rule: {} -> {}", wg_interface, lan_interface);
-    }
-    
-    // Add MSS clamping rules to fix MTU issues through WireGuard tunnel
-    // This prevents "some sites don't load" issues caused by large TCP segments
-    // WireGuard MTU is typically 1420, so TCP MSS needs to be clamped to fit
-    
-    // MSS clamp for traffic going OUT to WireGuard (FORWARD chain)
-    let mss_out_check = &[
-        "iptables", "-t", "mangle", "-C", "FORWARD",
-        "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-        "-o", wg_interface,
-        "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-    ];
-    
-    if shell_cmd(mss_out_check).is_err() {
-        let mss_out_cmd = &[
-            "iptables", "-t", "mangle", "-A", "FORWARD",
-            "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-            "-o", wg_interface,
-            "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-        ];
-        if let Err(e) = shell_cmd(mss_out_cmd) {
-            log::warn!("Failed to add MSS clamping rule (outgoing): {} (non-fatal)", e);
-        } else {
-            log::info!("Added MSS clamping rule: outgoing TCP SYN -> {} (clamp to PMTU)", wg_interface);
-        }
-    }
-    
-    // MSS clamp for traffic coming IN from WireGuard (FORWARD chain)
-    let mss_in_check = &[
-        "iptables", "-t", "mangle", "-C", "FORWARD",
-        "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-        "-i", wg_interface,
-        "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-    ];
-    
-    if shell_cmd(mss_in_check).is_err() {
-        let mss_in_cmd = &[
-            "iptables", "-t", "mangle", "-A", "FORWARD",
-            "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-            "-i", wg_interface,
-            "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-        ];
-        if let Err(e) = shell_cmd(mss_in_cmd) {
-            log::warn!("Failed to add MSS clamping rule (incoming): {} (non-fatal)", e);
-        } else {
-            log::info!("Added MSS clamping rule: incoming TCP SYN <- {} (clamp to PMTU)", wg_interface);
+    for rule in router_mode_firewall_rules(&cidrs, &lan_interface, wg_interface, &wg_subnet) {
+        if rule.is_present() {
+            log::debug!("Firewall rule already exists: {}", rule);
+            continue;
         }
-    }
-    
-    // MSS clamp in POSTROUTING for locally-originated traffic
-    let mss_post_check = &[
-        "iptables", "-t", "mangle", "-C", "POSTROUTING",
-        "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-        "-o", wg_interface,
-        "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-    ];
-    
-    if shell_cmd(mss_post_check).is_err() {
-        let mss_post_cmd = &[
-            "iptables", "-t", "mangle", "-A", "POSTROUTING",
-            "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-            "-o", wg_interface,
-            "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-        ];
-        if let Err(e) = shell_cmd(mss_post_cmd) {
-            log::warn!("Failed to add MSS clamping rule (postrouting): {} (non-fatal)", e);
-        } else {
-            log::info!("Added MSS clamping rule: POSTROUTING TCP SYN -> {} (clamp to PMTU)", wg_interface);
+        match rule.add() {
+            Ok(()) => {
+                log::info!("Added firewall rule: {}", rule);
+                rule.remove_untagged();
+            }
+            Err(e) if rule.required => return Err(rule.error(format!("Failed to add firewall rule {}: {}", rule, e))),
+            Err(e) => log::warn!("Failed to add firewall rule {}: {} (non-fatal)", rule, e),
         }
     }
     
@@ -281,128 +220,341 @@ pub fn disable_router_mode_firewall() -> Result<(), FirewallError> {
     
     let lan_interface = find_lan_interface(&cidrs[0])?;
     
-    // Remove MASQUERADE rules for each CIDR
-    for cidr in &cidrs {
-        let del_masq_cmd = &[
-            "iptables", "-t", "nat", "-D", "POSTROUTING",
-            "-s", cidr,
-            "-o", wg_interface,
-            "-j", "MASQUERADE"
-        ];
-        
-        if shell_cmd(del_masq_cmd).is_ok() {
-            log::info!("Removed NAT/MASQUERADE rule for {}", cidr);
+    let wg_subnet = config.network.subnet.to_string();
+    for rule in router_mode_firewall_rules(&cidrs, &lan_interface, wg_interface, &wg_subnet) {
+        if rule.delete() {
+            log::info!("Removed firewall rule: {}", rule);
         } else {
-            log::debug!("MASQUERADE rule not found for {} (may have been removed already)", cidr);
+            log::debug!("Firewall rule not found: {} (may have been removed already)", rule);
         }
     }
     
-    // Remove forwarding rules (only need to remove once, not per-CIDR)
-    let del_fwd_in_cmd = &[
-        "iptables", "-D", "FORWARD",
-        "-i", &lan_interface,
-        "-o", wg_interface,
-        "-j", "ACCEPT"
-    ];
-    
-    if shell_cmd(del_fwd_in_cmd).is_ok() {
-        log::info!("Removed forwarding rule (LAN->WG)");
+    log::info!("Successfully disabled Router Mode firewall rules");
+    Ok(())
+}
+
+// Firewall side of per-client exit nodes (routing_pbr::client_exit_firewall)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientExitFirewall {
+    pub exit_interfaces: Vec<(String, bool)>, // Interface of a pinned exit node, and its LAN access
+    pub marks: Vec<(String, u32)>,            // MAC address of a pinned client, and its firewall mark
+}
+
+// Add (if missing) the firewall rules for an exit node's own interface
+pub fn set_exit_interface_firewall(lan_cidr: &str, network: &Network, exit_interface: &str, lan_access: bool) -> Result<(), FirewallError> {
+    let cidrs = parse_lan_cidrs(lan_cidr);
+    if cidrs.is_empty() {
+        return Err(FirewallError::ConfigError("LAN CIDR is required for exit node interface firewall rules".to_string()));
     }
-    
-    let del_fwd_out_cmd = &[
-        "iptables", "-D", "FORWARD",
-        "-i", wg_interface,
-        "-o", &lan_interface,
-        "-j", "ACCEPT"
-    ];
-    
-    if shell_cmd(del_fwd_out_cmd).is_ok() {
-        log::info!("Removed forwarding rule (WG->LAN)");
+    let lan_interface = find_lan_interface(&cidrs[0])?;
+    for rule in exit_interface_rules(&cidrs, &lan_interface, network, exit_interface, lan_access) {
+        if !rule.is_present() {
+            rule.add().map_err(|e| rule.error(format!("Failed to add rule ({}): {}", rule, e)))?;
+            rule.remove_untagged();
+        }
     }
     
-    // Remove MSS clamping rules
-    let del_mss_out = &[
-        "iptables", "-t", "mangle", "-D", "FORWARD",
-        "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-        "-o", wg_interface,
-        "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-    ];
-    if shell_cmd(del_mss_out).is_ok() {
-        log::info!("Removed MSS clamping rule (outgoing)");
+    // The LAN access setting may have changed since the rules were added
+    exit_interface_lan_rule(&lan_interface, exit_interface, !lan_access).delete();
+    log::info!("Firewall rules for exit node interface {} in place (LAN access: {})", exit_interface, lan_access);
+    Ok(())
+}
+
+// Remove the firewall rules for an exit node's own interface
+pub fn remove_exit_interface_firewall(lan_cidr: &str, network: &Network, exit_interface: &str) -> Result<(), FirewallError> {
+    let cidrs = parse_lan_cidrs(lan_cidr);
+    if cidrs.is_empty() {
+        return Ok(());
     }
-    
-    let del_mss_in = &[
-        "iptables", "-t", "mangle", "-D", "FORWARD",
-        "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-        "-i", wg_interface,
-        "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-    ];
-    if shell_cmd(del_mss_in).is_ok() {
-        log::info!("Removed MSS clamping rule (incoming)");
+    let lan_interface = find_lan_interface(&cidrs[0])?;
+    let mut rules = exit_interface_rules(&cidrs, &lan_interface, network, exit_interface, true);
+    rules.push(exit_interface_lan_rule(&lan_interface, exit_interface, false));
+    for rule in &rules {
+        if !rule.delete() {
+            log::debug!("Firewall rule not found: {} (may have been removed already)", rule);
+        }
+    }
+    log::info!("Removed firewall rules for exit node interface {}", exit_interface);
+    Ok(())
+}
+
+// Mark packets from pinned LAN clients by MAC address; mark rules wg-quickrs added for other clients go
+pub fn sync_client_marks(lan_cidr: &str, marks: &[(String, u32)]) -> Result<(), FirewallError> {
+    let cidrs = parse_lan_cidrs(lan_cidr);
+    if cidrs.is_empty() {
+        return Ok(());
     }
+    let lan_interface = find_lan_interface(&cidrs[0])?;
+    let desired: Vec<FirewallRule> = marks.iter().map(|(mac, mark)| client_mark_rule(&lan_interface, mac, *mark)).collect();
+    let canonical: Vec<Vec<String>> = desired.iter().map(|rule| canonical_spec(&rule.spec)).collect();
+    for spec in firewall_backend().list("mangle", "PREROUTING")? {
+        let Some(spec) = untagged_spec(&spec) else {
+            continue; // Not ours
+        };
+        if canonical.contains(&canonical_spec(&spec)) {
+            continue;
+        }
+        let rule = FirewallRule { table: "mangle", chain: "PREROUTING", spec, required: false };
+        if rule.delete() {
+            log::info!("Removed client mark rule: {}", rule);
+        }
+    }
+    for rule in desired.iter().filter(|rule| !rule.is_present()) {
+        rule.add().map_err(|e| rule.error(format!("Failed to add client mark rule ({}): {}", rule, e)))?;
+        log::info!("Added client mark rule: {}", rule);
+    }
+    Ok(())
+}
+
+// Enable or disable the firewall rules for the local breakout exit (LAN and WireGuard traffic leaving via the uplink)
+// Adds NAT/MASQUERADE for the LAN CIDRs and the WireGuard subnet, plus forwarding in both directions
+// Rules are checked before being added and deleting a missing rule does nothing, so both directions are safe to repeat
+pub fn set_local_breakout_firewall(
+    uplink_interface: &str,
+    lan_cidr: &str,
+    network: &Network,
+    enabled: bool,
+) -> Result<(), FirewallError> {
+    let cidrs = parse_lan_cidrs(lan_cidr);
+    if cidrs.is_empty() {
+        return Err(FirewallError::ConfigError("LAN CIDR is required for local breakout firewall rules".to_string()));
+    }
+    let lan_interface = find_lan_interface(&cidrs[0])?;
     
-    let del_mss_post = &[
-        "iptables", "-t", "mangle", "-D", "POSTROUTING",
-        "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN",
-        "-o", wg_interface,
-        "-j", "TCPMSS", "--clamp-mss-to-pmtu"
-    ];
-    if shell_cmd(del_mss_post).is_ok() {
-        log::info!("Removed MSS clamping rule (postrouting)");
+    for rule in local_breakout_firewall_rules(uplink_interface, &cidrs, &lan_interface, network) {
+        if enabled && !rule.is_present() {
+            rule.add().map_err(|e| rule.error(format!("Failed to add local breakout rule ({}): {}", rule, e)))?;
+            log::info!("Added local breakout rule: {}", rule);
+            rule.remove_untagged();
+        } else if !enabled && rule.delete() {
+            log::info!("Removed local breakout rule: {}", rule);
+        }
     }
     
-    log::info!("Successfully disabled Router Mode firewall rules");
     Ok(())
 }
 
-// Helper: Find LAN interface by matching CIDR
-fn find_lan_interface(lan_cidr: &str) -> Result<String, FirewallError> {
-    // Extract network from CIDR (e.g., "192.168.1.0/24" -> "192.168.1")
-    let parts: Vec<&str> = lan_cidr.split('/').collect();
-    if parts.len() != 2 {
-        return Err(FirewallError::ConfigError(format!("Invalid CIDR format: {}", lan_cidr)));
+// The iptables rules Router Mode needs, for the reconciler
+// (local breakout rules are included when an uplink interface is given, i.e. while it is the exit,
+// and per-client exit node rules for the exit interfaces and client marks given)
+pub fn desired_firewall_rules(
+    lan_cidr: &str,
+    network: &Network,
+    uplink_interface: Option<&str>,
+    client_exits: &ClientExitFirewall,
+) -> Result<Vec<FirewallRule>, FirewallError> {
+    let cidrs = parse_lan_cidrs(lan_cidr);
+    if cidrs.is_empty() {
+        return Err(FirewallError::ConfigError("No valid LAN CIDRs provided".to_string()));
+    }
+    let lan_interface = find_lan_interface(&cidrs[0])?;
+    let mut rules = router_mode_firewall_rules(&cidrs, &lan_interface, &network.name, &network.subnet.to_string());
+    if let Some(uplink_interface) = uplink_interface {
+        rules.extend(local_breakout_firewall_rules(uplink_interface, &cidrs, &lan_interface, network));
+    }
+    for (exit_interface, lan_access) in &client_exits.exit_interfaces {
+        rules.extend(exit_interface_rules(&cidrs, &lan_interface, network, exit_interface, *lan_access));
+    }
+    rules.extend(client_exits.marks.iter().map(|(mac, mark)| client_mark_rule(&lan_interface, mac, *mark)));
+    Ok(rules)
+}
+
+// Rules wg-quickrs added (tagged) that are not desired any more, e.g. left behind by a previous LAN CIDR or uplink
+pub fn stale_firewall_rules(desired: &[FirewallRule]) -> Result<Vec<FirewallRule>, FirewallError> {
+    let backend = firewall_backend();
+    let desired: Vec<Vec<String>> = desired.iter().map(|rule| canonical_spec(&rule.spec)).collect();
+    let mut stale = Vec::new();
+    for (table, chain) in MANAGED_CHAINS {
+        for spec in backend.list(table, chain)? {
+            let Some(spec) = untagged_spec(&spec) else {
+                continue; // Not ours
+            };
+            if !desired.contains(&canonical_spec(&spec)) {
+                stale.push(FirewallRule { table, chain, spec, required: false });
+            }
+        }
+    }
+    Ok(stale)
+}
+
+// Remove the rules only the previous LAN CIDR needed, after a LAN CIDR change
+// (the new CIDR's rules are added first; rules both need are kept)
+pub fn remove_previous_lan_cidr_rules(
+    old_lan_cidr: &str,
+    new_lan_cidr: &str,
+    network: &Network,
+    uplink_interface: Option<&str>,
+    client_exits: &ClientExitFirewall,
+) {
+    let Ok(previous) = desired_firewall_rules(old_lan_cidr, network, uplink_interface, client_exits) else {
+        return;
+    };
+    let current = desired_firewall_rules(new_lan_cidr, network, uplink_interface, client_exits).unwrap_or_default();
+    for rule in previous.iter().filter(|rule| !current.contains(rule)) {
+        if rule.delete() {
+            log::info!("Removed firewall rule of the previous LAN CIDR: {}", rule);
+        }
+    }
+}
+
+// An iptables rule managed by wg-quickrs: table, chain and rule spec
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirewallRule {
+    pub table: &'static str,
+    pub chain: &'static str,
+    pub spec: Vec<String>,
+    pub required: bool, // Router Mode does not work without it (failing to add it is an error)
+}
+
+impl FirewallRule {
+    fn new(table: &'static str, chain: &'static str, spec: &[&str], required: bool) -> Self {
+        FirewallRule { table, chain, spec: spec.iter().map(|s| s.to_string()).collect(), required }
+    }
+    
+    // The spec as installed: the comment goes right before the target
+    fn tagged_spec(&self) -> Vec<String> {
+        let target = self.spec.iter().position(|arg| arg == "-j").unwrap_or(self.spec.len());
+        let mut spec = self.spec[..target].to_vec();
+        spec.extend(["-m", "comment", "--comment", RULE_COMMENT].map(str::to_string));
+        spec.extend_from_slice(&self.spec[target..]);
+        spec
     }
     
-    let network = parts[0];
-    let network_parts: Vec<&str> = network.split('.').collect();
-    if network_parts.len() < 3 {
-        return Err(FirewallError::ConfigError(format!("Invalid network address: {}", network)));
+    // The iptables command line for an operation (-A, -D, ...), for change plans
+    pub fn command_line(&self, op: &'static str) -> String {
+        IptablesBackend::command(self.table, op, self.chain, &self.tagged_spec()).join(" ")
+    }
+
+    pub fn is_present(&self) -> bool {
+        firewall_backend().exists(self.table, self.chain, &self.tagged_spec())
     }
     
-    // Extract first 3 octets for matching
-    let network_prefix = format!("{}.{}.{}", network_parts[0], network_parts[1], network_parts[2]);
+    pub fn add(&self) -> Result<(), FirewallError> {
+        firewall_backend().append(self.table, self.chain, &self.tagged_spec())
+    }
     
-    // List all interfaces and find one with matching IP
-    let ip_output = shell_cmd(&["ip", "-4", "addr", "show"])
-        .map_err(|e| FirewallError::ConfigError(format!("Failed to list interfaces: {}", e)))?;
+    // True if the rule was there and is gone now
+    // The same rule without the comment (added by a version before rules were tagged) goes too
+    pub fn delete(&self) -> bool {
+        let backend = firewall_backend();
+        let tagged = backend.delete(self.table, self.chain, &self.tagged_spec());
+        let untagged = backend.delete(self.table, self.chain, &self.spec);
+        tagged || untagged
+    }
     
-    let ip_output_str = String::from_utf8_lossy(&ip_output.stdout);
-    let mut current_interface: Option<String> = None;
+    // Drop the untagged copy of a rule that was just added tagged (upgrade from a version without tags)
+    pub fn remove_untagged(&self) -> bool {
+        firewall_backend().delete(self.table, self.chain, &self.spec)
+    }
     
-    // Parse ip addr show output to find interface with matching network
-    for line in ip_output_str.lines() {
-        // Interface line: "2: eth0@if56: <BROADCAST,MULTICAST,UP,LOWER_UP>"
-        if line.contains(':') && !line.starts_with("    ") && !line.starts_with(" ") {
-            let iface_part = line.split(':').nth(1);
-            if let Some(iface) = iface_part {
-                let iface_name = iface.split('@').next().unwrap_or("").trim();
-                if !iface_name.is_empty() && iface_name != "lo" {
-                    current_interface = Some(iface_name.to_string());
-                }
-            }
-        }
-        // IP line: "    inet 192.168.1.198/24 ..."
-        else if let Some(iface) = &current_interface {
-            if line.contains("inet") && line.contains(&network_prefix) {
-                log::debug!("Found LAN interface: {} for CIDR {}", iface, lan_cidr);
-                return Ok(iface.clone());
-            }
+    fn error(&self, message: String) -> FirewallError {
+        if self.table == "nat" {
+            FirewallError::NatRuleError(message)
+        } else {
+            FirewallError::ForwardingRuleError(message)
         }
     }
+}
+
+impl std::fmt::Display for FirewallRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "-t {} {} {}", self.table, self.chain, self.spec.join(" "))
+    }
+}
+
+// NAT/MASQUERADE for the LAN CIDRs and the WireGuard subnet out of the WireGuard interface,
+// forwarding between LAN and WireGuard, and MSS clamping to the tunnel's path MTU
+// (WireGuard's MTU is typically 1420; without clamping large TCP segments make "some sites not load")
+fn router_mode_firewall_rules(cidrs: &[String], lan_interface: &str, wg_interface: &str, wg_subnet: &str) -> Vec<FirewallRule> {
+    let mut rules: Vec<FirewallRule> = cidrs.iter()
+        .map(|cidr| FirewallRule::new("nat", "POSTROUTING", &["-s", cidr, "-o", wg_interface, "-j", "MASQUERADE"], true))
+        .collect();
+    // Lets WireGuard peers use the exit node for internet traffic
+    rules.push(FirewallRule::new("nat", "POSTROUTING", &["-s", wg_subnet, "-o", wg_interface, "-j", "MASQUERADE"], false));
+    rules.push(FirewallRule::new("filter", "FORWARD", &["-i", lan_interface, "-o", wg_interface, "-j", "ACCEPT"], true));
+    rules.push(FirewallRule::new("filter", "FORWARD", &["-i", wg_interface, "-o", lan_interface, "-j", "ACCEPT"], true));
+    let clamp = ["-p", "tcp", "--tcp-flags", "SYN,RST", "SYN"];
+    let to_pmtu = ["-j", "TCPMSS", "--clamp-mss-to-pmtu"];
+    for (chain, direction) in [("FORWARD", "-o"), ("FORWARD", "-i"), ("POSTROUTING", "-o")] {
+        let spec: Vec<&str> = clamp.iter().copied().chain([direction, wg_interface]).chain(to_pmtu).collect();
+        rules.push(FirewallRule::new("mangle", chain, &spec, false));
+    }
+    rules
+}
+
+fn local_breakout_firewall_rules(uplink_interface: &str, cidrs: &[String], lan_interface: &str, network: &Network) -> Vec<FirewallRule> {
+    let wg_subnet = network.subnet.trunc().to_string();
+    let mut rules: Vec<FirewallRule> = cidrs.iter().map(String::as_str).chain(std::iter::once(wg_subnet.as_str()))
+        .map(|cidr| FirewallRule::new("nat", "POSTROUTING", &["-s", cidr, "-o", uplink_interface, "-j", "MASQUERADE"], true))
+        .collect();
+    for interface in [lan_interface, network.name.as_str()] {
+        rules.push(FirewallRule::new("filter", "FORWARD", &["-i", interface, "-o", uplink_interface, "-j", "ACCEPT"], true));
+        rules.push(FirewallRule::new("filter", "FORWARD", &[
+            "-i", uplink_interface, "-o", interface,
+            "-m", "state", "--state", "RELATED,ESTABLISHED",
+            "-j", "ACCEPT",
+        ], true));
+    }
+    rules
+}
+
+// Rules for an exit node running on its own interface (LAN clients pinned to it):
+// NAT and forwarding for LAN clients and WireGuard peers going out of it, replies back in, MSS clamping,
+// and new connections from the exit node into the LAN
+fn exit_interface_rules(cidrs: &[String], lan_interface: &str, network: &Network, exit_interface: &str, lan_access: bool) -> Vec<FirewallRule> {
+    let wg_subnet = network.subnet.trunc().to_string();
+    let mut rules: Vec<FirewallRule> = cidrs.iter().map(String::as_str).chain(std::iter::once(wg_subnet.as_str()))
+        .map(|cidr| FirewallRule::new("nat", "POSTROUTING", &["-s", cidr, "-o", exit_interface, "-j", "MASQUERADE"], true))
+        .collect();
+    for interface in [lan_interface, network.name.as_str()] {
+        rules.push(FirewallRule::new("filter", "FORWARD", &["-i", interface, "-o", exit_interface, "-j", "ACCEPT"], true));
+        rules.push(FirewallRule::new("filter", "FORWARD", &[
+            "-i", exit_interface, "-o", interface,
+            "-m", "state", "--state", "RELATED,ESTABLISHED",
+            "-j", "ACCEPT",
+        ], true));
+    }
+    let clamp = ["-p", "tcp", "--tcp-flags", "SYN,RST", "SYN"];
+    let to_pmtu = ["-j", "TCPMSS", "--clamp-mss-to-pmtu"];
+    for direction in ["-o", "-i"] {
+        let spec: Vec<&str> = clamp.iter().copied().chain([direction, exit_interface]).chain(to_pmtu).collect();
+        rules.push(FirewallRule::new("mangle", "FORWARD", &spec, false));
+    }
+    rules.push(exit_interface_lan_rule(lan_interface, exit_interface, lan_access));
+    rules
+}
+
+// New connections from the exit node into the LAN: accepted with LAN access, dropped without
+fn exit_interface_lan_rule(lan_interface: &str, exit_interface: &str, lan_access: bool) -> FirewallRule {
+    let target = if lan_access { "ACCEPT" } else { "DROP" };
+    FirewallRule::new("filter", "FORWARD", &["-i", exit_interface, "-o", lan_interface, "-j", target], true)
+}
+
+// Mark packets from a LAN client by MAC address (the client exit rule matches the mark)
+fn client_mark_rule(lan_interface: &str, mac: &str, mark: u32) -> FirewallRule {
+    let mark = format!("{:#x}", mark);
+    FirewallRule::new("mangle", "PREROUTING", &["-i", lan_interface, "-m", "mac", "--mac-source", mac, "-j", "MARK", "--set-mark", &mark], false)
+}
+
+// Helper: Find LAN interface by matching CIDR
+// Picks the interface holding an address inside the CIDR, falling back to common interface names
+fn find_lan_interface(lan_cidr: &str) -> Result<String, FirewallError> {
+    let lan_net = Ipv4Net::from_str(lan_cidr.trim())
+        .map_err(|e| FirewallError::ConfigError(format!("Invalid CIDR format: {} ({})", lan_cidr, e)))?;
+    
+    let backend = routing_backend();
+    let addresses = backend.list_interface_addresses()
+        .map_err(|e| FirewallError::ConfigError(format!("Failed to list interfaces: {}", e)))?;
+    if let Some(address) = addresses.into_iter()
+        .filter(|a| a.interface != "lo")
+        .find(|a| lan_net.contains(&a.address.addr())) {
+        log::debug!("Found LAN interface: {} for CIDR {}", address.interface, lan_cidr);
+        return Ok(address.interface);
+    }
     
     // Fallback: try common interface names
     for iface in &["eth0", "ens3", "enp0s3", "enp1s0"] {
-        if shell_cmd(&["ip", "addr", "show", iface]).is_ok() {
+        if backend.interface_exists(iface).unwrap_or(false) {
             log::debug!("Using fallback LAN interface: {} for CIDR {}", iface, lan_cidr);
             return Ok(iface.to_string());
         }
@@ -413,6 +565,55 @@ fn find_lan_interface(lan_cidr: &str) -> Result<String, FirewallError> {
     Ok("eth0".to_string())
 }
 
+// A listed rule spec without the wg-quickrs comment (None if it does not carry it)
+fn untagged_spec(spec: &[String]) -> Option<Vec<String>> {
+    let tag = ["-m", "comment", "--comment", RULE_COMMENT];
+    let position = spec.windows(tag.len()).position(|window| window == tag)?;
+    Some(spec[..position].iter().chain(&spec[position + tag.len()..]).cloned().collect())
+}
+
+// Order-independent form of a rule spec, so a rule as iptables -S prints it compares equal to the one added:
+// options are grouped with their values and sorted, implicit match modules (-m tcp, -m state, ...) and comments
+// are dropped, addresses are normalized (iptables prints the network address, and /32 for a host), the
+// conntrack spelling of a state match (iptables-nft) is read as the state one and --set-mark as --set-xmark
+fn canonical_spec(spec: &[String]) -> Vec<String> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    for arg in spec {
+        match groups.last_mut() {
+            Some(group) if !arg.starts_with('-') => group.push(arg.clone()),
+            _ => groups.push(vec![arg.clone()]),
+        }
+    }
+    let mut canonical: Vec<String> = groups.into_iter()
+        .filter(|group| !matches!(group[0].as_str(), "-m" | "--match" | "--comment"))
+        .map(|mut group| {
+            match group[0].as_str() {
+                "-s" | "-d" | "--source" | "--destination" => {
+                    for value in group.iter_mut().skip(1) {
+                        if let Ok(net) = Ipv4Net::from_str(value) {
+                            *value = net.trunc().to_string();
+                        } else if let Ok(net) = value.parse::<std::net::Ipv4Addr>().map(Ipv4Net::from) {
+                            *value = net.to_string();
+                        }
+                    }
+                }
+                "--ctstate" => group[0] = "--state".to_string(),
+                // iptables prints a mark as set-xmark with the full mask
+                "--set-mark" => {
+                    group[0] = "--set-xmark".to_string();
+                    for value in group.iter_mut().skip(1).filter(|value| !value.contains('/')) {
+                        value.push_str("/0xffffffff");
+                    }
+                }
+                _ => {}
+            }
+            group.join(" ")
+        })
+        .collect();
+    canonical.sort();
+    canonical
+}
+
 // Helper: Remove firewall rules by pattern (fallback when config unavailable)
 fn remove_firewall_rules_by_pattern() -> Result<(), FirewallError> {
     // Try to remove MASQUERADE rules that match our pattern
@@ -421,3 +622,93 @@ fn remove_firewall_rules_by_pattern() -> Result<(), FirewallError> {
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_listed_rules_match_the_rules_added() {
+        let network_rules = router_mode_firewall_rules(&["192.168.1.10/24".to_string()], "eth0", "wg0", "10.0.34.0/24");
+        // As `iptables -S` prints them (iptables-legacy and iptables-nft)
+        let listed = [
+            "-s 192.168.1.0/24 -o wg0 -m comment --comment wg-quickrs -j MASQUERADE",
+            "-s 10.0.34.0/24 -o wg0 -m comment --comment wg-quickrs -j MASQUERADE",
+            "-i eth0 -o wg0 -m comment --comment wg-quickrs -j ACCEPT",
+            "-i wg0 -o eth0 -m comment --comment wg-quickrs -j ACCEPT",
+            "-o wg0 -p tcp -m tcp --tcp-flags SYN,RST SYN -m comment --comment wg-quickrs -j TCPMSS --clamp-mss-to-pmtu",
+            "-i wg0 -p tcp -m tcp --tcp-flags SYN,RST SYN -m comment --comment wg-quickrs -j TCPMSS --clamp-mss-to-pmtu",
+            "-o wg0 -p tcp -m tcp --tcp-flags SYN,RST SYN -m comment --comment wg-quickrs -j TCPMSS --clamp-mss-to-pmtu",
+        ];
+        for (rule, listed) in network_rules.iter().zip(listed) {
+            let untagged = untagged_spec(&spec(listed)).unwrap();
+            assert_eq!(canonical_spec(&untagged), canonical_spec(&rule.spec), "{}", listed);
+        }
+
+        let breakout = FirewallRule::new("filter", "FORWARD", &[
+            "-i", "eth1", "-o", "eth0", "-m", "state", "--state", "RELATED,ESTABLISHED", "-j", "ACCEPT",
+        ], true);
+        let nft = spec("-i eth1 -o eth0 -m conntrack --ctstate RELATED,ESTABLISHED -m comment --comment wg-quickrs -j ACCEPT");
+        assert_eq!(canonical_spec(&untagged_spec(&nft).unwrap()), canonical_spec(&breakout.spec));
+        assert_eq!(canonical_spec(&spec("-s 10.0.0.5/32 -j ACCEPT")), canonical_spec(&spec("-s 10.0.0.5 -j ACCEPT")));
+        let mark = client_mark_rule("eth0", "AA:BB:CC:DD:EE:FF", 0x10001);
+        let listed = spec("-i eth0 -m mac --mac-source AA:BB:CC:DD:EE:FF -m comment --comment wg-quickrs -j MARK --set-xmark 0x10001/0xffffffff");
+        assert_eq!(canonical_spec(&untagged_spec(&listed).unwrap()), canonical_spec(&mark.spec));
+        assert_ne!(canonical_spec(&spec("-s 10.0.0.0/24 -o wg0 -j MASQUERADE")), canonical_spec(&spec("-s 10.0.0.0/24 -o wg1 -j MASQUERADE")));
+    }
+
+    #[test]
+    fn test_only_tagged_rules_are_ours() {
+        let rule = FirewallRule::new("nat", "POSTROUTING", &["-s", "10.0.0.0/24", "-o", "wg0", "-j", "MASQUERADE"], true);
+        assert_eq!(rule.tagged_spec(), spec("-s 10.0.0.0/24 -o wg0 -m comment --comment wg-quickrs -j MASQUERADE"));
+        assert_eq!(untagged_spec(&rule.tagged_spec()), Some(rule.spec.clone()));
+        assert_eq!(untagged_spec(&rule.spec), None);
+        assert_eq!(untagged_spec(&spec("-s 10.0.0.0/24 -m comment --comment admin -j MASQUERADE")), None);
+    }
+
+    #[test]
+    fn test_lan_cidr_change_removes_only_the_previous_rules() {
+        use crate::mode::simulated::SimulatedKernel;
+        let _guard = crate::mode::simulated::test_lock();
+        let kernel = Arc::new(SimulatedKernel::new()
+            .with_interface("eth0", "192.168.1.10/24")
+            .with_interface("eth1", "10.20.0.1/16")
+            .with_interface("wg0", "10.0.34.1/24")
+            // An admin's own forwarding
+            .with_firewall_rule("filter", "FORWARD", "-i eth1 -o eth0 -j ACCEPT"));
+        crate::mode::backend::set_routing_backend(kernel.clone());
+        set_firewall_backend(kernel.clone());
+        let network = Network {
+            name: "wg0".to_string(),
+            subnet: "10.0.34.0/24".parse().unwrap(),
+            this_peer: Default::default(),
+            peers: Default::default(),
+            connections: Default::default(),
+            defaults: Default::default(),
+            reservations: Default::default(),
+            updated_at: Default::default(),
+        };
+        // As update_lan_cidr applies them: the new CIDR's rules are added next to the old ones first
+        let add_missing = |rules: &[FirewallRule]| for rule in rules {
+            if !rule.is_present() {
+                rule.add().unwrap();
+            }
+        };
+        let client_exits = ClientExitFirewall::default();
+        let previous = desired_firewall_rules("10.20.0.0/16", &network, None, &client_exits).unwrap();
+        let current = desired_firewall_rules("192.168.1.0/24", &network, None, &client_exits).unwrap();
+        add_missing(&previous);
+        add_missing(&current);
+
+        remove_previous_lan_cidr_rules("10.20.0.0/16", "192.168.1.0/24", &network, None, &client_exits);
+        assert!(stale_firewall_rules(&current).unwrap().is_empty());
+        assert!(current.iter().all(FirewallRule::is_present));
+        assert!(kernel.firewall_rules("filter", "FORWARD").contains(&"-i eth1 -o eth0 -j ACCEPT".to_string()));
+        assert!(!kernel.firewall_rules("nat", "POSTROUTING").iter().any(|rule| rule.contains("10.20.0.0/16")));
+        assert!(!kernel.firewall_rules("filter", "FORWARD").iter().any(|rule| rule.contains("eth1") && rule.contains("wg-quickrs")));
+    }
+}
@@ -34,6 +34,10 @@ pub enum CommandError {
     #[error("{0}")]
     AgentRun(#[from] commands::agent::run::AgentRunError),
     #[error("{0}")]
+    AgentState(#[from] commands::agent::state::AgentStateError),
+    #[error("{0}")]
+    AgentRouter(#[from] commands::agent::router::AgentRouterError),
+    #[error("{0}")]
     ConfigCommand(#[from] commands::config::ConfigCommandError),
 }
 
@@ -98,6 +102,8 @@ async fn entrypoint(args: Cli) -> Result<(), CommandError> {
             match target {
                 wg_quickrs_cli::agent::AgentCommands::Init(init_opts) => commands::agent::init::initialize_agent(init_opts)?,
                 wg_quickrs_cli::agent::AgentCommands::Run => commands::agent::run::run_agent().await?,
+                wg_quickrs_cli::agent::AgentCommands::State { target } => commands::agent::state::handle_state_command(target)?,
+                wg_quickrs_cli::agent::AgentCommands::Router { target } => commands::agent::router::handle_router_command(target)?,
             }
         },
         wg_quickrs_cli::Commands::Config { target } => {
@@ -0,0 +1,213 @@
+// Routing backend: the seam between policy routing logic and the kernel
+//
+// Responsibilities:
+// - Describe routes, rules and interface addresses as typed values instead of `ip` command lines
+// - Provide atomic add-or-replace for routes and idempotent rule installs
+// - Hold the process-wide backend used by routing_pbr (netlink on Linux, simulated kernel in tests)
+
+use ipnet::Ipv4Net;
+use once_cell::sync::Lazy;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+
+// Kernel table used by `lookup main`
+pub const MAIN_TABLE: u32 = 254;
+
+#[derive(Error, Debug)]
+pub enum BackendError {
+    #[error("netlink socket error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("already exists: {0}")]
+    AlreadyExists(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("no such interface: {0}")]
+    NoSuchInterface(String),
+    #[error("{0} rejected by kernel: {1}")]
+    Kernel(String, std::io::Error),
+    #[error("malformed netlink message: {0}")]
+    Malformed(String),
+    #[cfg(not(target_os = "linux"))]
+    #[error("routing backend unsupported on this platform")]
+    Unsupported(),
+}
+
+pub type BackendResult<T> = Result<T, BackendError>;
+
+// A unicast route inside a (peer) routing table, directly on the device or via a gateway
+// Equivalent to: ip route replace <destination> [via <gateway>] dev <device> table <table>
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Route {
+    pub table: u32,
+    pub destination: Ipv4Net,
+    pub device: String,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+// A policy routing rule that looks up a table
+// Equivalent to: ip rule add [from <from>] [iif <iif>] [to <to>] [fwmark <fwmark>] lookup <table> priority <priority>
+// A `/0` selector is the same as no selector, so `from`/`to` are normalized to None for it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Rule {
+    pub priority: u32,
+    pub from: Option<Ipv4Net>,
+    pub to: Option<Ipv4Net>,
+    pub iif: Option<String>,
+    pub fwmark: Option<u32>,
+    pub table: u32,
+}
+
+impl Rule {
+    pub fn normalized(mut self) -> Self {
+        self.from = self.from.filter(|net| net.prefix_len() > 0);
+        self.to = self.to.filter(|net| net.prefix_len() > 0);
+        self
+    }
+
+    // True if the rule has no destination selector (matches 0.0.0.0/0)
+    pub fn is_default_destination(&self) -> bool {
+        self.to.is_none_or(|net| net.prefix_len() == 0)
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.priority)?;
+        match &self.from {
+            Some(from) => write!(f, " from {}", from)?,
+            None => write!(f, " from all")?,
+        }
+        if let Some(iif) = &self.iif {
+            write!(f, " iif {}", iif)?;
+        }
+        if let Some(to) = &self.to {
+            write!(f, " to {}", to)?;
+        }
+        if let Some(fwmark) = self.fwmark {
+            write!(f, " fwmark {:#x}", fwmark)?;
+        }
+        if self.table == MAIN_TABLE {
+            write!(f, " lookup main")
+        } else {
+            write!(f, " lookup {}", self.table)
+        }
+    }
+}
+
+// An IPv4 address assigned to an interface, with its prefix length (e.g. eth0 192.168.1.10/24)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub interface: String,
+    pub address: Ipv4Net,
+}
+
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.destination)?;
+        if let Some(gateway) = &self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        write!(f, " dev {} table {}", self.device, self.table)
+    }
+}
+
+pub trait RoutingBackend: Send + Sync {
+    // Add the route, atomically replacing any route with the same destination in the table
+    fn replace_route(&self, route: &Route) -> BackendResult<()>;
+
+    // Remove exactly this route (table, destination, device and gateway must match)
+    fn delete_route(&self, route: &Route) -> BackendResult<()>;
+
+    // Remove every route from a table, returns the number of routes removed
+    fn flush_table(&self, table: u32) -> BackendResult<usize>;
+
+    // List the unicast routes of a table
+    fn list_routes(&self, table: u32) -> BackendResult<Vec<Route>>;
+
+    // List all IPv4 policy rules currently installed
+    fn list_rules(&self) -> BackendResult<Vec<Rule>>;
+
+    // Install a rule; an identical existing rule is treated as success
+    fn add_rule(&self, rule: &Rule) -> BackendResult<()>;
+
+    // Delete exactly this rule (priority, selectors and table must match)
+    fn delete_rule(&self, rule: &Rule) -> BackendResult<()>;
+
+    // True if a network interface with this name exists
+    fn interface_exists(&self, name: &str) -> BackendResult<bool>;
+
+    // List IPv4 addresses of all interfaces
+    fn list_interface_addresses(&self) -> BackendResult<Vec<InterfaceAddress>>;
+
+    // Create a WireGuard interface with this address and bring it up; an existing one is left as it is
+    fn add_wireguard_interface(&self, name: &str, address: Ipv4Net, mtu: Option<u16>) -> BackendResult<()>;
+
+    // Delete an interface (the kernel drops every route through it)
+    fn delete_interface(&self, name: &str) -> BackendResult<()>;
+}
+
+#[cfg(target_os = "linux")]
+fn default_backend() -> Arc<dyn RoutingBackend> {
+    Arc::new(super::netlink::NetlinkBackend)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_backend() -> Arc<dyn RoutingBackend> {
+    Arc::new(UnsupportedBackend)
+}
+
+static ROUTING_BACKEND: Lazy<RwLock<Arc<dyn RoutingBackend>>> =
+    Lazy::new(|| RwLock::new(default_backend()));
+
+// Get the process-wide routing backend
+pub fn routing_backend() -> Arc<dyn RoutingBackend> {
+    ROUTING_BACKEND.read().unwrap().clone()
+}
+
+// Swap the process-wide routing backend (tests install a simulated kernel)
+#[cfg(test)]
+pub fn set_routing_backend(backend: Arc<dyn RoutingBackend>) {
+    *ROUTING_BACKEND.write().unwrap() = backend;
+}
+
+// Router Mode relies on Linux policy routing; other platforms get a backend that refuses every change
+#[cfg(not(target_os = "linux"))]
+struct UnsupportedBackend;
+
+#[cfg(not(target_os = "linux"))]
+impl RoutingBackend for UnsupportedBackend {
+    fn replace_route(&self, _route: &Route) -> BackendResult<()> {
+        Err(BackendError::Unsupported())
+    }
+    fn delete_route(&self, _route: &Route) -> BackendResult<()> {
+        Err(BackendError::Unsupported())
+    }
+    fn flush_table(&self, _table: u32) -> BackendResult<usize> {
+        Err(BackendError::Unsupported())
+    }
+    fn list_routes(&self, _table: u32) -> BackendResult<Vec<Route>> {
+        Err(BackendError::Unsupported())
+    }
+    fn list_rules(&self) -> BackendResult<Vec<Rule>> {
+        Err(BackendError::Unsupported())
+    }
+    fn add_rule(&self, _rule: &Rule) -> BackendResult<()> {
+        Err(BackendError::Unsupported())
+    }
+    fn delete_rule(&self, _rule: &Rule) -> BackendResult<()> {
+        Err(BackendError::Unsupported())
+    }
+    fn interface_exists(&self, _name: &str) -> BackendResult<bool> {
+        Err(BackendError::Unsupported())
+    }
+    fn list_interface_addresses(&self) -> BackendResult<Vec<InterfaceAddress>> {
+        Err(BackendError::Unsupported())
+    }
+    fn add_wireguard_interface(&self, _name: &str, _address: Ipv4Net, _mtu: Option<u16>) -> BackendResult<()> {
+        Err(BackendError::Unsupported())
+    }
+    fn delete_interface(&self, _name: &str) -> BackendResult<()> {
+        Err(BackendError::Unsupported())
+    }
+}
@@ -0,0 +1,189 @@
+// Change plans: the ip rule, ip route, iptables and sysctl operations a Router Mode change would run
+//
+// switch_mode, update_lan_cidr, set_exit_node and set_peer_lan_access each have a plan_* counterpart
+// that validates the request like the real call, builds the mode state the change would leave behind
+// and diffs what that state needs against the kernel, without changing anything.
+// The routing half reuses the reconciler's plan and diff (routing_pbr::plan_routing, reconcile::diff_routing),
+// so a plan is the net difference to the kernel: drift the reconciler would correct shows up in it too.
+// WireGuard AllowedIPs (wg set, for exit nodes, load balancing and domain routes) are not part of a plan:
+// a plan that may leave them out, or could not work out part of the change, is marked not complete.
+// Served for ?dry_run=true on the API and --dry-run in the CLI.
+
+use super::backend::{routing_backend, Route, Rule};
+use super::mode::SystemMode;
+use super::persist::ModeState;
+use super::reconcile::{self, ReconcileError};
+use super::routing_pbr::{self, LOCAL_BREAKOUT_TABLE};
+use crate::firewall::FirewallRule;
+use ipnet::Ipv4Net;
+use serde::Serialize;
+use wg_quickrs_lib::types::network::Network;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    IpRule,
+    IpRoute,
+    Iptables,
+    Sysctl,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Add,
+    Delete,
+    Replace,
+    Set,
+}
+
+// One operation, with the command line that is equivalent to it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub action: ChangeAction,
+    pub command: String,
+}
+
+impl Change {
+    fn rule(action: ChangeAction, rule: &Rule) -> Self {
+        // Rule displays as "<priority>: from ... lookup <table>"
+        let rule_str = rule.to_string();
+        let selectors = rule_str.split_once(": ").map(|(_, selectors)| selectors).unwrap_or_default();
+        let verb = if action == ChangeAction::Delete { "del" } else { "add" };
+        Change {
+            kind: ChangeKind::IpRule,
+            action,
+            command: format!("ip rule {} {} priority {}", verb, selectors, rule.priority),
+        }
+    }
+
+    fn route(action: ChangeAction, route: &Route) -> Self {
+        let verb = if action == ChangeAction::Delete { "del" } else { "replace" };
+        Change { kind: ChangeKind::IpRoute, action, command: format!("ip route {} {}", verb, route) }
+    }
+
+    fn firewall(action: ChangeAction, rule: &FirewallRule) -> Self {
+        let op = if action == ChangeAction::Delete { "-D" } else { "-A" };
+        Change { kind: ChangeKind::Iptables, action, command: rule.command_line(op) }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ChangePlan {
+    pub operation: String, // e.g. "switch_mode router"
+    pub changes: Vec<Change>, // In the order they would run
+    pub notes: Vec<String>, // What the plan could not check or does not list
+    pub complete: bool, // False when the change may make changes the plan does not list (the notes say which)
+}
+
+impl ChangePlan {
+    pub fn new(operation: impl Into<String>) -> Self {
+        ChangePlan { operation: operation.into(), complete: true, ..Default::default() }
+    }
+
+    // sysctl -w, unless the key already has the value
+    pub fn sysctl(&mut self, key: &str, value: &str) {
+        let path = format!("/proc/sys/{}", key.replace('.', "/"));
+        if std::fs::read_to_string(path).is_ok_and(|current| current.trim() == value) {
+            return;
+        }
+        self.changes.push(Change {
+            kind: ChangeKind::Sysctl,
+            action: ChangeAction::Set,
+            command: format!("sysctl -w {}={}", key, value),
+        });
+    }
+
+    // A note on changes the call may make that the plan does not list
+    fn omitted(&mut self, note: String) {
+        self.notes.push(note);
+        self.complete = false;
+    }
+
+    // Firewall and routing changes that take the kernel from the current mode state to the target one
+    // (None, or a state not in Router Mode, stands for Host Mode)
+    // New iptables rules come first and stale ones last, like the call sites apply them
+    pub fn transition(&mut self, current: Option<&ModeState>, target: Option<&ModeState>, network: &Network) -> Result<(), ReconcileError> {
+        let current = current.filter(|state| state.last_mode == SystemMode::Router);
+        let target = target.filter(|state| state.last_mode == SystemMode::Router);
+        let lan_interface = routing_pbr::find_lan_interface()?;
+
+        let current_uplink = current.and_then(|state| routing_pbr::plan_routing(state, network, &lan_interface).uplink);
+        let mut plan = target.map(|state| routing_pbr::plan_routing(state, network, &lan_interface)).unwrap_or_default();
+        // Tables the change leaves behind are emptied
+        for table_id in current.iter().flat_map(|state| state.peer_table_ids.values()).chain([&LOCAL_BREAKOUT_TABLE]) {
+            plan.routes.entry(*table_id).or_default();
+        }
+
+        let current_firewall = self.firewall_rules(current, network, current_uplink.as_deref());
+        let target_firewall = self.firewall_rules(target, network, plan.uplink.as_deref());
+        for rule in target_firewall.iter().filter(|rule| !rule.is_present()) {
+            self.changes.push(Change::firewall(ChangeAction::Add, rule));
+        }
+
+        let lan_nets: Vec<Ipv4Net> = current.iter().chain(target.iter())
+            .flat_map(|state| routing_pbr::lan_nets(state))
+            .collect();
+        let is_managed = |rule: &Rule| routing_pbr::is_managed_rule(rule, &lan_nets, &lan_interface, &network.name);
+        let diff = reconcile::diff_routing(&plan, is_managed)?;
+        self.changes.extend(diff.add_routes.iter().map(|route| Change::route(ChangeAction::Replace, route)));
+        self.changes.extend(diff.add_rules.iter().map(|rule| Change::rule(ChangeAction::Add, rule)));
+        self.changes.extend(diff.delete_rules.iter().map(|rule| Change::rule(ChangeAction::Delete, rule)));
+        self.changes.extend(diff.delete_routes.iter().map(|route| Change::route(ChangeAction::Delete, route)));
+
+        for rule in current_firewall.iter().filter(|rule| !target_firewall.contains(rule) && rule.is_present()) {
+            self.changes.push(Change::firewall(ChangeAction::Delete, rule));
+        }
+
+        if target.is_some() && !routing_backend().interface_exists(&network.name)? {
+            self.notes.push(format!("The WireGuard interface {} is down: its routes are installed when it comes up", network.name));
+        }
+        // The exit node gets 0.0.0.0/0 in its AllowedIPs, the previous one loses it
+        let exit_node = |state: Option<&ModeState>| state
+            .and_then(|state| state.prefix_active_backup.get("0.0.0.0/0"))
+            .map(|prefix| prefix.active_peer_id.clone());
+        if exit_node(current) != exit_node(target) || target.is_some_and(|state| state.load_balance || !state.domain_routes.is_empty()) {
+            self.omitted("WireGuard AllowedIPs may also be updated (wg set), which this plan does not list".to_string());
+        }
+        Ok(())
+    }
+
+    // The iptables rules a Router Mode state needs (none in Host Mode)
+    fn firewall_rules(&mut self, state: Option<&ModeState>, network: &Network, uplink: Option<&str>) -> Vec<FirewallRule> {
+        let Some(lan_cidr) = state.and_then(|state| state.lan_cidr.as_deref()) else {
+            return Vec::new();
+        };
+        let client_exits = state.map(routing_pbr::client_exit_firewall).unwrap_or_default();
+        crate::firewall::desired_firewall_rules(lan_cidr, network, uplink, &client_exits).unwrap_or_else(|e| {
+            self.omitted(format!("Firewall rules for {} could not be planned: {}", lan_cidr, e));
+            Vec::new()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_command_lines() {
+        let rule = Rule {
+            priority: 20000,
+            iif: Some("eth0".to_string()),
+            table: 1000,
+            ..Default::default()
+        };
+        assert_eq!(Change::rule(ChangeAction::Add, &rule).command, "ip rule add from all iif eth0 lookup 1000 priority 20000");
+        assert_eq!(Change::rule(ChangeAction::Delete, &rule).command, "ip rule del from all iif eth0 lookup 1000 priority 20000");
+
+        let route = Route { table: 1000, destination: "0.0.0.0/0".parse().unwrap(), device: "wg0".to_string(), gateway: None };
+        assert_eq!(Change::route(ChangeAction::Replace, &route).command, "ip route replace 0.0.0.0/0 dev wg0 table 1000");
+        assert_eq!(Change::route(ChangeAction::Delete, &route).command, "ip route del 0.0.0.0/0 dev wg0 table 1000");
+
+        let json = serde_json::to_value(Change::route(ChangeAction::Replace, &route)).unwrap();
+        assert_eq!(json["kind"], "ip_route");
+        assert_eq!(json["action"], "replace");
+        assert_eq!(serde_json::to_value(ChangePlan::new("switch_mode router")).unwrap()["complete"], true);
+    }
+}
@@ -0,0 +1,487 @@
+// Commit confirm: roll back a mode switch or LAN CIDR change unless it is confirmed in time
+//
+// Switching modes or changing the LAN CIDR from a remote client can cut that client off, with nobody left
+// to undo it. With a confirm timeout (PATCH /api/mode/toggle?confirm_timeout=N) the change is applied as
+// usual, but the previous config and mode state are kept, in memory and in pending_confirm.json
+// next to router_mode_state.json, so a restart during the window does not lose them. Unless
+// POST /api/mode/confirm arrives within N seconds, the change is reverted:
+// - the inverse switch (or LAN CIDR update) runs, without the peer check, so the kernel is torn down or set up again
+// - the previous mode state and the whole previous config are restored as they were, so edits made
+//   during the window (peers included) are undone too; WireGuard is synced if the network changed
+// - in Router Mode the reconciler then converges rules, routes and iptables rules to the restored state
+// - if the rollback fails, the change stays pending (file included) and is tried again after ROLLBACK_RETRY_SECS
+// Only one change can wait for confirmation at a time. The slot is reserved before the change is applied
+// and the lock is not held while it runs, so reading the pending change never waits on a mode switch.
+
+use super::events::{self, EventDetails, EventKind, Initiator};
+use super::mode::{self as mode_switch, ModeError, SystemMode};
+use super::persist::{clear_mode_state, load_mode_state, save_mode_state, ModeState};
+use crate::conf;
+use crate::WG_QUICKRS_CONFIG_FOLDER;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::interval;
+use uuid::Uuid;
+use wg_quickrs_lib::types::config::Config;
+
+pub const MIN_CONFIRM_TIMEOUT_SECS: u64 = 10;
+pub const MAX_CONFIRM_TIMEOUT_SECS: u64 = 3600;
+const PENDING_FILE: &str = "pending_confirm.json";
+const PENDING_TEMP_FILE: &str = "pending_confirm.json.tmp";
+// A rollback that failed is tried again after this long (the change stays pending until then)
+const ROLLBACK_RETRY_SECS: u64 = 30;
+
+#[derive(Error, Debug)]
+pub enum ConfirmError {
+    #[error("Confirm timeout must be between {MIN_CONFIRM_TIMEOUT_SECS} and {MAX_CONFIRM_TIMEOUT_SECS} seconds, got {0}")]
+    InvalidTimeout(u64),
+    #[error("Another change ({0}) is waiting for confirmation")]
+    AlreadyPending(String),
+    #[error("No change is waiting for confirmation")]
+    NothingPending,
+    #[error("{0}")]
+    ModeError(#[from] ModeError),
+    #[error("Config error: {0}")]
+    ConfigError(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+// A change waiting for confirmation, as reported by the API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingChange {
+    pub id: Uuid, // Confirm this change, not whichever is pending by then
+    pub operation: String, // e.g. "switch_mode router"
+    pub applied_at: u64, // Unix seconds
+    pub expires_at: u64, // Unix seconds; rolled back after this
+}
+
+// What pending_confirm.json holds: the change and what it is rolled back to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Pending {
+    change: PendingChange,
+    previous_config: Config,
+    previous_state: Option<ModeState>, // None: there was no mode state (Host Mode)
+}
+
+// The one change that can be in flight: being applied or rolled back, or waiting for confirmation
+enum Slot {
+    Busy(String), // Operation being applied or rolled back
+    Pending(Box<Pending>),
+}
+
+impl Slot {
+    fn operation(&self) -> &str {
+        match self {
+            Slot::Busy(operation) => operation,
+            Slot::Pending(pending) => &pending.change.operation,
+        }
+    }
+}
+
+static PENDING: Mutex<Option<Slot>> = Mutex::new(None);
+
+// Frees a Busy slot when dropped, also if the change panics
+struct Reservation;
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut slot = PENDING.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        if matches!(*slot, Some(Slot::Busy(_))) {
+            *slot = None;
+        }
+    }
+}
+
+// Take the slot for an operation, unless another change has it
+fn reserve(operation: &str) -> Result<Reservation, ConfirmError> {
+    let mut slot = PENDING.lock().unwrap();
+    if let Some(slot) = slot.as_ref() {
+        return Err(ConfirmError::AlreadyPending(slot.operation().to_string()));
+    }
+    *slot = Some(Slot::Busy(operation.to_string()));
+    Ok(Reservation)
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// The change waiting for confirmation, if any
+pub fn pending() -> Option<PendingChange> {
+    match PENDING.lock().unwrap().as_ref() {
+        Some(Slot::Pending(pending)) => Some(pending.change.clone()),
+        _ => None,
+    }
+}
+
+// switch_mode (which also covers LAN CIDR updates in Router Mode), rolled back after timeout_secs unless confirmed
+pub fn switch_mode_with_confirm(target_mode: SystemMode, lan_cidr: Option<String>, timeout_secs: u64) -> Result<PendingChange, ConfirmError> {
+    if !(MIN_CONFIRM_TIMEOUT_SECS..=MAX_CONFIRM_TIMEOUT_SECS).contains(&timeout_secs) {
+        return Err(ConfirmError::InvalidTimeout(timeout_secs));
+    }
+    let load_config = || conf::util::get_config()
+        .map_err(|e| ConfirmError::ConfigError(format!("Failed to load config: {}", e)));
+    let operation = match (SystemMode::from(load_config()?.agent.router.mode.as_str()), target_mode, &lan_cidr) {
+        (SystemMode::Router, SystemMode::Router, Some(cidr)) => format!("update_lan_cidr {}", cidr),
+        _ => format!("switch_mode {}", String::from(target_mode)),
+    };
+    let _reservation = reserve(&operation)?;
+
+    // Taken once the slot is ours, so no other change or rollback runs in between
+    let previous_config = load_config()?;
+    let previous_state = load_mode_state()
+        .map_err(|e| ModeError::PersistenceError(format!("Failed to load mode state: {}", e)))?;
+
+    mode_switch::switch_mode(target_mode, lan_cidr)?;
+
+    let applied_at = now_secs();
+    let change = PendingChange { id: Uuid::new_v4(), operation, applied_at, expires_at: applied_at + timeout_secs };
+    let new_pending = Pending { change: change.clone(), previous_config, previous_state };
+    if let Err(e) = save_pending(Some(&new_pending)) {
+        // Still rolled back on time, just not after a restart
+        log::warn!("Failed to save the pending change: {}", e);
+    }
+    log::info!("Applied {}, rolling back at {} unless confirmed", change.operation, change.expires_at);
+    *PENDING.lock().unwrap() = Some(Slot::Pending(Box::new(new_pending)));
+    Ok(change)
+}
+
+// Keep the pending change (only the given one, if an id is given)
+pub fn confirm(id: Option<Uuid>) -> Result<PendingChange, ConfirmError> {
+    let mut slot = PENDING.lock().unwrap();
+    let confirmed = match slot.take() {
+        Some(Slot::Pending(pending)) if id.is_none_or(|id| id == pending.change.id) => pending.change,
+        other => {
+            *slot = other;
+            return Err(ConfirmError::NothingPending);
+        }
+    };
+    if let Err(e) = save_pending(None) {
+        log::warn!("Failed to remove the pending change file: {}", e);
+    }
+    log::info!("Confirmed {}", confirmed.operation);
+    Ok(confirmed)
+}
+
+// Undo a change: the inverse switch or LAN CIDR update, then the previous mode state and config exactly
+fn rollback(pending: &Pending) -> Result<(), ConfirmError> {
+    let config = conf::util::get_config()
+        .map_err(|e| ConfirmError::ConfigError(format!("Failed to load config: {}", e)))?;
+    let current_mode = SystemMode::from(config.agent.router.mode.as_str());
+    let previous_mode = SystemMode::from(pending.previous_config.agent.router.mode.as_str());
+    let previous_cidr = pending.previous_config.agent.router.lan_cidr.clone()
+        .or_else(|| pending.previous_state.as_ref().and_then(|state| state.lan_cidr.clone()));
+
+    if current_mode != previous_mode {
+        mode_switch::revert_mode_switch(previous_mode, previous_cidr)?;
+    } else if previous_mode == SystemMode::Router
+        && let Some(previous_cidr) = previous_cidr.filter(|cidr| Some(cidr) != config.agent.router.lan_cidr.as_ref()) {
+            mode_switch::update_lan_cidr(&previous_cidr)?;
+        }
+
+    // Exit node, peer tables, LAN access and the rest are back as they were; the reconciler applies them
+    match &pending.previous_state {
+        Some(state) => save_mode_state(state),
+        None => clear_mode_state(),
+    }.map_err(|e| ModeError::PersistenceError(format!("Failed to restore mode state: {}", e)))?;
+    // Peers and other edits made during the window go too
+    let digest = conf::util::get_network_digest().ok();
+    let mut config = pending.previous_config.clone();
+    conf::util::set_config(&mut config)
+        .map_err(|e| ConfirmError::ConfigError(format!("Failed to restore config: {}", e)))?;
+    if config.agent.vpn.enabled
+        && conf::util::get_network_digest().ok() != digest
+        && let Err(e) = crate::wireguard::cmd::sync_conf(&config) {
+            log::warn!("Failed to sync WireGuard after rolling back {}: {}", pending.change.operation, e);
+        }
+    if previous_mode == SystemMode::Router
+        && let Err(e) = super::reconcile::reconcile(&config.network) {
+            log::warn!("Failed to converge routing after rolling back {}: {}", pending.change.operation, e);
+        }
+    Ok(())
+}
+
+// Roll back the pending change if its time is up
+fn expire() {
+    let expired = {
+        let mut slot = PENDING.lock().unwrap();
+        match slot.take() {
+            Some(Slot::Pending(pending)) if now_secs() >= pending.change.expires_at => {
+                // Busy until the rollback is done, so no new change starts from a half-restored state
+                *slot = Some(Slot::Busy(format!("rollback of {}", pending.change.operation)));
+                pending
+            }
+            other => {
+                *slot = other;
+                return;
+            }
+        }
+    };
+    let _reservation = Reservation;
+    log::warn!("{} was not confirmed in time, rolling it back", expired.change.operation);
+    if let Err(e) = rollback(&expired) {
+        // Keep the change (and what it rolls back to) pending, also across a restart, and try again
+        log::error!("Failed to roll back {}: {} (trying again in {}s)", expired.change.operation, e, ROLLBACK_RETRY_SECS);
+        events::record(EventKind::RollbackFailed, Initiator::Monitor, EventDetails {
+            reason: Some(format!("{}: {}", expired.change.operation, e)),
+            ..Default::default()
+        });
+        let mut retry = expired;
+        retry.change.expires_at = now_secs() + ROLLBACK_RETRY_SECS;
+        if let Err(e) = save_pending(Some(&retry)) {
+            log::warn!("Failed to save the pending change: {}", e);
+        }
+        *PENDING.lock().unwrap() = Some(Slot::Pending(retry));
+        return;
+    }
+    log::info!("Rolled back {}", expired.change.operation);
+    if let Err(e) = save_pending(None) {
+        log::warn!("Failed to remove the pending change file: {}", e);
+    }
+    events::record(EventKind::Rollback, Initiator::Monitor, EventDetails {
+        reason: Some(format!("{} was not confirmed", expired.change.operation)),
+        ..Default::default()
+    });
+}
+
+// Background task: picks up a change left pending by a restart, and rolls back unconfirmed changes
+pub async fn start_rollback_timer() -> std::io::Result<()> {
+    match load_pending() {
+        Ok(Some(pending)) => {
+            log::warn!("{} is still waiting for confirmation (rolled back at {})", pending.change.operation, pending.change.expires_at);
+            *PENDING.lock().unwrap() = Some(Slot::Pending(Box::new(pending)));
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to load the pending change: {}", e),
+    }
+
+    let mut ticker = interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        if pending().is_some_and(|change| now_secs() >= change.expires_at)
+            && let Err(e) = tokio::task::spawn_blocking(expire).await {
+                log::warn!("Rollback task failed: {}", e);
+            }
+    }
+}
+
+fn get_pending_file_path() -> Result<PathBuf, ConfirmError> {
+    WG_QUICKRS_CONFIG_FOLDER.get()
+        .map(|folder| folder.join(PENDING_FILE))
+        .ok_or_else(|| ConfirmError::IoError(std::io::Error::new(std::io::ErrorKind::NotFound, "Config folder not initialized")))
+}
+
+fn load_pending() -> Result<Option<Pending>, ConfirmError> {
+    match fs::read_to_string(get_pending_file_path()?) {
+        Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Atomic write (temp file and rename), like router_mode_state.json; None removes the file
+fn save_pending(pending: Option<&Pending>) -> Result<(), ConfirmError> {
+    let file_path = get_pending_file_path()?;
+    let Some(pending) = pending else {
+        return match fs::remove_file(&file_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    };
+    let temp_path = file_path.with_file_name(PENDING_TEMP_FILE);
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(serde_json::to_string_pretty(pending)?.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, &file_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall::MANAGED_CHAINS;
+    use crate::mode::simulated::SimulatedKernel;
+    use once_cell::sync::Lazy;
+    use std::path::Path;
+    use std::sync::{Arc, MutexGuard};
+    use wg_quickrs_lib::types::config::ConfigFile;
+
+    // The config folder is process-wide, so it has to outlive the test
+    static CONFIG_FOLDER: Lazy<tempfile::TempDir> = Lazy::new(|| tempfile::tempdir().unwrap());
+
+    fn fixture_config() -> Config {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/config/router_mode.yml");
+        let config_file: ConfigFile = serde_yml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        Config::from(&config_file)
+    }
+
+    // Router Mode on 192.168.1.0/24 with a table for each peer, converged into a simulated kernel
+    fn router_mode() -> (MutexGuard<'static, ()>, Arc<SimulatedKernel>, Config, ModeState) {
+        let guard = crate::mode::simulated::test_lock();
+        let _ = WG_QUICKRS_CONFIG_FOLDER.set(CONFIG_FOLDER.path().to_path_buf());
+        let _ = crate::WG_QUICKRS_CONFIG_FILE.set(WG_QUICKRS_CONFIG_FOLDER.get().unwrap().join("conf.yml"));
+        let kernel = Arc::new(SimulatedKernel::new()
+            .with_interface("eth0", "192.168.1.10/24")
+            .with_interface("wg0", "10.0.34.1/24"));
+        crate::mode::backend::set_routing_backend(kernel.clone());
+        crate::firewall::set_firewall_backend(kernel.clone());
+
+        let mut config = fixture_config();
+        conf::util::set_config(&mut config).unwrap();
+        let mut state = ModeState::new(SystemMode::Router, config.agent.router.lan_cidr.clone());
+        for (table_id, peer_id) in (1000..).zip(config.network.peers.keys().filter(|id| **id != config.network.this_peer)) {
+            state.peer_table_ids.insert(peer_id.to_string(), table_id);
+        }
+        save_mode_state(&state).unwrap();
+        *PENDING.lock().unwrap() = None;
+        save_pending(None).unwrap();
+        super::super::reconcile::reconcile(&config.network).unwrap();
+        (guard, kernel, config, state)
+    }
+
+    // Rules, routes and iptables rules, in a stable order
+    fn kernel_state(kernel: &SimulatedKernel) -> (Vec<String>, Vec<String>, Vec<String>) {
+        let mut rules: Vec<String> = kernel.rules().iter().map(ToString::to_string).collect();
+        rules.sort();
+        let routes = kernel.tables().into_iter()
+            .flat_map(|table| kernel.routes(table))
+            .map(|route| route.to_string())
+            .collect();
+        let firewall = MANAGED_CHAINS.iter()
+            .flat_map(|(table, chain)| kernel.firewall_rules(table, chain).into_iter().map(move |rule| format!("-t {} {} {}", table, chain, rule)))
+            .collect();
+        (rules, routes, firewall)
+    }
+
+    fn json<T: Serialize>(value: &T) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_pending_change_survives_restart() {
+        let _ = WG_QUICKRS_CONFIG_FOLDER.set(CONFIG_FOLDER.path().to_path_buf());
+
+        let mut previous_state = ModeState::new(SystemMode::Router, Some("192.168.1.0/24".to_string()));
+        previous_state.peer_table_ids.insert(Uuid::new_v4().to_string(), 1000);
+        let pending = Pending {
+            change: PendingChange { id: Uuid::new_v4(), operation: "update_lan_cidr 10.0.0.0/24".to_string(), applied_at: 100, expires_at: 160 },
+            previous_config: fixture_config(),
+            previous_state: Some(previous_state),
+        };
+        save_pending(Some(&pending)).unwrap();
+        let loaded = load_pending().unwrap().unwrap();
+        assert_eq!(loaded.change, pending.change);
+        assert_eq!(json(&loaded.previous_config), json(&pending.previous_config));
+        assert_eq!(loaded.previous_state.unwrap().peer_table_ids, pending.previous_state.unwrap().peer_table_ids);
+
+        save_pending(None).unwrap();
+        assert!(load_pending().unwrap().is_none());
+        save_pending(None).unwrap();
+    }
+
+    #[test]
+    fn test_confirm_timeout_bounds_and_nothing_pending() {
+        let _guard = crate::mode::simulated::test_lock();
+        assert!(matches!(switch_mode_with_confirm(SystemMode::Router, None, 5), Err(ConfirmError::InvalidTimeout(5))));
+        assert!(matches!(switch_mode_with_confirm(SystemMode::Router, None, 7200), Err(ConfirmError::InvalidTimeout(7200))));
+        assert!(matches!(confirm(None), Err(ConfirmError::NothingPending)));
+    }
+
+    #[test]
+    fn test_unconfirmed_change_is_rolled_back() {
+        let (_guard, kernel, config, state) = router_mode();
+        let before = kernel_state(&kernel);
+        assert!(!before.0.is_empty() && !before.1.is_empty() && !before.2.is_empty(), "{:?}", before);
+
+        let change = switch_mode_with_confirm(SystemMode::Router, Some("192.168.1.0/24,192.168.2.0/24".to_string()), 60).unwrap();
+        assert_eq!(pending(), Some(change.clone()));
+        assert!(matches!(
+            switch_mode_with_confirm(SystemMode::Host, None, 60),
+            Err(ConfirmError::AlreadyPending(operation)) if operation == "update_lan_cidr 192.168.1.0/24,192.168.2.0/24"
+        ));
+        assert!(kernel_state(&kernel).2.iter().any(|rule| rule.contains("-s 192.168.2.0/24")));
+        // An unrelated edit during the window is undone as well
+        let mut edited = conf::util::get_config().unwrap();
+        edited.agent.router.failover.consecutive_failures += 1;
+        edited.network.reservations.clear();
+        conf::util::set_config(&mut edited).unwrap();
+
+        // Not yet due
+        expire();
+        assert_eq!(pending(), Some(change.clone()));
+        if let Some(Slot::Pending(pending)) = PENDING.lock().unwrap().as_mut() {
+            pending.change.expires_at = now_secs();
+        }
+        expire();
+        assert_eq!(pending(), None);
+        assert!(PENDING.lock().unwrap().is_none());
+        assert!(load_pending().unwrap().is_none());
+        assert_eq!(json(&conf::util::get_config().unwrap()), json(&config));
+        assert_eq!(json(&load_mode_state().unwrap().unwrap()), json(&state));
+        assert_eq!(kernel_state(&kernel), before);
+    }
+
+    #[test]
+    fn test_failed_rollback_stays_pending() {
+        let (_guard, _kernel, config, _state) = router_mode();
+        let rollbacks = |kind| events::query(&events::EventFilter { kinds: vec![kind], ..Default::default() }, 100).unwrap();
+        let succeeded = rollbacks(EventKind::Rollback).len();
+
+        let change = switch_mode_with_confirm(SystemMode::Router, Some("192.168.1.0/24,192.168.2.0/24".to_string()), 60).unwrap();
+        // A previous LAN CIDR update_lan_cidr rejects
+        if let Some(Slot::Pending(pending)) = PENDING.lock().unwrap().as_mut() {
+            pending.previous_config.agent.router.lan_cidr = Some("192.168.1.0/33".to_string());
+            pending.change.expires_at = now_secs();
+        }
+        expire();
+        let retry = pending().unwrap();
+        assert_eq!(retry.id, change.id);
+        assert!(retry.expires_at >= now_secs() + ROLLBACK_RETRY_SECS - 1);
+        assert_eq!(load_pending().unwrap().unwrap().change, retry);
+        let failed = rollbacks(EventKind::RollbackFailed);
+        assert!(failed[0].details.reason.as_ref().is_some_and(|reason| reason.starts_with(&change.operation)), "{:?}", failed[0]);
+        assert_eq!(rollbacks(EventKind::Rollback).len(), succeeded);
+        assert_eq!(conf::util::get_config().unwrap().agent.router.lan_cidr.as_deref(), Some("192.168.1.0/24,192.168.2.0/24"));
+
+        // The next attempt succeeds
+        if let Some(Slot::Pending(pending)) = PENDING.lock().unwrap().as_mut() {
+            pending.previous_config.agent.router.lan_cidr = config.agent.router.lan_cidr.clone();
+            pending.change.expires_at = now_secs();
+        }
+        expire();
+        assert_eq!(pending(), None);
+        assert!(load_pending().unwrap().is_none());
+        assert_eq!(rollbacks(EventKind::Rollback).len(), succeeded + 1);
+        assert_eq!(conf::util::get_config().unwrap().agent.router.lan_cidr, config.agent.router.lan_cidr);
+    }
+
+    #[test]
+    fn test_confirmed_change_is_kept() {
+        let (_guard, kernel, _config, _state) = router_mode();
+
+        let change = switch_mode_with_confirm(SystemMode::Router, Some("192.168.1.0/24,192.168.2.0/24".to_string()), 60).unwrap();
+        let after = kernel_state(&kernel);
+        assert!(matches!(confirm(Some(Uuid::new_v4())), Err(ConfirmError::NothingPending)));
+        assert_eq!(confirm(Some(change.id)).unwrap(), change);
+        assert_eq!(pending(), None);
+        assert!(load_pending().unwrap().is_none());
+
+        expire();
+        assert_eq!(conf::util::get_config().unwrap().agent.router.lan_cidr.as_deref(), Some("192.168.1.0/24,192.168.2.0/24"));
+        assert_eq!(load_mode_state().unwrap().unwrap().lan_cidr.as_deref(), Some("192.168.1.0/24,192.168.2.0/24"));
+        assert_eq!(kernel_state(&kernel), after);
+    }
+}
@@ -0,0 +1,342 @@
+// Event journal: what changed in Router Mode, when, why and who did it
+//
+// The health monitor's switches used to leave only log lines behind, read back from journalctl.
+// Every status transition, failover, fail-back, manual switch, mode change, LAN access change, quota
+// overrun and corrected routing drift is also appended as one JSON line to router_events.jsonl next to router_mode_state.json:
+// - Each event has an increasing id, which is the paging cursor of GET /api/events
+// - Once the journal holds MAX_EVENTS plus a tenth, it is rewritten with the newest MAX_EVENTS
+// - Lines that cannot be parsed (an interrupted write) are skipped
+
+use super::routing_pbr::ExitNodeHealth;
+use crate::WG_QUICKRS_CONFIG_FOLDER;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use thiserror::Error;
+use uuid::Uuid;
+
+const EVENTS_FILE: &str = "router_events.jsonl";
+const EVENTS_TEMP_FILE: &str = "router_events.jsonl.tmp";
+const MAX_EVENTS: usize = 10_000;
+
+#[derive(Error, Debug)]
+pub enum EventError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Config folder not initialized")]
+    NoConfigFolder,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Online,         // An exit node is healthy again
+    Offline,        // An exit node stopped answering
+    Degraded,       // An exit node answers but exceeds an SLA threshold
+    Failover,       // Smart Gateway left a failed exit node
+    Failback,       // Smart Gateway went back to a higher tier
+    PrefixFailover, // An overlapping prefix moved to its backup peer
+    PrefixFailback, // An overlapping prefix went back to the peer it failed over from
+    ManualSwitch,   // The exit node or a prefix's active peer was chosen through the API
+    ModeChange,     // Host Mode ↔ Router Mode
+    LanAccess,      // A peer's LAN access was allowed or denied
+    QuotaExceeded,  // A peer used up its daily or monthly traffic quota
+    QuotaReset,     // A new period began (or the quota was raised) for a peer over its quota
+    Drift,          // The reconciler found kernel routing or firewall state off the desired state and corrected it
+    Rollback,       // A mode switch or LAN CIDR change was not confirmed in time and was rolled back
+    RollbackFailed, // Rolling back an unconfirmed change failed (it is tried again)
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 15] = [
+        EventKind::Online,
+        EventKind::Offline,
+        EventKind::Degraded,
+        EventKind::Failover,
+        EventKind::Failback,
+        EventKind::PrefixFailover,
+        EventKind::PrefixFailback,
+        EventKind::ManualSwitch,
+        EventKind::ModeChange,
+        EventKind::LanAccess,
+        EventKind::QuotaExceeded,
+        EventKind::QuotaReset,
+        EventKind::Drift,
+        EventKind::Rollback,
+        EventKind::RollbackFailed,
+    ];
+
+    // The name used in the journal and the API
+    pub fn name(&self) -> String {
+        serde_json::to_value(self).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default()
+    }
+
+    // From the name used in the journal and the API ("failover", "manual_switch", ...)
+    pub fn parse(name: &str) -> Option<EventKind> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Initiator {
+    Monitor, // The health monitor / Smart Gateway / usage accounting
+    Api,     // A request to the web API (web UI or a script)
+}
+
+// Health of the exit node that triggered an event
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EventMetrics {
+    pub latency_ms: Option<u64>,
+    pub packet_loss_percent: Option<f64>,
+    pub jitter_ms: Option<u64>,
+}
+
+impl From<&ExitNodeHealth> for EventMetrics {
+    fn from(health: &ExitNodeHealth) -> Self {
+        EventMetrics {
+            latency_ms: health.latency_ms,
+            packet_loss_percent: health.packet_loss_percent,
+            jitter_ms: health.jitter_ms,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EventDetails {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<Uuid>, // The peer the event is about (status and LAN access changes)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_peer: Option<Uuid>, // Switches: the exit node (or active peer) before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_peer: Option<Uuid>, // Switches: the exit node (or active peer) after
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<EventMetrics>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: u64,
+    pub ts: u64, // Unix seconds
+    pub kind: EventKind,
+    pub initiator: Initiator,
+    #[serde(flatten)]
+    pub details: EventDetails,
+}
+
+impl Event {
+    fn involves(&self, peer_id: &Uuid) -> bool {
+        [self.details.peer_id, self.details.old_peer, self.details.new_peer].contains(&Some(*peer_id))
+    }
+}
+
+// Which events to return: all conditions that are set must match
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub kinds: Vec<EventKind>, // Any of these (all kinds when empty)
+    pub peer: Option<Uuid>,    // As the subject, the old or the new peer
+    pub since: Option<u64>,    // ts >= since
+    pub until: Option<u64>,    // ts <= until
+    pub before: Option<u64>,   // id < before (the paging cursor)
+}
+
+impl EventFilter {
+    fn matches(&self, event: &Event) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && self.peer.is_none_or(|peer| event.involves(&peer))
+            && self.since.is_none_or(|since| event.ts >= since)
+            && self.until.is_none_or(|until| event.ts <= until)
+            && self.before.is_none_or(|before| event.id < before)
+    }
+}
+
+// Next id and number of events in the file, read from it on first use
+struct JournalCursor {
+    next_id: u64,
+    count: usize,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    max_events: usize,
+    cursor: Mutex<Option<JournalCursor>>,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf, max_events: usize) -> Self {
+        Journal { path, max_events, cursor: Mutex::new(None) }
+    }
+
+    fn read_all(&self) -> Result<Vec<Event>, EventError> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut events = Vec::new();
+        for line in BufReader::new(file).lines() {
+            if let Ok(event) = serde_json::from_str::<Event>(&line?) {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    // Append an event taken at `ts` and return it with its id
+    pub fn append(&self, kind: EventKind, initiator: Initiator, details: EventDetails, ts: u64) -> Result<Event, EventError> {
+        let mut cursor = self.cursor.lock().unwrap();
+        if cursor.is_none() {
+            let events = self.read_all()?;
+            *cursor = Some(JournalCursor {
+                next_id: events.last().map_or(1, |event| event.id + 1),
+                count: events.len(),
+            });
+        }
+        let cursor = cursor.as_mut().unwrap();
+
+        let event = Event { id: cursor.next_id, ts, kind, initiator, details };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&event)?)?;
+        cursor.next_id += 1;
+        cursor.count += 1;
+
+        if cursor.count > self.max_events + self.max_events / 10 {
+            cursor.count = self.trim()?;
+        }
+        Ok(event)
+    }
+
+    // Keep the newest max_events (atomic rewrite); returns how many are left
+    fn trim(&self) -> Result<usize, EventError> {
+        let events = self.read_all()?;
+        let keep = &events[events.len().saturating_sub(self.max_events)..];
+        let temp_path = self.path.with_file_name(EVENTS_TEMP_FILE);
+        {
+            let mut file = File::create(&temp_path)?;
+            for event in keep {
+                writeln!(file, "{}", serde_json::to_string(event)?)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.path)?;
+        Ok(keep.len())
+    }
+
+    // Matching events, newest first, at most `limit`
+    pub fn query(&self, filter: &EventFilter, limit: usize) -> Result<Vec<Event>, EventError> {
+        let _cursor = self.cursor.lock().unwrap();
+        Ok(self.read_all()?.into_iter().rev().filter(|event| filter.matches(event)).take(limit).collect())
+    }
+}
+
+static JOURNAL: OnceCell<Journal> = OnceCell::new();
+
+fn journal() -> Result<&'static Journal, EventError> {
+    let config_folder = WG_QUICKRS_CONFIG_FOLDER.get().ok_or(EventError::NoConfigFolder)?;
+    Ok(JOURNAL.get_or_init(|| Journal::new(config_folder.join(EVENTS_FILE), MAX_EVENTS)))
+}
+
+// Events recorded since the agent started (for /metrics)
+#[derive(Debug, Clone, Default)]
+pub struct EventCounters {
+    pub by_kind: HashMap<EventKind, u64>,
+    pub failovers_from: HashMap<Uuid, u64>, // automatic failovers (exit node or prefix) away from a peer
+}
+
+static COUNTERS: Lazy<Mutex<EventCounters>> = Lazy::new(|| Mutex::new(EventCounters::default()));
+
+pub fn counters() -> EventCounters {
+    COUNTERS.lock().unwrap().clone()
+}
+
+// Append an event to the journal and notify the configured sinks
+// (failures are logged, they never stop the change itself)
+pub fn record(kind: EventKind, initiator: Initiator, details: EventDetails) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let event = match journal().and_then(|journal| journal.append(kind, initiator, details.clone(), now)) {
+        Ok(event) => event,
+        Err(e) => {
+            log::warn!("Failed to record {:?} event: {}", kind, e);
+            Event { id: 0, ts: now, kind, initiator, details }
+        }
+    };
+    {
+        let mut counters = COUNTERS.lock().unwrap();
+        *counters.by_kind.entry(kind).or_default() += 1;
+        if matches!(kind, EventKind::Failover | EventKind::PrefixFailover)
+            && let Some(old_peer) = event.details.old_peer {
+                *counters.failovers_from.entry(old_peer).or_default() += 1;
+            }
+    }
+    super::notify::dispatch(&event);
+    crate::web::stream::publish(crate::web::stream::StreamMessage::Event { event });
+}
+
+// Journal events, newest first, see Journal::query
+pub fn query(filter: &EventFilter, limit: usize) -> Result<Vec<Event>, EventError> {
+    journal()?.query(filter, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_filter_paging_and_trim() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(EVENTS_FILE);
+        let journal = Journal::new(path.clone(), 10);
+        let (peer_a, peer_b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        for ts in 0..6 {
+            journal.append(EventKind::Offline, Initiator::Monitor, EventDetails { peer_id: Some(peer_a), ..Default::default() }, ts).unwrap();
+        }
+        let failover = journal.append(EventKind::Failover, Initiator::Monitor, EventDetails {
+            old_peer: Some(peer_a),
+            new_peer: Some(peer_b),
+            reason: Some("went offline".to_string()),
+            metrics: Some(EventMetrics { packet_loss_percent: Some(100.0), ..Default::default() }),
+            ..Default::default()
+        }, 6).unwrap();
+        assert_eq!(failover.id, 7);
+
+        // Newest first, filtered by kind or by any peer involved
+        let filter = EventFilter { kinds: vec![EventKind::Failover], ..Default::default() };
+        assert_eq!(journal.query(&filter, 50).unwrap(), vec![failover.clone()]);
+        let filter = EventFilter { peer: Some(peer_b), ..Default::default() };
+        assert_eq!(journal.query(&filter, 50).unwrap().len(), 1);
+
+        // Paging with the id cursor
+        let page = journal.query(&EventFilter::default(), 3).unwrap();
+        assert_eq!(page.iter().map(|e| e.id).collect::<Vec<_>>(), vec![7, 6, 5]);
+        let filter = EventFilter { before: Some(5), since: Some(1), ..Default::default() };
+        assert_eq!(journal.query(&filter, 50).unwrap().iter().map(|e| e.id).collect::<Vec<_>>(), vec![4, 3, 2]);
+
+        // Ids continue after a restart, and the journal is trimmed to the newest events
+        let journal = Journal::new(path, 10);
+        for ts in 7..12 {
+            journal.append(EventKind::Online, Initiator::Monitor, EventDetails::default(), ts).unwrap();
+        }
+        let events = journal.query(&EventFilter::default(), 50).unwrap();
+        assert_eq!(events.len(), 10);
+        assert_eq!((events[0].id, events[9].id), (12, 3));
+
+        assert_eq!(EventKind::parse("manual_switch"), Some(EventKind::ManualSwitch));
+        assert_eq!(EventKind::parse("reboot"), None);
+        assert!(EventKind::ALL.iter().all(|kind| EventKind::parse(&kind.name()) == Some(*kind)));
+    }
+}
@@ -0,0 +1,341 @@
+// Persistent health history: per exit node time series on disk at three resolutions
+//
+// PING_HISTORY and the telemetry buffer only hold the last minute in memory, so an outage during
+// the night is gone by the morning (or by the next restart). Every health monitor round is also
+// written to ring files in <config folder>/health_history/, one per exit node and resolution:
+// - <peer-id>.1s: one slot per second for 6 hours
+// - <peer-id>.1m: one slot per minute for 30 days
+// - <peer-id>.1h: one slot per hour for a year
+//
+// A sample taken at `ts` goes to slot (ts / step) % slots of each file, so a write touches one
+// fixed-size record per file and the oldest data is overwritten in place (like an RRD). Samples
+// that fall into the same slot are averaged, which is the downsampling. Every slot stores its own
+// start time, so a slot still holding data from an earlier lap is skipped when read.
+
+use super::routing_pbr::ExitNodeHealth;
+use crate::WG_QUICKRS_CONFIG_FOLDER;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use thiserror::Error;
+use uuid::Uuid;
+
+const HISTORY_DIR: &str = "health_history";
+
+// Slot layout (little endian): ts u64, samples u32, online u32, latency f32, loss f32, jitter f32,
+// handshake age f32, rx f64, tx f64; NaN is a missing value, ts 0 an empty slot
+const RECORD_SIZE: usize = 48;
+
+// (step in seconds, slots, file suffix), finest first
+const RESOLUTIONS: [(u64, u64, &str); 3] = [
+    (1, 6 * 3600, "1s"),
+    (60, 30 * 24 * 60, "1m"),
+    (3600, 365 * 24, "1h"),
+];
+
+// Longest answer when the step is picked automatically
+const MAX_AUTO_POINTS: u64 = 1500;
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Config folder not initialized")]
+    NoConfigFolder,
+    #[error("step {0} is not stored (use 1, 60 or 3600 seconds)")]
+    InvalidStep(u64),
+    #[error("from ({0}) is after to ({1})")]
+    InvalidRange(u64, u64),
+}
+
+// One slot of the history: the averages of the samples taken in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct HistoryPoint {
+    pub ts: u64,             // Start of the slot (Unix seconds)
+    pub samples: u32,        // Health monitor rounds in the slot
+    pub online_samples: u32, // Rounds in which the exit node was online
+    pub latency_ms: Option<f64>,
+    pub packet_loss_percent: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    pub handshake_age_secs: Option<f64>,
+    pub rx_bytes_per_sec: Option<f64>,
+    pub tx_bytes_per_sec: Option<f64>,
+}
+
+impl HistoryPoint {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let narrow = |value: Option<f64>| value.map_or(f32::NAN, |v| v as f32).to_le_bytes();
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..8].copy_from_slice(&self.ts.to_le_bytes());
+        record[8..12].copy_from_slice(&self.samples.to_le_bytes());
+        record[12..16].copy_from_slice(&self.online_samples.to_le_bytes());
+        record[16..20].copy_from_slice(&narrow(self.latency_ms));
+        record[20..24].copy_from_slice(&narrow(self.packet_loss_percent));
+        record[24..28].copy_from_slice(&narrow(self.jitter_ms));
+        record[28..32].copy_from_slice(&narrow(self.handshake_age_secs));
+        record[32..40].copy_from_slice(&self.rx_bytes_per_sec.unwrap_or(f64::NAN).to_le_bytes());
+        record[40..48].copy_from_slice(&self.tx_bytes_per_sec.unwrap_or(f64::NAN).to_le_bytes());
+        record
+    }
+
+    fn decode(record: &[u8]) -> Self {
+        let narrow = |at: usize| {
+            let value = f32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+            (!value.is_nan()).then_some(value as f64)
+        };
+        let wide = |at: usize| {
+            let value = f64::from_le_bytes(record[at..at + 8].try_into().unwrap());
+            (!value.is_nan()).then_some(value)
+        };
+        HistoryPoint {
+            ts: u64::from_le_bytes(record[0..8].try_into().unwrap()),
+            samples: u32::from_le_bytes(record[8..12].try_into().unwrap()),
+            online_samples: u32::from_le_bytes(record[12..16].try_into().unwrap()),
+            latency_ms: narrow(16),
+            packet_loss_percent: narrow(20),
+            jitter_ms: narrow(24),
+            handshake_age_secs: narrow(28),
+            rx_bytes_per_sec: wide(32),
+            tx_bytes_per_sec: wide(40),
+        }
+    }
+
+    // Fold another sample into the running averages of this slot
+    fn merge(&mut self, sample: &HistoryPoint) {
+        let (old, new) = (self.samples as f64, sample.samples as f64);
+        let average = |a: Option<f64>, b: Option<f64>| match (a, b) {
+            (Some(a), Some(b)) => Some((a * old + b * new) / (old + new)),
+            (a, b) => a.or(b),
+        };
+        self.latency_ms = average(self.latency_ms, sample.latency_ms);
+        self.packet_loss_percent = average(self.packet_loss_percent, sample.packet_loss_percent);
+        self.jitter_ms = average(self.jitter_ms, sample.jitter_ms);
+        self.handshake_age_secs = average(self.handshake_age_secs, sample.handshake_age_secs);
+        self.rx_bytes_per_sec = average(self.rx_bytes_per_sec, sample.rx_bytes_per_sec);
+        self.tx_bytes_per_sec = average(self.tx_bytes_per_sec, sample.tx_bytes_per_sec);
+        self.samples += sample.samples;
+        self.online_samples += sample.online_samples;
+    }
+}
+
+// Ring files of every exit node under one folder
+pub struct HistoryStore {
+    dir: PathBuf,
+}
+
+impl HistoryStore {
+    pub fn new(dir: PathBuf) -> Self {
+        HistoryStore { dir }
+    }
+
+    fn open(&self, peer_id: &Uuid, suffix: &str, slots: u64) -> Result<File, HistoryError> {
+        fs::create_dir_all(&self.dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join(format!("{}.{}", peer_id, suffix)))?;
+        // A new file is sparse: all slots read as empty
+        let size = slots * RECORD_SIZE as u64;
+        if file.metadata()?.len() != size {
+            file.set_len(size)?;
+        }
+        Ok(file)
+    }
+
+    // Add one sample (taken at `ts`) to the slot it falls into at every resolution
+    pub fn record(&self, peer_id: &Uuid, ts: u64, sample: &HistoryPoint) -> Result<(), HistoryError> {
+        for (step, slots, suffix) in RESOLUTIONS {
+            let file = self.open(peer_id, suffix, slots)?;
+            let slot_ts = ts - ts % step;
+            let offset = (ts / step % slots) * RECORD_SIZE as u64;
+            let mut record = [0u8; RECORD_SIZE];
+            file.read_exact_at(&mut record, offset)?;
+            let mut point = HistoryPoint::decode(&record);
+            if point.ts == slot_ts && point.samples > 0 {
+                point.merge(sample);
+            } else {
+                point = HistoryPoint { ts: slot_ts, ..*sample };
+            }
+            file.write_all_at(&point.encode(), offset)?;
+        }
+        Ok(())
+    }
+
+    // Points between `from` and `to` (inclusive) at `step` seconds, oldest first; empty slots are left out
+    // Without a step, the finest resolution that still covers `from` in at most MAX_AUTO_POINTS points is used
+    pub fn query(&self, peer_id: &Uuid, from: u64, to: u64, step: Option<u64>, now: u64) -> Result<(u64, Vec<HistoryPoint>), HistoryError> {
+        if from > to {
+            return Err(HistoryError::InvalidRange(from, to));
+        }
+        let (step, slots, suffix) = match step {
+            Some(step) => *RESOLUTIONS.iter().find(|(s, _, _)| *s == step).ok_or(HistoryError::InvalidStep(step))?,
+            None => *RESOLUTIONS.iter()
+                .find(|(step, slots, _)| now.saturating_sub(from) < step * slots && (to - from) / step <= MAX_AUTO_POINTS)
+                .unwrap_or(&RESOLUTIONS[RESOLUTIONS.len() - 1]),
+        };
+        let path = self.dir.join(format!("{}.{}", peer_id, suffix));
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((step, Vec::new())),
+            Err(e) => return Err(e.into()),
+        };
+        // Slots older than one lap have been overwritten
+        let oldest = now.saturating_sub(step * (slots - 1));
+        let mut points = Vec::new();
+        let mut slot_ts = from.max(oldest) - from.max(oldest) % step;
+        // Nothing is stored ahead of now
+        while slot_ts <= to.min(now) {
+            let offset = (slot_ts / step % slots) as usize * RECORD_SIZE;
+            if let Some(record) = data.get(offset..offset + RECORD_SIZE) {
+                let point = HistoryPoint::decode(record);
+                if point.ts == slot_ts && point.samples > 0 {
+                    points.push(point);
+                }
+            }
+            slot_ts += step;
+        }
+        Ok((step, points))
+    }
+}
+
+// Serializes file access between the monitor tasks and API readers
+static HISTORY_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// Transfer counters of an exit node at its previous sample (rx, tx, Unix seconds)
+type Counters = (u64, u64, u64);
+
+static LAST_COUNTERS: Lazy<Mutex<HashMap<Uuid, Counters>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn store() -> Result<HistoryStore, HistoryError> {
+    let config_folder = WG_QUICKRS_CONFIG_FOLDER.get().ok_or(HistoryError::NoConfigFolder)?;
+    Ok(HistoryStore::new(config_folder.join(HISTORY_DIR)))
+}
+
+// Bytes per second since the previous sample; None for the first sample or after a counter reset
+fn throughput(previous: Option<Counters>, rx: u64, tx: u64, now: u64) -> (Option<f64>, Option<f64>) {
+    match previous {
+        Some((prev_rx, prev_tx, prev_ts)) if now > prev_ts && rx >= prev_rx && tx >= prev_tx => {
+            let elapsed = (now - prev_ts) as f64;
+            (Some((rx - prev_rx) as f64 / elapsed), Some((tx - prev_tx) as f64 / elapsed))
+        }
+        _ => (None, None),
+    }
+}
+
+// Record one health monitor result of an exit node (or the local breakout)
+pub fn record(health: &ExitNodeHealth) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let previous = LAST_COUNTERS.lock().unwrap()
+        .insert(health.peer_id, (health.transfer_rx, health.transfer_tx, now));
+    let (rx_bytes_per_sec, tx_bytes_per_sec) = throughput(previous, health.transfer_rx, health.transfer_tx, now);
+    let sample = HistoryPoint {
+        ts: now,
+        samples: 1,
+        online_samples: health.is_online as u32,
+        latency_ms: health.latency_ms.map(|v| v as f64),
+        packet_loss_percent: health.packet_loss_percent,
+        jitter_ms: health.jitter_ms.map(|v| v as f64),
+        handshake_age_secs: health.last_handshake
+            .filter(|ts| *ts > 0)
+            .map(|ts| now.saturating_sub(ts) as f64),
+        rx_bytes_per_sec,
+        tx_bytes_per_sec,
+    };
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    if let Err(e) = store().and_then(|store| store.record(&health.peer_id, now, &sample)) {
+        log::debug!("Failed to record health history for {}: {}", health.peer_id, e);
+    }
+}
+
+// Health history of one exit node, see HistoryStore::query
+pub fn query(peer_id: &Uuid, from: u64, to: u64, step: Option<u64>) -> Result<(u64, Vec<HistoryPoint>), HistoryError> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    store()?.query(peer_id, from, to, step, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(latency_ms: f64, online: bool) -> HistoryPoint {
+        HistoryPoint {
+            samples: 1,
+            online_samples: online as u32,
+            latency_ms: Some(latency_ms),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_history_downsampling() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = HistoryStore::new(dir.path().to_path_buf());
+        let peer_id = Uuid::new_v4();
+        let start = 1_767_225_600; // on a minute and hour boundary
+
+        // Two minutes of 1s samples: 10ms in the first minute, 30ms (and offline half the time) in the second
+        for second in 0..120 {
+            let point = if second < 60 { sample(10.0, true) } else { sample(30.0, second % 2 == 0) };
+            store.record(&peer_id, start + second, &point).unwrap();
+        }
+        let now = start + 120;
+
+        let (step, points) = store.query(&peer_id, start, now, Some(1), now).unwrap();
+        assert_eq!(step, 1);
+        assert_eq!(points.len(), 120);
+        assert_eq!(points[0].latency_ms, Some(10.0));
+        assert_eq!(points[0].packet_loss_percent, None);
+
+        let (_, points) = store.query(&peer_id, start, now, Some(60), now).unwrap();
+        assert_eq!(points.iter().map(|p| (p.ts, p.samples, p.online_samples)).collect::<Vec<_>>(),
+                   vec![(start, 60, 60), (start + 60, 60, 30)]);
+        assert!((points[1].latency_ms.unwrap() - 30.0).abs() < 1e-3);
+
+        let (_, points) = store.query(&peer_id, start, now, Some(3600), now).unwrap();
+        assert_eq!(points.len(), 1);
+        assert!((points[0].latency_ms.unwrap() - 20.0).abs() < 1e-3);
+
+        // Picked automatically: 1s still covers the range
+        assert_eq!(store.query(&peer_id, start, now, None, now).unwrap().0, 1);
+        // Last night is only kept per minute
+        assert_eq!(store.query(&peer_id, start, now, None, now + 12 * 3600).unwrap().0, 60);
+
+        assert!(matches!(store.query(&peer_id, start, now, Some(5), now), Err(HistoryError::InvalidStep(5))));
+        assert!(matches!(store.query(&peer_id, now, start, None, now), Err(HistoryError::InvalidRange(_, _))));
+        assert!(store.query(&Uuid::new_v4(), start, now, None, now).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn test_history_ring_overwrites_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = HistoryStore::new(dir.path().to_path_buf());
+        let peer_id = Uuid::new_v4();
+        let (_, slots, _) = RESOLUTIONS[0];
+        let start = 1_767_225_600;
+
+        store.record(&peer_id, start, &sample(10.0, true)).unwrap();
+        // One lap later the same 1s slot holds the new sample, not a merge with the old one
+        store.record(&peer_id, start + slots, &sample(50.0, true)).unwrap();
+        let now = start + slots;
+        let (_, points) = store.query(&peer_id, start, now, Some(1), now).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!((points[0].ts, points[0].samples, points[0].latency_ms), (start + slots, 1, Some(50.0)));
+
+        assert_eq!(throughput(None, 100, 100, 10), (None, None));
+        assert_eq!(throughput(Some((100, 0, 10)), 300, 50, 12), (Some(100.0), Some(25.0)));
+        assert_eq!(throughput(Some((100, 0, 10)), 50, 50, 12), (None, None));
+    }
+}
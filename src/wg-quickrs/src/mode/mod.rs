@@ -1,6 +1,19 @@
 // Mode module: Handles Router Mode and Host Mode switching
 pub mod ui_mode;
+#[allow(clippy::module_inception)]
 pub mod mode;
 pub mod routing_pbr;
 pub mod persist;
+pub mod runtime;
+pub mod reconcile;
+pub mod change_plan;
+pub mod confirm;
+pub mod backend;
+pub mod netlink;
+pub mod prober;
+pub mod history;
+pub mod events;
+pub mod notify;
+#[cfg(test)]
+pub mod simulated;
 
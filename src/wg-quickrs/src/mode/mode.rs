@@ -9,6 +9,8 @@
 
 use super::persist::{clear_mode_state, load_mode_state, save_mode_state, validate_and_cleanup_persisted_state, ModeState};
 use super::routing_pbr;
+use super::backend::routing_backend;
+use super::change_plan::ChangePlan;
 use crate::conf;
 use crate::helpers::shell_cmd;
 use crate::WG_QUICKRS_CONFIG_FILE;
@@ -67,6 +69,22 @@ fn validate_cidr(cidr: &str) -> Result<(), ModeError> {
 pub fn switch_mode(
     target_mode: SystemMode,
     lan_cidr: Option<String>,
+) -> Result<(), ModeError> {
+    switch_mode_impl(target_mode, lan_cidr, true)
+}
+
+// Switch back after an unconfirmed switch (see confirm.rs): peers added in the meantime do not block it
+pub fn revert_mode_switch(
+    target_mode: SystemMode,
+    lan_cidr: Option<String>,
+) -> Result<(), ModeError> {
+    switch_mode_impl(target_mode, lan_cidr, false)
+}
+
+fn switch_mode_impl(
+    target_mode: SystemMode,
+    lan_cidr: Option<String>,
+    check_peers: bool,
 ) -> Result<(), ModeError> {
     // Load config once at the beginning (fixes duplicate loading issue)
     let mut config = conf::util::get_config()
@@ -89,7 +107,7 @@ pub fn switch_mode(
     // Only allow switch if no peers exist (or only the agent's own peer exists)
     let peer_count = config.network.peers.len();
     // If there are peers other than the agent itself, block the switch
-    if peer_count > 1 {
+    if check_peers && peer_count > 1 {
         return Err(ModeError::PeersExist);
     }
     
@@ -122,19 +140,7 @@ pub fn switch_mode(
             }
             
             // Step 3: Persist mode state
-            let state = ModeState {
-                last_mode: SystemMode::Router,
-                lan_cidr: Some(cidr.clone()),
-                peer_table_ids: std::collections::HashMap::new(),
-                prefix_active_backup: std::collections::HashMap::new(),
-                peer_first_handshake: std::collections::HashMap::new(),
-                peer_last_online_state: std::collections::HashMap::new(),
-                peer_last_successful_ping: std::collections::HashMap::new(),
-                peer_lan_access: std::collections::HashMap::new(),
-                auto_failover: false,
-                primary_exit_node: None,
-                primary_online_since: None,
-            };
+            let state = ModeState::new(SystemMode::Router, Some(cidr.clone()));
             
             if let Err(e) = save_mode_state(&state) {
                 // Rollback: disable forwarding
@@ -156,7 +162,7 @@ pub fn switch_mode(
             
             let mut peers_with_default = Vec::new();
             
-            for (peer_id, _peer) in &config.network.peers {
+            for peer_id in config.network.peers.keys() {
                 // Skip the agent's own peer
                 if *peer_id == config.network.this_peer {
                     continue;
@@ -194,13 +200,17 @@ pub fn switch_mode(
             }
             
             // Set first peer with default route as exit node if none is set
-            if let Some(first_peer) = peers_with_default.first() {
-                if routing_pbr::get_exit_node().unwrap_or(None).is_none() {
+            if let Some(first_peer) = peers_with_default.first()
+                && routing_pbr::get_exit_node().unwrap_or(None).is_none() {
                     log::info!("Setting first peer with default route as exit node: {}", first_peer);
                     if let Err(e) = routing_pbr::set_exit_node(first_peer, Some(&config.network)) {
                         log::warn!("Failed to set exit node: {}", e);
                     }
                 }
+            
+            // Pick an active peer for every prefix advertised by more than one peer
+            if let Err(e) = routing_pbr::sync_prefix_active_backup(&config.network) {
+                log::warn!("Failed to set up active/backup for overlapping prefixes: {}", e);
             }
         }
         SystemMode::Host => {
@@ -218,27 +228,34 @@ pub fn switch_mode(
             let config = conf::util::get_config()
                 .map_err(|e| ModeError::ConfigError(format!("Failed to load config: {}", e)))?;
             
-            for (peer_id, _) in &config.network.peers {
+            // Pinned exit nodes go back to the WireGuard interface
+            super::routing_pbr::remove_exit_interfaces(&config.network);
+            
+            for peer_id in config.network.peers.keys() {
                 // Skip the host peer
                 if *peer_id == config.network.this_peer {
                     continue;
                 }
                 
-                if let Ok(Some(table_id)) = super::routing_pbr::get_peer_table_id(peer_id) {
-                    if let Err(e) = super::routing_pbr::remove_pbr_rules_for_peer(peer_id, table_id) {
+                if let Ok(Some(table_id)) = super::routing_pbr::get_peer_table_id(peer_id)
+                    && let Err(e) = super::routing_pbr::remove_pbr_rules_for_peer(peer_id, table_id) {
                         log::warn!("Failed to remove PBR rules for peer {}: {} (continuing anyway)", peer_id, e);
                     }
-                }
             }
-            
+
+            // The local breakout has no peer table, so it is torn down separately
+            if let Err(e) = super::routing_pbr::remove_local_breakout(&config.network) {
+                log::warn!("Failed to remove local breakout: {} (continuing anyway)", e);
+            }
+
             // Step 2: Disable packet forwarding
             if let Err(e) = disable_packet_forwarding() {
                 return Err(ModeError::RoutingError(format!("Failed to disable packet forwarding: {}", e)));
             }
             
             // Step 3: Delete all peer-specific routing tables
-            if let Some(ref state) = state {
-                if !state.peer_table_ids.is_empty() {
+            if let Some(ref state) = state
+                && !state.peer_table_ids.is_empty() {
                     log::info!("Deleting {} peer-specific routing tables...", state.peer_table_ids.len());
                     for (peer_id_str, table_id) in &state.peer_table_ids {
                         // Parse peer_id from string
@@ -252,7 +269,6 @@ pub fn switch_mode(
                         }
                     }
                 }
-            }
             
             // Step 4: Clear persisted state
             if let Err(e) = clear_mode_state() {
@@ -297,17 +313,20 @@ pub fn update_lan_cidr(new_cidr: &str) -> Result<(), ModeError> {
     // Update config file
     update_config_mode(SystemMode::Router, Some(new_cidr))?;
     
-    // Remove old firewall rules (if old CIDR existed)
-    if let Some(ref old) = old_cidr {
-        // We don't need to explicitly remove old rules, enable_router_mode_firewall will handle it
-        log::debug!("Previous LAN CIDR was: {}", old);
-    }
-    
     // Apply new firewall rules
     if let Err(e) = crate::firewall::enable_router_mode_firewall(new_cidr) {
         log::warn!("Failed to update firewall rules: {} (continuing anyway)", e);
     }
     
+    // Then remove the ones only the old CIDR needed
+    if let Some(ref old) = old_cidr
+        && let Ok(config) = conf::util::get_config() {
+            log::debug!("Previous LAN CIDR was: {}", old);
+            let lan_interface = routing_pbr::find_lan_interface().unwrap_or_else(|_| "eth0".to_string());
+            let plan = routing_pbr::plan_routing(&state, &config.network, &lan_interface);
+            crate::firewall::remove_previous_lan_cidr_rules(old, new_cidr, &config.network, plan.uplink.as_deref(), &plan.client_exits);
+        }
+    
     // Re-apply routing rules with new CIDR
     // Get the current exit node and re-apply its routes
     if let Some(prefix_state) = state.prefix_active_backup.get("0.0.0.0/0") {
@@ -315,11 +334,10 @@ pub fn update_lan_cidr(new_cidr: &str) -> Result<(), ModeError> {
         if let Ok(exit_uuid) = uuid::Uuid::parse_str(&exit_node_id) {
             log::info!("Re-applying routes for exit node {} with new LAN CIDR", exit_node_id);
             // Get network config
-            if let Ok(config) = conf::util::get_config() {
-                if let Err(e) = routing_pbr::set_exit_node(&exit_uuid, Some(&config.network)) {
+            if let Ok(config) = conf::util::get_config()
+                && let Err(e) = routing_pbr::set_exit_node(&exit_uuid, Some(&config.network)) {
                     log::warn!("Failed to re-apply exit node routes: {}", e);
                 }
-            }
         }
     }
     
@@ -327,6 +345,66 @@ pub fn update_lan_cidr(new_cidr: &str) -> Result<(), ModeError> {
     Ok(())
 }
 
+// Plan of switch_mode: the same checks, and the changes it would make without making them (see change_plan.rs)
+pub fn plan_switch_mode(target_mode: SystemMode, lan_cidr: Option<String>) -> Result<ChangePlan, ModeError> {
+    let config = conf::util::get_config()
+        .map_err(|e| ModeError::ConfigError(format!("Failed to load config: {}", e)))?;
+    let current_mode = SystemMode::from(config.agent.router.mode.as_str());
+    let mut plan = ChangePlan::new(format!("switch_mode {}", String::from(target_mode)));
+    
+    if current_mode == target_mode && target_mode == SystemMode::Router {
+        if let Some(new_cidr) = &lan_cidr {
+            return plan_update_lan_cidr(new_cidr);
+        }
+        return Ok(plan);
+    }
+    if config.network.peers.len() > 1 {
+        return Err(ModeError::PeersExist);
+    }
+    
+    let current = load_mode_state()
+        .map_err(|e| ModeError::PersistenceError(format!("Failed to load mode state: {}", e)))?;
+    match target_mode {
+        SystemMode::Router => {
+            let cidr = lan_cidr
+                .ok_or_else(|| ModeError::InvalidCidr("LAN CIDR is required for Router Mode".to_string()))?;
+            validate_cidr(&cidr)?;
+            
+            plan.sysctl("net.ipv4.ip_forward", "1");
+            plan.sysctl("net.ipv4.tcp_mtu_probing", "1");
+            let target = ModeState::new(SystemMode::Router, Some(cidr));
+            plan.transition(current.as_ref(), Some(&target), &config.network).map_err(plan_error)?;
+        }
+        SystemMode::Host => {
+            plan.transition(current.as_ref(), None, &config.network).map_err(plan_error)?;
+            plan.sysctl("net.ipv4.ip_forward", "0");
+        }
+    }
+    Ok(plan)
+}
+
+// Plan of update_lan_cidr
+pub fn plan_update_lan_cidr(new_cidr: &str) -> Result<ChangePlan, ModeError> {
+    for cidr in new_cidr.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        validate_cidr(cidr)?;
+    }
+    let current = load_mode_state()
+        .map_err(|e| ModeError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| ModeError::PersistenceError("No mode state found".to_string()))?;
+    let config = conf::util::get_config()
+        .map_err(|e| ModeError::ConfigError(format!("Failed to load config: {}", e)))?;
+    
+    let mut target = current.clone();
+    target.lan_cidr = Some(new_cidr.to_string());
+    let mut plan = ChangePlan::new(format!("update_lan_cidr {}", new_cidr));
+    plan.transition(Some(&current), Some(&target), &config.network).map_err(plan_error)?;
+    Ok(plan)
+}
+
+fn plan_error(e: super::reconcile::ReconcileError) -> ModeError {
+    ModeError::RoutingError(format!("Failed to plan the change: {}", e))
+}
+
 // Enable packet forwarding
 fn enable_packet_forwarding() -> Result<(), ModeError> {
     shell_cmd(&["sysctl", "-w", "net.ipv4.ip_forward=1"])
@@ -410,19 +488,7 @@ pub fn initialize_mode_on_startup() -> Result<(), ModeError> {
                     log::warn!("Config says Router Mode but state file is missing. Auto-recovering with fresh state.");
                     let lan_cidr = config.agent.router.lan_cidr.clone()
                         .unwrap_or_else(|| "192.168.1.0/24".to_string());
-                    let fresh_state = ModeState {
-                        last_mode: SystemMode::Router,
-                        lan_cidr: Some(lan_cidr.clone()),
-                        peer_table_ids: std::collections::HashMap::new(),
-                        prefix_active_backup: std::collections::HashMap::new(),
-                        peer_first_handshake: std::collections::HashMap::new(),
-                        peer_last_online_state: std::collections::HashMap::new(),
-                        peer_last_successful_ping: std::collections::HashMap::new(),
-                        peer_lan_access: std::collections::HashMap::new(),
-                        auto_failover: false,
-                        primary_exit_node: None,
-                        primary_online_since: None,
-                    };
+                    let fresh_state = ModeState::new(SystemMode::Router, Some(lan_cidr.clone()));
                     if let Err(e) = save_mode_state(&fresh_state) {
                         log::warn!("Failed to save recovered state: {}", e);
                     }
@@ -633,8 +699,7 @@ pub fn restore_peer_routes_after_interface_up() -> Result<(), ModeError> {
     let wg_interface = &config.network.name;
     
     // Verify interface exists before trying to install routes
-    let interface_check = shell_cmd(&["ip", "link", "show", wg_interface]);
-    if interface_check.is_err() {
+    if !routing_backend().interface_exists(wg_interface).unwrap_or(false) {
         log::warn!("WireGuard interface {} does not exist yet. Will retry later.", wg_interface);
         return Err(ModeError::RoutingError(format!("Interface {} does not exist", wg_interface)));
     }
@@ -645,7 +710,7 @@ pub fn restore_peer_routes_after_interface_up() -> Result<(), ModeError> {
     let mut restored_count = 0;
     let mut failed_count = 0;
     
-    for (peer_id, _peer) in &config.network.peers {
+    for peer_id in config.network.peers.keys() {
         // Skip the agent's own peer
         if *peer_id == config.network.this_peer {
             continue;
@@ -700,12 +765,17 @@ pub fn restore_peer_routes_after_interface_up() -> Result<(), ModeError> {
         log::warn!("Failed to restore routing tables for {} peer(s)", failed_count);
     }
     
+    // Pinned exit nodes run on interfaces of their own (their routes move there)
+    if let Err(e) = routing_pbr::sync_exit_interfaces(&config.network) {
+        log::warn!("Failed to restore exit node interfaces: {}", e);
+    }
+    
     // Restore exit node from persisted state, or auto-select from config if none persisted
     let mut exit_node_restored = false;
     if let Some(exit_node_id) = routing_pbr::get_exit_node().unwrap_or(None) {
         // Verify exit node still exists in config before restoring
         let exit_node_id_str = exit_node_id.to_string();
-        if current_peer_ids.contains(&exit_node_id_str) {
+        if current_peer_ids.contains(&exit_node_id_str) || exit_node_id == routing_pbr::LOCAL_BREAKOUT_EXIT_ID {
             log::info!("Restoring exit node: {}", exit_node_id);
             // Clone network to avoid lifetime issues
             let network_clone = config.network.clone();
@@ -742,6 +812,11 @@ pub fn restore_peer_routes_after_interface_up() -> Result<(), ModeError> {
         }
     }
     
+    // Restore active/backup selection for overlapping prefixes
+    if let Err(e) = routing_pbr::sync_prefix_active_backup(&config.network) {
+        log::warn!("Failed to restore active/backup for overlapping prefixes: {}", e);
+    }
+    
     // Reload state from disk before saving - set_exit_node() may have modified it
     // This ensures we don't overwrite changes made by set_exit_node()
     let state = match load_mode_state() {
@@ -756,24 +831,15 @@ pub fn restore_peer_routes_after_interface_up() -> Result<(), ModeError> {
         }
     };
     
-    // Bug 2 fix: Restore fail-back timer from persisted state
-    routing_pbr::restore_primary_online_since_from_state();
-    
-    // Bug 2 fix: If auto-failover is enabled and we have a primary that's different from current exit,
-    // check if we should start fail-back timer (primary might have come online while service was down)
-    if state.auto_failover {
-        if let Some(ref primary_str) = state.primary_exit_node {
-            if let Ok(primary_id) = uuid::Uuid::parse_str(primary_str) {
-                // Check if current exit node is different from primary
-                if let Ok(Some(current_exit)) = routing_pbr::get_exit_node() {
-                    if current_exit != primary_id {
-                        log::info!("Smart Gateway: Auto-failover is enabled and we're on backup. Will monitor primary {} for fail-back.", primary_str);
-                        // The health monitoring loop will check if primary is online and start the timer
+    // If auto-failover is enabled and we're below the active gateway group's top tier, the health
+    // monitoring loop climbs back once a higher tier is stable (the fail-back timer is persisted)
+    if state.auto_failover
+        && let Some(group_name) = state.active_gateway_group.as_deref()
+            && let Some(group) = state.gateway_groups.get(group_name)
+                && let Ok(Some(current_exit)) = routing_pbr::get_exit_node()
+                    && !group.tiers.first().is_some_and(|tier| tier.contains(&current_exit.to_string())) {
+                        log::info!("Smart Gateway: Auto-failover is enabled and we're below tier 1 of gateway group '{}'. Will monitor higher tiers for fail-back.", group_name);
                     }
-                }
-            }
-        }
-    }
     
     // Save cleaned up state after restoration
     if let Err(e) = save_mode_state(&state) {
@@ -0,0 +1,587 @@
+#![cfg(target_os = "linux")]
+// rtnetlink client (NETLINK_ROUTE) backing the Linux routing backend
+//
+// Route, rule and link changes go straight to the kernel instead of forking `ip`,
+// so failover doesn't spawn processes and nothing depends on iproute2's
+// output format or locale. Kernel errors come back as errno values and are
+// mapped onto BackendError (EEXIST/ENOENT are distinguishable, no string matching).
+// Messages are encoded and decoded with netlink-packet-route over a netlink-sys socket: every message and
+// attribute length is checked against the datagram, so a truncated or malformed reply is a Malformed error.
+
+use super::backend::{BackendError, BackendResult, InterfaceAddress, Route, Rule, RoutingBackend};
+use ipnet::Ipv4Net;
+use netlink_packet_core::{
+    NetlinkHeader, NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REPLACE,
+    NLM_F_REQUEST,
+};
+use netlink_packet_route::address::Nla as AddressNla;
+use netlink_packet_route::link::nlas::{Info as LinkInfo, InfoKind, Nla as LinkNla};
+use netlink_packet_route::route::Nla as RouteNla;
+use netlink_packet_route::rule::Nla as RuleNla;
+use netlink_packet_route::{
+    AddressMessage, LinkMessage, RouteMessage, RtnlMessage, RuleMessage, AF_INET, FR_ACT_TO_TBL, IFF_UP, RTN_UNICAST,
+    RTPROT_STATIC, RT_SCOPE_LINK, RT_SCOPE_NOWHERE, RT_SCOPE_UNIVERSE, RT_TABLE_UNSPEC,
+};
+use netlink_sys::{protocols::NETLINK_ROUTE, SocketAddr};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+static SEQUENCE: AtomicU32 = AtomicU32::new(1);
+
+fn malformed(e: impl std::fmt::Display) -> BackendError {
+    BackendError::Malformed(e.to_string())
+}
+
+struct Socket(netlink_sys::Socket);
+
+impl Socket {
+    fn open() -> BackendResult<Self> {
+        let mut socket = netlink_sys::Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        Ok(Socket(socket))
+    }
+
+    fn send(&self, message: RtnlMessage, flags: u16) -> BackendResult<u32> {
+        let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let mut header = NetlinkHeader::default();
+        header.flags = flags | NLM_F_REQUEST;
+        header.sequence_number = seq;
+        let mut packet = NetlinkMessage::new(header, NetlinkPayload::from(message));
+        packet.finalize();
+        let mut buf = vec![0u8; packet.buffer_len()];
+        packet.serialize(&mut buf);
+        self.0.send(&buf, 0)?;
+        Ok(seq)
+    }
+
+    // Hand every reply to `seq` to `on_payload` until it returns true
+    fn receive(&self, seq: u32, mut on_payload: impl FnMut(NetlinkPayload<RtnlMessage>) -> BackendResult<bool>) -> BackendResult<()> {
+        let mut rx: Vec<u8> = Vec::with_capacity(RECV_BUFFER_SIZE);
+        loop {
+            rx.clear();
+            if let Err(e) = self.0.recv(&mut rx, 0) {
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            for message in parse_messages(&rx)? {
+                if message.header.sequence_number == seq && on_payload(message.payload)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Send a request with NLM_F_ACK and wait for the kernel's verdict
+    fn request_ack(&self, message: RtnlMessage, flags: u16, what: &str) -> BackendResult<()> {
+        let seq = self.send(message, flags | NLM_F_ACK)?;
+        let mut result = Ok(());
+        self.receive(seq, |payload| match payload {
+            NetlinkPayload::Error(error) => {
+                result = errno_result(error.code.map_or(0, |code| code.get()), what);
+                Ok(true)
+            }
+            _ => Ok(false),
+        })?;
+        result
+    }
+
+    // Send a dump request and hand every reply message to `on_message`
+    fn request_dump(&self, message: RtnlMessage, mut on_message: impl FnMut(RtnlMessage) -> BackendResult<()>) -> BackendResult<()> {
+        let seq = self.send(message, NLM_F_DUMP)?;
+        let mut result = Ok(());
+        self.receive(seq, |payload| match payload {
+            NetlinkPayload::Done(_) => Ok(true),
+            NetlinkPayload::Error(error) => {
+                result = errno_result(error.code.map_or(0, |code| code.get()), "dump");
+                Ok(true)
+            }
+            NetlinkPayload::InnerMessage(message) => on_message(message).map(|_| false),
+            _ => Ok(false),
+        })?;
+        result
+    }
+
+    // Interface index -> name
+    fn links(&self) -> BackendResult<BTreeMap<u32, String>> {
+        let mut links = BTreeMap::new();
+        self.request_dump(RtnlMessage::GetLink(LinkMessage::default()), |message| {
+            if let RtnlMessage::NewLink(link) = message
+                && let Some(name) = link.nlas.iter().find_map(|nla| match nla {
+                    LinkNla::IfName(name) => Some(name.clone()),
+                    _ => None,
+                }) {
+                    links.insert(link.header.index, name);
+                }
+            Ok(())
+        })?;
+        Ok(links)
+    }
+}
+
+// Index of one interface, without dumping every link (routes and interfaces are changed by name)
+fn ifindex(name: &str) -> BackendResult<u32> {
+    let c_name = std::ffi::CString::new(name).map_err(|_| BackendError::NoSuchInterface(name.to_string()))?;
+    // SAFETY: if_nametoindex(3) only reads the NUL-terminated name
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => match std::io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ENODEV) => Err(BackendError::NoSuchInterface(name.to_string())),
+            e => Err(BackendError::Kernel(format!("interface {}", name), e)),
+        },
+        index => Ok(index),
+    }
+}
+
+// Split a datagram into messages (netlink-packet-core checks each length against what is left)
+fn parse_messages(mut data: &[u8]) -> BackendResult<Vec<NetlinkMessage<RtnlMessage>>> {
+    let mut messages = Vec::new();
+    while !data.is_empty() {
+        let message = NetlinkMessage::<RtnlMessage>::deserialize(data).map_err(malformed)?;
+        let len = (message.header.length as usize + 3) & !3;
+        messages.push(message);
+        data = &data[len.min(data.len())..];
+    }
+    Ok(messages)
+}
+
+// NLMSG_ERROR carries a negative errno (0 means ACK)
+fn errno_result(errno: i32, what: &str) -> BackendResult<()> {
+    match -errno {
+        0 => Ok(()),
+        libc::EEXIST => Err(BackendError::AlreadyExists(what.to_string())),
+        libc::ENOENT | libc::ESRCH => Err(BackendError::NotFound(what.to_string())),
+        code => Err(BackendError::Kernel(what.to_string(), std::io::Error::from_raw_os_error(code))),
+    }
+}
+
+fn ipv4(bytes: &[u8]) -> BackendResult<Ipv4Addr> {
+    <[u8; 4]>::try_from(bytes)
+        .map(Ipv4Addr::from)
+        .map_err(|_| malformed(format!("IPv4 address of {} bytes", bytes.len())))
+}
+
+fn prefix(addr: Option<&[u8]>, len: u8) -> BackendResult<Option<Ipv4Net>> {
+    if len == 0 {
+        return Ok(None);
+    }
+    let addr = ipv4(addr.ok_or_else(|| malformed("prefix length without address"))?)?;
+    Ipv4Net::new(addr, len)
+        .map(Some)
+        .map_err(|e| malformed(format!("invalid prefix {}/{}: {}", addr, len, e)))
+}
+
+fn table_u8(table: u32) -> u8 {
+    if table < 256 { table as u8 } else { RT_TABLE_UNSPEC }
+}
+
+// RTM_NEWROUTE/RTM_DELROUTE for a route out of interface `oif`
+fn route_message(route: &Route, oif: u32, scope: u8) -> RouteMessage {
+    let mut message = RouteMessage::default();
+    message.header.address_family = AF_INET as u8;
+    message.header.destination_prefix_length = route.destination.prefix_len();
+    message.header.table = table_u8(route.table);
+    message.header.scope = scope;
+    message.header.kind = RTN_UNICAST;
+    message.nlas.push(RouteNla::Table(route.table));
+    message.nlas.push(RouteNla::Oif(oif));
+    if route.destination.prefix_len() > 0 {
+        message.nlas.push(RouteNla::Destination(route.destination.network().octets().to_vec()));
+    }
+    if let Some(gateway) = route.gateway {
+        message.nlas.push(RouteNla::Gateway(gateway.octets().to_vec()));
+    }
+    message
+}
+
+// Table id, preferring the 32-bit attribute over the 8-bit header field
+fn route_table(message: &RouteMessage) -> u32 {
+    message.nlas.iter()
+        .find_map(|nla| match nla { RouteNla::Table(table) => Some(*table), _ => None })
+        .unwrap_or(message.header.table as u32)
+}
+
+// A unicast route from a dump; multipath routes carry no RTA_OIF and are skipped along with non-unicast ones
+fn decode_route(message: &RouteMessage, links: &BTreeMap<u32, String>) -> BackendResult<Option<Route>> {
+    if message.header.kind != RTN_UNICAST {
+        return Ok(None);
+    }
+    let mut destination = None;
+    let mut gateway = None;
+    let mut oif = None;
+    for nla in &message.nlas {
+        match nla {
+            RouteNla::Destination(bytes) => destination = Some(bytes.as_slice()),
+            RouteNla::Gateway(bytes) => gateway = Some(ipv4(bytes)?),
+            RouteNla::Oif(index) => oif = Some(*index),
+            _ => {}
+        }
+    }
+    let Some(device) = oif.and_then(|index| links.get(&index)) else {
+        return Ok(None);
+    };
+    Ok(Some(Route {
+        table: route_table(message),
+        destination: prefix(destination, message.header.destination_prefix_length)?.unwrap_or_default(),
+        device: device.clone(),
+        gateway,
+    }))
+}
+
+fn rule_message(rule: &Rule) -> RuleMessage {
+    let rule = rule.clone().normalized();
+    let mut message = RuleMessage::default();
+    message.header.family = AF_INET as u8;
+    message.header.dst_len = rule.to.map(|net| net.prefix_len()).unwrap_or(0);
+    message.header.src_len = rule.from.map(|net| net.prefix_len()).unwrap_or(0);
+    message.header.table = table_u8(rule.table);
+    message.header.action = FR_ACT_TO_TBL;
+    message.nlas.push(RuleNla::Priority(rule.priority));
+    message.nlas.push(RuleNla::Table(rule.table));
+    if let Some(from) = rule.from {
+        message.nlas.push(RuleNla::Source(from.network().octets().to_vec()));
+    }
+    if let Some(to) = rule.to {
+        message.nlas.push(RuleNla::Destination(to.network().octets().to_vec()));
+    }
+    if let Some(iif) = rule.iif {
+        message.nlas.push(RuleNla::Iifname(iif));
+    }
+    if let Some(fwmark) = rule.fwmark {
+        message.nlas.push(RuleNla::FwMark(fwmark));
+    }
+    message
+}
+
+// Only "lookup <table>" rules are ours to manage
+fn decode_rule(message: &RuleMessage) -> BackendResult<Option<Rule>> {
+    if message.header.action != FR_ACT_TO_TBL {
+        return Ok(None);
+    }
+    let mut rule = Rule { table: message.header.table as u32, ..Default::default() };
+    let mut source = None;
+    let mut destination = None;
+    for nla in &message.nlas {
+        match nla {
+            RuleNla::Priority(priority) => rule.priority = *priority,
+            RuleNla::Table(table) => rule.table = *table,
+            RuleNla::Source(bytes) => source = Some(bytes.as_slice()),
+            RuleNla::Destination(bytes) => destination = Some(bytes.as_slice()),
+            RuleNla::Iifname(iif) => rule.iif = Some(iif.clone()),
+            RuleNla::FwMark(fwmark) => rule.fwmark = Some(*fwmark),
+            _ => {}
+        }
+    }
+    rule.from = prefix(source, message.header.src_len)?;
+    rule.to = prefix(destination, message.header.dst_len)?;
+    Ok(Some(rule))
+}
+
+fn decode_address(message: &AddressMessage, links: &BTreeMap<u32, String>) -> BackendResult<Option<InterfaceAddress>> {
+    // IFA_LOCAL is the interface's own address; IFA_ADDRESS is the peer on point-to-point links
+    let mut local = None;
+    let mut address = None;
+    for nla in &message.nlas {
+        match nla {
+            AddressNla::Local(bytes) => local = Some(ipv4(bytes)?),
+            AddressNla::Address(bytes) => address = Some(ipv4(bytes)?),
+            _ => {}
+        }
+    }
+    let (Some(interface), Some(addr)) = (links.get(&message.header.index), local.or(address)) else {
+        return Ok(None);
+    };
+    let address = Ipv4Net::new(addr, message.header.prefix_len)
+        .map_err(|e| malformed(format!("invalid address {}/{}: {}", addr, message.header.prefix_len, e)))?;
+    Ok(Some(InterfaceAddress { interface: interface.clone(), address }))
+}
+
+fn dump_request_route() -> RtnlMessage {
+    let mut message = RouteMessage::default();
+    message.header.address_family = AF_INET as u8;
+    RtnlMessage::GetRoute(message)
+}
+
+// Routing backend that talks rtnetlink directly
+pub struct NetlinkBackend;
+
+impl RoutingBackend for NetlinkBackend {
+    fn replace_route(&self, route: &Route) -> BackendResult<()> {
+        let socket = Socket::open()?;
+        let oif = ifindex(&route.device)?;
+        // Routes through a gateway reach beyond the link
+        let scope = if route.gateway.is_some() { RT_SCOPE_UNIVERSE } else { RT_SCOPE_LINK };
+        let mut message = route_message(route, oif, scope);
+        message.header.protocol = RTPROT_STATIC;
+        socket.request_ack(RtnlMessage::NewRoute(message), NLM_F_CREATE | NLM_F_REPLACE, &format!("route {}", route))
+    }
+
+    fn delete_route(&self, route: &Route) -> BackendResult<()> {
+        let socket = Socket::open()?;
+        // The kernel drops a device's routes with the device
+        let oif = match ifindex(&route.device) {
+            Err(BackendError::NoSuchInterface(_)) => return Err(BackendError::NotFound(route.to_string())),
+            other => other?,
+        };
+        let message = route_message(route, oif, RT_SCOPE_NOWHERE);
+        socket.request_ack(RtnlMessage::DelRoute(message), 0, &format!("route {}", route))
+    }
+
+    fn flush_table(&self, table: u32) -> BackendResult<usize> {
+        let socket = Socket::open()?;
+
+        // Build the deletes while dumping, send them afterwards; deleting while the dump is in flight is unreliable
+        let mut deletes: Vec<RouteMessage> = Vec::new();
+        socket.request_dump(dump_request_route(), |message| {
+            if let RtnlMessage::NewRoute(mut route) = message
+                && route_table(&route) == table {
+                    route.header.scope = RT_SCOPE_NOWHERE; // wildcard scope on delete
+                    route.nlas.retain(|nla| matches!(nla,
+                        RouteNla::Destination(_) | RouteNla::Oif(_) | RouteNla::Gateway(_) | RouteNla::Priority(_) | RouteNla::Table(_)));
+                    deletes.push(route);
+                }
+            Ok(())
+        })?;
+
+        let mut removed = 0;
+        for route in deletes {
+            match socket.request_ack(RtnlMessage::DelRoute(route), 0, &format!("flush table {}", table)) {
+                Ok(()) => removed += 1,
+                Err(BackendError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+
+    fn list_routes(&self, table: u32) -> BackendResult<Vec<Route>> {
+        let socket = Socket::open()?;
+        let links = socket.links()?;
+        let mut routes = Vec::new();
+        socket.request_dump(dump_request_route(), |message| {
+            if let RtnlMessage::NewRoute(route) = message
+                && route_table(&route) == table
+                && let Some(route) = decode_route(&route, &links)? {
+                    routes.push(route);
+                }
+            Ok(())
+        })?;
+        Ok(routes)
+    }
+
+    fn list_rules(&self) -> BackendResult<Vec<Rule>> {
+        let mut request = RuleMessage::default();
+        request.header.family = AF_INET as u8;
+        let mut rules = Vec::new();
+        Socket::open()?.request_dump(RtnlMessage::GetRule(request), |message| {
+            if let RtnlMessage::NewRule(rule) = message
+                && let Some(rule) = decode_rule(&rule)? {
+                    rules.push(rule);
+                }
+            Ok(())
+        })?;
+        Ok(rules)
+    }
+
+    fn add_rule(&self, rule: &Rule) -> BackendResult<()> {
+        // Rules have no kernel-side replace; NLM_F_EXCL makes an identical rule report EEXIST
+        let message = RtnlMessage::NewRule(rule_message(rule));
+        match Socket::open()?.request_ack(message, NLM_F_CREATE | NLM_F_EXCL, &format!("rule {}", rule)) {
+            Err(BackendError::AlreadyExists(_)) => Ok(()),
+            other => other,
+        }
+    }
+
+    fn delete_rule(&self, rule: &Rule) -> BackendResult<()> {
+        Socket::open()?.request_ack(RtnlMessage::DelRule(rule_message(rule)), 0, &format!("rule {}", rule))
+    }
+
+    fn interface_exists(&self, name: &str) -> BackendResult<bool> {
+        match ifindex(name) {
+            Ok(_) => Ok(true),
+            Err(BackendError::NoSuchInterface(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_interface_addresses(&self) -> BackendResult<Vec<InterfaceAddress>> {
+        let socket = Socket::open()?;
+        let links = socket.links()?;
+        let mut request = AddressMessage::default();
+        request.header.family = AF_INET as u8;
+        let mut addresses = Vec::new();
+        socket.request_dump(RtnlMessage::GetAddress(request), |message| {
+            if let RtnlMessage::NewAddress(address) = message
+                && let Some(address) = decode_address(&address, &links)? {
+                    addresses.push(address);
+                }
+            Ok(())
+        })?;
+        Ok(addresses)
+    }
+
+    fn add_wireguard_interface(&self, name: &str, address: Ipv4Net, mtu: Option<u16>) -> BackendResult<()> {
+        let socket = Socket::open()?;
+        if ifindex(name).is_err() {
+            let mut link = LinkMessage::default();
+            link.nlas.push(LinkNla::IfName(name.to_string()));
+            link.nlas.push(LinkNla::Info(vec![LinkInfo::Kind(InfoKind::Wireguard)]));
+            if let Some(mtu) = mtu {
+                link.nlas.push(LinkNla::Mtu(mtu as u32));
+            }
+            match socket.request_ack(RtnlMessage::NewLink(link), NLM_F_CREATE | NLM_F_EXCL, &format!("interface {}", name)) {
+                Err(BackendError::AlreadyExists(_)) => {}
+                other => other?,
+            }
+        }
+        let index = ifindex(name)?;
+
+        let mut request = AddressMessage::default();
+        request.header.family = AF_INET as u8;
+        request.header.prefix_len = address.prefix_len();
+        request.header.index = index;
+        request.nlas.push(AddressNla::Local(address.addr().octets().to_vec()));
+        request.nlas.push(AddressNla::Address(address.addr().octets().to_vec()));
+        socket.request_ack(RtnlMessage::NewAddress(request), NLM_F_CREATE | NLM_F_REPLACE, &format!("address {} on {}", address, name))?;
+
+        let mut link = LinkMessage::default();
+        link.header.index = index;
+        link.header.flags = IFF_UP;
+        link.header.change_mask = IFF_UP;
+        socket.request_ack(RtnlMessage::NewLink(link), 0, &format!("interface {}", name))
+    }
+
+    fn delete_interface(&self, name: &str) -> BackendResult<()> {
+        let socket = Socket::open()?;
+        let index = match ifindex(name) {
+            Err(BackendError::NoSuchInterface(_)) => return Err(BackendError::NotFound(name.to_string())),
+            other => other?,
+        };
+        let mut link = LinkMessage::default();
+        link.header.index = index;
+        socket.request_ack(RtnlMessage::DelLink(link), 0, &format!("interface {}", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // A reply as the kernel sends it: netlink header, then the message
+    fn reply(message: RtnlMessage, seq: u32) -> Vec<u8> {
+        let mut header = NetlinkHeader::default();
+        header.flags = netlink_packet_core::NLM_F_MULTIPART;
+        header.sequence_number = seq;
+        let mut packet = NetlinkMessage::new(header, NetlinkPayload::from(message));
+        packet.finalize();
+        let mut buf = vec![0u8; packet.buffer_len()];
+        packet.serialize(&mut buf);
+        buf
+    }
+
+    // RTM_NEWRULE for "20000: from all iif eth0 lookup 1000", laid out as a 6.x kernel dumps it:
+    // fib_rule_hdr (table 252 = RT_TABLE_COMPAT, action FR_ACT_TO_TBL), then FRA_TABLE,
+    // FRA_SUPPRESS_PREFIXLEN, FRA_IIFNAME (NUL-terminated and padded), FRA_PRIORITY and FRA_PROTOCOL
+    const RULE_REPLY: [u8; 72] = [
+        72, 0, 0, 0, 32, 0, 2, 0, 7, 0, 0, 0, 0, 0, 0, 0,
+        2, 0, 0, 0, 252, 0, 0, 1, 0, 0, 0, 0,
+        8, 0, 15, 0, 0xe8, 0x03, 0, 0,
+        8, 0, 14, 0, 0xff, 0xff, 0xff, 0xff,
+        9, 0, 3, 0, b'e', b't', b'h', b'0', 0, 0, 0, 0,
+        8, 0, 6, 0, 0x20, 0x4e, 0, 0,
+        5, 0, 21, 0, 4, 0, 0, 0,
+    ];
+
+    #[test]
+    fn test_ifindex_by_name() {
+        // Every network namespace has a loopback interface
+        assert!(ifindex("lo").unwrap() > 0);
+        for name in ["wgq-missing0", "lo\0"] {
+            assert!(matches!(ifindex(name), Err(BackendError::NoSuchInterface(_))), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_kernel_rule_reply_decodes() {
+        let messages = parse_messages(&RULE_REPLY).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].header.sequence_number, 7);
+        let NetlinkPayload::InnerMessage(RtnlMessage::NewRule(message)) = &messages[0].payload else {
+            panic!("not a rule: {:?}", messages[0].payload);
+        };
+        let rule = decode_rule(message).unwrap().unwrap();
+        assert_eq!(rule.to_string(), "20000: from all iif eth0 lookup 1000");
+    }
+
+    #[test]
+    fn test_truncated_replies_are_malformed() {
+        // Cut anywhere inside the message: the length no longer fits the datagram
+        for len in 1..RULE_REPLY.len() {
+            assert!(matches!(parse_messages(&RULE_REPLY[..len]), Err(BackendError::Malformed(_))), "cut at {}", len);
+        }
+        // An attribute claiming more bytes than the message holds
+        let mut reply = RULE_REPLY;
+        reply[28] = 64;
+        assert!(parse_messages(&reply).is_err());
+        // An address of the wrong size
+        let mut message = rule_message(&Rule { priority: 1, table: 1000, ..Default::default() });
+        message.header.src_len = 24;
+        message.nlas.push(RuleNla::Source(vec![10, 0]));
+        assert!(matches!(decode_rule(&message), Err(BackendError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_rules_and_routes_round_trip() {
+        let rules = [
+            Rule { priority: 20000, iif: Some("eth0".to_string()), table: 1000, ..Default::default() },
+            Rule { priority: 10001, from: Some("192.168.1.0/24".parse().unwrap()), to: Some("10.50.0.0/16".parse().unwrap()), table: 1001, ..Default::default() },
+            Rule { priority: 9000, fwmark: Some(0x51820), table: 254, ..Default::default() },
+        ];
+        let mut datagram = Vec::new();
+        for rule in &rules {
+            datagram.extend(reply(RtnlMessage::NewRule(rule_message(rule)), 1));
+        }
+        let decoded: Vec<Rule> = parse_messages(&datagram).unwrap().into_iter()
+            .filter_map(|message| match message.payload {
+                NetlinkPayload::InnerMessage(RtnlMessage::NewRule(rule)) => decode_rule(&rule).unwrap(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(decoded, rules);
+
+        let links = BTreeMap::from([(3, "wg0".to_string()), (2, "eth0".to_string())]);
+        let routes = [
+            Route { table: 1000, destination: "0.0.0.0/0".parse().unwrap(), device: "wg0".to_string(), gateway: None },
+            Route { table: 1100, destination: "0.0.0.0/0".parse().unwrap(), device: "eth0".to_string(), gateway: Some("192.168.1.1".parse().unwrap()) },
+        ];
+        for (route, oif) in routes.iter().zip([3, 2]) {
+            let bytes = reply(RtnlMessage::NewRoute(route_message(route, oif, RT_SCOPE_LINK)), 1);
+            let NetlinkPayload::InnerMessage(RtnlMessage::NewRoute(message)) = parse_messages(&bytes).unwrap().remove(0).payload else {
+                panic!("not a route");
+            };
+            assert_eq!(decode_route(&message, &links).unwrap().as_ref(), Some(route));
+        }
+    }
+
+    proptest! {
+        // Whatever the kernel (or anything else) sends, parsing returns an error instead of reading out of bounds
+        #[test]
+        fn prop_garbage_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..256), flip in 0usize..72, value in any::<u8>()) {
+            let _ = parse_messages(&bytes);
+            let mut reply = RULE_REPLY;
+            reply[flip] = value;
+            if let Ok(messages) = parse_messages(&reply) {
+                for message in messages {
+                    if let NetlinkPayload::InnerMessage(RtnlMessage::NewRule(rule)) = message.payload {
+                        let _ = decode_rule(&rule);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,608 @@
+// Notifications: journal events pushed to the sinks in agent.notifications
+//
+// Every event the journal records (status changes, failovers, fail-backs, manual switches, ...)
+// is also sent to each configured sink from a background thread:
+// - webhook: POST of the event as JSON, signed with HMAC-SHA256 of the body when a secret is set
+//   (X-Wg-Quickrs-Signature: sha256=<hex>)
+// - smtp: a plain-text mail, over STARTTLS, TLS or a plain connection, with optional AUTH PLAIN
+// - ntfy / gotify: an HTTP push with a title and priority
+//
+// A failed delivery is retried up to max_retries times, waiting 1s, 2s, 4s, ... (at most 5 minutes)
+// in between; a permanent failure (4xx, 5xx SMTP reply, invalid URL) is not retried.
+// HTTPS and SMTP TLS trust the system CA bundle (SSL_CERT_FILE overrides it).
+//
+// The HTTP/1.1 and SMTP clients are written here on top of rustls (already used by the web server)
+// rather than pulled in from reqwest or lettre: the build has to work offline from the vendored
+// dependency set, and a one-shot POST or mail needs only a small part of either crate.
+
+use super::events::{Event, EventKind};
+use aws_lc_rs::hmac;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use once_cell::sync::Lazy;
+use rustls::pki_types::{CertificateDer, ServerName, pem::PemObject};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+use uuid::Uuid;
+use wg_quickrs_lib::types::config::{parse_http_url, AgentNotifications, HttpUrl, NotificationSink, SmtpSecurity};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const CA_BUNDLES: [&str; 4] = [
+    "/etc/ssl/certs/ca-certificates.crt", // Debian, Ubuntu, Alpine, Arch
+    "/etc/pki/tls/certs/ca-bundle.crt",   // Fedora, RHEL
+    "/etc/ssl/ca-bundle.pem",             // openSUSE
+    "/etc/ssl/cert.pem",                  // macOS, OpenWrt
+];
+
+#[derive(Error, Debug)]
+pub enum NotifyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("TLS error: {0}")]
+    TlsError(String),
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("HTTP status {0}")]
+    HttpStatus(u16),
+    #[error("SMTP server replied: {0}")]
+    SmtpReply(String),
+}
+
+impl NotifyError {
+    // Retrying will not help: the request itself was rejected
+    fn is_permanent(&self) -> bool {
+        match self {
+            NotifyError::InvalidUrl(_) => true,
+            NotifyError::HttpStatus(status) => (400..500).contains(status) && *status != 429,
+            NotifyError::SmtpReply(reply) => reply.starts_with('5'),
+            _ => false,
+        }
+    }
+}
+
+// Sinks from conf.yml (refreshed by the health monitor)
+static NOTIFICATIONS: Lazy<RwLock<AgentNotifications>> = Lazy::new(|| RwLock::new(AgentNotifications::default()));
+
+// Pick up agent.notifications edits in conf.yml without a restart
+pub fn reload() {
+    match crate::conf::util::reload_notifications() {
+        Ok(Some(notifications)) => {
+            log::debug!("Loaded {} notification sink(s) from conf.yml", notifications.sinks.len());
+            *NOTIFICATIONS.write().unwrap() = notifications;
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Ignoring notifications in conf.yml: {}", e),
+    }
+}
+
+// What is sent for one event
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub title: String,
+    pub message: String,
+    pub urgent: bool,
+    pub event: serde_json::Value,
+}
+
+impl Notification {
+    pub fn from_event(event: &Event, name: impl Fn(&Uuid) -> String) -> Self {
+        let details = &event.details;
+        let peer = |id: Option<Uuid>| id.as_ref().map_or("none".to_string(), &name);
+        let reason = details.reason.clone().unwrap_or_default();
+        let title = match event.kind {
+            EventKind::Online => format!("{} is online", peer(details.peer_id)),
+            EventKind::Offline => format!("{} is offline", peer(details.peer_id)),
+            EventKind::Degraded => format!("{} is degraded: {}", peer(details.peer_id), reason),
+            EventKind::Failover => format!("Failover from {} to {} ({})", peer(details.old_peer), peer(details.new_peer), reason),
+            EventKind::Failback => format!("Fail-back from {} to {}", peer(details.old_peer), peer(details.new_peer)),
+            EventKind::PrefixFailover => format!("{} failed over from {} to {}", reason, peer(details.old_peer), peer(details.new_peer)),
+            EventKind::PrefixFailback => format!("{} failed back from {} to {}", reason, peer(details.old_peer), peer(details.new_peer)),
+            EventKind::ManualSwitch => format!("{} switched from {} to {}", reason, peer(details.old_peer), peer(details.new_peer)),
+            EventKind::ModeChange => format!("Mode changed: {}", reason),
+            EventKind::LanAccess => format!("LAN access {} for {}", reason, peer(details.peer_id)),
+            EventKind::QuotaExceeded => format!("{} is over quota: {}", peer(details.peer_id), reason),
+            EventKind::QuotaReset => format!("{} is within quota again: {}", peer(details.peer_id), reason),
+            EventKind::Drift => format!("Routing drift corrected: {}", reason),
+            EventKind::Rollback => format!("Change rolled back: {}", reason),
+            EventKind::RollbackFailed => format!("Rollback failed: {}", reason),
+        };
+        let mut message = title.clone();
+        if let Some(metrics) = &details.metrics {
+            let value = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+            message.push_str(&format!(
+                "\nLatency {}, packet loss {}, jitter {}",
+                value(metrics.latency_ms.map(|l| format!("{}ms", l))),
+                value(metrics.packet_loss_percent.map(|l| format!("{:.0}%", l))),
+                value(metrics.jitter_ms.map(|j| format!("{}ms", j))),
+            ));
+        }
+        let at = chrono::DateTime::from_timestamp(event.ts as i64, 0).unwrap_or_default();
+        message.push_str(&format!("\nAt {} (event #{})", at.format("%Y-%m-%d %H:%M:%S UTC"), event.id));
+        Notification {
+            title,
+            message,
+            urgent: matches!(event.kind, EventKind::Offline | EventKind::Degraded | EventKind::Failover | EventKind::PrefixFailover | EventKind::QuotaExceeded | EventKind::RollbackFailed),
+            event: serde_json::to_value(event).unwrap_or_default(),
+        }
+    }
+}
+
+// Send an event to every sink in the background
+pub fn dispatch(event: &Event) {
+    let notifications = NOTIFICATIONS.read().unwrap().clone();
+    if notifications.sinks.is_empty() {
+        return;
+    }
+    let network = crate::conf::util::get_config().ok().map(|config| config.network);
+    let notification = Notification::from_event(event, |id| {
+        if id.is_nil() {
+            return super::routing_pbr::LOCAL_BREAKOUT_NAME.to_string();
+        }
+        network.as_ref()
+            .and_then(|network| network.peers.get(id))
+            .map_or_else(|| id.to_string(), |peer| peer.name.clone())
+    });
+    for sink in notifications.sinks {
+        let notification = notification.clone();
+        let spawned = std::thread::Builder::new()
+            .name("notify".to_string())
+            .spawn(move || deliver_with_retry(&sink, &notification, notifications.max_retries));
+        if let Err(e) = spawned {
+            log::warn!("Failed to start notification delivery: {}", e);
+        }
+    }
+}
+
+// Send a test notification to every sink once; returns each sink's result
+pub fn send_test() -> Vec<(String, Result<(), NotifyError>)> {
+    let sinks = NOTIFICATIONS.read().unwrap().sinks.clone();
+    let now = chrono::Utc::now();
+    let notification = Notification {
+        title: "wg-quickrs test notification".to_string(),
+        message: format!("Notifications from wg-quickrs reach this target.\nAt {}", now.format("%Y-%m-%d %H:%M:%S UTC")),
+        urgent: false,
+        event: serde_json::json!({"kind": "test", "ts": now.timestamp()}),
+    };
+    sinks.iter().map(|sink| (sink_name(sink), deliver(sink, &notification))).collect()
+}
+
+fn sink_name(sink: &NotificationSink) -> String {
+    match sink {
+        NotificationSink::Webhook { url, .. } => format!("webhook {}", url),
+        NotificationSink::Smtp { host, port, .. } => format!("smtp {}:{}", host, port),
+        NotificationSink::Ntfy { url, .. } => format!("ntfy {}", url),
+        NotificationSink::Gotify { url, .. } => format!("gotify {}", url),
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.min(16)).min(MAX_BACKOFF)
+}
+
+fn deliver_with_retry(sink: &NotificationSink, notification: &Notification, max_retries: u32) {
+    for attempt in 0..=max_retries {
+        match deliver(sink, notification) {
+            Ok(()) => {
+                log::debug!("Sent notification \"{}\" to {}", notification.title, sink_name(sink));
+                return;
+            }
+            Err(e) if e.is_permanent() || attempt == max_retries => {
+                log::warn!("Giving up on notification \"{}\" to {}: {}", notification.title, sink_name(sink), e);
+                return;
+            }
+            Err(e) => {
+                log::debug!("Notification to {} failed ({}), retrying in {:?}", sink_name(sink), e, backoff(attempt));
+                std::thread::sleep(backoff(attempt));
+            }
+        }
+    }
+}
+
+pub fn deliver(sink: &NotificationSink, notification: &Notification) -> Result<(), NotifyError> {
+    match sink {
+        NotificationSink::Webhook { url, secret } => {
+            let body = serde_json::to_vec(&serde_json::json!({
+                "title": notification.title,
+                "message": notification.message,
+                "event": notification.event,
+            })).unwrap_or_default();
+            let mut headers = Vec::new();
+            if let Some(kind) = notification.event.get("kind").and_then(|kind| kind.as_str()) {
+                headers.push(("X-Wg-Quickrs-Event", kind.to_string()));
+            }
+            if let Some(secret) = secret {
+                headers.push(("X-Wg-Quickrs-Signature", format!("sha256={}", sign(secret, &body))));
+            }
+            http_post(url, &headers, "application/json", &body)
+        }
+        NotificationSink::Ntfy { url, token } => {
+            let mut headers = vec![
+                ("Title", notification.title.clone()),
+                ("Priority", if notification.urgent { "high" } else { "default" }.to_string()),
+            ];
+            if let Some(token) = token {
+                headers.push(("Authorization", format!("Bearer {}", token)));
+            }
+            http_post(url, &headers, "text/plain; charset=utf-8", notification.message.as_bytes())
+        }
+        NotificationSink::Gotify { url, token } => {
+            let body = serde_json::to_vec(&serde_json::json!({
+                "title": notification.title,
+                "message": notification.message,
+                "priority": if notification.urgent { 8 } else { 4 },
+            })).unwrap_or_default();
+            let url = format!("{}/message", url.trim_end_matches('/'));
+            http_post(&url, &[("X-Gotify-Key", token.clone())], "application/json", &body)
+        }
+        NotificationSink::Smtp { host, port, security, username, password, from, to } => {
+            let credentials = username.as_deref().zip(password.as_deref());
+            send_mail(host, *port, *security, credentials, from, to, notification)
+        }
+    }
+}
+
+// Hex HMAC-SHA256 of a body, as sent in X-Wg-Quickrs-Signature
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut hex = [0u8; 64];
+    base16ct::lower::encode_str(hmac::sign(&key, body).as_ref(), &mut hex).unwrap_or_default().to_string()
+}
+
+static TLS_CONFIG: Lazy<Result<Arc<ClientConfig>, String>> = Lazy::new(|| {
+    let mut roots = RootCertStore::empty();
+    let bundles = std::env::var("SSL_CERT_FILE").into_iter().chain(CA_BUNDLES.iter().map(|path| path.to_string()));
+    for bundle in bundles {
+        if let Ok(certs) = CertificateDer::pem_file_iter(&bundle) {
+            roots.add_parsable_certificates(certs.flatten());
+        }
+        if !roots.is_empty() {
+            break;
+        }
+    }
+    if roots.is_empty() {
+        return Err("no CA certificates found (set SSL_CERT_FILE)".to_string());
+    }
+    ClientConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map(|builder| Arc::new(builder.with_root_certificates(roots).with_no_client_auth()))
+        .map_err(|e| e.to_string())
+});
+
+// A TCP connection, optionally wrapped in TLS
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    fn open(host: &str, port: u16, tls: bool) -> Result<Self, NotifyError> {
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} did not resolve", host));
+        for address in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, IO_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                    let connection = Connection::Plain(stream);
+                    return if tls { connection.start_tls(host) } else { Ok(connection) };
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error.into())
+    }
+
+    fn start_tls(self, host: &str) -> Result<Self, NotifyError> {
+        let Connection::Plain(stream) = self else {
+            return Ok(self);
+        };
+        let config = TLS_CONFIG.clone().map_err(NotifyError::TlsError)?;
+        let server_name = ServerName::try_from(host.to_string()).map_err(|e| NotifyError::TlsError(e.to_string()))?;
+        let connection = ClientConnection::new(config, server_name).map_err(|e| NotifyError::TlsError(e.to_string()))?;
+        Ok(Connection::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// POST a body; any 2xx status is success
+fn http_post(url: &str, headers: &[(&str, String)], content_type: &str, body: &[u8]) -> Result<(), NotifyError> {
+    let HttpUrl { https, host, port, path } = parse_http_url(url).ok_or_else(|| NotifyError::InvalidUrl(url.to_string()))?;
+    let mut connection = Connection::open(&host, port, https)?;
+    let default_port = if https { 443 } else { 80 };
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: wg-quickrs\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        path,
+        if port == default_port { host.clone() } else { format!("{}:{}", host, port) },
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        // Header values come from the config and event; keep them on one line
+        request.push_str(&format!("{}: {}\r\n", name, value.replace(['\r', '\n'], " ")));
+    }
+    request.push_str("\r\n");
+    connection.write_all(request.as_bytes())?;
+    connection.write_all(body)?;
+    connection.flush()?;
+
+    // Only the status line is needed
+    let mut response = Vec::new();
+    let mut chunk = [0u8; 512];
+    while !response.windows(2).any(|w| w == b"\r\n") {
+        let read = connection.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        response.extend_from_slice(&chunk[..read]);
+    }
+    let status = String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| NotifyError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, "no HTTP status line")))?;
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(NotifyError::HttpStatus(status))
+    }
+}
+
+// Read an SMTP reply (the last line of a multi-line one) and check its code class
+fn smtp_reply(connection: &mut Connection, expected: char) -> Result<String, NotifyError> {
+    loop {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            if connection.read(&mut byte)? == 0 {
+                return Err(NotifyError::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        // "250-..." continues, "250 ..." ends the reply
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        return if line.starts_with(expected) { Ok(line) } else { Err(NotifyError::SmtpReply(line)) };
+    }
+}
+
+fn smtp_command(connection: &mut Connection, command: &str, expected: char) -> Result<String, NotifyError> {
+    connection.write_all(format!("{}\r\n", command).as_bytes())?;
+    connection.flush()?;
+    smtp_reply(connection, expected)
+}
+
+// RFC 2047 encoded word for a header that is not plain ASCII
+fn mail_header_text(text: &str) -> String {
+    if text.is_ascii() {
+        text.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(text))
+    }
+}
+
+fn send_mail(
+    host: &str,
+    port: u16,
+    security: SmtpSecurity,
+    credentials: Option<(&str, &str)>,
+    from: &str,
+    to: &[String],
+    notification: &Notification,
+) -> Result<(), NotifyError> {
+    let mut connection = Connection::open(host, port, security == SmtpSecurity::Tls)?;
+    let hostname = std::fs::read_to_string("/etc/hostname").map(|h| h.trim().to_string())
+        .ok().filter(|h| !h.is_empty()).unwrap_or_else(|| "wg-quickrs".to_string());
+    smtp_reply(&mut connection, '2')?;
+    smtp_command(&mut connection, &format!("EHLO {}", hostname), '2')?;
+    if security == SmtpSecurity::Starttls {
+        smtp_command(&mut connection, "STARTTLS", '2')?;
+        connection = connection.start_tls(host)?;
+        smtp_command(&mut connection, &format!("EHLO {}", hostname), '2')?;
+    }
+    if let Some((username, password)) = credentials {
+        let token = STANDARD.encode(format!("\0{}\0{}", username, password));
+        smtp_command(&mut connection, &format!("AUTH PLAIN {}", token), '2')?;
+    }
+    smtp_command(&mut connection, &format!("MAIL FROM:<{}>", from), '2')?;
+    for recipient in to {
+        smtp_command(&mut connection, &format!("RCPT TO:<{}>", recipient), '2')?;
+    }
+    smtp_command(&mut connection, "DATA", '3')?;
+
+    let mut mail = format!(
+        "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        to.iter().map(|address| format!("<{}>", address)).collect::<Vec<_>>().join(", "),
+        mail_header_text(&notification.title.replace(['\r', '\n'], " ")),
+        chrono::Utc::now().to_rfc2822(),
+    );
+    for line in notification.message.lines() {
+        // Dot-stuffing: a line starting with "." would end the mail early
+        if line.starts_with('.') {
+            mail.push('.');
+        }
+        mail.push_str(line);
+        mail.push_str("\r\n");
+    }
+    mail.push_str(".\r\n");
+    connection.write_all(mail.as_bytes())?;
+    smtp_reply(&mut connection, '2')?;
+    let _ = smtp_command(&mut connection, "QUIT", '2');
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::events::{EventDetails, EventMetrics, Initiator};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    fn failover_event() -> Event {
+        Event {
+            id: 12,
+            ts: 1_767_225_600,
+            kind: EventKind::Failover,
+            initiator: Initiator::Monitor,
+            details: EventDetails {
+                old_peer: Some(Uuid::from_u128(1)),
+                new_peer: Some(Uuid::from_u128(2)),
+                reason: Some("offline".to_string()),
+                metrics: Some(EventMetrics { packet_loss_percent: Some(100.0), ..Default::default() }),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn name(id: &Uuid) -> String {
+        if *id == Uuid::from_u128(1) { "site-a" } else { "site-b" }.to_string()
+    }
+
+    // Local stub target: answers each HTTP request with the next status and hands the requests back
+    fn http_stub(statuses: Vec<u16>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            statuses.into_iter().map(|status| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length: ") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                write!(stream, "HTTP/1.1 {} Stub\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                request
+            }).collect()
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_webhook_is_signed_and_retried() {
+        let notification = Notification::from_event(&failover_event(), name);
+        assert_eq!(notification.title, "Failover from site-a to site-b (offline)");
+        assert!(notification.message.contains("packet loss 100%") && notification.urgent);
+
+        let (url, stub) = http_stub(vec![503, 200]);
+        let sink = NotificationSink::Webhook { url, secret: Some("s3cret".to_string()) };
+        // The first attempt gets a 503 and is retried after a second
+        deliver_with_retry(&sink, &notification, 1);
+        let requests = stub.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (head, body) = requests[1].split_once("\r\n\r\n").unwrap();
+        let head = format!("{}\r\n", head);
+        assert!(head.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(head.contains("X-Wg-Quickrs-Event: failover\r\n"));
+        assert!(head.contains(&format!("X-Wg-Quickrs-Signature: sha256={}\r\n", sign("s3cret", body.as_bytes()))));
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["event"]["new_peer"], Uuid::from_u128(2).to_string());
+
+        // Rejected requests are not retried
+        let (url, stub) = http_stub(vec![404]);
+        assert!(deliver(&NotificationSink::Ntfy { url, token: None }, &notification).unwrap_err().is_permanent());
+        assert!(stub.join().unwrap()[0].contains("Priority: high\r\n"));
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(20), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_smtp_conversation() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Local stub target: a minimal SMTP server that records the commands and the mail
+        let stub = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut transcript = Vec::new();
+            stream.write_all(b"220 stub ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                transcript.push(line.trim_end().to_string());
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-stub\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                stream.write_all(reply).unwrap();
+            }
+            transcript
+        });
+
+        let sink = NotificationSink::Smtp {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: Some("router".to_string()),
+            password: Some("pw".to_string()),
+            from: "router@example.com".to_string(),
+            to: vec!["ops@example.com".to_string()],
+        };
+        let mut notification = Notification::from_event(&failover_event(), name);
+        notification.message.push_str("\n.hidden");
+        deliver(&sink, &notification).unwrap();
+
+        let transcript = stub.join().unwrap();
+        assert!(transcript.contains(&format!("AUTH PLAIN {}", STANDARD.encode("\0router\0pw"))));
+        assert!(transcript.contains(&"RCPT TO:<ops@example.com>".to_string()));
+        assert!(transcript.contains(&"Subject: Failover from site-a to site-b (offline)".to_string()));
+        assert!(transcript.contains(&"..hidden".to_string()));
+        assert_eq!(transcript.last().unwrap(), "QUIT");
+    }
+}
@@ -1,19 +1,22 @@
 // All persistent state across restarts:
-// last mode, LAN CIDR, peer table mapping, prefix active/backup state
+// last mode, LAN CIDR, peer table mapping, prefix active/backup state, client exit nodes, domain routes, gateway groups
+// (volatile health state is in the runtime health store, see runtime.rs)
 //
 // Responsibilities:
 // - STEP 2: Persist mode state (restart logic)
 // - STEP 7: Persist peer table mappings and prefix active/backup state
 
 use super::mode::SystemMode;
+use super::runtime::PeerRuntime;
 use crate::WG_QUICKRS_CONFIG_FOLDER;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use thiserror::Error;
 
@@ -25,6 +28,7 @@ const MODE_STATE_TEMP_FILE: &str = "router_mode_state.json.tmp";
 static STATE_FILE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum PersistenceError {
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
@@ -32,38 +36,203 @@ pub enum PersistenceError {
     SerializationError(String),
     #[error("Deserialization error: {0}")]
     DeserializationError(String),
+    #[error("State format version {0} is newer than the supported version {MODE_STATE_VERSION}")]
+    UnsupportedVersion(u64),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModeState {
+    pub version: u32, // state format version (see MIGRATIONS)
     pub last_mode: SystemMode,
     pub lan_cidr: Option<String>,
     pub peer_table_ids: HashMap<String, u32>, // peer_id -> table_id
     pub prefix_active_backup: HashMap<String, PrefixState>, // prefix -> state
-    #[serde(default)]
-    pub peer_first_handshake: HashMap<String, u64>, // peer_id -> first_handshake_timestamp (Unix seconds)
-    #[serde(default)]
-    pub peer_last_online_state: HashMap<String, bool>, // peer_id -> was_online (tracks previous online state)
-    #[serde(default)]
-    pub peer_last_successful_ping: HashMap<String, u64>, // peer_id -> last_successful_ping_timestamp (Unix seconds)
-    #[serde(default = "default_peer_lan_access")]
-    pub peer_lan_access: HashMap<String, bool>, // peer_id -> has_lan_access (default true)
-    #[serde(default)]
+    pub peer_lan_access: HashMap<String, bool>, // peer_id -> has_lan_access (missing = true)
     pub auto_failover: bool, // Smart Gateway - automatically switch to healthy peer when exit node goes offline
-    #[serde(default)]
-    pub primary_exit_node: Option<String>, // User's preferred gateway - for fail-back after failover
-    #[serde(default)]
-    pub primary_online_since: Option<u64>, // Timestamp when primary came back online (for fail-back timing)
+    pub gateway_groups: BTreeMap<String, GatewayGroup>, // name -> exit node tiers for Smart Gateway
+    pub active_gateway_group: Option<String>, // group Smart Gateway fails over and back within
+    pub failback_timer: Option<FailbackTimer>, // a tier above the exit node's is healthy again (for fail-back timing)
+    pub load_balance: bool, // Active-active exit mode - spread LAN flows across all healthy exit nodes
+    pub exit_node_weights: HashMap<String, u32>, // peer_id -> load balancing weight (missing = 1, 0 = excluded)
+    pub client_exits: Vec<ClientExit>, // LAN clients pinned to an exit node of their own (in priority order)
+    pub domain_routes: HashMap<String, String>, // domain -> peer_id (domain-based split tunneling)
+    pub local_breakout_fallback: bool, // Smart Gateway may fall back to the local uplink when no exit node is healthy
 }
 
-fn default_peer_lan_access() -> HashMap<String, bool> {
-    HashMap::new() // Empty means all peers default to having LAN access
+impl ModeState {
+    // Fresh state in the current format (nothing routed yet, Smart Gateway off)
+    pub fn new(last_mode: SystemMode, lan_cidr: Option<String>) -> Self {
+        ModeState {
+            version: MODE_STATE_VERSION,
+            last_mode,
+            lan_cidr,
+            peer_table_ids: HashMap::new(),
+            prefix_active_backup: HashMap::new(),
+            peer_lan_access: HashMap::new(),
+            auto_failover: false,
+            gateway_groups: BTreeMap::new(),
+            active_gateway_group: None,
+            failback_timer: None,
+            load_balance: false,
+            exit_node_weights: HashMap::new(),
+            client_exits: Vec::new(),
+            domain_routes: HashMap::new(),
+            local_breakout_fallback: false,
+        }
+    }
+
+    // Drop a peer from every gateway group; groups left without members are removed
+    pub fn remove_from_gateway_groups(&mut self, peer_id: &str) {
+        let mut changed = false;
+        for group in self.gateway_groups.values_mut() {
+            for tier in group.tiers.iter_mut() {
+                let before = tier.len();
+                tier.retain(|id| id != peer_id);
+                changed |= tier.len() != before;
+            }
+            group.tiers.retain(|tier| !tier.is_empty());
+        }
+        self.gateway_groups.retain(|_, group| !group.tiers.is_empty());
+        if self.active_gateway_group.as_ref().is_some_and(|name| !self.gateway_groups.contains_key(name)) {
+            self.active_gateway_group = None;
+        }
+        // Tier indexes may have moved
+        if changed {
+            self.failback_timer = None;
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Smart Gateway priority failover: exit node ids per tier, tier 1 first (the nil id is the local breakout)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GatewayGroup {
+    pub tiers: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FailbackTimer {
+    pub tier: usize, // index of the tier that is healthy again
+    pub since: u64,  // Unix seconds
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PrefixState {
     pub active_peer_id: String,
     pub backup_peer_ids: Vec<String>,
+    pub failed_over_from: Option<String>, // peer the monitor failed over from (fail back to it once healthy)
+    pub failback_since: Option<u64>,      // Unix seconds failed_over_from has been healthy since
+}
+
+// A LAN client pinned to an exit node
+// source: address or CIDR (normalized, e.g. 192.168.1.50/32) or MAC address (upper case, colon separated)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientExit {
+    pub source: String,
+    pub exit_peer_id: String,
+}
+
+// State format versions
+// Every format change (a new field included) bumps MODE_STATE_VERSION and appends the step that upgrades the
+// previous version to MIGRATIONS, so a state file from any release loads the same way
+pub const MODE_STATE_VERSION: u32 = 1;
+
+// MIGRATIONS[n] upgrades version n to n + 1
+type Migration = fn(&mut Map<String, Value>);
+const MIGRATIONS: [Migration; MODE_STATE_VERSION as usize] = [migrate_v0_to_v1];
+
+// Version 0: the unversioned format, where fields were added with serde defaults
+// - primary_exit_node / primary_online_since (fail back to the exit node Smart Gateway left) became the
+//   "auto" gateway group and its fail-back timer
+// - the per-peer health maps moved to the runtime health store, which upgrade_state_file seeds from them
+fn migrate_v0_to_v1(state: &mut Map<String, Value>) {
+    let primary_exit_node = state.remove("primary_exit_node");
+    let primary_online_since = state.remove("primary_online_since");
+    for health_map in ["peer_first_handshake", "peer_last_online_state", "peer_last_successful_ping"] {
+        state.remove(health_map);
+    }
+    let defaults = [
+        ("lan_cidr", Value::Null),
+        ("peer_table_ids", json!({})),
+        ("prefix_active_backup", json!({})),
+        ("peer_lan_access", json!({})),
+        ("auto_failover", json!(false)),
+        ("gateway_groups", json!({})),
+        ("active_gateway_group", Value::Null),
+        ("failback_timer", Value::Null),
+        ("load_balance", json!(false)),
+        ("exit_node_weights", json!({})),
+        ("client_exits", json!([])),
+        ("domain_routes", json!({})),
+        ("local_breakout_fallback", json!(false)),
+    ];
+    for (field, default) in defaults {
+        state.entry(field).or_insert(default);
+    }
+    if let Some(prefixes) = state["prefix_active_backup"].as_object_mut() {
+        for prefix_state in prefixes.values_mut().filter_map(Value::as_object_mut) {
+            prefix_state.entry("failed_over_from").or_insert(Value::Null);
+            prefix_state.entry("failback_since").or_insert(Value::Null);
+        }
+    }
+
+    if let Some(Value::String(primary)) = primary_exit_node
+        && state["active_gateway_group"].is_null() {
+            let auto_group = super::routing_pbr::AUTO_GATEWAY_GROUP;
+            if let Some(groups) = state["gateway_groups"].as_object_mut() {
+                groups.insert(auto_group.to_string(), json!({"tiers": [[primary]]}));
+            }
+            state.insert("active_gateway_group".to_string(), json!(auto_group));
+            if let Some(since) = primary_online_since.as_ref().and_then(Value::as_u64) {
+                state.insert("failback_timer".to_string(), json!({"tier": 0, "since": since}));
+            }
+        }
+}
+
+// Per-peer health kept by version 0 state files, as the runtime health store holds it
+// "Up Since" (peer_first_handshake) starts over on restart, so it only stands in for a missing last successful ping
+fn v0_peer_health(state: &Value) -> HashMap<uuid::Uuid, PeerRuntime> {
+    let health_map = |name: &str| state.get(name).and_then(Value::as_object);
+    let peer_ids: HashSet<&String> = ["peer_last_online_state", "peer_last_successful_ping", "peer_first_handshake"]
+        .into_iter()
+        .filter_map(health_map)
+        .flat_map(|map| map.keys())
+        .collect();
+    let value = |name: &str, peer_id: &str| health_map(name).and_then(|map| map.get(peer_id));
+    peer_ids.into_iter()
+        .filter_map(|peer_id| {
+            let runtime = PeerRuntime {
+                online: value("peer_last_online_state", peer_id).and_then(Value::as_bool).unwrap_or(false),
+                last_successful_ping: value("peer_last_successful_ping", peer_id)
+                    .or_else(|| value("peer_first_handshake", peer_id))
+                    .and_then(Value::as_u64),
+            };
+            uuid::Uuid::parse_str(peer_id).ok().map(|peer_id| (peer_id, runtime))
+        })
+        .collect()
+}
+
+// Upgrade a parsed state file to the current format
+// Returns the state and the version it was stored in
+pub fn migrate(mut value: Value) -> Result<(ModeState, u32), PersistenceError> {
+    let Value::Object(state) = &mut value else {
+        return Err(PersistenceError::DeserializationError("State is not a JSON object".to_string()));
+    };
+    let stored_version = match state.get("version") {
+        None => 0,
+        Some(version) => version.as_u64()
+            .ok_or_else(|| PersistenceError::DeserializationError(format!("Invalid state format version {}", version)))?,
+    };
+    if stored_version > MODE_STATE_VERSION as u64 {
+        return Err(PersistenceError::UnsupportedVersion(stored_version));
+    }
+    let stored_version = stored_version as u32;
+    for (version, step) in MIGRATIONS.iter().enumerate().skip(stored_version as usize) {
+        step(state);
+        state.insert("version".to_string(), json!(version + 1));
+    }
+    let mode_state = serde_json::from_value(value)
+        .map_err(|e| PersistenceError::DeserializationError(e.to_string()))?;
+    Ok((mode_state, stored_version))
 }
 
 fn get_state_file_path() -> Result<PathBuf, PersistenceError> {
@@ -77,24 +246,32 @@ fn get_state_file_path() -> Result<PathBuf, PersistenceError> {
     Ok(config_folder.join(MODE_STATE_FILE))
 }
 
+fn lock_state_file() -> Result<std::sync::MutexGuard<'static, ()>, PersistenceError> {
+    STATE_FILE_LOCK.lock().map_err(|e| {
+        PersistenceError::IoError(std::io::Error::other(
+            format!("Failed to acquire state file lock: {}", e)
+        ))
+    })
+}
+
 // Save mode state to file using atomic writes
 // This prevents file corruption from concurrent access or interrupted writes
 pub fn save_mode_state(state: &ModeState) -> Result<(), PersistenceError> {
     // Acquire lock to prevent concurrent state file operations
-    let _lock = STATE_FILE_LOCK.lock().map_err(|e| {
-        PersistenceError::IoError(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to acquire state file lock: {}", e)
-        ))
-    })?;
-    
-    let file_path = get_state_file_path()?;
+    let _lock = lock_state_file()?;
+    write_state_file(&get_state_file_path()?, state)?;
+    // Let the reconciler catch whatever the caller does not apply itself
+    super::reconcile::request();
+    Ok(())
+}
+
+fn write_state_file(file_path: &Path, state: &ModeState) -> Result<(), PersistenceError> {
     let temp_path = file_path.with_file_name(MODE_STATE_TEMP_FILE);
     
     // Ensure config folder exists
     if let Some(parent) = file_path.parent() {
         fs::create_dir_all(parent)
-            .map_err(|e| PersistenceError::IoError(e))?;
+            .map_err(PersistenceError::IoError)?;
     }
     
     // Serialize to JSON
@@ -104,19 +281,19 @@ pub fn save_mode_state(state: &ModeState) -> Result<(), PersistenceError> {
     // ATOMIC WRITE: Write to temp file first
     {
         let mut file = File::create(&temp_path)
-            .map_err(|e| PersistenceError::IoError(e))?;
+            .map_err(PersistenceError::IoError)?;
         
         file.write_all(json.as_bytes())
-            .map_err(|e| PersistenceError::IoError(e))?;
+            .map_err(PersistenceError::IoError)?;
         
         // Ensure data is flushed to disk before renaming
         file.sync_all()
-            .map_err(|e| PersistenceError::IoError(e))?;
+            .map_err(PersistenceError::IoError)?;
     }
     
     // ATOMIC RENAME: Replace the original file with the temp file
     // This is atomic on most filesystems (ext4, etc.)
-    fs::rename(&temp_path, &file_path)
+    fs::rename(&temp_path, file_path)
         .map_err(|e| {
             // If rename fails, try to clean up temp file
             let _ = fs::remove_file(&temp_path);
@@ -127,16 +304,33 @@ pub fn save_mode_state(state: &ModeState) -> Result<(), PersistenceError> {
     Ok(())
 }
 
-// Load mode state from file
+// Read and migrate the state file without changing it
+// Returns None if there is no state file, and the state with the version it was stored in otherwise
+fn read_state_file(file_path: &Path) -> Result<Option<(ModeState, u32)>, PersistenceError> {
+    if !file_path.exists() {
+        return Ok(None);
+    }
+    
+    let mut contents = String::new();
+    File::open(file_path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(PersistenceError::IoError)?;
+    
+    if contents.trim().is_empty() {
+        return Err(PersistenceError::DeserializationError("State file is empty".to_string()));
+    }
+    
+    let value = serde_json::from_str::<Value>(&contents)
+        .map_err(|e| PersistenceError::DeserializationError(e.to_string()))?;
+    migrate(value).map(Some)
+}
+
+// Load mode state from file, upgrading an older format in place
 // Self-healing: if file is empty or corrupted, delete it and return None
+// A state file from a newer release is left alone and reported as an error
 pub fn load_mode_state() -> Result<Option<ModeState>, PersistenceError> {
     // Acquire lock to prevent concurrent state file operations
-    let _lock = STATE_FILE_LOCK.lock().map_err(|e| {
-        PersistenceError::IoError(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to acquire state file lock: {}", e)
-        ))
-    })?;
+    let _lock = lock_state_file()?;
     
     let file_path = get_state_file_path()?;
     let temp_path = file_path.with_file_name(MODE_STATE_TEMP_FILE);
@@ -147,53 +341,81 @@ pub fn load_mode_state() -> Result<Option<ModeState>, PersistenceError> {
         let _ = fs::remove_file(&temp_path);
     }
     
-    // Check if file exists
-    if !file_path.exists() {
-        return Ok(None);
-    }
-    
-    // Read file
-    let mut file = File::open(&file_path)
-        .map_err(|e| PersistenceError::IoError(e))?;
-    
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)
-        .map_err(|e| PersistenceError::IoError(e))?;
-    
-    // Check for empty file
-    if contents.trim().is_empty() {
-        log::warn!("Router mode state file is empty. Deleting corrupted file for self-recovery.");
-        if let Err(e) = fs::remove_file(&file_path) {
-            log::warn!("Failed to delete empty state file: {}", e);
-        }
-        return Ok(None);
-    }
-    
-    // Deserialize from JSON - with self-healing on corruption
-    match serde_json::from_str::<ModeState>(&contents) {
-        Ok(state) => {
+    match read_state_file(&file_path) {
+        Ok(Some((state, stored_version))) => {
             log::debug!("Loaded router mode state from {:?}", file_path);
+            if stored_version < MODE_STATE_VERSION {
+                upgrade_state_file(&file_path, &state, stored_version)?;
+            }
             Ok(Some(state))
         }
-        Err(e) => {
+        Ok(None) => Ok(None),
+        Err(PersistenceError::DeserializationError(e)) => {
             log::warn!("Router mode state file is corrupted ({}). Deleting for self-recovery.", e);
             if let Err(del_err) = fs::remove_file(&file_path) {
                 log::warn!("Failed to delete corrupted state file: {}", del_err);
             }
             Ok(None)
         }
+        Err(e) => Err(e),
+    }
+}
+
+// State file check for `wg-quickrs agent state check`: reads and migrates in memory only
+pub fn check_mode_state() -> Result<Option<(ModeState, u32)>, PersistenceError> {
+    let _lock = lock_state_file()?;
+    read_state_file(&get_state_file_path()?)
+}
+
+// Upgrade the state file to the current format for `wg-quickrs agent state migrate`
+// Returns the version it was stored in (None if there is no state file)
+pub fn migrate_mode_state() -> Result<Option<u32>, PersistenceError> {
+    let _lock = lock_state_file()?;
+    let file_path = get_state_file_path()?;
+    let Some((state, stored_version)) = read_state_file(&file_path)? else {
+        return Ok(None);
+    };
+    if stored_version < MODE_STATE_VERSION {
+        upgrade_state_file(&file_path, &state, stored_version)?;
+    }
+    Ok(Some(stored_version))
+}
+
+// Rewrite a migrated state file, keeping the previous one as router_mode_state.v<version>.json
+// (so going back to an older release can start from it)
+fn upgrade_state_file(file_path: &Path, state: &ModeState, stored_version: u32) -> Result<(), PersistenceError> {
+    if stored_version == 0 {
+        seed_peer_health(file_path);
+    }
+    let backup_path = file_path.with_file_name(format!("router_mode_state.v{}.json", stored_version));
+    fs::copy(file_path, &backup_path).map_err(PersistenceError::IoError)?;
+    write_state_file(file_path, state)?;
+    log::info!("Migrated router mode state from version {} to {} (previous file kept at {:?})",
+        stored_version, MODE_STATE_VERSION, backup_path);
+    Ok(())
+}
+
+// Carry the health a version 0 state file kept over to the runtime health store next to it
+// (best effort: the probes rebuild it anyway)
+fn seed_peer_health(file_path: &Path) {
+    let peers = fs::read_to_string(file_path).ok()
+        .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+        .map(|state| v0_peer_health(&state))
+        .unwrap_or_default();
+    if peers.is_empty() {
+        return;
+    }
+    let count = peers.len();
+    match super::runtime::seed(file_path.with_file_name(super::runtime::HEALTH_STATE_FILE), peers) {
+        Ok(()) => log::info!("Moved the health state of {} peer(s) to the runtime health store", count),
+        Err(e) => log::warn!("Failed to move peer health state to the runtime health store: {}", e),
     }
 }
 
 // Clear mode state (when switching to Host Mode)
 pub fn clear_mode_state() -> Result<(), PersistenceError> {
     // Acquire lock to prevent concurrent state file operations
-    let _lock = STATE_FILE_LOCK.lock().map_err(|e| {
-        PersistenceError::IoError(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to acquire state file lock: {}", e)
-        ))
-    })?;
+    let _lock = lock_state_file()?;
     
     let file_path = get_state_file_path()?;
     let temp_path = file_path.with_file_name(MODE_STATE_TEMP_FILE);
@@ -201,7 +423,7 @@ pub fn clear_mode_state() -> Result<(), PersistenceError> {
     // Delete the state file if it exists
     if file_path.exists() {
         fs::remove_file(&file_path)
-            .map_err(|e| PersistenceError::IoError(e))?;
+            .map_err(PersistenceError::IoError)?;
         log::info!("Cleared router mode state file {:?}", file_path);
     }
     
@@ -210,6 +432,9 @@ pub fn clear_mode_state() -> Result<(), PersistenceError> {
         let _ = fs::remove_file(&temp_path);
     }
     
+    // Health tracking starts over with the next Router Mode session
+    super::runtime::clear();
+    
     Ok(())
 }
 
@@ -254,14 +479,20 @@ pub fn validate_and_cleanup_persisted_state(
             // Remove from peer_table_ids
             state.peer_table_ids.remove(peer_id);
             
-            // Remove from peer health tracking
-            state.peer_first_handshake.remove(peer_id);
-            state.peer_last_online_state.remove(peer_id);
-            state.peer_last_successful_ping.remove(peer_id);
+            // Remove from peer health tracking (runtime health store)
+            if let Ok(peer_uuid) = uuid::Uuid::parse_str(peer_id) {
+                super::runtime::forget(&peer_uuid);
+            }
             
             // Remove from peer LAN access settings
             state.peer_lan_access.remove(peer_id);
             
+            // Clients pinned to this peer go back to the exit node
+            state.client_exits.retain(|entry| entry.exit_peer_id != *peer_id);
+            
+            // Remove from gateway group tiers
+            state.remove_from_gateway_groups(peer_id);
+            
             // Remove from prefix_active_backup if this peer was an exit node
             state.prefix_active_backup.retain(|_prefix, prefix_state| {
                 let mut updated = false;
@@ -287,3 +518,115 @@ pub fn validate_and_cleanup_persisted_state(
     true
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIMARY: &str = "0b4c2d1e-9a3f-4c55-8e21-6f0d7a1b2c3d";
+    const BACKUP: &str = "5e6f7a8b-1c2d-4e3f-9a0b-c1d2e3f4a5b6";
+
+    fn fixture(name: &str) -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/mode_state").join(name);
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_migrate_v0_primary_exit_node() {
+        let (state, stored_version) = migrate(fixture("v0_primary_exit_node.json")).unwrap();
+        assert_eq!(stored_version, 0);
+        assert_eq!(state.version, MODE_STATE_VERSION);
+        assert_eq!(state.peer_table_ids.len(), 2);
+        assert_eq!(state.peer_lan_access.get(BACKUP), Some(&false));
+        assert!(state.auto_failover);
+
+        // Fail-back to the primary exit node is now the auto gateway group
+        assert_eq!(state.active_gateway_group.as_deref(), Some("auto"));
+        assert_eq!(state.gateway_groups["auto"].tiers, vec![vec![PRIMARY.to_string()]]);
+        assert_eq!(state.failback_timer, Some(FailbackTimer { tier: 0, since: 1760000800 }));
+        assert!(!state.load_balance && !state.local_breakout_fallback);
+        assert!(state.domain_routes.is_empty() && state.exit_node_weights.is_empty() && state.client_exits.is_empty());
+    }
+
+    #[test]
+    fn test_migrate_v0_gateway_groups() {
+        let (state, stored_version) = migrate(fixture("v0_gateway_groups.json")).unwrap();
+        assert_eq!(stored_version, 0);
+        assert_eq!(state.active_gateway_group.as_deref(), Some("sites"));
+        assert_eq!(state.gateway_groups["sites"].tiers.len(), 2);
+        assert_eq!(state.failback_timer, None);
+        assert_eq!(state.prefix_active_backup["10.50.0.0/16"].backup_peer_ids, vec![BACKUP.to_string()]);
+        assert_eq!(state.prefix_active_backup["10.50.0.0/16"].failed_over_from, None);
+        assert_eq!(state.exit_node_weights.get(BACKUP), Some(&3));
+        assert_eq!(state.domain_routes.get("video.example.com").map(String::as_str), Some(BACKUP));
+        assert_eq!(state.client_exits, vec![ClientExit { source: "192.168.1.50/32".to_string(), exit_peer_id: BACKUP.to_string() }]);
+        assert!(!state.local_breakout_fallback);
+
+        let migrated = serde_json::to_value(&state).unwrap();
+        for health_map in ["peer_first_handshake", "peer_last_online_state", "peer_last_successful_ping"] {
+            assert!(migrated.get(health_map).is_none());
+        }
+        let health = v0_peer_health(&fixture("v0_gateway_groups.json"));
+        assert_eq!(health.len(), 1);
+        assert_eq!(health[&PRIMARY.parse().unwrap()], PeerRuntime::default());
+        // "Up Since" stands in for a missing last successful ping
+        let health = v0_peer_health(&json!({"peer_first_handshake": {BACKUP: 1760000000}, "peer_last_online_state": {BACKUP: true}}));
+        assert_eq!(health[&BACKUP.parse().unwrap()], PeerRuntime { online: true, last_successful_ping: Some(1760000000) });
+    }
+
+    #[test]
+    fn test_migrate_current_version_round_trips() {
+        let (state, stored_version) = migrate(fixture("v1.json")).unwrap();
+        assert_eq!(stored_version, MODE_STATE_VERSION);
+        assert_eq!(serde_json::to_value(&state).unwrap(), fixture("v1.json"));
+
+        let fresh = serde_json::to_value(ModeState::new(SystemMode::Router, None)).unwrap();
+        assert_eq!(migrate(fresh).unwrap().1, MODE_STATE_VERSION);
+    }
+
+    #[test]
+    fn test_migrate_rejects_invalid_and_newer_versions() {
+        let mut newer = fixture("v1.json");
+        newer["version"] = json!(MODE_STATE_VERSION + 1);
+        assert!(matches!(migrate(newer), Err(PersistenceError::UnsupportedVersion(v)) if v == MODE_STATE_VERSION as u64 + 1));
+
+        let mut invalid = fixture("v1.json");
+        invalid["version"] = json!("one");
+        assert!(matches!(migrate(invalid), Err(PersistenceError::DeserializationError(_))));
+
+        // A current-format file missing a field is not filled in
+        let mut incomplete = fixture("v1.json");
+        incomplete.as_object_mut().unwrap().remove("load_balance");
+        assert!(matches!(migrate(incomplete), Err(PersistenceError::DeserializationError(_))));
+        assert!(matches!(migrate(json!([])), Err(PersistenceError::DeserializationError(_))));
+    }
+
+    #[test]
+    fn test_upgrade_state_file_keeps_previous_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join(MODE_STATE_FILE);
+        fs::write(&file_path, serde_json::to_string(&fixture("v0_primary_exit_node.json")).unwrap()).unwrap();
+
+        // A peer the health store already knows keeps its newer state
+        let health_path = dir.path().join(super::super::runtime::HEALTH_STATE_FILE);
+        let known = uuid::Uuid::new_v4();
+        let known_runtime = PeerRuntime { online: false, last_successful_ping: Some(1760001000) };
+        fs::write(&health_path, serde_json::to_string(&HashMap::from([(known, known_runtime)])).unwrap()).unwrap();
+
+        let (state, stored_version) = read_state_file(&file_path).unwrap().unwrap();
+        upgrade_state_file(&file_path, &state, stored_version).unwrap();
+        assert_eq!(read_state_file(&file_path).unwrap().unwrap().1, MODE_STATE_VERSION);
+        let backup = dir.path().join("router_mode_state.v0.json");
+        assert_eq!(read_state_file(&backup).unwrap().unwrap().1, 0);
+
+        // The version 0 health maps went to the runtime health store
+        let health = super::super::runtime::RuntimeStore::load(health_path);
+        assert_eq!(health.get(&PRIMARY.parse().unwrap()), PeerRuntime { online: true, last_successful_ping: Some(1760000900) });
+        assert_eq!(health.get(&BACKUP.parse().unwrap()), PeerRuntime { online: true, last_successful_ping: None });
+        assert_eq!(health.get(&known), known_runtime);
+
+        fs::write(&file_path, " ").unwrap();
+        assert!(matches!(read_state_file(&file_path), Err(PersistenceError::DeserializationError(_))));
+        assert!(read_state_file(&dir.path().join("missing.json")).unwrap().is_none());
+    }
+}
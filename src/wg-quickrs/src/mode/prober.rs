@@ -0,0 +1,575 @@
+// Native health prober: ICMP echo over the WireGuard interface without spawning `ping`,
+// and internet probes (HTTP, DNS, TCP) through a chosen exit node
+//
+// The health monitor probes every exit node every second; forking `ping` for each probe
+// churned processes, needed iputils on the image and scraped a localized `time=` out of
+// stdout. Probes are sent from a socket instead, in order of preference:
+// - Unprivileged ICMP (SOCK_DGRAM, needs the group in net.ipv4.ping_group_range)
+// - Raw ICMP (SOCK_RAW, needs CAP_NET_RAW)
+// - UDP to a closed high port, the ICMP port unreachable counts as the reply (needs nothing)
+// The first method the kernel allows is remembered for later probes.
+//
+// Every probe carries its own sequence number and the send time in microseconds, and only a
+// reply echoing both is accepted, so a late reply to an earlier probe (or another exit node's
+// reply on a raw socket) is never counted as success.
+//
+// Internet probes check that an exit node's own upstream works, not just the tunnel. Their sockets
+// carry SO_MARK, and a `fwmark <mark> lookup <table>` rule sends them into the exit node's table
+// (setting the mark needs CAP_NET_ADMIN).
+
+use once_cell::sync::Lazy;
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpStream, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use wg_quickrs_lib::types::config::{parse_http_probe_url, HealthProbe};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HEADER_LEN: usize = 8;
+// Same payload size as ping's default (64 bytes on the wire with the header)
+const PAYLOAD_LEN: usize = 56;
+// traceroute's base port, nothing listens there
+const UDP_PROBE_BASE_PORT: u16 = 33434;
+const UDP_PROBE_PORTS: u16 = 64;
+
+#[derive(Error, Debug)]
+pub enum ProbeError {
+    #[error("socket error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("interface {0} not found")]
+    NoSuchInterface(String),
+    #[error("no probe method is permitted (ICMP and UDP sockets refused)")]
+    NotPermitted,
+    #[error("{0}")]
+    Failed(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeMethod {
+    IcmpDgram,
+    IcmpRaw,
+    Udp,
+}
+
+impl std::fmt::Display for ProbeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeMethod::IcmpDgram => write!(f, "unprivileged ICMP"),
+            ProbeMethod::IcmpRaw => write!(f, "raw ICMP"),
+            ProbeMethod::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+// First method the kernel allowed (None until the first probe)
+static PROBE_METHOD: Lazy<Mutex<Option<ProbeMethod>>> = Lazy::new(|| Mutex::new(None));
+static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(1);
+
+// Probe a target once out of `interface`; Ok(None) when no reply came within `timeout`
+// Blocking: the health monitor runs it on the blocking thread pool
+pub fn probe(target: Ipv4Addr, interface: &str, timeout: Duration) -> Result<Option<Duration>, ProbeError> {
+    let remembered = *PROBE_METHOD.lock().unwrap();
+    let methods = match remembered {
+        Some(method) => vec![method],
+        None => vec![ProbeMethod::IcmpDgram, ProbeMethod::IcmpRaw, ProbeMethod::Udp],
+    };
+    for method in methods {
+        let socket = match ProbeSocket::open(method) {
+            Ok(socket) => socket,
+            Err(e) if remembered.is_none() && is_permission_error(&e) => {
+                log::debug!("Prober: {} sockets not permitted: {}", method, e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if remembered.is_none() {
+            log::info!("Prober: probing exit nodes with {} sockets", method);
+            *PROBE_METHOD.lock().unwrap() = Some(method);
+        }
+        socket.bind_to_interface(interface)?;
+        return socket.probe(target, timeout);
+    }
+    Err(ProbeError::NotPermitted)
+}
+
+fn is_permission_error(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EPROTONOSUPPORT))
+}
+
+fn next_sequence() -> u16 {
+    NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+// RFC 1071 internet checksum
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Echo request carrying the sequence and the send time (microseconds since the epoch)
+fn echo_request(identifier: u16, sequence: u16, sent_micros: u64) -> Vec<u8> {
+    let mut packet = vec![0u8; ICMP_HEADER_LEN + PAYLOAD_LEN];
+    packet[0] = ICMP_ECHO_REQUEST;
+    packet[4..6].copy_from_slice(&identifier.to_be_bytes());
+    packet[6..8].copy_from_slice(&sequence.to_be_bytes());
+    packet[8..16].copy_from_slice(&sent_micros.to_be_bytes());
+    for (i, byte) in packet[16..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let sum = checksum(&packet);
+    packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    packet
+}
+
+// Whether a received ICMP message is the reply to our request
+// Raw sockets see every ICMP packet with its IP header (and the identifier is ours to check);
+// unprivileged sockets get the ICMP message only, already filtered by the kernel-assigned identifier
+fn is_echo_reply(
+    packet: &[u8],
+    with_ip_header: bool,
+    target: Ipv4Addr,
+    identifier: Option<u16>,
+    sequence: u16,
+    sent_micros: u64,
+) -> bool {
+    let icmp = if with_ip_header {
+        let Some(first) = packet.first() else { return false };
+        let header_len = ((first & 0x0f) as usize) * 4;
+        if packet.len() < header_len.max(20) || packet[12..16] != target.octets() {
+            return false;
+        }
+        &packet[header_len..]
+    } else {
+        packet
+    };
+    if icmp.len() < ICMP_HEADER_LEN + 8 || icmp[0] != ICMP_ECHO_REPLY {
+        return false;
+    }
+    if identifier.is_some_and(|id| icmp[4..6] != id.to_be_bytes()) {
+        return false;
+    }
+    icmp[6..8] == sequence.to_be_bytes() && icmp[8..16] == sent_micros.to_be_bytes()
+}
+
+struct ProbeSocket {
+    fd: OwnedFd,
+    method: ProbeMethod,
+}
+
+impl ProbeSocket {
+    fn open(method: ProbeMethod) -> std::io::Result<Self> {
+        let (kind, protocol) = match method {
+            ProbeMethod::IcmpDgram => (libc::SOCK_DGRAM, libc::IPPROTO_ICMP),
+            ProbeMethod::IcmpRaw => (libc::SOCK_RAW, libc::IPPROTO_ICMP),
+            ProbeMethod::Udp => (libc::SOCK_DGRAM, libc::IPPROTO_UDP),
+        };
+        // SAFETY: plain socket(2) call; the returned fd is owned by OwnedFd
+        let fd = unsafe { libc::socket(libc::AF_INET, kind | libc::SOCK_CLOEXEC, protocol) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(ProbeSocket { fd: unsafe { OwnedFd::from_raw_fd(fd) }, method })
+    }
+
+    // Keep probes on the interface regardless of policy routing (like `ping -I`)
+    // SO_BINDTODEVICE needs CAP_NET_RAW; without it, bind to the interface's address instead
+    fn bind_to_interface(&self, interface: &str) -> Result<(), ProbeError> {
+        let name = CString::new(interface).map_err(|_| ProbeError::NoSuchInterface(interface.to_string()))?;
+        #[cfg(target_os = "linux")]
+        {
+            let bytes = name.as_bytes_with_nul();
+            // SAFETY: the option value is the NUL-terminated interface name
+            let ret = unsafe {
+                libc::setsockopt(
+                    self.fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    bytes.as_ptr() as *const libc::c_void,
+                    bytes.len() as libc::socklen_t,
+                )
+            };
+            if ret == 0 {
+                return Ok(());
+            }
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENODEV) {
+                return Err(ProbeError::NoSuchInterface(interface.to_string()));
+            }
+        }
+        let address = get_if_addrs::get_if_addrs()?
+            .into_iter()
+            .find_map(|iface| match iface.addr {
+                get_if_addrs::IfAddr::V4(v4) if iface.name == name.to_string_lossy() => Some(v4.ip),
+                _ => None,
+            })
+            .ok_or_else(|| ProbeError::NoSuchInterface(interface.to_string()))?;
+        self.bind(address)
+    }
+
+    fn bind(&self, address: Ipv4Addr) -> Result<(), ProbeError> {
+        let addr = sockaddr(address, 0);
+        // SAFETY: addr is a valid sockaddr_in for the duration of the call
+        let ret = unsafe {
+            libc::bind(
+                self.fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn probe(&self, target: Ipv4Addr, timeout: Duration) -> Result<Option<Duration>, ProbeError> {
+        let sequence = next_sequence();
+        let sent_micros = now_micros();
+        let started = Instant::now();
+        let deadline = started + timeout;
+        match self.method {
+            ProbeMethod::IcmpDgram | ProbeMethod::IcmpRaw => {
+                // The kernel replaces the identifier of unprivileged sockets with the socket's port
+                let identifier = std::process::id() as u16;
+                self.send_to(&echo_request(identifier, sequence, sent_micros), target, 0)?;
+                let (with_ip_header, identifier) = match self.method {
+                    ProbeMethod::IcmpRaw => (true, Some(identifier)),
+                    _ => (false, None),
+                };
+                let mut buf = [0u8; 1500];
+                while let Some(len) = self.recv_until(&mut buf, deadline)? {
+                    if is_echo_reply(&buf[..len], with_ip_header, target, identifier, sequence, sent_micros) {
+                        return Ok(Some(started.elapsed()));
+                    }
+                }
+                Ok(None)
+            }
+            ProbeMethod::Udp => {
+                // A connected UDP socket reports the ICMP port unreachable as ECONNREFUSED
+                let port = UDP_PROBE_BASE_PORT + sequence % UDP_PROBE_PORTS;
+                self.connect(target, port)?;
+                let mut payload = sequence.to_be_bytes().to_vec();
+                payload.extend_from_slice(&sent_micros.to_be_bytes());
+                self.send_to(&payload, target, port)?;
+                let mut buf = [0u8; 64];
+                match self.recv_until(&mut buf, deadline) {
+                    Ok(Some(_)) => Ok(Some(started.elapsed())),
+                    Ok(None) => Ok(None),
+                    Err(ProbeError::Io(e)) if e.raw_os_error() == Some(libc::ECONNREFUSED) => Ok(Some(started.elapsed())),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    fn connect(&self, target: Ipv4Addr, port: u16) -> Result<(), ProbeError> {
+        let addr = sockaddr(target, port);
+        // SAFETY: addr is a valid sockaddr_in for the duration of the call
+        let ret = unsafe {
+            libc::connect(
+                self.fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn send_to(&self, buf: &[u8], target: Ipv4Addr, port: u16) -> Result<(), ProbeError> {
+        let addr = sockaddr(target, port);
+        // SAFETY: buf and addr outlive the call
+        let ret = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    // Next datagram received before the deadline (None once it passed)
+    fn recv_until(&self, buf: &mut [u8], deadline: Instant) -> Result<Option<usize>, ProbeError> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let mut poll = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let timeout_ms = remaining.as_millis().clamp(1, i32::MAX as u128) as libc::c_int;
+            // SAFETY: a single valid pollfd
+            let ready = unsafe { libc::poll(&mut poll, 1, timeout_ms) };
+            if ready < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if ready == 0 {
+                return Ok(None);
+            }
+            // SAFETY: buf is valid for buf.len() bytes
+            let ret = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            return Ok(Some(ret as usize));
+        }
+    }
+}
+
+// Run an internet probe with its packets marked `fwmark`; Ok(round trip) once it succeeded
+// Blocking: the health monitor runs it on the blocking thread pool
+pub fn probe_internet(probe: &HealthProbe, fwmark: u32, timeout: Duration) -> Result<Duration, ProbeError> {
+    let started = Instant::now();
+    match probe {
+        HealthProbe::Tcp { address } => {
+            connect_marked(*address, fwmark, timeout)?;
+        }
+        HealthProbe::Http { url, host } => {
+            let (address, path) = parse_http_probe_url(url)
+                .ok_or_else(|| ProbeError::Failed(format!("invalid url {}", url)))?;
+            let mut stream = connect_marked(address, fwmark, timeout)?;
+            let remaining = timeout.saturating_sub(started.elapsed()).max(Duration::from_millis(1));
+            stream.set_read_timeout(Some(remaining))?;
+            stream.set_write_timeout(Some(remaining))?;
+            let host = host.clone().unwrap_or_else(|| address.ip().to_string());
+            write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: wg-quickrs\r\nConnection: close\r\n\r\n", path, host)?;
+            let mut buf = [0u8; 64];
+            let mut len = 0;
+            while len < buf.len() && !buf[..len].contains(&b'\n') {
+                match stream.read(&mut buf[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            let status = http_status(&buf[..len])
+                .ok_or_else(|| ProbeError::Failed("no HTTP response".to_string()))?;
+            if !(200..400).contains(&status) {
+                return Err(ProbeError::Failed(format!("HTTP status {}", status)));
+            }
+        }
+        HealthProbe::Dns { server, name } => {
+            let socket = UdpSocket::from(marked_socket(libc::SOCK_DGRAM, fwmark)?);
+            socket.connect(SocketAddrV4::new(*server, 53))?;
+            socket.set_read_timeout(Some(timeout))?;
+            let id = next_sequence();
+            socket.send(&dns_query(id, name))?;
+            let mut buf = [0u8; 512];
+            loop {
+                let len = match socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                        return Err(ProbeError::Failed("no DNS answer".to_string()));
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                match dns_answer_rcode(&buf[..len], id) {
+                    Some(0) | Some(3) => break,
+                    Some(rcode) => return Err(ProbeError::Failed(format!("DNS answer with rcode {}", rcode))),
+                    None if started.elapsed() >= timeout => return Err(ProbeError::Failed("no DNS answer".to_string())),
+                    None => continue, // Not the answer to this query
+                }
+            }
+        }
+    }
+    Ok(started.elapsed())
+}
+
+// IPv4 socket with SO_MARK set
+fn marked_socket(kind: libc::c_int, fwmark: u32) -> Result<OwnedFd, ProbeError> {
+    // SAFETY: plain socket(2) call; the returned fd is owned by OwnedFd
+    let fd = unsafe { libc::socket(libc::AF_INET, kind | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: the option value is a u32
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_MARK,
+                &fwmark as *const u32 as *const libc::c_void,
+                std::mem::size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(fd)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (fd, fwmark);
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+}
+
+// Marked TCP connection, given up after `timeout`
+fn connect_marked(address: SocketAddrV4, fwmark: u32, timeout: Duration) -> Result<TcpStream, ProbeError> {
+    let fd = marked_socket(libc::SOCK_STREAM | libc::SOCK_NONBLOCK, fwmark)?;
+    let addr = sockaddr(*address.ip(), address.port());
+    // SAFETY: addr is a valid sockaddr_in for the duration of the call
+    let ret = unsafe {
+        libc::connect(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err.into());
+        }
+        let mut poll = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLOUT, revents: 0 };
+        let timeout_ms = timeout.as_millis().clamp(1, i32::MAX as u128) as libc::c_int;
+        // SAFETY: a single valid pollfd
+        if unsafe { libc::poll(&mut poll, 1, timeout_ms) } <= 0 {
+            return Err(ProbeError::Failed(format!("connect to {} timed out", address)));
+        }
+    }
+    let stream = TcpStream::from(fd);
+    if let Some(err) = stream.take_error()? {
+        return Err(ProbeError::Failed(format!("connect to {} failed: {}", address, err)));
+    }
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+// Status code of an HTTP status line ("HTTP/1.1 204 No Content")
+fn http_status(response: &[u8]) -> Option<u16> {
+    let line = std::str::from_utf8(response).ok()?.lines().next()?;
+    let mut parts = line.split_whitespace();
+    parts.next().filter(|version| version.starts_with("HTTP/"))?;
+    parts.next()?.parse().ok()
+}
+
+// Recursive A query for `name`
+fn dns_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]); // RD, one question
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]); // root, type A, class IN
+    query
+}
+
+// Response code of the answer to query `id` (None if the packet is not that answer)
+fn dns_answer_rcode(answer: &[u8], id: u16) -> Option<u8> {
+    if answer.len() < 12 || answer[0..2] != id.to_be_bytes() || answer[2] & 0x80 == 0 {
+        return None;
+    }
+    Some(answer[3] & 0x0f)
+}
+
+fn sockaddr(address: Ipv4Addr, port: u16) -> libc::sockaddr_in {
+    // SAFETY: sockaddr_in is plain old data, zeroed is a valid value
+    let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = port.to_be();
+    addr.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(address.octets()) };
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Echo reply to a request: type 0, same identifier, sequence and payload
+    fn reply_to(request: &[u8]) -> Vec<u8> {
+        let mut reply = request.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let sum = checksum(&reply);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+        reply
+    }
+
+    fn with_ip_header(source: Ipv4Addr, icmp: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0];
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&[10, 0, 34, 1]);
+        packet.extend_from_slice(icmp);
+        packet
+    }
+
+    #[test]
+    fn test_echo_request_checksum() {
+        let request = echo_request(0x1234, 7, 1_700_000_000_123_456);
+        assert_eq!(request.len(), 64);
+        assert_eq!(request[0], ICMP_ECHO_REQUEST);
+        // A packet including its checksum sums to zero
+        assert_eq!(checksum(&request), 0);
+        assert_eq!(checksum(&reply_to(&request)), 0);
+    }
+
+    #[test]
+    fn test_internet_probe_parsing() {
+        assert_eq!(http_status(b"HTTP/1.1 204 No Content\r\n"), Some(204));
+        assert_eq!(http_status(b"SSH-2.0-OpenSSH\r\n"), None);
+
+        let query = dns_query(0xbeef, "example.com.");
+        assert_eq!(&query[12..25], b"\x07example\x03com\x00");
+        let mut answer = query.clone();
+        answer[2] |= 0x80;
+        assert_eq!(dns_answer_rcode(&answer, 0xbeef), Some(0));
+        answer[3] |= 3;
+        assert_eq!(dns_answer_rcode(&answer, 0xbeef), Some(3));
+        // Our own query, or the answer to another one
+        assert_eq!(dns_answer_rcode(&query, 0xbeef), None);
+        assert_eq!(dns_answer_rcode(&answer, 0xbeee), None);
+    }
+
+    #[test]
+    fn test_only_matching_reply_accepted() {
+        let target: Ipv4Addr = "10.0.34.2".parse().unwrap();
+        let sent = 1_700_000_000_123_456;
+        let reply = reply_to(&echo_request(0x1234, 7, sent));
+
+        assert!(is_echo_reply(&reply, false, target, None, 7, sent));
+        assert!(is_echo_reply(&with_ip_header(target, &reply), true, target, Some(0x1234), 7, sent));
+
+        // Late reply to an earlier probe, a reply to another probe with the same sequence, or our own request
+        assert!(!is_echo_reply(&reply, false, target, None, 8, sent));
+        assert!(!is_echo_reply(&reply, false, target, None, 7, sent + 1));
+        assert!(!is_echo_reply(&echo_request(0x1234, 7, sent), false, target, None, 7, sent));
+        // On raw sockets: another process' identifier or another exit node's reply
+        assert!(!is_echo_reply(&with_ip_header(target, &reply), true, target, Some(0x4321), 7, sent));
+        assert!(!is_echo_reply(&with_ip_header("10.0.34.3".parse().unwrap(), &reply), true, target, Some(0x1234), 7, sent));
+        assert!(!is_echo_reply(&reply[..10], false, target, None, 7, sent));
+    }
+}
@@ -0,0 +1,396 @@
+// Reconciler: converge kernel routing state and the firewall to what Router Mode wants
+//
+// Routing state is applied imperatively from many call sites (config changes, peer control, interface
+// restarts, the health monitor), and anything they miss or leave behind used to stay until the next switch.
+// The reconciler derives the desired rules, routes and iptables rules from the config and the mode state
+// (routing_pbr::plan_routing, firewall::desired_firewall_rules), diffs them against the kernel and applies
+// only the delta:
+// - missing routes and rules first, then extra rules and routes, so traffic never falls through mid-change
+// - only rules and tables wg-quickrs owns are deleted (routing_pbr::is_managed_rule)
+// - missing iptables rules are re-added; extra ones are deleted only if they carry the wg-quickrs comment
+//   (firewall::stale_firewall_rules), so an admin's rules are left alone
+// - a pass holds the routing lock (routing_pbr::routing_lock), which every setter takes as well, so it
+//   never plans from a state whose kernel changes are still being applied
+// Not reconciled: WireGuard AllowedIPs. The exit node's 0.0.0.0/0, load balancing buckets, domain route
+// addresses and probe targets are set with `wg set` by the call sites that change them (and restored
+// when the interface comes back up); a peer whose AllowedIPs were changed behind wg-quickrs' back stays
+// that way until the next such change.
+// It runs shortly after every change to the mode state or config and every RECONCILE_INTERVAL_SECS,
+// and keeps the last drift report for GET /api/router-mode/drift.
+
+use super::backend::{routing_backend, BackendError, Route, Rule};
+use super::events::{self, EventDetails, EventKind, Initiator};
+use super::mode::SystemMode;
+use super::persist::{load_mode_state, PersistenceError};
+use super::routing_pbr::{self, PolicyRoutingError};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+use wg_quickrs_lib::types::network::Network;
+
+pub const RECONCILE_INTERVAL_SECS: u64 = 60;
+// Lets the change that asked for a pass finish, and folds bursts of changes into one pass
+const RECONCILE_DEBOUNCE: Duration = Duration::from_secs(2);
+
+#[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum ReconcileError {
+    #[error("Routing error: {0}")]
+    RoutingError(#[from] PolicyRoutingError),
+    #[error("Routing backend error: {0}")]
+    BackendError(#[from] BackendError),
+    #[error("Persistence error: {0}")]
+    PersistenceError(#[from] PersistenceError),
+}
+
+// What a pass found different from the desired state (and corrected, unless listed in errors)
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct DriftReport {
+    pub checked_at: u64, // Unix seconds
+    pub missing_rules: Vec<String>,
+    pub extra_rules: Vec<String>,
+    pub missing_routes: Vec<String>, // Absent, or present with another device or gateway
+    pub extra_routes: Vec<String>,
+    pub missing_firewall_rules: Vec<String>,
+    pub extra_firewall_rules: Vec<String>,
+    pub errors: Vec<String>, // Changes that could not be applied, or parts that could not be checked
+}
+
+impl DriftReport {
+    pub fn has_drift(&self) -> bool {
+        self.drift_count() > 0
+    }
+
+    pub fn drift_count(&self) -> usize {
+        self.missing_rules.len() + self.extra_rules.len() + self.missing_routes.len()
+            + self.extra_routes.len() + self.missing_firewall_rules.len() + self.extra_firewall_rules.len()
+    }
+
+    fn same_drift(&self, other: &DriftReport) -> bool {
+        DriftReport { checked_at: 0, errors: Vec::new(), ..self.clone() }
+            == DriftReport { checked_at: 0, errors: Vec::new(), ..other.clone() }
+    }
+}
+
+// Changes that bring the kernel in line with the plan
+#[derive(Debug, Default, PartialEq)]
+pub struct RoutingDiff {
+    pub add_routes: Vec<Route>,
+    pub add_rules: Vec<Rule>,
+    pub delete_rules: Vec<Rule>,
+    pub delete_routes: Vec<Route>,
+}
+
+static LAST_REPORT: Lazy<RwLock<Option<DriftReport>>> = Lazy::new(|| RwLock::new(None));
+static TRIGGER: Lazy<Notify> = Lazy::new(Notify::new);
+
+// Ask for a pass soon (after a change to the mode state or config)
+pub fn request() {
+    TRIGGER.notify_one();
+}
+
+// Report of the last pass (None before the first pass, or while not in Router Mode)
+pub fn last_report() -> Option<DriftReport> {
+    LAST_REPORT.read().unwrap().clone()
+}
+
+// Planned rules missing from the kernel, and owned kernel rules that are not planned
+pub fn diff_rules(planned: &[Rule], kernel: &[Rule], is_managed: impl Fn(&Rule) -> bool) -> (Vec<Rule>, Vec<Rule>) {
+    let missing = planned.iter().filter(|rule| !kernel.contains(rule)).cloned().collect();
+    let extra = kernel.iter().filter(|rule| is_managed(rule) && !planned.contains(rule)).cloned().collect();
+    (missing, extra)
+}
+
+// Planned routes missing from (or different in) the kernel, and kernel routes in owned tables that are not planned
+// A route with a planned destination is replaced rather than deleted
+pub fn diff_routes(planned: &BTreeMap<u32, Vec<Route>>, kernel: &BTreeMap<u32, Vec<Route>>) -> (Vec<Route>, Vec<Route>) {
+    let mut missing = Vec::new();
+    let mut extra = Vec::new();
+    for (table_id, routes) in planned {
+        let current = kernel.get(table_id).map(Vec::as_slice).unwrap_or_default();
+        missing.extend(routes.iter().filter(|route| !current.contains(route)).cloned());
+        extra.extend(current.iter()
+            .filter(|route| !routes.iter().any(|planned| planned.destination == route.destination))
+            .cloned());
+    }
+    (missing, extra)
+}
+
+// Diff the routing plan against the kernel
+pub fn diff_routing(plan: &routing_pbr::RoutingPlan, is_managed: impl Fn(&Rule) -> bool) -> Result<RoutingDiff, ReconcileError> {
+    let backend = routing_backend();
+    let kernel_rules = backend.list_rules()?;
+
+    // Tables referenced by owned rules are owned too, so a table left behind by a removed peer is emptied
+    let mut planned_routes = plan.routes.clone();
+    for rule in kernel_rules.iter().filter(|rule| is_managed(rule) && rule.table != super::backend::MAIN_TABLE) {
+        planned_routes.entry(rule.table).or_default();
+    }
+    let mut kernel_routes = BTreeMap::new();
+    for table_id in planned_routes.keys() {
+        kernel_routes.insert(*table_id, backend.list_routes(*table_id)?);
+    }
+
+    let (add_rules, delete_rules) = diff_rules(&plan.rules, &kernel_rules, is_managed);
+    let (add_routes, delete_routes) = diff_routes(&planned_routes, &kernel_routes);
+    Ok(RoutingDiff { add_routes, add_rules, delete_rules, delete_routes })
+}
+
+// Apply a diff: missing routes and rules first, then remove the extra ones
+// Every change is attempted; failures are listed in the report instead of stopping the pass
+pub fn apply_routing(diff: &RoutingDiff, report: &mut DriftReport) {
+    let backend = routing_backend();
+    for route in &diff.add_routes {
+        report.missing_routes.push(route.to_string());
+        if let Err(e) = backend.replace_route(route) {
+            report.errors.push(format!("Failed to install route {}: {}", route, e));
+        }
+    }
+    for rule in &diff.add_rules {
+        report.missing_rules.push(rule.to_string());
+        if let Err(e) = backend.add_rule(rule) {
+            report.errors.push(format!("Failed to install rule {}: {}", rule, e));
+        }
+    }
+    for rule in &diff.delete_rules {
+        report.extra_rules.push(rule.to_string());
+        match backend.delete_rule(rule) {
+            Ok(()) | Err(BackendError::NotFound(_)) => {}
+            Err(e) => report.errors.push(format!("Failed to delete rule {}: {}", rule, e)),
+        }
+    }
+    for route in &diff.delete_routes {
+        report.extra_routes.push(route.to_string());
+        match backend.delete_route(route) {
+            Ok(()) | Err(BackendError::NotFound(_)) => {}
+            Err(e) => report.errors.push(format!("Failed to delete route {}: {}", route, e)),
+        }
+    }
+}
+
+// Re-add missing iptables rules, then delete stale ones wg-quickrs added
+fn reconcile_firewall(lan_cidr: &str, network: &Network, plan: &routing_pbr::RoutingPlan, report: &mut DriftReport) {
+    let rules = match crate::firewall::desired_firewall_rules(lan_cidr, network, plan.uplink.as_deref(), &plan.client_exits) {
+        Ok(rules) => rules,
+        Err(e) => {
+            report.errors.push(format!("Failed to check firewall rules: {}", e));
+            return;
+        }
+    };
+    for rule in rules.iter().filter(|rule| !rule.is_present()) {
+        report.missing_firewall_rules.push(rule.to_string());
+        match rule.add() {
+            // An untagged copy from an earlier version would otherwise stay forever
+            Ok(()) => {
+                rule.remove_untagged();
+            }
+            Err(e) => report.errors.push(format!("Failed to add firewall rule {}: {}", rule, e)),
+        }
+    }
+    let stale = match crate::firewall::stale_firewall_rules(&rules) {
+        Ok(stale) => stale,
+        Err(e) => {
+            report.errors.push(format!("Failed to list firewall rules: {}", e));
+            return;
+        }
+    };
+    for rule in stale {
+        report.extra_firewall_rules.push(rule.to_string());
+        if !rule.delete() {
+            report.errors.push(format!("Failed to delete firewall rule {}", rule));
+        }
+    }
+}
+
+// Converge the kernel's rules and routes to the plan (the routing half of a pass)
+// None when there is nothing to converge: not in Router Mode, or the WireGuard interface is down
+// (restore_peer_routes_after_interface_up takes over when it comes back)
+pub fn reconcile_routing(network: &Network, report: &mut DriftReport) -> Result<Option<routing_pbr::RoutingPlan>, ReconcileError> {
+    // No setter changes the mode state or the kernel between the plan and the diff being applied
+    let _routing = routing_pbr::routing_lock();
+    let Some(state) = load_mode_state()? else {
+        return Ok(None);
+    };
+    if state.last_mode != SystemMode::Router || !routing_backend().interface_exists(&network.name)? {
+        return Ok(None);
+    }
+
+    let lan_interface = routing_pbr::find_lan_interface()?;
+    let lan_nets = routing_pbr::lan_nets(&state);
+    let plan = routing_pbr::plan_routing(&state, network, &lan_interface);
+    let is_managed = |rule: &Rule| routing_pbr::is_managed_rule(rule, &lan_nets, &lan_interface, &network.name);
+    let diff = diff_routing(&plan, is_managed)?;
+    apply_routing(&diff, report);
+    Ok(Some(plan))
+}
+
+// One full pass: routing, then the firewall; stores and returns the report
+pub fn reconcile(network: &Network) -> Result<Option<DriftReport>, ReconcileError> {
+    let mut report = DriftReport {
+        checked_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        ..Default::default()
+    };
+    // The firewall is checked against the same state as the routing
+    let _routing = routing_pbr::routing_lock();
+    let Some(plan) = reconcile_routing(network, &mut report)? else {
+        *LAST_REPORT.write().unwrap() = None;
+        return Ok(None);
+    };
+    let lan_cidr = load_mode_state()?.and_then(|state| state.lan_cidr).unwrap_or_default();
+    reconcile_firewall(&lan_cidr, network, &plan, &mut report);
+
+    let previous = LAST_REPORT.write().unwrap().replace(report.clone());
+    if report.has_drift() {
+        log::warn!("Reconciler corrected {} drifted item(s): missing rules {:?}, extra rules {:?}, missing routes {:?}, \
+            extra routes {:?}, missing firewall rules {:?}, extra firewall rules {:?}",
+            report.drift_count(), report.missing_rules, report.extra_rules, report.missing_routes,
+            report.extra_routes, report.missing_firewall_rules, report.extra_firewall_rules);
+        // Journal it once, not on every pass that runs into the same (uncorrectable) drift
+        if !previous.is_some_and(|previous| previous.same_drift(&report)) {
+            events::record(EventKind::Drift, Initiator::Monitor, EventDetails {
+                reason: Some(drift_summary(&report)),
+                ..Default::default()
+            });
+        }
+    }
+    for error in &report.errors {
+        log::warn!("Reconciler: {}", error);
+    }
+    Ok(Some(report))
+}
+
+// "2 missing rules, 1 extra route" for the journal
+fn drift_summary(report: &DriftReport) -> String {
+    [
+        (report.missing_rules.len(), "missing rule"),
+        (report.extra_rules.len(), "extra rule"),
+        (report.missing_routes.len(), "missing route"),
+        (report.extra_routes.len(), "extra route"),
+        (report.missing_firewall_rules.len(), "missing firewall rule"),
+        (report.extra_firewall_rules.len(), "extra firewall rule"),
+    ].iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, what)| format!("{} {}{}", count, what, if *count == 1 { "" } else { "s" }))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Background task: a pass after every change and every RECONCILE_INTERVAL_SECS
+pub async fn start_reconciler() -> std::io::Result<()> {
+    loop {
+        let _ = timeout(Duration::from_secs(RECONCILE_INTERVAL_SECS), TRIGGER.notified()).await;
+        sleep(RECONCILE_DEBOUNCE).await;
+
+        let pass = tokio::task::spawn_blocking(|| -> Result<_, ReconcileError> {
+            let config = crate::conf::util::get_config()
+                .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?;
+            reconcile(&config.network)
+        });
+        match pass.await {
+            Ok(Err(e)) => log::warn!("Reconciliation failed: {}", e),
+            Err(e) => log::warn!("Reconciler task failed: {}", e),
+            Ok(Ok(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(priority: u32, table: u32) -> Rule {
+        Rule { priority, table, ..Default::default() }
+    }
+
+    fn route(table: u32, destination: &str, device: &str) -> Route {
+        Route { table, destination: destination.parse().unwrap(), device: device.to_string(), gateway: None }
+    }
+
+    #[test]
+    fn test_diff_rules_only_deletes_managed_rules() {
+        let planned = [rule(10000, 1000), rule(20000, 1000)];
+        let kernel = [rule(0, 255), rule(10000, 1000), rule(20001, 1001), rule(30000, 50)];
+        let (missing, extra) = diff_rules(&planned, &kernel, |rule| (1000..=9999).contains(&rule.table));
+        assert_eq!(missing, [rule(20000, 1000)]);
+        assert_eq!(extra, [rule(20001, 1001)]);
+    }
+
+    #[test]
+    fn test_diff_routes_replaces_changed_and_deletes_unplanned() {
+        let planned = BTreeMap::from([
+            (1000, vec![route(1000, "0.0.0.0/0", "wg0"), route(1000, "10.1.0.0/16", "wg0")]),
+            (1001, vec![]),
+        ]);
+        let kernel = BTreeMap::from([
+            (1000, vec![route(1000, "0.0.0.0/0", "eth0"), route(1000, "10.9.0.0/16", "wg0")]),
+            (1001, vec![route(1001, "10.2.0.0/16", "wg0")]),
+        ]);
+        let (missing, extra) = diff_routes(&planned, &kernel);
+        // The default route on the wrong device is replaced, not deleted
+        assert_eq!(missing, [route(1000, "0.0.0.0/0", "wg0"), route(1000, "10.1.0.0/16", "wg0")]);
+        assert_eq!(extra, [route(1000, "10.9.0.0/16", "wg0"), route(1001, "10.2.0.0/16", "wg0")]);
+    }
+
+    #[test]
+    fn test_reconcile_firewall_deletes_only_stale_tagged_rules() {
+        use crate::mode::simulated::SimulatedKernel;
+        use std::sync::Arc;
+        let _guard = crate::mode::simulated::test_lock();
+        let kernel = Arc::new(SimulatedKernel::new()
+            .with_interface("eth0", "192.168.1.10/24")
+            .with_interface("wg0", "10.0.34.1/24")
+            // Left behind by a previous LAN CIDR
+            .with_firewall_rule("nat", "POSTROUTING", "-s 10.9.0.0/24 -o wg0 -m comment --comment wg-quickrs -j MASQUERADE")
+            // An admin's own NAT
+            .with_firewall_rule("nat", "POSTROUTING", "-s 192.168.1.0/24 -o eth1 -j MASQUERADE")
+            // Added by a version before rules were tagged
+            .with_firewall_rule("nat", "POSTROUTING", "-s 192.168.1.0/24 -o wg0 -j MASQUERADE"));
+        crate::mode::backend::set_routing_backend(kernel.clone());
+        crate::firewall::set_firewall_backend(kernel.clone());
+        let network = Network {
+            name: "wg0".to_string(),
+            subnet: "10.0.34.0/24".parse().unwrap(),
+            this_peer: Default::default(),
+            peers: Default::default(),
+            connections: Default::default(),
+            defaults: Default::default(),
+            reservations: Default::default(),
+            updated_at: Default::default(),
+        };
+
+        let mut report = DriftReport::default();
+        reconcile_firewall("192.168.1.0/24", &network, &Default::default(), &mut report);
+        assert_eq!(report.extra_firewall_rules, ["-t nat POSTROUTING -s 10.9.0.0/24 -o wg0 -j MASQUERADE"]);
+        assert!(report.missing_firewall_rules.contains(&"-t nat POSTROUTING -s 192.168.1.0/24 -o wg0 -j MASQUERADE".to_string()));
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(kernel.firewall_rules("nat", "POSTROUTING"), [
+            "-s 192.168.1.0/24 -o eth1 -j MASQUERADE",
+            "-s 192.168.1.0/24 -o wg0 -m comment --comment wg-quickrs -j MASQUERADE",
+            "-s 10.0.34.0/24 -o wg0 -m comment --comment wg-quickrs -j MASQUERADE",
+        ]);
+
+        // Converged: the next pass finds nothing
+        let mut report = DriftReport::default();
+        reconcile_firewall("192.168.1.0/24", &network, &Default::default(), &mut report);
+        assert!(!report.has_drift(), "{:?}", report);
+    }
+
+    #[test]
+    fn test_drift_summary() {
+        let report = DriftReport {
+            missing_rules: vec!["a".to_string(), "b".to_string()],
+            extra_routes: vec!["c".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(drift_summary(&report), "2 missing rules, 1 extra route");
+        assert!(report.same_drift(&DriftReport { checked_at: 5, errors: vec!["x".to_string()], ..report.clone() }));
+    }
+}
@@ -6,21 +6,30 @@
 // - STEP 7: Policy-based routing for overlapping destinations
 
 use crate::helpers::{shell_cmd, parse_lan_cidrs};
-use super::persist::{load_mode_state, save_mode_state};
+use super::backend::{routing_backend, BackendError, Route, Rule, MAIN_TABLE};
+use super::change_plan::ChangePlan;
+use super::persist::{load_mode_state, save_mode_state, FailbackTimer, GatewayGroup};
 use super::mode::SystemMode;
+use super::events::{self, EventDetails, EventKind, EventMetrics, Initiator};
+use crate::web::stream::StreamMessage;
 use thiserror::Error;
 use uuid::Uuid;
-use wg_quickrs_lib::types::network::Network;
-use wg_quickrs_lib::helpers::wg_public_key_from_private_key;
+use wg_quickrs_lib::types::network::{Network, Peer};
+use wg_quickrs_lib::types::config::{AgentRouterFailover, Liveness};
+use wg_quickrs_lib::types::network::EndpointAddress;
+use wg_quickrs_lib::helpers::{get_peer_wg_config, wg_public_key_from_private_key};
 use std::str::FromStr;
+use ipnet::Ipv4Net;
 use once_cell::sync::Lazy;
 use std::sync::{Arc, Mutex, RwLock};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::collections::VecDeque;
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 use tokio::time::{interval, sleep};
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum PolicyRoutingError {
     #[error("Table ID error: {0}")]
     TableIdError(String),
@@ -30,6 +39,16 @@ pub enum PolicyRoutingError {
     RouteInstallationError(String),
     #[error("Persistence error: {0}")]
     PersistenceError(String),
+    #[error("Prefix error: {0}")]
+    PrefixError(String),
+    #[error("Load balancing error: {0}")]
+    LoadBalanceError(String),
+    #[error("Client exit error: {0}")]
+    ClientExitError(String),
+    #[error("Domain route error: {0}")]
+    DomainRouteError(String),
+    #[error("Gateway group error: {0}")]
+    GatewayGroupError(String),
 }
 
 // Cached LAN interface (lazy initialization)
@@ -39,12 +58,68 @@ static LAN_INTERFACE_CACHE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::ne
 static EXIT_NODE_HEALTH_CACHE: Lazy<Arc<RwLock<HashMap<Uuid, ExitNodeHealth>>>> = 
     Lazy::new(|| Arc::new(RwLock::new(HashMap::new())));
 
-// Health monitoring interval (ping every 1 second, like OPNsense dpinger)
-// Peer is marked offline after CONSECUTIVE_FAILURES_THRESHOLD (3) consecutive failed pings
-const HEALTH_MONITOR_INTERVAL_SECS: u64 = 1;
+// Failover tuning from agent.router.failover in conf.yml (refreshed by the health monitor)
+// Defaults: ping every second like OPNsense dpinger, offline after 3 consecutive failed pings,
+// 60 samples of history for loss and jitter, 60s fail-back stability, 30s startup grace period
+static FAILOVER_TUNING: Lazy<RwLock<AgentRouterFailover>> =
+    Lazy::new(|| RwLock::new(AgentRouterFailover::default()));
 
-// Track ping history for loss and jitter calculation (60 samples = 60 seconds like OPNsense)
-const PING_HISTORY_SIZE: usize = 60;
+// Failover tuning in effect for one exit node (global settings with its overrides applied)
+fn failover_tuning(peer_id: &Uuid) -> AgentRouterFailover {
+    FAILOVER_TUNING.read().unwrap().for_exit_node(peer_id)
+}
+
+// One lock for every change to routing state: the setters below (exit node, prefixes, LAN access, domain routes,
+// load balancing, ...) and the reconciler take it, so a reconciler pass never diffs the kernel against a change
+// that is still being applied. Re-entrant, since setters call each other (a gateway group switches the exit node)
+struct RoutingLock {
+    owner: Mutex<(Option<std::thread::ThreadId>, usize)>, // Holding thread, and how often it took the lock
+    released: std::sync::Condvar,
+}
+
+static ROUTING_LOCK: Lazy<RoutingLock> =
+    Lazy::new(|| RoutingLock { owner: Mutex::new((None, 0)), released: std::sync::Condvar::new() });
+
+// Held routing lock, released on drop (on the thread that took it)
+pub struct RoutingGuard(std::marker::PhantomData<*const ()>);
+
+pub fn routing_lock() -> RoutingGuard {
+    let this_thread = std::thread::current().id();
+    let mut owner = ROUTING_LOCK.owner.lock().unwrap_or_else(|e| e.into_inner());
+    while owner.0.is_some_and(|thread| thread != this_thread) {
+        owner = ROUTING_LOCK.released.wait(owner).unwrap_or_else(|e| e.into_inner());
+    }
+    *owner = (Some(this_thread), owner.1 + 1);
+    RoutingGuard(std::marker::PhantomData)
+}
+
+impl Drop for RoutingGuard {
+    fn drop(&mut self) {
+        let mut owner = ROUTING_LOCK.owner.lock().unwrap_or_else(|e| e.into_inner());
+        owner.1 -= 1;
+        if owner.1 == 0 {
+            owner.0 = None;
+            ROUTING_LOCK.released.notify_one();
+        }
+    }
+}
+
+// When each exit node was last probed (for per-node health monitor intervals)
+static LAST_PROBE: Lazy<Mutex<HashMap<Uuid, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Whether an exit node's probe interval has elapsed; records the probe when it has.
+// `tolerance` absorbs ticker jitter so a 2s interval on a 1s tick probes every other tick.
+fn probe_due(peer_id: Uuid, interval_secs: u64, tolerance: Duration) -> bool {
+    let now = Instant::now();
+    let mut last_probe = LAST_PROBE.lock().unwrap();
+    let due = last_probe.get(&peer_id).is_none_or(|last| {
+        now.duration_since(*last) + tolerance >= Duration::from_secs(interval_secs)
+    });
+    if due {
+        last_probe.insert(peer_id, now);
+    }
+    due
+}
 
 // Ping history entry
 #[derive(Debug, Clone)]
@@ -55,7 +130,8 @@ struct PingResult {
 }
 
 // Per-peer ping history (for loss and jitter calculation)
-static PING_HISTORY: Lazy<Arc<RwLock<HashMap<Uuid, VecDeque<PingResult>>>>> = 
+type PingHistoryMap = HashMap<Uuid, VecDeque<PingResult>>;
+static PING_HISTORY: Lazy<Arc<RwLock<PingHistoryMap>>> = 
     Lazy::new(|| Arc::new(RwLock::new(HashMap::new())));
 
 // In-memory "Up Since" tracking (resets on restart, not persisted)
@@ -64,26 +140,10 @@ static SESSION_UP_SINCE: Lazy<Arc<RwLock<HashMap<Uuid, u64>>>> =
     Lazy::new(|| Arc::new(RwLock::new(HashMap::new())));
 
 // Consecutive ping failures per peer (for offline detection)
-// Peer is marked offline only after CONSECUTIVE_FAILURES_THRESHOLD failures
+// Peer is marked offline only after `consecutive_failures` failures
 static CONSECUTIVE_FAILURES: Lazy<Arc<RwLock<HashMap<Uuid, u32>>>> = 
     Lazy::new(|| Arc::new(RwLock::new(HashMap::new())));
 
-// Number of consecutive ping failures required to mark peer as offline
-const CONSECUTIVE_FAILURES_THRESHOLD: u32 = 3;
-
-// Fail-back delay: seconds the primary must be online before switching back
-const FAILBACK_STABILITY_SECS: u64 = 60;
-
-// Startup grace period: seconds after startup before Smart Gateway takes failover action
-// This prevents flip-flopping during initial handshake establishment
-const STARTUP_GRACE_PERIOD_SECS: u64 = 30;
-
-// Track when primary exit node came back online (for fail-back timing)
-// This is synced with persisted state on startup and save
-// Key: peer_id, Value: timestamp when peer came back online
-static PRIMARY_ONLINE_SINCE: Lazy<Arc<RwLock<Option<(Uuid, u64)>>>> = 
-    Lazy::new(|| Arc::new(RwLock::new(None)));
-
 // Track when the service started (for startup grace period)
 static SERVICE_START_TIME: Lazy<Arc<RwLock<u64>>> = Lazy::new(|| {
     let now = std::time::SystemTime::now()
@@ -93,7 +153,7 @@ static SERVICE_START_TIME: Lazy<Arc<RwLock<u64>>> = Lazy::new(|| {
     Arc::new(RwLock::new(now))
 });
 
-// Check if we're still in the startup grace period
+// Check if we're still in the startup grace period (no failover action before handshakes settle)
 fn is_in_startup_grace_period() -> bool {
     let start_time = *SERVICE_START_TIME.read().unwrap();
     let now = std::time::SystemTime::now()
@@ -101,34 +161,7 @@ fn is_in_startup_grace_period() -> bool {
         .map(|d| d.as_secs())
         .unwrap_or(0);
     let elapsed = now.saturating_sub(start_time);
-    elapsed < STARTUP_GRACE_PERIOD_SECS
-}
-
-// Persist the PRIMARY_ONLINE_SINCE value to state file
-fn persist_primary_online_since(peer_id: Option<Uuid>, timestamp: Option<u64>) {
-    if let Ok(Some(mut state)) = load_mode_state() {
-        state.primary_online_since = timestamp;
-        if let Err(e) = save_mode_state(&state) {
-            log::warn!("Failed to persist primary_online_since: {}", e);
-        }
-    }
-    
-    // Also update the in-memory static
-    let mut tracker = PRIMARY_ONLINE_SINCE.write().unwrap();
-    *tracker = peer_id.zip(timestamp);
-}
-
-// Restore PRIMARY_ONLINE_SINCE from persisted state on startup
-pub fn restore_primary_online_since_from_state() {
-    if let Ok(Some(state)) = load_mode_state() {
-        if let (Some(primary_str), Some(timestamp)) = (&state.primary_exit_node, state.primary_online_since) {
-            if let Ok(peer_id) = Uuid::parse_str(primary_str) {
-                let mut tracker = PRIMARY_ONLINE_SINCE.write().unwrap();
-                *tracker = Some((peer_id, timestamp));
-                log::info!("Restored fail-back timer for primary {} (online since {})", peer_id, timestamp);
-            }
-        }
-    }
+    elapsed < FAILOVER_TUNING.read().unwrap().startup_grace_period_secs
 }
 
 // Calculate packet loss and jitter from ping history (like OPNsense dpinger)
@@ -175,97 +208,139 @@ fn calculate_loss_and_jitter(history: &VecDeque<PingResult>) -> (Option<f64>, Op
     (packet_loss_percent, jitter_ms)
 }
 
-// Parsed IP rule structure for efficient rule management
-#[derive(Debug, Clone)]
-struct ParsedRule {
-    priority: u32,
-    table_id: Option<u32>,
-    from: Option<String>,
-    to: Option<String>,
-    iif: Option<String>,
+// Average latency of the successful pings in the history (the dpinger "delay")
+fn average_latency(history: &VecDeque<PingResult>) -> Option<u64> {
+    let latencies: Vec<u64> = history.iter().filter_map(|r| r.latency_ms).collect();
+    (!latencies.is_empty()).then(|| latencies.iter().sum::<u64>() / latencies.len() as u64)
 }
 
-// Parse ip rule show output into structured rules
-fn parse_ip_rules(output: &str) -> Vec<ParsedRule> {
-    let mut rules = Vec::new();
-    
-    for line in output.lines() {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.is_empty() {
-            continue;
+// When each exit node started exceeding an SLA threshold (cleared once it is back within them)
+static SLA_VIOLATION_SINCE: Lazy<RwLock<HashMap<Uuid, u64>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// First SLA threshold an exit node's ping statistics exceed, as a human-readable reason
+fn sla_violation(
+    tuning: &AgentRouterFailover,
+    latency_ms: Option<u64>,
+    packet_loss_percent: Option<f64>,
+    jitter_ms: Option<u64>,
+) -> Option<String> {
+    if let (Some(loss), Some(max)) = (packet_loss_percent, tuning.max_packet_loss_percent)
+        && loss > max {
+            return Some(format!("packet loss {:.0}% above {}%", loss, max));
         }
-        
-        // Extract priority (first part, ends with ':')
-        let priority_str = parts[0].trim_end_matches(':');
-        let priority = match priority_str.parse::<u32>() {
-            Ok(p) => p,
-            Err(_) => continue,
-        };
-        
-        let mut rule = ParsedRule {
-            priority,
-            table_id: None,
-            from: None,
-            to: None,
-            iif: None,
-        };
-        
-        // Parse rule components
-        let mut i = 1;
-        while i < parts.len() {
-            match parts[i] {
-                "from" => {
-                    if i + 1 < parts.len() {
-                        rule.from = Some(parts[i + 1].to_string());
-                        i += 2;
-                    } else {
-                        i += 1;
-                    }
-                }
-                "to" => {
-                    if i + 1 < parts.len() {
-                        rule.to = Some(parts[i + 1].to_string());
-                        i += 2;
-                    } else {
-                        i += 1;
-                    }
-                }
-                "iif" => {
-                    if i + 1 < parts.len() {
-                        rule.iif = Some(parts[i + 1].to_string());
-                        i += 2;
-                    } else {
-                        i += 1;
-                    }
-                }
-                "lookup" => {
-                    if i + 1 < parts.len() {
-                        if let Ok(table_id) = parts[i + 1].parse::<u32>() {
-                            rule.table_id = Some(table_id);
-                        }
-                        i += 2;
-                    } else {
-                        i += 1;
-                    }
-                }
-                _ => i += 1,
+    if let (Some(latency), Some(max)) = (latency_ms, tuning.max_latency_ms)
+        && latency > max {
+            return Some(format!("latency {}ms above {}ms", latency, max));
+        }
+    if let (Some(jitter), Some(max)) = (jitter_ms, tuning.max_jitter_ms)
+        && jitter > max {
+            return Some(format!("jitter {}ms above {}ms", jitter, max));
+        }
+    None
+}
+
+// Received byte counter of each exit node and when it last grew (for handshake liveness)
+static RX_PROGRESS: Lazy<RwLock<HashMap<Uuid, (u64, u64)>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Handshake liveness: the last handshake and the last growth of the rx counter are both at most
+// max_age seconds old. A peer seen for the first time counts its last handshake as progress,
+// since the handshake itself was received; a counter that went backwards (interface recreated) is progress too.
+fn handshake_alive(peer_id: Uuid, last_handshake: Option<u64>, transfer_rx: u64, max_age: u64, now: u64) -> bool {
+    let Some(last_handshake) = last_handshake.filter(|ts| *ts > 0) else {
+        return false;
+    };
+    let rx_since = {
+        let mut progress = RX_PROGRESS.write().unwrap();
+        let entry = progress.entry(peer_id).or_insert((transfer_rx, last_handshake));
+        if transfer_rx != entry.0 {
+            *entry = (transfer_rx, now);
+        }
+        entry.1
+    };
+    now.saturating_sub(last_handshake) <= max_age && now.saturating_sub(rx_since) <= max_age
+}
+
+// Snapshot the kernel's IPv4 policy rules (dump once, reuse for all matching in an operation)
+fn get_ip_rules() -> Result<Vec<Rule>, PolicyRoutingError> {
+    routing_backend().list_rules()
+        .map_err(|e| PolicyRoutingError::IpRuleError(format!("Failed to get ip rules: {}", e)))
+}
+
+// Delete every rule from a snapshot that matches the predicate
+// Rules that are already gone (deleted earlier in the same operation) are not an error
+fn delete_matching_rules(rules: &[Rule], predicate: impl Fn(&Rule) -> bool) -> usize {
+    let backend = routing_backend();
+    let mut removed = 0;
+    for rule in rules.iter().filter(|r| predicate(r)) {
+        match backend.delete_rule(rule) {
+            Ok(()) => {
+                log::debug!("Deleted rule {}", rule);
+                removed += 1;
             }
+            Err(BackendError::NotFound(_)) => {}
+            Err(e) => log::warn!("Failed to delete rule {}: {}", rule, e),
         }
-        
-        rules.push(rule);
     }
-    
-    rules
+    removed
+}
+
+// Peer routing tables live in 1000-9999 (see create_peer_routing_table)
+fn is_peer_table(table: u32) -> bool {
+    (1000..=9999).contains(&table)
+}
+
+// Parse the comma-separated LAN CIDR string into networks, skipping unparsable entries
+fn parse_lan_nets(lan_cidr_str: &str) -> Vec<Ipv4Net> {
+    parse_lan_cidrs(lan_cidr_str)
+        .iter()
+        .filter_map(|cidr| match Ipv4Net::from_str(cidr) {
+            Ok(net) => Some(net.trunc()),
+            Err(e) => {
+                log::warn!("Ignoring invalid LAN CIDR {}: {}", cidr, e);
+                None
+            }
+        })
+        .collect()
 }
 
-// Get and cache ip rules (parse once, reuse)
-fn get_ip_rules_cached() -> Result<Vec<ParsedRule>, PolicyRoutingError> {
-    let output = shell_cmd(&["ip", "rule", "show"])
-        .map_err(|e| PolicyRoutingError::IpRuleError(format!("Failed to get ip rules: {}", e)))?;
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    Ok(parse_ip_rules(&output_str))
+// Remove LAN exception rules for the given LAN networks:
+// the LAN interface exception and the WireGuard peer (per-peer and legacy subnet-wide) exceptions
+fn remove_lan_exception_rules(rules: &[Rule], lan_nets: &[Ipv4Net], lan_interface: Option<&str>, network: Option<&Network>) {
+    for lan_net in lan_nets {
+        if let Some(iface) = lan_interface {
+            delete_matching_rules(rules, |r| {
+                r.table == MAIN_TABLE && r.to == Some(*lan_net) && r.iif.as_deref() == Some(iface)
+            });
+            log::info!("Removed LAN exception rule for {}", lan_net);
+        }
+
+        if let Some(network_ref) = network {
+            let wg_interface = network_ref.name.as_str();
+            let wg_subnet = network_ref.subnet.trunc();
+            let peer_nets: Vec<Ipv4Net> = network_ref.peers.iter()
+                .filter(|(pid, _)| **pid != network_ref.this_peer)
+                .map(|(_, p)| Ipv4Net::from(p.address))
+                .collect();
+            delete_matching_rules(rules, |r| {
+                r.table == MAIN_TABLE
+                    && r.to == Some(*lan_net)
+                    && r.iif.as_deref() == Some(wg_interface)
+                    && r.from.is_some_and(|from| from == wg_subnet || peer_nets.contains(&from))
+            });
+            log::info!("Removed WireGuard peer LAN exception rules for {}", lan_net);
+        }
+    }
 }
 
+// Lowest table ID in range 1000-9999 not assigned to a peer yet
+fn next_free_table_id(state: &super::persist::ModeState) -> Result<u32, PolicyRoutingError> {
+    let used_table_ids: std::collections::HashSet<u32> = state.peer_table_ids.values().cloned().collect();
+    log::debug!("[create_peer_routing_table] Currently used table IDs: {:?}", used_table_ids);
+    (1000..=9999u32).find(|table_id| !used_table_ids.contains(table_id)).ok_or_else(|| {
+        log::error!("[create_peer_routing_table] No available table IDs in range 1000-9999");
+        PolicyRoutingError::TableIdError("No available table IDs in range 1000-9999".to_string())
+    })
+}
 
 // Create routing table for a peer
 // Assigns a unique table ID in range 1000-9999 and persists it
@@ -298,23 +373,7 @@ pub fn create_peer_routing_table(peer_id: &Uuid) -> Result<u32, PolicyRoutingErr
     
     // Find next available table ID in range 1000-9999
     log::debug!("[create_peer_routing_table] Finding available table ID...");
-    let mut table_id = 1000u32;
-    let used_table_ids: std::collections::HashSet<u32> = state.peer_table_ids.values().cloned().collect();
-    log::debug!("[create_peer_routing_table] Currently used table IDs: {:?}", used_table_ids);
-    
-    while table_id <= 9999 {
-        if !used_table_ids.contains(&table_id) {
-            break;
-        }
-        table_id += 1;
-    }
-    
-    if table_id > 9999 {
-        log::error!("[create_peer_routing_table] No available table IDs in range 1000-9999");
-        return Err(PolicyRoutingError::TableIdError(
-            "No available table IDs in range 1000-9999".to_string()
-        ));
-    }
+    let table_id = next_free_table_id(&state)?;
     
     log::info!("[create_peer_routing_table] Selected table ID {} for peer {}", table_id, peer_id_str);
     
@@ -352,9 +411,9 @@ fn validate_route(route: &str) -> bool {
         // Check if the network address matches the CIDR (e.g., 10.100.105.1/24 is invalid, should be 10.100.105.0/24)
         let network_addr = net.network();
         let addr_parts: Vec<&str> = route.split('/').collect();
-        if addr_parts.len() == 2 {
-            if let Ok(prefix_len) = addr_parts[1].parse::<u8>() {
-                if prefix_len <= 32 {
+        if addr_parts.len() == 2
+            && let Ok(prefix_len) = addr_parts[1].parse::<u8>()
+                && prefix_len <= 32 {
                     // Check if the address is actually the network address
                     // For /24, the last octet should be 0
                     // For /32, any address is valid
@@ -365,8 +424,6 @@ fn validate_route(route: &str) -> bool {
                     let expected_network = ipnet::Ipv4Net::new(network_addr, prefix_len).unwrap();
                     return expected_network.to_string() == route;
                 }
-            }
-        }
     }
     
     false
@@ -411,45 +468,34 @@ pub fn install_peer_routes(
     log::info!("Installing {} valid routes into table {} for peer {} (filtered {} invalid routes)", 
         valid_count, table_id, peer_id_str, invalid_count);
     
-    // Pre-allocate table_id string to avoid repeated allocations
-    let table_id_str = table_id.to_string();
+    let backend = routing_backend();
     
     for route in &valid_routes {
         // Handle default route specially
         let route_str = if *route == "default" { "0.0.0.0/0" } else { route };
+        let destination = match Ipv4Net::from_str(route_str) {
+            Ok(net) => net,
+            Err(e) => {
+                log::warn!("Failed to parse route {} for peer {}: {} (skipping)", route_str, peer_id_str, e);
+                continue;
+            }
+        };
         
-        // Install route into peer's table: ip route add <cidr> dev <interface> table <table_id>
-        let cmd = &[
-            "ip", "route", "add",
-            route_str,
-            "dev", wg_interface,
-            "table", &table_id_str,
-        ];
+        // Install (or atomically replace) route in peer's table: <cidr> dev <interface> table <table_id>
+        let spec = Route {
+            table: table_id,
+            destination,
+            device: wg_interface.to_string(),
+            gateway: None,
+        };
         
-        match shell_cmd(cmd) {
-            Ok(_) => {
+        match backend.replace_route(&spec) {
+            Ok(()) => {
                 log::debug!("Installed route {} into table {} for peer {}", route_str, table_id, peer_id_str);
             }
             Err(e) => {
-                // If route already exists, try replace
-                let error_str = e.to_string();
-                if error_str.contains("File exists") || error_str.contains("RTNETLINK answers: File exists") {
-                    log::debug!("Route {} already exists in table {}, replacing...", route_str, table_id);
-                    let replace_cmd = &[
-                        "ip", "route", "replace",
-                        route_str,
-                        "dev", wg_interface,
-                        "table", &table_id_str,
-                    ];
-                    if let Err(replace_err) = shell_cmd(replace_cmd) {
-                        return Err(PolicyRoutingError::RouteInstallationError(
-                            format!("Failed to replace route {} in table {}: {}", route_str, table_id, replace_err)
-                        ));
-                    }
-                } else {
-                    log::warn!("Failed to install route {} into table {} for peer {}: {} (skipping)", route_str, table_id, peer_id_str, e);
-                    // Continue with other routes instead of failing completely
-                }
+                log::warn!("Failed to install route {} into table {} for peer {}: {} (skipping)", route_str, table_id, peer_id_str, e);
+                // Continue with other routes instead of failing completely
             }
         }
     }
@@ -488,55 +534,40 @@ pub fn install_pbr_rules_for_peer(
     log::info!("Installing PBR rules for {} specific routes from {} to table {} (peer {})", 
         specific_routes_count, lan_interface, table_id, peer_id_str);
     
-    // Pre-allocate strings to avoid repeated allocations
-    let table_id_str = table_id.to_string();
+    // Overlapping prefixes this peer is only a backup for stay with the active peer's table
+    let backup_prefixes = load_mode_state().ok().flatten()
+        .map(|state| backup_prefixes_for_peer(&state, &peer_id_str))
+        .unwrap_or_default();
+    
+    let backend = routing_backend();
     let base_priority = 10000 + (table_id % 1000);
     
     for route in specific_routes {
         // Specific routes: higher priority (10000+), checked first
         let priority = base_priority;
-        let priority_str = priority.to_string();
+        let destination = Ipv4Net::from_str(route)
+            .map_err(|e| PolicyRoutingError::IpRuleError(format!("Invalid route {} for PBR rule: {}", route, e)))?;
+        if backup_prefixes.contains(&destination.trunc().to_string()) {
+            log::debug!("Skipping PBR rule for {} (peer {} is a backup for this prefix)", route, peer_id_str);
+            continue;
+        }
         
         // Install ip rule: iif <lan_interface> to <route> lookup <table_id>
         // Use "iif" (input interface) instead of "from" (source IP)
-        let cmd = &[
-            "ip", "rule", "add",
-            "iif", lan_interface,
-            "to", route,
-            "lookup", &table_id_str,
-            "priority", &priority_str,
-        ];
+        // An identical existing rule counts as installed
+        let rule = Rule {
+            priority,
+            iif: Some(lan_interface.to_string()),
+            to: Some(destination.trunc()),
+            table: table_id,
+            ..Default::default()
+        };
         
-        match shell_cmd(cmd) {
-            Ok(_) => {
-                log::debug!("Installed PBR rule: from {} to {} -> table {} (priority {})", 
-                    lan_interface, route, table_id, priority);
-            }
-            Err(e) => {
-                let error_str = e.to_string();
-                // If rule already exists, try to replace it
-                if error_str.contains("File exists") || error_str.contains("RTNETLINK answers: File exists") {
-                    log::debug!("PBR rule already exists, replacing...");
-                    // Delete old rule first
-                    let _ = shell_cmd(&[
-                        "ip", "rule", "del",
-                        "iif", lan_interface,
-                        "to", route,
-                        "lookup", &table_id_str,
-                    ]);
-                    // Add new rule
-                    if let Err(replace_err) = shell_cmd(cmd) {
-                        return Err(PolicyRoutingError::IpRuleError(
-                            format!("Failed to replace PBR rule for {} -> {}: {}", route, table_id, replace_err)
-                        ));
-                    }
-                } else {
-                    return Err(PolicyRoutingError::IpRuleError(
-                        format!("Failed to install PBR rule for {} -> {}: {}", route, table_id, e)
-                    ));
-                }
-            }
-        }
+        backend.add_rule(&rule).map_err(|e| PolicyRoutingError::IpRuleError(
+            format!("Failed to install PBR rule for {} -> {}: {}", route, table_id, e)
+        ))?;
+        log::debug!("Installed PBR rule: from {} to {} -> table {} (priority {})", 
+            lan_interface, route, table_id, priority);
     }
     
     log::info!("Successfully installed {} PBR rules for peer {} (skipped default route - handled by exit node)", 
@@ -553,25 +584,9 @@ pub fn remove_pbr_rules_for_peer(
     
     log::info!("Removing PBR rules for table {} (peer {})", table_id, peer_id_str);
     
-    // Get parsed rules (cached)
-    let rules = get_ip_rules_cached()?;
-    let mut removed_count = 0;
-    let _priority_str = table_id.to_string();
-    
-    for rule in &rules {
-        // Check if this rule references our table and is not an exit node rule
-        if rule.table_id == Some(table_id) && rule.priority < 20000 {
-            // Rule exists (we just parsed it), delete it
-            let priority_str = rule.priority.to_string();
-            let del_cmd = &["ip", "rule", "del", "priority", &priority_str];
-            if let Err(e) = shell_cmd(del_cmd) {
-                log::warn!("Failed to delete PBR rule with priority {}: {}", rule.priority, e);
-            } else {
-                log::debug!("Deleted PBR rule with priority {} for table {}", rule.priority, table_id);
-                removed_count += 1;
-            }
-        }
-    }
+    // Delete every rule that references our table and is not an exit node rule
+    let rules = get_ip_rules()?;
+    let removed_count = delete_matching_rules(&rules, |rule| rule.table == table_id && rule.priority < 20000 && !is_client_exit_rule(rule));
     
     if removed_count > 0 {
         log::info!("Removed {} PBR rules for peer {}", removed_count, peer_id_str);
@@ -624,9 +639,52 @@ pub fn set_exit_node(peer_id: &Uuid, network: Option<&Network>) -> Result<(), Po
     }
 }
 
+// Plan of set_exit_node: the changes it would make, without making them (see change_plan.rs)
+pub fn plan_set_exit_node(peer_id: &Uuid, network: &Network) -> Result<ChangePlan, PolicyRoutingError> {
+    let peer_id_str = peer_id.to_string();
+    let current = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?;
+    // Without a mode state set_exit_node starts from a fresh one if the config says Router Mode
+    let mut target = match &current {
+        Some(state) => state.clone(),
+        None => {
+            let config = crate::conf::util::get_config()
+                .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?;
+            if config.agent.router.mode != "router" {
+                return Err(PolicyRoutingError::PersistenceError(
+                    "No mode state found - enable Router Mode first".to_string()
+                ));
+            }
+            let lan_cidr = config.agent.router.lan_cidr.unwrap_or_else(|| "192.168.1.0/24".to_string());
+            super::persist::ModeState::new(SystemMode::Router, Some(lan_cidr))
+        }
+    };
+
+    if *peer_id != LOCAL_BREAKOUT_EXIT_ID && !target.peer_table_ids.contains_key(&peer_id_str) {
+        let table_id = next_free_table_id(&target)?;
+        target.peer_table_ids.insert(peer_id_str.clone(), table_id);
+    }
+    let backup_peer_ids = get_peers_with_default_route(network).iter()
+        .filter(|other| *other != peer_id)
+        .map(Uuid::to_string)
+        .collect();
+    target.prefix_active_backup.insert("0.0.0.0/0".to_string(), super::persist::PrefixState {
+        active_peer_id: peer_id_str.clone(),
+        backup_peer_ids,
+        ..Default::default()
+    });
+
+    let mut plan = ChangePlan::new(format!("set_exit_node {}", peer_id_str));
+    plan.transition(current.as_ref(), Some(&target), network)
+        .map_err(|e| PolicyRoutingError::IpRuleError(format!("Failed to plan the change: {}", e)))?;
+    Ok(plan)
+}
+
 // Internal implementation that does the actual work
 fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
     let peer_id_str = peer_id.to_string();
+    let is_local_breakout = *peer_id == LOCAL_BREAKOUT_EXIT_ID;
     
     // Load current state ONCE at the beginning - reuse throughout the function
     // If no state exists but config says router mode, auto-create fresh state
@@ -644,19 +702,7 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
                 let lan_cidr = config.agent.router.lan_cidr.clone()
                     .unwrap_or_else(|| "192.168.1.0/24".to_string());
                 
-                let fresh_state = super::persist::ModeState {
-                    last_mode: super::mode::SystemMode::Router,
-                    lan_cidr: Some(lan_cidr),
-                    peer_table_ids: std::collections::HashMap::new(),
-                    prefix_active_backup: std::collections::HashMap::new(),
-                    peer_first_handshake: std::collections::HashMap::new(),
-                    peer_last_online_state: std::collections::HashMap::new(),
-                    peer_last_successful_ping: std::collections::HashMap::new(),
-                    peer_lan_access: std::collections::HashMap::new(),
-                    auto_failover: false,
-                    primary_exit_node: None,
-                    primary_online_since: None,
-                };
+                let fresh_state = super::persist::ModeState::new(super::mode::SystemMode::Router, Some(lan_cidr));
                 
                 // Save the fresh state
                 if let Err(e) = save_mode_state(&fresh_state) {
@@ -674,7 +720,9 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
     
     // Get table ID for this peer - use loaded state to avoid redundant load
     // If no table exists, auto-create one (handles case where mode state was just auto-created)
+    // The local breakout has no peer table; its table is (re)filled from the uplink default route instead
     let table_id = match state.peer_table_ids.get(&peer_id_str).copied() {
+        _ if is_local_breakout => install_local_breakout(state.lan_cidr.as_deref().unwrap_or_default(), network)?,
         Some(id) => id,
         None => {
             log::info!("No routing table found for peer {}. Auto-creating one.", peer_id_str);
@@ -706,94 +754,47 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
     // Get current exit node if any
     let old_exit_node = state.prefix_active_backup
         .get("0.0.0.0/0")
-        .and_then(|ps| state.peer_table_ids.get(&ps.active_peer_id))
-        .copied();
+        .and_then(|ps| exit_table_id(&state, &ps.active_peer_id));
     
     // Remove old exit node rule if different
     let wg_interface = &network.name;
-    let wg_subnet = network.subnet.to_string();
+    let wg_subnet = network.subnet.trunc();
+    // Exit nodes with pinned clients run on their own interface, where they always hold 0.0.0.0/0
+    let pinned = pinned_exit_interfaces(&state);
     
-    // Parse rules once for all cleanup operations
-    let all_rules = get_ip_rules_cached()?;
+    // Dump rules once for all cleanup operations
+    let all_rules = get_ip_rules()?;
     let lan_interface = find_lan_interface().unwrap_or_else(|_| "eth0".to_string());
     
     // ALWAYS clean up stale exit node rules from OTHER peer tables (not the current exit node's table)
     // This handles cases where rules from previous exit nodes were not properly cleaned up
-    for rule in &all_rules {
-        // Only process exit node rules (priority >= 20000)
-        if rule.priority < 20000 {
-            continue;
+    let stale_removed = delete_matching_rules(&all_rules, |rule| {
+        // Only exit node rules (priority >= 20000) from other peer tables
+        if rule.priority < 20000 || rule.table == table_id || !is_exit_table(rule.table) || !rule.is_default_destination() {
+            return false;
         }
         
-        // Skip rules that belong to the current exit node's table
-        if rule.table_id == Some(table_id) {
-            continue;
-        }
-        
-        // Check if this is a LAN exit node rule from another table
-        let is_stale_lan_exit_rule = rule.iif == Some(lan_interface.clone())
-            && rule.table_id.is_some()
-            && (rule.to.is_none() || rule.to == Some("0.0.0.0/0".to_string()));
-        
-        // Check if this is a WireGuard peer exit node rule from another table
-        let is_stale_wg_exit_rule = rule.from == Some(wg_subnet.clone())
-            && rule.iif == Some(wg_interface.to_string())
-            && rule.table_id.is_some()
-            && (rule.to.is_none() || rule.to == Some("0.0.0.0/0".to_string()));
-        
-        if is_stale_lan_exit_rule || is_stale_wg_exit_rule {
-            let priority_str = rule.priority.to_string();
-            let stale_table = rule.table_id.unwrap_or(0);
-            let _ = shell_cmd(&["ip", "rule", "del", "priority", &priority_str]);
-            log::info!("Removed stale exit node rule: priority {} -> table {} (current exit node uses table {})", 
-                      rule.priority, stale_table, table_id);
-        }
+        // LAN exit node rule or WireGuard peer exit node rule
+        let is_stale_lan_exit_rule = rule.iif.as_deref() == Some(lan_interface.as_str());
+        let is_stale_wg_exit_rule = rule.from == Some(wg_subnet)
+            && rule.iif.as_deref() == Some(wg_interface.as_str());
+        is_stale_lan_exit_rule || is_stale_wg_exit_rule
+    });
+    if stale_removed > 0 {
+        log::info!("Removed {} stale exit node rule(s) (current exit node uses table {})", stale_removed, table_id);
     }
     
-    if let Some(old_table_id) = old_exit_node {
-        if old_table_id != table_id {
+    if let Some(old_table_id) = old_exit_node
+        && old_table_id != table_id {
             log::info!("Removing old exit node rule for table {}", old_table_id);
             
             // Also remove old LAN exception rules if they exist
             if let Some(lan_cidr_str) = &state.lan_cidr {
-                let lan_cidrs = parse_lan_cidrs(lan_cidr_str);
+                let lan_nets = parse_lan_nets(lan_cidr_str);
                 let lan_interface = find_lan_interface().ok();
-                for lan_cidr in &lan_cidrs {
-                    if let Some(ref iface) = lan_interface {
-                        // Remove eth0 LAN exception rule
-                        let _ = shell_cmd(&[
-                            "ip", "rule", "del",
-                            "iif", iface,
-                            "to", lan_cidr,
-                            "lookup", "main",
-                        ]);
-                    }
-                    // Remove old subnet-wide WireGuard peer LAN exception rule (migration)
-                    let _ = shell_cmd(&[
-                        "ip", "rule", "del",
-                        "from", &wg_subnet,
-                        "iif", wg_interface,
-                        "to", lan_cidr,
-                        "lookup", "main",
-                    ]);
-                    // Remove per-peer LAN exception rules
-                    for (peer_id, peer) in &network.peers {
-                        if *peer_id == network.this_peer {
-                            continue;
-                        }
-                        let peer_addr = format!("{}/32", peer.address);
-                        let _ = shell_cmd(&[
-                            "ip", "rule", "del",
-                            "from", &peer_addr,
-                            "iif", wg_interface,
-                            "to", lan_cidr,
-                            "lookup", "main",
-                        ]);
-                    }
-                }
+                remove_lan_exception_rules(&all_rules, &lan_nets, lan_interface.as_deref(), Some(network));
             }
         }
-    }
     
     // Dynamically manage 0.0.0.0/0 in WireGuard allowed IPs
     // Remove 0.0.0.0/0 from old exit node if different
@@ -809,8 +810,9 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
         log::debug!("[set_exit_node_impl] Found old exit node: {}, comparing with new: {}", old_exit_node_peer_id_str, peer_id_str);
         if old_exit_node_peer_id_str != &peer_id_str {
             log::debug!("[set_exit_node_impl] Old and new exit nodes are different, removing 0.0.0.0/0 from old exit node");
-            if let Ok(old_peer_uuid) = Uuid::parse_str(&old_exit_node_peer_id_str) {
-                if let Some(old_peer) = network.peers.get(&old_peer_uuid) {
+            if let Ok(old_peer_uuid) = Uuid::parse_str(old_exit_node_peer_id_str)
+                && !pinned.contains_key(&old_peer_uuid)
+                && let Some(old_peer) = network.peers.get(&old_peer_uuid) {
                     let old_public_key = wg_public_key_from_private_key(&old_peer.private_key);
                     let old_public_key_b64 = old_public_key.to_base64();
                     log::info!("Removing 0.0.0.0/0 from old exit node {} (public key: {})", old_exit_node_peer_id_str, old_public_key_b64);
@@ -850,6 +852,9 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
                         }
                     }
                     
+                    // Keep the internet probe targets routed through it
+                    current_allowed_ips.extend(probe_allowed_ips(&old_peer_uuid));
+                    
                     // Remove 0.0.0.0/0 and set remaining allowed IPs
                     let allowed_ips_str = current_allowed_ips.join(",");
                     log::info!("Setting allowed IPs for old exit node {} to: {}", old_exit_node_peer_id_str, allowed_ips_str);
@@ -860,7 +865,6 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
                         log::info!("Removed 0.0.0.0/0 from old exit node {} and set allowed IPs to: {}", old_exit_node_peer_id_str, allowed_ips_str);
                     }
                 }
-            }
         }
     }
     
@@ -882,6 +886,7 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
         super::persist::PrefixState {
             active_peer_id: peer_id_str.clone(),
             backup_peer_ids,
+            ..Default::default()
         },
     );
     
@@ -890,15 +895,15 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
     
     // Install exit node rule: iif <lan_interface> to 0.0.0.0/0 lookup <table_id> priority 20000
     // But first, add exception rule for LAN CIDR to keep local traffic local
+    // New rules are installed before the old ones are removed, so traffic never falls through mid-change
     // Find LAN interface (cached)
     let lan_interface = find_lan_interface()?;
     let priority = 20000 + (table_id % 1000);
-    let priority_str = priority.to_string();
-    let table_id_str = table_id.to_string();
+    let backend = routing_backend();
     
     // Get LAN CIDRs from state to create exception rules (supports multiple comma-separated CIDRs)
     if let Some(lan_cidr_str) = &state.lan_cidr {
-        let lan_cidrs = parse_lan_cidrs(lan_cidr_str);
+        let lan_nets = parse_lan_nets(lan_cidr_str);
         
         // Install exception rule: LAN traffic stays in main table (priority < 20000)
         let exception_priority = priority - 1; // One less than default route rule
@@ -907,73 +912,38 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
         // This allows individual control over which peers can reach the local LAN
         let wg_peer_lan_base_priority = exception_priority - 100; // Start 100 below eth0 exception
         
-        for (cidr_idx, lan_cidr) in lan_cidrs.iter().enumerate() {
-            // Remove existing exception rule if any
-            let _ = shell_cmd(&[
-                "ip", "rule", "del",
-                "iif", &lan_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-            ]);
-            
+        let peer_nets: Vec<Ipv4Net> = network.peers.iter()
+            .filter(|(pid, _)| **pid != network.this_peer)
+            .map(|(_, p)| Ipv4Net::from(p.address))
+            .collect();
+        
+        for (cidr_idx, lan_net) in lan_nets.iter().enumerate() {
             // Add exception rule: iif <lan_interface> to <lan_cidr> lookup main
             // Use slightly different priorities for each CIDR to avoid conflicts
-            let cidr_exception_priority = exception_priority - (cidr_idx as u32);
-            let cidr_exception_priority_str = cidr_exception_priority.to_string();
-            
-            let exception_cmd = &[
-                "ip", "rule", "add",
-                "iif", &lan_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-                "priority", &cidr_exception_priority_str,
-            ];
+            let exception_rule = Rule {
+                priority: exception_priority - (cidr_idx as u32),
+                iif: Some(lan_interface.clone()),
+                to: Some(*lan_net),
+                table: MAIN_TABLE,
+                ..Default::default()
+            };
             
-            if let Err(e) = shell_cmd(exception_cmd) {
-                log::warn!("Failed to install LAN exception rule for {}: {} (continuing anyway)", lan_cidr, e);
+            if let Err(e) = backend.add_rule(&exception_rule) {
+                log::warn!("Failed to install LAN exception rule for {}: {} (continuing anyway)", lan_net, e);
             } else {
-                log::info!("Installed LAN exception rule: {} -> main table (priority {})", lan_cidr, cidr_exception_priority);
-            }
-            
-            // First, remove any existing per-peer LAN rules (clean slate)
-            // Remove old subnet-wide rule if it exists (migration from old format)
-            let _ = shell_cmd(&[
-                "ip", "rule", "del",
-                "from", &wg_subnet,
-                "iif", wg_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-            ]);
-            
-            // Remove existing per-peer rules (priority range 19800-19899)
-            for rule in &all_rules {
-                if rule.iif == Some(wg_interface.to_string())
-                    && rule.to == Some(lan_cidr.to_string())
-                    && rule.table_id.is_none() // lookup main doesn't have a numeric table_id in our parsing
-                    && rule.priority >= wg_peer_lan_base_priority as u32
-                    && rule.priority < exception_priority as u32 {
-                    let priority_str = rule.priority.to_string();
-                    let _ = shell_cmd(&["ip", "rule", "del", "priority", &priority_str]);
-                    log::debug!("Removed old per-peer LAN rule with priority {}", rule.priority);
-                }
+                log::info!("Installed LAN exception rule: {} -> main table (priority {})", lan_net, exception_rule.priority);
             }
             
-            // Also try to remove by matching criteria for each peer
-            for (peer_id, peer) in &network.peers {
-                if *peer_id == network.this_peer {
-                    continue; // Skip the router itself
-                }
-                let peer_addr = format!("{}/32", peer.address);
-                let _ = shell_cmd(&[
-                    "ip", "rule", "del",
-                    "from", &peer_addr,
-                    "iif", wg_interface,
-                    "to", lan_cidr,
-                    "lookup", "main",
-                ]);
-            }
+            // Remove existing exception rule at any other priority
+            delete_matching_rules(&all_rules, |rule| {
+                rule.table == MAIN_TABLE
+                    && rule.to == Some(*lan_net)
+                    && rule.iif.as_deref() == Some(lan_interface.as_str())
+                    && *rule != exception_rule
+            });
             
             // Add rules for each peer that has LAN access
+            let mut desired_peer_rules = Vec::new();
             let mut peer_index = 0u32;
             for (peer_id, peer) in &network.peers {
                 if *peer_id == network.this_peer {
@@ -989,26 +959,15 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
                     .unwrap_or(true); // Default to true (has LAN access)
                 
                 if has_lan_access {
-                    let peer_addr = format!("{}/32", peer.address);
-                    // Use unique priority: base + (cidr_index * 100) + peer_index
-                    let peer_priority = wg_peer_lan_base_priority + (cidr_idx as u32 * 100) + peer_index;
-                    let peer_priority_str = peer_priority.to_string();
+                    let peer_lan_rule = peer_lan_rule(exception_priority, cidr_idx, peer_index, peer, wg_interface, *lan_net);
                     
-                    let peer_lan_cmd = &[
-                        "ip", "rule", "add",
-                        "from", &peer_addr,
-                        "iif", wg_interface,
-                        "to", lan_cidr,
-                        "lookup", "main",
-                        "priority", &peer_priority_str,
-                    ];
-                    
-                    if let Err(e) = shell_cmd(peer_lan_cmd) {
-                        log::warn!("Failed to install LAN access rule for peer {} ({}) to {}: {}", peer.name, peer_addr, lan_cidr, e);
+                    if let Err(e) = backend.add_rule(&peer_lan_rule) {
+                        log::warn!("Failed to install LAN access rule for peer {} ({}/32) to {}: {}", peer.name, peer.address, lan_net, e);
                     } else {
-                        log::info!("Installed LAN access rule for peer {} ({}) to {}: -> main table (priority {})", 
-                            peer.name, peer_addr, lan_cidr, peer_priority);
+                        log::info!("Installed LAN access rule for peer {} ({}/32) to {}: -> main table (priority {})", 
+                            peer.name, peer.address, lan_net, peer_lan_rule.priority);
                     }
+                    desired_peer_rules.push(peer_lan_rule);
                 } else if cidr_idx == 0 {
                     // Only log once per peer, not for each CIDR
                     log::info!("Peer {} ({}) does not have LAN access - no rule installed", peer.name, peer.address);
@@ -1016,185 +975,152 @@ fn set_exit_node_impl(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRou
                 
                 peer_index += 1;
             }
+            
+            // Remove the rest: old subnet-wide rule (migration from old format), per-peer rules in the
+            // 19800-19899 range and any per-peer rule that is no longer wanted (LAN access revoked, reordered)
+            delete_matching_rules(&all_rules, |rule| {
+                rule.table == MAIN_TABLE
+                    && rule.to == Some(*lan_net)
+                    && rule.iif.as_deref() == Some(wg_interface.as_str())
+                    && (rule.from == Some(wg_subnet)
+                        || rule.from.is_some_and(|from| peer_nets.contains(&from))
+                        || (rule.priority >= wg_peer_lan_base_priority && rule.priority < exception_priority))
+                    && !desired_peer_rules.contains(rule)
+            });
         } // end for lan_cidr
     }
     
     // Install default route rule: iif <lan_interface> to 0.0.0.0/0 lookup <table_id>
-    // First, remove any existing rule for this table using parsed rules
-    for rule in &all_rules {
-        // Check if this rule matches our criteria (iif lan_interface and lookup our table)
-        // Note: `to 0.0.0.0/0` may appear as `to = None` in parsed output
-        if rule.iif == Some(lan_interface.clone())
-            && rule.table_id == Some(table_id)
+    let exit_rule = Rule {
+        priority,
+        iif: Some(lan_interface.clone()),
+        table: table_id,
+        ..Default::default()
+    };
+    backend.add_rule(&exit_rule).map_err(|e| PolicyRoutingError::IpRuleError(
+        format!("Failed to install exit node rule: {}", e)
+    ))?;
+    log::info!("Installed exit node rule {}", exit_rule);
+    
+    // Then remove any other exit node rule for this table (e.g. left over at a different priority)
+    delete_matching_rules(&all_rules, |rule| {
+        rule.iif.as_deref() == Some(lan_interface.as_str())
+            && rule.table == table_id
             && rule.priority >= 20000
-            && (rule.to.is_none() || rule.to == Some("0.0.0.0/0".to_string())) {
-            let priority_str = rule.priority.to_string();
-            let _ = shell_cmd(&["ip", "rule", "del", "priority", &priority_str]);
-            log::debug!("Removed old exit node rule with priority {}", rule.priority);
-        }
-    }
-    
-    // Also try to remove by matching criteria in case priority-based removal failed
-    let _ = shell_cmd(&[
-        "ip", "rule", "del",
-        "iif", &lan_interface,
-        "to", "0.0.0.0/0",
-        "lookup", &table_id_str,
-    ]);
-    
-    let cmd = &[
-        "ip", "rule", "add",
-        "iif", &lan_interface,
-        "to", "0.0.0.0/0",
-        "lookup", &table_id_str,
-        "priority", &priority_str,
-    ];
-    
-    if let Err(e) = shell_cmd(cmd) {
-        let error_str = e.to_string();
-        // If rule already exists, try to replace it
-        if error_str.contains("File exists") || error_str.contains("RTNETLINK answers: File exists") {
-            log::debug!("Exit node rule already exists, replacing...");
-            // Delete by matching criteria
-            let _ = shell_cmd(&[
-                "ip", "rule", "del",
-                "iif", &lan_interface,
-                "to", "0.0.0.0/0",
-                "lookup", &table_id_str,
-            ]);
-            // Try adding again
-            if let Err(e2) = shell_cmd(cmd) {
-                return Err(PolicyRoutingError::IpRuleError(
-                    format!("Failed to install exit node rule after replacement attempt: {}", e2)
-                ));
-            } else {
-                log::info!("Successfully replaced exit node rule for table {}", table_id);
-            }
-        } else {
-            return Err(PolicyRoutingError::IpRuleError(
-                format!("Failed to install exit node rule: {}", e)
-            ));
-        }
-    }
+            && rule.is_default_destination()
+            && *rule != exit_rule
+    });
     
     // Install PBR rule for WireGuard peers: from <wg_subnet> iif <wg_interface> to 0.0.0.0/0 lookup <table_id>
     // This allows all WireGuard peers to use the exit node for internet traffic
-    // (wg_interface and wg_subnet are already defined above)
-    
-    // Remove old WireGuard peer rules for this table if they exist (using already parsed rules)
-    for rule in &all_rules {
-        // Check if this rule matches our criteria (from wg_subnet, iif wg_interface, lookup our table)
-        // Note: `to 0.0.0.0/0` may appear as `to = None` in parsed output
-        if rule.from == Some(wg_subnet.clone())
-            && rule.iif == Some(wg_interface.to_string())
-            && rule.table_id == Some(table_id)
-            && rule.priority >= 20000
-            && (rule.to.is_none() || rule.to == Some("0.0.0.0/0".to_string())) {
-            let priority_str = rule.priority.to_string();
-            let _ = shell_cmd(&["ip", "rule", "del", "priority", &priority_str]);
-            log::debug!("Removed old WireGuard peer exit node rule with priority {}", rule.priority);
-        }
-    }
-    
-    // Add PBR rule for WireGuard peers: from <wg_subnet> iif <wg_interface> to 0.0.0.0/0 lookup <table_id>
     // Use priority 20001 (one higher than LAN rule) so it takes precedence
-    let wg_peer_priority = priority + 1;
-    let wg_peer_priority_str = wg_peer_priority.to_string();
-    let wg_peer_cmd = &[
-        "ip", "rule", "add",
-        "from", &wg_subnet,
-        "iif", wg_interface,
-        "to", "0.0.0.0/0",
-        "lookup", &table_id_str,
-        "priority", &wg_peer_priority_str,
-    ];
-    
-    if let Err(e) = shell_cmd(wg_peer_cmd) {
+    let wg_peer_rule = Rule {
+        priority: priority + 1,
+        from: Some(wg_subnet),
+        iif: Some(wg_interface.to_string()),
+        table: table_id,
+        ..Default::default()
+    };
+    
+    if let Err(e) = backend.add_rule(&wg_peer_rule) {
         log::warn!("Failed to install WireGuard peer exit node rule: {} (continuing anyway)", e);
     } else {
         log::info!("Installed WireGuard peer exit node rule: from {} iif {} to 0.0.0.0/0 -> table {} (priority {})", 
-            wg_subnet, wg_interface, table_id, wg_peer_priority);
-    }
-    
-    // Install default route in the peer's table
-    
-    // Install default route: ip route add 0.0.0.0/0 dev <wg_interface> table <table_id>
-    let route_cmd = &[
-        "ip", "route", "add",
-        "0.0.0.0/0",
-        "dev", wg_interface,
-        "table", &table_id_str,
-    ];
-    
-    // Try to add, if it exists, replace it
-    if let Err(e) = shell_cmd(route_cmd) {
-        let error_str = e.to_string();
-        if error_str.contains("File exists") || error_str.contains("RTNETLINK answers: File exists") {
-            log::debug!("Default route already exists in table {}, replacing...", table_id);
-            let replace_cmd = &[
-                "ip", "route", "replace",
-                "0.0.0.0/0",
-                "dev", wg_interface,
-                "table", &table_id_str,
-            ];
-            if let Err(replace_err) = shell_cmd(replace_cmd) {
-                return Err(PolicyRoutingError::RouteInstallationError(
-                    format!("Failed to install default route in table {}: {}", table_id, replace_err)
-                ));
-            }
-        } else {
-            return Err(PolicyRoutingError::RouteInstallationError(
-                format!("Failed to install default route in table {}: {}", table_id, e)
-            ));
-        }
+            wg_subnet, wg_interface, table_id, wg_peer_rule.priority);
     }
     
-    // Add 0.0.0.0/0 to new exit node
-    let new_peer = network.peers.get(peer_id)
-        .ok_or_else(|| PolicyRoutingError::TableIdError(format!("Peer {} not found in network", peer_id_str)))?;
-    let new_public_key = wg_public_key_from_private_key(&new_peer.private_key);
-    let new_public_key_b64 = new_public_key.to_base64();
-    
-    // Get current allowed IPs for the new peer (excluding 0.0.0.0/0)
-    // IMPORTANT: AllowedIPs should be from the ROUTER's perspective - what IPs to route to this peer
-    // The peer's own address should always be included so we can reach the peer
-    let mut current_allowed_ips = vec![format!("{}/32", new_peer.address)];
+    // Remove old WireGuard peer rules for this table if they exist
+    delete_matching_rules(&all_rules, |rule| {
+        rule.from == Some(wg_subnet)
+            && rule.iif.as_deref() == Some(wg_interface.as_str())
+            && rule.table == table_id
+            && rule.priority >= 20000
+            && rule.is_default_destination()
+            && *rule != wg_peer_rule
+    });
     
-    for (conn_id, conn_details) in &network.connections {
-        if conn_id.contains(peer_id) && conn_id.contains(&network.this_peer) {
-            // Get allowed_ips based on ROUTER's position in the connection
-            // If router is peer A, use allowed_ips_a_to_b (router's config for the other peer)
-            // If router is peer B, use allowed_ips_b_to_a (router's config for the other peer)
-            let allowed_ips = if conn_id.a == network.this_peer {
-                &conn_details.allowed_ips_a_to_b
-            } else {
-                &conn_details.allowed_ips_b_to_a
-            };
+    if is_local_breakout {
+        // The table already routes via the uplink and no peer owns 0.0.0.0/0 any more
+        log::info!("Exit node is now the local uplink (table {})", table_id);
+    } else {
+        // Install (or atomically replace) the default route in the peer's table:
+        // 0.0.0.0/0 dev <wg_interface> table <table_id> (or the exit node's own interface)
+        let default_route = Route {
+            table: table_id,
+            destination: Ipv4Net::default(),
+            device: pinned.get(peer_id).cloned().unwrap_or_else(|| wg_interface.to_string()),
+            gateway: None,
+        };
+        backend.replace_route(&default_route).map_err(|e| PolicyRoutingError::RouteInstallationError(
+            format!("Failed to install default route in table {}: {}", table_id, e)
+        ))?;
+    
+        // Add 0.0.0.0/0 to new exit node
+        let new_peer = network.peers.get(peer_id)
+            .ok_or_else(|| PolicyRoutingError::TableIdError(format!("Peer {} not found in network", peer_id_str)))?;
+        let new_public_key = wg_public_key_from_private_key(&new_peer.private_key);
+        let new_public_key_b64 = new_public_key.to_base64();
+    
+        // Get current allowed IPs for the new peer (excluding 0.0.0.0/0)
+        // IMPORTANT: AllowedIPs should be from the ROUTER's perspective - what IPs to route to this peer
+        // The peer's own address should always be included so we can reach the peer
+        let mut current_allowed_ips = vec![format!("{}/32", new_peer.address)];
+    
+        for (conn_id, conn_details) in &network.connections {
+            if conn_id.contains(peer_id) && conn_id.contains(&network.this_peer) {
+                // Get allowed_ips based on ROUTER's position in the connection
+                // If router is peer A, use allowed_ips_a_to_b (router's config for the other peer)
+                // If router is peer B, use allowed_ips_b_to_a (router's config for the other peer)
+                let allowed_ips = if conn_id.a == network.this_peer {
+                    &conn_details.allowed_ips_a_to_b
+                } else {
+                    &conn_details.allowed_ips_b_to_a
+                };
             
-            for ip in allowed_ips {
-                let ip_str = ip.to_string();
-                // Exclude 0.0.0.0/0 (will be added separately), default, and peer's own address (already added)
-                if ip_str != "0.0.0.0/0" 
-                    && ip_str != "default" 
-                    && ip_str != format!("{}/32", new_peer.address) {
-                    current_allowed_ips.push(ip_str);
+                for ip in allowed_ips {
+                    let ip_str = ip.to_string();
+                    // Exclude 0.0.0.0/0 (will be added separately), default, and peer's own address (already added)
+                    if ip_str != "0.0.0.0/0" 
+                        && ip_str != "default" 
+                        && ip_str != format!("{}/32", new_peer.address) {
+                        current_allowed_ips.push(ip_str);
+                    }
                 }
+                break;
             }
-            break;
+        }
+    
+        // Keep the internet probe targets routed through it, and add 0.0.0.0/0 to the list
+        current_allowed_ips.extend(probe_allowed_ips(peer_id));
+        current_allowed_ips.push("0.0.0.0/0".to_string());
+        let allowed_ips_str = current_allowed_ips.join(",");
+    
+        log::info!("Adding 0.0.0.0/0 to new exit node {} (public key: {})", peer_id_str, new_public_key_b64);
+        if let Some(exit_interface) = pinned.get(peer_id) {
+            log::info!("Exit node {} already holds 0.0.0.0/0 on {}", peer_id_str, exit_interface);
+        } else if let Err(e) = shell_cmd(&["wg", "set", wg_interface, "peer", &new_public_key_b64, 
+                                    "allowed-ips", &allowed_ips_str]) {
+            log::warn!("Failed to add 0.0.0.0/0 to new exit node {}: {}", peer_id_str, e);
+            // Don't fail the entire operation, but log the warning
+        } else {
+            log::info!("Successfully added 0.0.0.0/0 to exit node {}", peer_id_str);
         }
     }
     
-    // Add 0.0.0.0/0 to the list
-    current_allowed_ips.push("0.0.0.0/0".to_string());
-    let allowed_ips_str = current_allowed_ips.join(",");
+    // Leaving the local breakout: its exit rules are gone (stale cleanup above), drop its table and NAT rules
+    if old_exit_node == Some(LOCAL_BREAKOUT_TABLE) && !is_local_breakout {
+        release_local_breakout(state.lan_cidr.as_deref().unwrap_or_default(), network);
+    }
     
-    log::info!("Adding 0.0.0.0/0 to new exit node {} (public key: {})", peer_id_str, new_public_key_b64);
-    if let Err(e) = shell_cmd(&["wg", "set", wg_interface, "peer", &new_public_key_b64, 
-                                "allowed-ips", &allowed_ips_str]) {
-        log::warn!("Failed to add 0.0.0.0/0 to new exit node {}: {}", peer_id_str, e);
-        // Don't fail the entire operation, but log the warning
-    } else {
-        log::info!("Successfully added 0.0.0.0/0 to exit node {}", peer_id_str);
+    // In active-active mode the exit node only owns the table; 0.0.0.0/0 is shared again
+    if state.load_balance
+        && let Err(e) = apply_load_balance(network, &health_snapshot()) {
+            log::warn!("Failed to rebalance exit nodes: {}", e);
+        }
+    
+    // Replacing AllowedIPs above dropped any domain route addresses of the old and new exit node
+    if let Err(e) = apply_domain_routes(network) {
+        log::warn!("Failed to restore domain routes: {}", e);
     }
     
     Ok(())
@@ -1209,11 +1135,10 @@ pub fn get_exit_node() -> Result<Option<Uuid>, PolicyRoutingError> {
         None => return Ok(None),
     };
     
-    if let Some(prefix_state) = state.prefix_active_backup.get("0.0.0.0/0") {
-        if let Ok(peer_id) = Uuid::parse_str(&prefix_state.active_peer_id) {
+    if let Some(prefix_state) = state.prefix_active_backup.get("0.0.0.0/0")
+        && let Ok(peer_id) = Uuid::parse_str(&prefix_state.active_peer_id) {
             return Ok(Some(peer_id));
         }
-    }
     
     Ok(None)
 }
@@ -1248,192 +1173,1616 @@ pub fn set_auto_failover(enabled: bool) -> Result<(), PolicyRoutingError> {
     Ok(())
 }
 
-// Get primary exit node (user's preferred gateway for fail-back)
-pub fn get_primary_exit_node() -> Result<Option<Uuid>, PolicyRoutingError> {
-    let state = match load_mode_state()
+// ============================================================================
+// Smart Gateway priority failover (gateway groups)
+// ============================================================================
+//
+// A gateway group lists exit nodes in tiers, tier 1 first. While a group is active, Smart Gateway
+// fails over to the highest tier that still has a healthy member (the one with the lowest latency
+// within a tier), and climbs back up once a higher tier has been healthy for failback_stability_secs.
+// Exit nodes outside the group rank below its last tier.
+//
+// Without an active group, a failover creates the "auto" group with the failed exit node as its
+// only tier, so Smart Gateway fails back to it; the group is dropped once it is the exit node again.
+
+pub const AUTO_GATEWAY_GROUP: &str = "auto";
+
+// Tier of an exit node in a group (exit nodes outside it rank below every tier)
+fn group_tier(group: &GatewayGroup, peer_id: &Uuid) -> usize {
+    let peer_id = peer_id.to_string();
+    group.tiers.iter().position(|tier| tier.contains(&peer_id)).unwrap_or(group.tiers.len())
+}
+
+// Highest tier with a healthy member, and its healthy member with the lowest latency
+fn best_group_member(
+    group: &GatewayGroup,
+    health: &HashMap<Uuid, ExitNodeHealth>,
+    exclude: Option<&Uuid>,
+) -> Option<(usize, Uuid)> {
+    group.tiers.iter().enumerate().find_map(|(tier, members)| {
+        members.iter()
+            .filter_map(|id| Uuid::parse_str(id).ok())
+            .filter(|id| Some(id) != exclude)
+            .filter_map(|id| health.get(&id).filter(|h| h.is_healthy()).map(|h| (id, h.latency_ms.unwrap_or(u64::MAX))))
+            .min_by_key(|(_, latency)| *latency)
+            .map(|(id, _)| (tier, id))
+    })
+}
+
+// Active gateway group and its tiers
+fn active_gateway_group(state: &super::persist::ModeState) -> Option<(String, GatewayGroup)> {
+    let name = state.active_gateway_group.as_ref()?;
+    state.gateway_groups.get(name).map(|group| (name.clone(), group.clone()))
+}
+
+// Gateway groups, the active one and the pending fail-back (if any)
+pub type GatewayGroupsStatus = (BTreeMap<String, GatewayGroup>, Option<String>, Option<FailbackTimer>);
+
+pub fn get_gateway_groups() -> Result<GatewayGroupsStatus, PolicyRoutingError> {
+    let state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?;
+    Ok(state.map(|s| (s.gateway_groups, s.active_gateway_group, s.failback_timer)).unwrap_or_default())
+}
+
+// Replace the gateway groups and choose the active one (None: plain Smart Gateway failover)
+// The "auto" group is managed by Smart Gateway and kept as it is
+// Activating a group moves the exit node to its best healthy tier right away
+pub fn set_gateway_groups(
+    mut groups: BTreeMap<String, GatewayGroup>,
+    active: Option<String>,
+    network: &Network,
+) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let mut state = load_mode_state()
         .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
-    {
-        Some(s) => s,
-        None => return Ok(None),
-    };
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found - enable Router Mode first".to_string()))?;
     
-    if let Some(primary_str) = state.primary_exit_node {
-        if let Ok(peer_id) = Uuid::parse_str(&primary_str) {
-            return Ok(Some(peer_id));
+    let candidates = get_peers_with_default_route(network);
+    groups.remove(AUTO_GATEWAY_GROUP);
+    for (name, group) in &groups {
+        if name.trim().is_empty() {
+            return Err(PolicyRoutingError::GatewayGroupError("Group name cannot be empty".to_string()));
+        }
+        if group.tiers.is_empty() || group.tiers.iter().any(|tier| tier.is_empty()) {
+            return Err(PolicyRoutingError::GatewayGroupError(format!("Group {} has an empty tier", name)));
         }
+        let mut seen = std::collections::HashSet::new();
+        for id in group.tiers.iter().flatten() {
+            let peer_id = Uuid::parse_str(id)
+                .map_err(|_| PolicyRoutingError::GatewayGroupError(format!("Invalid exit node id {} in group {}", id, name)))?;
+            if peer_id != LOCAL_BREAKOUT_EXIT_ID && !candidates.contains(&peer_id) {
+                return Err(PolicyRoutingError::GatewayGroupError(format!(
+                    "{} in group {} is not an exit node (no 0.0.0.0/0 in its AllowedIPs)", id, name
+                )));
+            }
+            if !seen.insert(peer_id) {
+                return Err(PolicyRoutingError::GatewayGroupError(format!("{} is listed twice in group {}", id, name)));
+            }
+        }
+    }
+    if let Some(auto) = state.gateway_groups.remove(AUTO_GATEWAY_GROUP) {
+        groups.insert(AUTO_GATEWAY_GROUP.to_string(), auto);
     }
+    if let Some(name) = &active
+        && !groups.contains_key(name) {
+            return Err(PolicyRoutingError::GatewayGroupError(format!("No gateway group named {}", name)));
+        }
     
-    Ok(None)
-}
-
-// Set primary exit node (user's preferred gateway for fail-back)
-pub fn set_primary_exit_node(peer_id: Option<Uuid>) -> Result<(), PolicyRoutingError> {
-    let mut state = match load_mode_state()
+    if state.active_gateway_group != active {
+        state.failback_timer = None;
+    }
+    state.gateway_groups = groups;
+    state.active_gateway_group = active;
+    // The auto group only lives while it is active
+    if state.active_gateway_group.as_deref() != Some(AUTO_GATEWAY_GROUP) {
+        state.gateway_groups.remove(AUTO_GATEWAY_GROUP);
+    }
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    
+    if let Some((name, group)) = active_gateway_group(&state)
+        && !state.load_balance {
+            let health = EXIT_NODE_HEALTH_CACHE.read().unwrap().clone();
+            let current_tier = get_exit_node()?.map_or(usize::MAX, |current| group_tier(&group, &current));
+            if let Some((tier, best)) = best_group_member(&group, &health, None)
+                && tier < current_tier {
+                    log::info!("Gateway group {} activated, switching to {} (tier {})", name, exit_node_name(network, &best), tier + 1);
+                    set_exit_node(&best, Some(network))?;
+                }
+        }
+    log::info!("Gateway groups updated ({} groups, active: {})", state.gateway_groups.len(),
+        state.active_gateway_group.as_deref().unwrap_or("none"));
+    Ok(())
+}
+
+// Per-client exit nodes
+// LAN clients (an address, a CIDR or a MAC address) can be pinned to an exit node of their own and fall
+// back to the exit node while it is offline. WireGuard picks the peer by destination, so only one peer can
+// hold 0.0.0.0/0 on the main interface: a pinned exit node moves to an interface of its own (wgqx<table id>,
+// the router's key and address) where it is the only peer, and its table routes there. Rules between the
+// prefix rules (10000+) and the exit node rules (20000+) send each client to that table; clients given by
+// MAC address are matched by a firewall mark set on the LAN interface.
+// Not combined with active-active mode, which splits 0.0.0.0/0 over the peers of the main interface.
+pub const CLIENT_EXIT_PRIORITY: u32 = 17000;
+const CLIENT_EXIT_PRIORITY_STEP: u32 = 10; // exceptions for local destinations first, the exit rule last
+pub const MAX_CLIENT_EXITS: usize = 64;
+pub const CLIENT_EXIT_FWMARK: u32 = 0x10000; // + the entry's position
+const EXIT_INTERFACE_PREFIX: &str = "wgqx";
+
+// A pinned LAN client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientSource {
+    Net(Ipv4Net),
+    Mac(String),
+}
+
+impl std::fmt::Display for ClientSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientSource::Net(net) => write!(f, "{}", net),
+            ClientSource::Mac(mac) => write!(f, "{}", mac),
+        }
+    }
+}
+
+// Parse a client given as an address (a /32), a CIDR or a MAC address (upper-case, colon-separated)
+pub fn parse_client_source(source: &str) -> Result<ClientSource, PolicyRoutingError> {
+    let source = source.trim();
+    if let Ok(addr) = std::net::Ipv4Addr::from_str(source) {
+        return Ok(ClientSource::Net(Ipv4Net::from(addr)));
+    }
+    if let Ok(net) = Ipv4Net::from_str(source) {
+        return Ok(ClientSource::Net(net.trunc()));
+    }
+    let octets: Vec<&str> = source.split([':', '-']).collect();
+    if octets.len() == 6 && octets.iter().all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit())) {
+        return Ok(ClientSource::Mac(octets.join(":").to_ascii_uppercase()));
+    }
+    Err(PolicyRoutingError::ClientExitError(format!("{} is not an address, a CIDR or a MAC address", source)))
+}
+
+// Interface a pinned exit node runs on
+pub fn exit_interface_name(table_id: u32) -> String {
+    format!("{}{}", EXIT_INTERFACE_PREFIX, table_id)
+}
+
+// Exit nodes with pinned clients and the interface each one runs on
+pub fn pinned_exit_interfaces(state: &super::persist::ModeState) -> HashMap<Uuid, String> {
+    state.client_exits.iter()
+        .filter_map(|entry| {
+            let table_id = state.peer_table_ids.get(&entry.exit_peer_id)?;
+            Some((Uuid::parse_str(&entry.exit_peer_id).ok()?, exit_interface_name(*table_id)))
+        })
+        .collect()
+}
+
+// Pinned exit nodes whose interface is up (one that couldn't be set up stays on the main interface)
+fn attached_exit_interfaces(state: &super::persist::ModeState) -> HashMap<Uuid, String> {
+    let backend = routing_backend();
+    let mut pinned = pinned_exit_interfaces(state);
+    pinned.retain(|_, interface| backend.interface_exists(interface).unwrap_or(false));
+    pinned
+}
+
+// Exit node health as the client exit rules see it: an exit node whose interface couldn't be set up counts as offline
+fn client_exit_health(state: &super::persist::ModeState, online: &HashMap<Uuid, bool>) -> HashMap<Uuid, bool> {
+    let attached = attached_exit_interfaces(state);
+    let mut online = online.clone();
+    for peer_id in pinned_exit_interfaces(state).into_keys().filter(|peer_id| !attached.contains_key(peer_id)) {
+        online.insert(peer_id, false);
+    }
+    online
+}
+
+// Firewall side of the pinned clients: exit node interfaces (with the exit node's LAN access) and MAC marks
+pub fn client_exit_firewall(state: &super::persist::ModeState) -> crate::firewall::ClientExitFirewall {
+    let mut exit_interfaces: Vec<(String, bool)> = pinned_exit_interfaces(state).into_iter()
+        .map(|(peer_id, interface)| (interface, state.peer_lan_access.get(&peer_id.to_string()).copied().unwrap_or(true)))
+        .collect();
+    exit_interfaces.sort();
+    let marks = state.client_exits.iter().enumerate().take(MAX_CLIENT_EXITS)
+        .filter_map(|(slot, entry)| match parse_client_source(&entry.source) {
+            Ok(ClientSource::Mac(mac)) => Some((mac, client_exit_mark(slot))),
+            _ => None,
+        })
+        .collect();
+    crate::firewall::ClientExitFirewall { exit_interfaces, marks }
+}
+
+// Interface the router reaches a peer on (its own one if clients are pinned to it)
+pub fn peer_interface(network: &Network, peer_id: &Uuid) -> String {
+    match load_mode_state() {
+        Ok(Some(state)) if state.last_mode == SystemMode::Router => pinned_exit_interfaces(&state)
+            .remove(peer_id)
+            .unwrap_or_else(|| network.name.clone()),
+        _ => network.name.clone(),
+    }
+}
+
+// The network as the main interface carries it: pinned exit nodes run on their own interface
+pub fn main_interface_network(network: &Network) -> Network {
+    let mut network = network.clone();
+    if let Ok(Some(state)) = load_mode_state()
+        && state.last_mode == SystemMode::Router {
+            let pinned = pinned_exit_interfaces(&state);
+            let this_peer = network.this_peer;
+            network.connections.retain(|conn_id, _| {
+                !(conn_id.contains(&this_peer) && (pinned.contains_key(&conn_id.a) || pinned.contains_key(&conn_id.b)))
+            });
+        }
+    network
+}
+
+// Priority and firewall mark of the client exit rules at a position
+fn client_exit_priority(slot: usize) -> u32 {
+    CLIENT_EXIT_PRIORITY + slot as u32 * CLIENT_EXIT_PRIORITY_STEP
+}
+
+fn client_exit_mark(slot: usize) -> u32 {
+    CLIENT_EXIT_FWMARK + slot as u32
+}
+
+fn is_client_exit_rule(rule: &Rule) -> bool {
+    (CLIENT_EXIT_PRIORITY..client_exit_priority(MAX_CLIENT_EXITS)).contains(&rule.priority)
+}
+
+// Rules for the pinned clients whose exit node is up (unknown counts as up):
+// local destinations (LAN and WireGuard subnet) stay in the main table, everything else goes to the exit node's table
+fn plan_client_exit_rules(state: &super::persist::ModeState, network: &Network, online: &HashMap<Uuid, bool>, lan_interface: &str) -> Vec<Rule> {
+    let mut local_nets = parse_lan_nets(state.lan_cidr.as_deref().unwrap_or_default());
+    local_nets.push(network.subnet.trunc());
+    local_nets.truncate(CLIENT_EXIT_PRIORITY_STEP as usize - 1);
+    
+    let mut rules = Vec::new();
+    for (slot, entry) in state.client_exits.iter().enumerate().take(MAX_CLIENT_EXITS) {
+        let Ok(exit_peer_id) = Uuid::parse_str(&entry.exit_peer_id) else {
+            continue;
+        };
+        let Some(&table) = state.peer_table_ids.get(&entry.exit_peer_id) else {
+            continue;
+        };
+        if !online.get(&exit_peer_id).copied().unwrap_or(true) {
+            continue;
+        }
+        let (from, fwmark) = match parse_client_source(&entry.source) {
+            Ok(ClientSource::Net(net)) => (Some(net), None),
+            Ok(ClientSource::Mac(_)) => (None, Some(client_exit_mark(slot))),
+            Err(_) => continue,
+        };
+        let base = client_exit_priority(slot);
+        for (i, local_net) in local_nets.iter().enumerate() {
+            rules.push(Rule {
+                priority: base + i as u32,
+                from,
+                to: Some(*local_net),
+                iif: Some(lan_interface.to_string()),
+                fwmark,
+                table: MAIN_TABLE,
+            });
+        }
+        rules.push(Rule {
+            priority: base + CLIENT_EXIT_PRIORITY_STEP - 1,
+            from,
+            to: None,
+            iif: Some(lan_interface.to_string()),
+            fwmark,
+            table,
+        });
+    }
+    rules
+}
+
+// Bring the client exit rules and MAC marks in line with the state
+fn apply_client_exits(network: &Network, online: &HashMap<Uuid, bool>) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    let lan_interface = find_lan_interface()?;
+    let backend = routing_backend();
+    
+    let desired = plan_client_exit_rules(&state, network, &client_exit_health(&state, online), &lan_interface);
+    let current = get_ip_rules()?;
+    delete_matching_rules(&current, |rule| is_client_exit_rule(rule) && !desired.contains(rule));
+    for rule in desired.iter().filter(|rule| !current.contains(rule)) {
+        backend.add_rule(rule).map_err(|e| PolicyRoutingError::IpRuleError(
+            format!("Failed to install client exit rule {}: {}", rule, e)
+        ))?;
+    }
+    
+    if let Some(lan_cidr) = &state.lan_cidr
+        && let Err(e) = crate::firewall::sync_client_marks(lan_cidr, &client_exit_firewall(&state).marks) {
+            log::warn!("Failed to update client marks: {}", e);
+        }
+    
+    let routed = desired.iter().filter(|rule| rule.table != MAIN_TABLE).count();
+    log::info!("Client exit nodes: {} of {} pinned client(s) routed to their exit node", routed, state.client_exits.len());
+    Ok(())
+}
+
+// Routes of a peer in the main table: its address plus the prefixes only it advertises
+// (overlapping prefixes are arbitrated by rules and stay on the main interface)
+fn peer_main_routes(peer_id: &Uuid, network: &Network) -> Vec<Ipv4Net> {
+    let Some(peer) = network.peers.get(peer_id) else {
+        return Vec::new();
+    };
+    let overlapping = get_overlapping_prefixes(network);
+    let mut routes = vec![Ipv4Net::from(peer.address)];
+    for route in get_peer_advertised_routes(peer_id, network) {
+        if let Ok(net) = Ipv4Net::from_str(&route)
+            && net.prefix_len() > 0
+            && !overlapping.contains_key(&net.trunc())
+            && !routes.contains(&net.trunc()) {
+                routes.push(net.trunc());
+            }
+    }
+    routes
+}
+
+// Point a peer's table and main table routes at an interface
+fn route_peer_via(peer_id: &Uuid, network: &Network, state: &super::persist::ModeState, interface: &str) {
+    if let Some(&table_id) = state.peer_table_ids.get(&peer_id.to_string())
+        && let Err(e) = install_peer_routes(peer_id, table_id, &get_peer_advertised_routes(peer_id, network), interface) {
+            log::warn!("Failed to install routes for peer {} via {}: {}", peer_id, interface, e);
+        }
+    let backend = routing_backend();
+    for destination in peer_main_routes(peer_id, network) {
+        let route = Route { table: MAIN_TABLE, destination, device: interface.to_string(), gateway: None };
+        if let Err(e) = backend.replace_route(&route) {
+            log::warn!("Failed to install route {}: {}", route, e);
+        }
+    }
+}
+
+// Forwarding and NAT for an exit node's own interface
+fn exit_interface_firewall(network: &Network, state: &super::persist::ModeState, peer_id: &Uuid, interface: &str, enabled: bool) {
+    let Some(lan_cidr) = &state.lan_cidr else {
+        return;
+    };
+    let result = if enabled {
+        let lan_access = state.peer_lan_access.get(&peer_id.to_string()).copied().unwrap_or(true);
+        crate::firewall::set_exit_interface_firewall(lan_cidr, network, interface, lan_access)
+    } else {
+        crate::firewall::remove_exit_interface_firewall(lan_cidr, network, interface)
+    };
+    if let Err(e) = result {
+        log::warn!("Failed to update firewall rules for {}: {}", interface, e);
+    }
+}
+
+// Configure a peer on an interface with `wg syncconf`: the router's key, the peer as only peer
+// (no fixed listen port unless keep_listen_port, 0.0.0.0/0 is left out like on the main interface)
+fn sync_single_peer_config(network: &Network, peer_id: &Uuid, interface: &str, command: &str, keep_listen_port: bool) -> Result<(), PolicyRoutingError> {
+    let mut single = network.clone();
+    single.connections.retain(|conn_id, _| conn_id.contains(peer_id) && conn_id.contains(&network.this_peer));
+    if !keep_listen_port && let Some(router) = single.peers.get_mut(&network.this_peer) {
+        router.endpoint.enabled = false;
+    }
+    let config = get_peer_wg_config(&single, &network.this_peer, true)
+        .map_err(|e| PolicyRoutingError::ClientExitError(format!("Failed to generate the configuration of {}: {}", interface, e)))?;
+    
+    let mut temp = tempfile::NamedTempFile::new()
+        .map_err(|e| PolicyRoutingError::ClientExitError(format!("Failed to create temp file: {}", e)))?;
+    std::io::Write::write_all(&mut temp, config.as_bytes())
+        .map_err(|e| PolicyRoutingError::ClientExitError(format!("Failed to write temp file: {}", e)))?;
+    let path = temp.path().to_string_lossy().to_string();
+    shell_cmd(&["wg", command, interface, &path])
+        .map_err(|e| PolicyRoutingError::ClientExitError(format!("Failed to configure {}: {}", interface, e)))?;
+    Ok(())
+}
+
+// Move a pinned exit node to its own interface: create and configure it, give the peer 0.0.0.0/0 there,
+// take it off the main interface (one key can't reach the peer from two interfaces) and route it there
+fn attach_exit_interface(network: &Network, state: &super::persist::ModeState, peer_id: &Uuid, interface: &str) -> Result<(), PolicyRoutingError> {
+    let peer = network.peers.get(peer_id)
+        .ok_or_else(|| PolicyRoutingError::ClientExitError(format!("Peer {} not found", peer_id)))?;
+    let router = network.peers.get(&network.this_peer)
+        .ok_or_else(|| PolicyRoutingError::ClientExitError("Router peer not found".to_string()))?;
+    let backend = routing_backend();
+    backend.add_wireguard_interface(interface, Ipv4Net::from(router.address), router.mtu.enabled.then_some(router.mtu.value))
+        .map_err(|e| PolicyRoutingError::ClientExitError(format!("Failed to create {}: {}", interface, e)))?;
+    if let Err(e) = sync_single_peer_config(network, peer_id, interface, "syncconf", false) {
+        // Leave the exit node on the main interface rather than half-moved
+        let _ = backend.delete_interface(interface);
+        return Err(e);
+    }
+    
+    let public_key_b64 = wg_public_key_from_private_key(&peer.private_key).to_base64();
+    let mut allowed_ips = router_allowed_ips(peer_id, network);
+    allowed_ips.push("0.0.0.0/0".to_string());
+    if let Err(e) = shell_cmd(&["wg", "set", interface, "peer", &public_key_b64, "allowed-ips", &allowed_ips.join(",")]) {
+        log::warn!("Failed to add 0.0.0.0/0 to {} on {}: {}", peer.name, interface, e);
+    }
+    if let Err(e) = shell_cmd(&["wg", "set", &network.name, "peer", &public_key_b64, "remove"]) {
+        log::debug!("Peer {} was not on {}: {}", peer.name, network.name, e);
+    }
+    
+    route_peer_via(peer_id, network, state, interface);
+    exit_interface_firewall(network, state, peer_id, interface, true);
+    log::info!("Exit node {} runs on {}", peer.name, interface);
+    Ok(())
+}
+
+// Return an exit node to the main interface once no client is pinned to it
+fn detach_exit_interface(network: &Network, state: &super::persist::ModeState, peer_id: Option<&Uuid>, interface: &str) -> Result<(), PolicyRoutingError> {
+    match routing_backend().delete_interface(interface) {
+        Ok(()) | Err(BackendError::NotFound(_)) => {}
+        Err(e) => return Err(PolicyRoutingError::ClientExitError(format!("Failed to delete {}: {}", interface, e))),
+    }
+    let Some(peer_id) = peer_id.filter(|id| network.peers.contains_key(id)) else {
+        return Ok(());
+    };
+    exit_interface_firewall(network, state, peer_id, interface, false);
+    sync_single_peer_config(network, peer_id, &network.name, "addconf", true)?;
+    
+    // The exit node gets 0.0.0.0/0 back on the main interface
+    let is_exit_node = state.prefix_active_backup.get("0.0.0.0/0")
+        .is_some_and(|ps| ps.active_peer_id == peer_id.to_string());
+    if is_exit_node && let Some(peer) = network.peers.get(peer_id) {
+        let mut allowed_ips = router_allowed_ips(peer_id, network);
+        allowed_ips.push("0.0.0.0/0".to_string());
+        let public_key_b64 = wg_public_key_from_private_key(&peer.private_key).to_base64();
+        if let Err(e) = shell_cmd(&["wg", "set", &network.name, "peer", &public_key_b64, "allowed-ips", &allowed_ips.join(",")]) {
+            log::warn!("Failed to add 0.0.0.0/0 to exit node {}: {}", peer.name, e);
+        }
+    }
+    
+    route_peer_via(peer_id, network, state, &network.name);
+    log::info!("Exit node {} is back on {}", peer_id, network.name);
+    Ok(())
+}
+
+// Run every pinned exit node on its own interface, return the others to the main interface
+// and bring the client rules in line (called after the main interface is configured)
+pub fn sync_exit_interfaces(network: &Network) -> Result<(), PolicyRoutingError> {
+    let state = match load_mode_state()
         .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
     {
-        Some(s) => s,
-        None => return Err(PolicyRoutingError::PersistenceError("No mode state found - enable Router Mode first".to_string())),
+        Some(s) if s.last_mode == SystemMode::Router => s,
+        _ => return Ok(()),
     };
+    let pinned = pinned_exit_interfaces(&state);
     
-    state.primary_exit_node = peer_id.map(|id| id.to_string());
+    let mut existing: Vec<String> = routing_backend().list_interface_addresses()
+        .map_err(|e| PolicyRoutingError::ClientExitError(format!("Failed to list interfaces: {}", e)))?
+        .into_iter()
+        .map(|a| a.interface)
+        .filter(|name| name.starts_with(EXIT_INTERFACE_PREFIX))
+        .collect();
+    existing.sort();
+    existing.dedup();
+    for interface in existing.iter().filter(|name| !pinned.values().any(|p| p == *name)) {
+        let peer_id = state.peer_table_ids.iter()
+            .find(|(_, table_id)| exit_interface_name(**table_id) == *interface)
+            .and_then(|(id, _)| Uuid::parse_str(id).ok());
+        if let Err(e) = detach_exit_interface(network, &state, peer_id.as_ref(), interface) {
+            log::warn!("Failed to return {} to {}: {}", interface, network.name, e);
+        }
+    }
+    
+    for (peer_id, interface) in &pinned {
+        if let Err(e) = attach_exit_interface(network, &state, peer_id, interface) {
+            log::error!("Failed to move exit node {} to {}: {}", peer_id, interface, e);
+        }
+    }
+    
+    apply_client_exits(network, &health_snapshot())
+}
+
+// Remove every exit node interface and client rule (leaving Router Mode)
+pub fn remove_exit_interfaces(network: &Network) {
+    let state = match load_mode_state() {
+        Ok(Some(s)) => s,
+        _ => return,
+    };
+    for (peer_id, interface) in pinned_exit_interfaces(&state) {
+        exit_interface_firewall(network, &state, &peer_id, &interface, false);
+        if let Err(e) = routing_backend().delete_interface(&interface) {
+            log::debug!("Failed to delete {}: {}", interface, e);
+        }
+        if network.peers.contains_key(&peer_id)
+            && let Err(e) = sync_single_peer_config(network, &peer_id, &network.name, "addconf", true) {
+                log::warn!("Failed to return exit node {} to {}: {}", peer_id, network.name, e);
+            }
+    }
+    if let Ok(rules) = get_ip_rules() {
+        delete_matching_rules(&rules, is_client_exit_rule);
+    }
+    if let Some(lan_cidr) = &state.lan_cidr
+        && let Err(e) = crate::firewall::sync_client_marks(lan_cidr, &[]) {
+            log::warn!("Failed to remove client marks: {}", e);
+        }
+}
+
+// Check the pinned clients against the network and normalize their sources
+fn validate_client_exits(entries: &[super::persist::ClientExit], state: &super::persist::ModeState, network: &Network) -> Result<Vec<super::persist::ClientExit>, PolicyRoutingError> {
+    if entries.len() > MAX_CLIENT_EXITS {
+        return Err(PolicyRoutingError::ClientExitError(format!("At most {} clients can be pinned", MAX_CLIENT_EXITS)));
+    }
+    if !entries.is_empty() && state.load_balance {
+        return Err(PolicyRoutingError::ClientExitError("Clients can't be pinned to exit nodes in active-active mode".to_string()));
+    }
+    let lan_nets = parse_lan_nets(state.lan_cidr.as_deref().unwrap_or_default());
+    let exit_candidates = get_peers_with_default_route(network);
+    
+    let mut normalized: Vec<super::persist::ClientExit> = Vec::new();
+    for entry in entries {
+        let source = parse_client_source(&entry.source)?;
+        if let ClientSource::Net(net) = &source
+            && !lan_nets.iter().any(|lan| lan.contains(net)) {
+                return Err(PolicyRoutingError::ClientExitError(format!("{} is not inside a LAN CIDR", net)));
+            }
+        let source = source.to_string();
+        if normalized.iter().any(|e| e.source == source) {
+            return Err(PolicyRoutingError::ClientExitError(format!("{} is pinned more than once", source)));
+        }
+        
+        let exit_peer_id = Uuid::parse_str(&entry.exit_peer_id)
+            .map_err(|e| PolicyRoutingError::ClientExitError(format!("Invalid peer ID {}: {}", entry.exit_peer_id, e)))?;
+        let peer = network.peers.get(&exit_peer_id)
+            .ok_or_else(|| PolicyRoutingError::ClientExitError(format!("Peer {} not found", exit_peer_id)))?;
+        if !exit_candidates.contains(&exit_peer_id) {
+            return Err(PolicyRoutingError::ClientExitError(format!("{} does not advertise 0.0.0.0/0", peer.name)));
+        }
+        if !state.peer_table_ids.contains_key(&entry.exit_peer_id) {
+            return Err(PolicyRoutingError::ClientExitError(format!("{} has no routing table", peer.name)));
+        }
+        // The router reaches it from a fresh port, so it has to be the one starting the handshake
+        if !peer.endpoint.enabled || peer.endpoint.address == EndpointAddress::None {
+            return Err(PolicyRoutingError::ClientExitError(format!("{} has no endpoint", peer.name)));
+        }
+        normalized.push(super::persist::ClientExit { source, exit_peer_id: exit_peer_id.to_string() });
+    }
+    Ok(normalized)
+}
+
+// Get the pinned clients (in priority order)
+pub fn get_client_exits() -> Result<Vec<super::persist::ClientExit>, PolicyRoutingError> {
+    let state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?;
+    Ok(state.map(|s| s.client_exits).unwrap_or_default())
+}
+
+// Replace the pinned clients and apply them
+// network: Optional network config to avoid deadlock (if None, will load config)
+pub fn set_client_exits(entries: &[super::persist::ClientExit], network: Option<&Network>) -> Result<Vec<super::persist::ClientExit>, PolicyRoutingError> {
+    let _routing = routing_lock();
+    let loaded_config = match network {
+        Some(_) => None,
+        None => Some(crate::conf::util::get_config()
+            .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?),
+    };
+    let network = network.or(loaded_config.as_ref().map(|c| &c.network)).unwrap();
     
+    let mut state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) if s.last_mode == SystemMode::Router => s,
+        _ => return Err(PolicyRoutingError::PersistenceError("Router Mode is not enabled".to_string())),
+    };
+    state.client_exits = validate_client_exits(entries, &state, network)?;
     save_mode_state(&state)
         .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    log::info!("Pinned {} client(s) to exit nodes", state.client_exits.len());
     
-    if let Some(id) = peer_id {
-        log::debug!("Smart Gateway: Set primary exit node to {}", id);
-    } else {
-        log::debug!("Smart Gateway: Cleared primary exit node");
+    sync_exit_interfaces(network)?;
+    Ok(state.client_exits)
+}
+
+// Remember the exit node Smart Gateway fails over from, unless a gateway group is active
+fn start_auto_gateway_group(failed_exit: &Uuid) -> Result<(), PolicyRoutingError> {
+    let mut state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found - enable Router Mode first".to_string()))?;
+    if state.active_gateway_group.is_some() {
+        return Ok(());
     }
+    state.gateway_groups.insert(AUTO_GATEWAY_GROUP.to_string(), GatewayGroup { tiers: vec![vec![failed_exit.to_string()]] });
+    state.active_gateway_group = Some(AUTO_GATEWAY_GROUP.to_string());
+    state.failback_timer = None;
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    log::debug!("Smart Gateway: Will fail back to {}", failed_exit);
     Ok(())
 }
 
-// Get all peers that advertise default route
-// Optimized: Cache routes per peer to avoid redundant computation
-pub fn get_peers_with_default_route(network: &Network) -> Vec<Uuid> {
-    let mut peers = Vec::new();
-    // Cache routes per peer to avoid recomputing for the same peer
-    let mut route_cache: std::collections::HashMap<Uuid, Vec<String>> = std::collections::HashMap::new();
+// A manual exit node choice ends the active gateway group, so Smart Gateway does not climb back
+pub fn release_gateway_group() -> Result<(), PolicyRoutingError> {
+    let Some(mut state) = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))? else {
+        return Ok(());
+    };
+    let Some(name) = state.active_gateway_group.take() else {
+        return Ok(());
+    };
+    state.gateway_groups.remove(AUTO_GATEWAY_GROUP);
+    state.failback_timer = None;
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    log::info!("Gateway group {} deactivated by a manual exit node change", name);
+    Ok(())
+}
+
+// Fail-back: once a tier above the exit node's has been healthy for failback_stability_secs, switch to it
+// Called by the health monitor after every health update (with a snapshot of the health cache)
+// Returns the exit node it switched to
+fn check_gateway_group_failback(
+    health: &HashMap<Uuid, ExitNodeHealth>,
+    network: &Network,
+    now: u64,
+) -> Result<Option<Uuid>, PolicyRoutingError> {
+    let _routing = routing_lock();
+    let Some(mut state) = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))? else {
+        return Ok(None);
+    };
+    if !state.auto_failover || state.load_balance {
+        return Ok(None);
+    }
+    let (Some((name, group)), Some(current)) = (active_gateway_group(&state), get_exit_node()?) else {
+        return Ok(None);
+    };
     
-    for (peer_id, _) in &network.peers {
-        // Skip this router itself - it can't be an exit node
-        if *peer_id == network.this_peer {
-            continue;
+    let higher_tier = best_group_member(&group, health, None)
+        .filter(|(tier, _)| *tier < group_tier(&group, &current));
+    let Some((tier, best)) = higher_tier else {
+        if state.failback_timer.take().is_some() {
+            log::debug!("Smart Gateway: No higher tier of group {} is healthy, resetting fail-back timer", name);
+            save_mode_state(&state)
+                .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
         }
-        
-        // Check cache first
-        let routes = route_cache.entry(*peer_id).or_insert_with(|| {
-            get_peer_advertised_routes(peer_id, network)
-        });
-        
-        if routes.contains(&"0.0.0.0/0".to_string()) || routes.contains(&"default".to_string()) {
-            peers.push(*peer_id);
+        return Ok(None);
+    };
+    
+    let stability = failover_tuning(&best).failback_stability_secs;
+    match state.failback_timer {
+        Some(timer) if timer.tier == tier => {
+            let online_duration = now.saturating_sub(timer.since);
+            if online_duration < stability {
+                return Ok(None);
+            }
+            log::info!(
+                "Smart Gateway: Tier {} of group {} has been healthy for {}s, failing back to {}...",
+                tier + 1, name, online_duration, exit_node_name(network, &best)
+            );
+            set_exit_node(&best, Some(network))?;
+            events::record(EventKind::Failback, Initiator::Monitor, EventDetails {
+                old_peer: Some(current),
+                new_peer: Some(best),
+                reason: Some(format!("tier {} of group {} healthy for {}s", tier + 1, name, online_duration)),
+                metrics: health.get(&best).map(EventMetrics::from),
+                ..Default::default()
+            });
+            
+            // set_exit_node saved its own state
+            let mut state = load_mode_state()
+                .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+                .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+            state.failback_timer = None;
+            // Back on the exit node the auto group remembered
+            if name == AUTO_GATEWAY_GROUP && tier == 0 {
+                state.gateway_groups.remove(AUTO_GATEWAY_GROUP);
+                state.active_gateway_group = None;
+            }
+            save_mode_state(&state)
+                .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+            Ok(Some(best))
+        }
+        _ => {
+            state.failback_timer = Some(FailbackTimer { tier, since: now });
+            save_mode_state(&state)
+                .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+            log::info!(
+                "Smart Gateway: Tier {} of group {} is healthy again ({}), will fail-back in {}s if stable",
+                tier + 1, name, exit_node_name(network, &best), stability
+            );
+            Ok(None)
         }
     }
-    
-    peers
 }
 
-// Health status for an exit node
-#[derive(Debug, Clone)]
-pub struct ExitNodeHealth {
-    pub peer_id: Uuid,
-    pub is_online: bool,
-    pub last_handshake: Option<u64>, // Unix timestamp in seconds
-    pub first_handshake: Option<u64>, // Unix timestamp in seconds (when peer first came online via ping - used for "Up Since")
-    pub latency_ms: Option<u64>,     // Latency in milliseconds (current/average)
-    pub packet_loss_percent: Option<f64>, // Packet loss percentage (0.0-100.0)
-    pub jitter_ms: Option<u64>,      // Jitter in milliseconds (latency variation)
-    pub transfer_rx: u64,           // Bytes received
-    pub transfer_tx: u64,           // Bytes sent
-    pub endpoint: Option<String>,   // Endpoint address:port
+// Direct / local breakout
+// A virtual exit node (LOCAL_BREAKOUT_EXIT_ID) that sends LAN traffic out of the router's own uplink instead of a tunnel.
+// Its table holds a copy of the main table's default route, so the exit node rules, LAN exceptions and per-peer
+// LAN access rules work exactly as for a tunnel, and it can be selected, failed over to and failed back from like one.
+pub const LOCAL_BREAKOUT_EXIT_ID: Uuid = Uuid::nil();
+pub const LOCAL_BREAKOUT_NAME: &str = "Direct (local WAN)";
+pub const LOCAL_BREAKOUT_TABLE: u32 = 900;
+// Pinged through the uplink to decide whether direct internet access works
+const LOCAL_BREAKOUT_PROBE_TARGET: &str = "1.1.1.1";
+
+// Tables exit node rules can point to: peer tables and the local breakout table
+fn is_exit_table(table: u32) -> bool {
+    is_peer_table(table) || table == LOCAL_BREAKOUT_TABLE
 }
 
-// Get health status for exit nodes (reads from cache, updated by background monitor)
-pub fn get_exit_node_health(network: &Network, _wg_interface: &str) -> Vec<ExitNodeHealth> {
-    let peers_with_default = get_peers_with_default_route(network);
-    let cache = EXIT_NODE_HEALTH_CACHE.read().unwrap();
-    
-    // Return cached health status for peers with default routes
-    peers_with_default
-        .iter()
-        .filter_map(|peer_id| cache.get(peer_id).cloned())
-        .collect()
+// Routing table of an exit node (peer table or the local breakout table)
+fn exit_table_id(state: &super::persist::ModeState, peer_id_str: &str) -> Option<u32> {
+    if peer_id_str == LOCAL_BREAKOUT_EXIT_ID.to_string() {
+        Some(LOCAL_BREAKOUT_TABLE)
+    } else {
+        state.peer_table_ids.get(peer_id_str).copied()
+    }
 }
 
-// Background health monitoring task (runs continuously, updates cache)
-// Matches OPNsense dpinger behavior: single lightweight ping every 1 second
-pub async fn start_health_monitor() -> std::io::Result<()> {
-    let mut ticker = interval(Duration::from_secs(HEALTH_MONITOR_INTERVAL_SECS)); // Check every 1 second
+// Display name of an exit node
+fn exit_node_name(network: &Network, peer_id: &Uuid) -> String {
+    if *peer_id == LOCAL_BREAKOUT_EXIT_ID {
+        return LOCAL_BREAKOUT_NAME.to_string();
+    }
+    network.peers.get(peer_id)
+        .map(|p| p.name.clone())
+        .unwrap_or_else(|| peer_id.to_string())
+}
+
+// The router's own default route (main table)
+fn uplink_default_route() -> Result<Route, PolicyRoutingError> {
+    let routes = routing_backend().list_routes(MAIN_TABLE).map_err(|e| PolicyRoutingError::RouteInstallationError(
+        format!("Failed to read the main routing table: {}", e)
+    ))?;
+    routes.into_iter()
+        .find(|route| route.destination.prefix_len() == 0)
+        .ok_or_else(|| PolicyRoutingError::RouteInstallationError(
+            "No default route in the main table to break out through".to_string()
+        ))
+}
+
+// Copy the uplink default route into the local breakout table and allow NAT/forwarding through the uplink
+fn install_local_breakout(lan_cidr: &str, network: &Network) -> Result<u32, PolicyRoutingError> {
+    let route = Route { table: LOCAL_BREAKOUT_TABLE, ..uplink_default_route()? };
+    routing_backend().replace_route(&route).map_err(|e| PolicyRoutingError::RouteInstallationError(
+        format!("Failed to install local breakout route: {}", e)
+    ))?;
+    log::info!("Installed local breakout route {}", route);
+    
+    if let Err(e) = crate::firewall::set_local_breakout_firewall(&route.device, lan_cidr, network, true) {
+        log::warn!("Failed to enable local breakout firewall rules: {} (continuing anyway)", e);
+    }
+    Ok(LOCAL_BREAKOUT_TABLE)
+}
+
+// Drop the local breakout's rules, table and firewall rules (LAN exception rules are left to the caller)
+fn release_local_breakout(lan_cidr: &str, network: &Network) {
+    let backend = routing_backend();
+    let uplink = backend.list_routes(LOCAL_BREAKOUT_TABLE).ok()
+        .and_then(|routes| routes.into_iter().next())
+        .map(|route| route.device);
+    
+    if let Ok(rules) = get_ip_rules() {
+        delete_matching_rules(&rules, |rule| rule.table == LOCAL_BREAKOUT_TABLE);
+    }
+    if let Err(e) = backend.flush_table(LOCAL_BREAKOUT_TABLE) {
+        log::warn!("Failed to flush local breakout table: {}", e);
+    }
+    if let Some(uplink) = uplink
+        && let Err(e) = crate::firewall::set_local_breakout_firewall(&uplink, lan_cidr, network, false) {
+            log::warn!("Failed to remove local breakout firewall rules: {}", e);
+        }
+    log::info!("Removed local breakout (table {})", LOCAL_BREAKOUT_TABLE);
+}
+
+// Tear down the local breakout when leaving Router Mode while it is the exit node
+// (tunnel exit nodes are cleaned up together with their peer tables)
+pub fn remove_local_breakout(network: &Network) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    if get_exit_node()? != Some(LOCAL_BREAKOUT_EXIT_ID) {
+        return Ok(());
+    }
+    let lan_cidr_str = load_mode_state().ok().flatten().and_then(|s| s.lan_cidr).unwrap_or_default();
+    let rules = get_ip_rules()?;
+    let lan_interface = find_lan_interface().ok();
+    remove_lan_exception_rules(&rules, &parse_lan_nets(&lan_cidr_str), lan_interface.as_deref(), Some(network));
+    release_local_breakout(&lan_cidr_str, network);
+    Ok(())
+}
+
+// Get whether Smart Gateway may fall back to the local uplink
+pub fn get_local_breakout() -> Result<bool, PolicyRoutingError> {
+    let state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) => s,
+        None => return Ok(false), // Default to disabled
+    };
+    
+    Ok(state.local_breakout_fallback)
+}
+
+// Allow/disallow Smart Gateway to fall back to the local uplink when no exit node is healthy
+pub fn set_local_breakout(fallback: bool) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let mut state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) => s,
+        None => return Err(PolicyRoutingError::PersistenceError("No mode state found - enable Router Mode first".to_string())),
+    };
+    
+    state.local_breakout_fallback = fallback;
+    
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    
+    log::info!("Smart Gateway local breakout fallback set to: {}", fallback);
+    Ok(())
+}
+
+// Active-active exit mode (load balancing)
+// WireGuard picks the peer for a packet by its destination (AllowedIPs), not by route nexthop or fwmark,
+// so flows are spread by splitting 0.0.0.0/0 into equal buckets and giving every healthy exit node a
+// weighted share of them. A destination always maps to the same exit node, which keeps flows sticky.
+// The exit node rules and the exit node's table (default route dev <wg_interface>) stay as they are.
+const LOAD_BALANCE_BUCKET_PREFIX_LEN: u8 = 6; // 64 buckets of /6
+pub const MAX_EXIT_NODE_WEIGHT: u32 = 100;
+
+// Weighted split of 0.0.0.0/0 into buckets (smooth weighted round-robin, so each member's share
+// is spread over the whole address space instead of one contiguous block)
+fn load_balance_shares(members: &[(Uuid, u32)]) -> Vec<(Uuid, Vec<Ipv4Net>)> {
+    let members: Vec<(Uuid, u32)> = members.iter().filter(|(_, weight)| *weight > 0).copied().collect();
+    let mut shares: Vec<(Uuid, Vec<Ipv4Net>)> = members.iter().map(|(id, _)| (*id, Vec::new())).collect();
+    if members.is_empty() {
+        return shares;
+    }
+    
+    let total_weight: i64 = members.iter().map(|(_, weight)| *weight as i64).sum();
+    let mut current = vec![0i64; members.len()];
+    let bucket_size = 1u64 << (32 - LOAD_BALANCE_BUCKET_PREFIX_LEN);
+    for bucket in 0..(1u64 << LOAD_BALANCE_BUCKET_PREFIX_LEN) {
+        for (i, (_, weight)) in members.iter().enumerate() {
+            current[i] += *weight as i64;
+        }
+        let chosen = (0..members.len()).max_by_key(|i| (current[*i], std::cmp::Reverse(*i))).unwrap();
+        current[chosen] -= total_weight;
+        let network = std::net::Ipv4Addr::from((bucket * bucket_size) as u32);
+        shares[chosen].1.push(Ipv4Net::new(network, LOAD_BALANCE_BUCKET_PREFIX_LEN).unwrap());
+    }
+    shares
+}
+
+// AllowedIPs the router configures for a peer without any default route share:
+// the peer's own address plus the other (non-default) IPs of the router's connection to it
+fn router_allowed_ips(peer_id: &Uuid, network: &Network) -> Vec<String> {
+    let Some(peer) = network.peers.get(peer_id) else {
+        return Vec::new();
+    };
+    let peer_addr = format!("{}/32", peer.address);
+    let mut allowed_ips = vec![peer_addr.clone()];
+    for (conn_id, conn_details) in &network.connections {
+        if conn_id.contains(peer_id) && conn_id.contains(&network.this_peer) {
+            let ips = if conn_id.a == network.this_peer {
+                &conn_details.allowed_ips_a_to_b
+            } else {
+                &conn_details.allowed_ips_b_to_a
+            };
+            for ip in ips {
+                let ip_str = ip.to_string();
+                if ip.prefix_len() > 0 && ip_str != peer_addr && !allowed_ips.contains(&ip_str) {
+                    allowed_ips.push(ip_str);
+                }
+            }
+            break;
+        }
+    }
+    for target in probe_allowed_ips(peer_id) {
+        if !allowed_ips.contains(&target) {
+            allowed_ips.push(target);
+        }
+    }
+    allowed_ips
+}
+
+// Load balancing weight of an exit node (missing = 1, 0 = excluded)
+fn exit_node_weight(state: &super::persist::ModeState, peer_id: &Uuid) -> u32 {
+    state.exit_node_weights.get(&peer_id.to_string()).copied().unwrap_or(1)
+}
+
+// Health (online and within the SLA) of monitored peers (unknown counts as healthy)
+fn health_snapshot() -> HashMap<Uuid, bool> {
+    EXIT_NODE_HEALTH_CACHE.read().unwrap().iter().map(|(id, h)| (*id, h.is_healthy())).collect()
+}
+
+// AllowedIPs for every exit node candidate in active-active mode
+// Healthy members with weight > 0 share 0.0.0.0/0; if none is healthy, the exit node keeps all of it
+fn plan_load_balance(state: &super::persist::ModeState, network: &Network, online: &HashMap<Uuid, bool>) -> Vec<(Uuid, Vec<String>)> {
+    let candidates = get_peers_with_default_route(network);
+    let mut members: Vec<(Uuid, u32)> = candidates.iter()
+        .filter(|id| online.get(id).copied().unwrap_or(true))
+        .map(|id| (*id, exit_node_weight(state, id)))
+        .filter(|(_, weight)| *weight > 0)
+        .collect();
+    if members.is_empty() {
+        let exit_node = state.prefix_active_backup.get("0.0.0.0/0")
+            .and_then(|ps| Uuid::parse_str(&ps.active_peer_id).ok())
+            .filter(|id| candidates.contains(id));
+        members.extend(exit_node.map(|id| (id, 1)));
+    }
+    
+    let shares = load_balance_shares(&members);
+    candidates.iter().map(|peer_id| {
+        let mut allowed_ips = router_allowed_ips(peer_id, network);
+        if let Some((_, buckets)) = shares.iter().find(|(id, _)| id == peer_id) {
+            if shares.len() == 1 {
+                allowed_ips.push("0.0.0.0/0".to_string());
+            } else {
+                allowed_ips.extend(buckets.iter().map(|net| net.to_string()));
+            }
+        }
+        (*peer_id, allowed_ips)
+    }).collect()
+}
+
+// Push AllowedIPs to WireGuard, one peer at a time
+fn set_peer_allowed_ips(network: &Network, plan: &[(Uuid, Vec<String>)]) {
+    for (peer_id, allowed_ips) in plan {
+        let Some(peer) = network.peers.get(peer_id) else {
+            continue;
+        };
+        let public_key_b64 = wg_public_key_from_private_key(&peer.private_key).to_base64();
+        if let Err(e) = shell_cmd(&["wg", "set", &network.name, "peer", &public_key_b64, 
+                                    "allowed-ips", &allowed_ips.join(",")]) {
+            log::warn!("Failed to set allowed IPs for exit node {}: {}", peer.name, e);
+        }
+    }
+}
+
+// Redistribute the default route over healthy exit nodes (no-op unless active-active is enabled)
+fn apply_load_balance(network: &Network, online: &HashMap<Uuid, bool>) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    if !state.load_balance {
+        return Ok(());
+    }
+    
+    let plan = plan_load_balance(&state, network, online);
+    set_peer_allowed_ips(network, &plan);
+    if let Err(e) = apply_domain_routes(network) {
+        log::warn!("Failed to restore domain routes after rebalancing: {}", e);
+    }
+    
+    let sharing = plan.iter()
+        .filter(|(peer_id, allowed_ips)| allowed_ips.len() > router_allowed_ips(peer_id, network).len())
+        .count();
+    log::info!("Load balancing the default route across {} of {} exit node(s)", sharing, plan.len());
+    Ok(())
+}
+
+// Get active-active exit mode status and per exit node weights
+pub fn get_load_balance() -> Result<(bool, HashMap<String, u32>), PolicyRoutingError> {
+    let state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) => s,
+        None => return Ok((false, HashMap::new())), // Default to disabled
+    };
+    
+    Ok((state.load_balance, state.exit_node_weights))
+}
+
+// Enable/disable active-active exit mode and optionally replace the weights
+// network: Optional network config to avoid deadlock (if None, will load config)
+pub fn set_load_balance(enabled: bool, weights: Option<HashMap<String, u32>>, network: Option<&Network>) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    if let Some(weights) = &weights {
+        for (peer_id, weight) in weights {
+            Uuid::parse_str(peer_id)
+                .map_err(|e| PolicyRoutingError::LoadBalanceError(format!("Invalid peer ID {}: {}", peer_id, e)))?;
+            if *weight > MAX_EXIT_NODE_WEIGHT {
+                return Err(PolicyRoutingError::LoadBalanceError(
+                    format!("Weight {} for peer {} exceeds the maximum of {}", weight, peer_id, MAX_EXIT_NODE_WEIGHT)
+                ));
+            }
+        }
+    }
+    
+    let loaded_config = match network {
+        Some(_) => None,
+        None => Some(crate::conf::util::get_config()
+            .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?),
+    };
+    let network = network.or(loaded_config.as_ref().map(|c| &c.network)).unwrap();
+    
+    let mut state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) => s,
+        None => return Err(PolicyRoutingError::PersistenceError("No mode state found - enable Router Mode first".to_string())),
+    };
+    if enabled && !state.client_exits.is_empty() {
+        return Err(PolicyRoutingError::LoadBalanceError(
+            "Active-active mode can't be enabled while clients are pinned to exit nodes".to_string()
+        ));
+    }
+    let was_enabled = state.load_balance;
+    state.load_balance = enabled;
+    if let Some(weights) = weights {
+        state.exit_node_weights = weights;
+    }
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    log::info!("Active-active exit mode set to: {} (weights: {:?})", enabled, state.exit_node_weights);
+    
+    if enabled {
+        apply_load_balance(network, &health_snapshot())
+    } else if was_enabled {
+        // Back to a single exit node: take the shares away from the others, then give it 0.0.0.0/0 again
+        let exit_node = state.prefix_active_backup.get("0.0.0.0/0")
+            .and_then(|ps| Uuid::parse_str(&ps.active_peer_id).ok());
+        let plan: Vec<(Uuid, Vec<String>)> = get_peers_with_default_route(network).into_iter()
+            .filter(|id| Some(*id) != exit_node)
+            .map(|id| (id, router_allowed_ips(&id, network)))
+            .collect();
+        set_peer_allowed_ips(network, &plan);
+        match exit_node {
+            Some(exit_node) => set_exit_node(&exit_node, Some(network)),
+            None => Ok(()),
+        }
+    } else {
+        Ok(())
+    }
+}
+
+// Domain-based split tunneling
+// Selected domains are resolved periodically and every IPv4 address they resolve to is routed into the chosen
+// exit node's table with a destination rule (iif <lan_interface> to <address>/32), after the prefix PBR rules
+// (10000+) and before the exit node rules (20000+). The same /32s are added to that exit node's AllowedIPs,
+// which win over the current exit node's 0.0.0.0/0 (or load balancing buckets) in WireGuard's longest-prefix lookup.
+// The set is resolver-driven, not answer-driven: the agent resolves each domain itself instead of following the
+// answers LAN clients get. A client whose resolver answers differently (geo DNS, a CDN, DNS over HTTPS in the
+// browser) can reach addresses outside the set, which leave through the default path; following the answers
+// would need the agent in the clients' DNS path, which it is not.
+const DOMAIN_ROUTE_PRIORITY_BASE: u32 = 15000;
+const DOMAIN_ROUTE_REFRESH_SECS: u64 = 60;
+// Addresses a domain no longer resolves to are kept this long, so DNS rotation does not cut open connections
+const DOMAIN_ROUTE_ADDRESS_TTL_SECS: u64 = 3600;
+// A domain whose answers rotate through a large pool would otherwise pile up /32 rules and AllowedIPs for an hour;
+// past this many addresses the least recently seen ones are dropped
+const DOMAIN_ROUTE_MAX_ADDRESSES: usize = 32;
+// The resolved addresses survive a restart, so the routes of open connections are not withdrawn until re-resolved
+const DOMAIN_ROUTE_ADDRESSES_FILE: &str = "router_domain_routes.json";
+const DOMAIN_ROUTE_ADDRESSES_TEMP_FILE: &str = "router_domain_routes.json.tmp";
+
+type DomainRouteAddresses = HashMap<String, HashMap<Ipv4Addr, u64>>;
+
+// Resolved addresses per domain -> last time they were seen (Unix seconds), loaded from the last snapshot
+static DOMAIN_ROUTE_ADDRESSES: Lazy<RwLock<DomainRouteAddresses>> =
+    Lazy::new(|| RwLock::new(domain_route_addresses_path().map(|path| read_domain_route_addresses(&path)).unwrap_or_default()));
+
+fn domain_route_addresses_path() -> Option<std::path::PathBuf> {
+    crate::WG_QUICKRS_CONFIG_FOLDER.get().map(|folder| folder.join(DOMAIN_ROUTE_ADDRESSES_FILE))
+}
+
+// A missing or unreadable snapshot starts empty (the next resolution fills it again)
+fn read_domain_route_addresses(path: &std::path::Path) -> DomainRouteAddresses {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!("Domain route snapshot {:?} is corrupted ({}). Starting empty.", path, e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+// Temp file and rename, like the health snapshot
+fn write_domain_route_addresses(path: &std::path::Path, addresses: &DomainRouteAddresses) -> std::io::Result<()> {
+    let temp_path = path.with_file_name(DOMAIN_ROUTE_ADDRESSES_TEMP_FILE);
+    std::fs::write(&temp_path, serde_json::to_string(addresses)?)?;
+    std::fs::rename(&temp_path, path)
+}
+
+fn snapshot_domain_route_addresses() {
+    let Some(path) = domain_route_addresses_path() else {
+        return;
+    };
+    if let Err(e) = write_domain_route_addresses(&path, &DOMAIN_ROUTE_ADDRESSES.read().unwrap()) {
+        log::warn!("Failed to snapshot domain route addresses: {}", e);
+    }
+}
+
+// Lowercase, without a trailing dot; only plain host names are accepted (no wildcards)
+fn normalize_domain(domain: &str) -> Result<String, PolicyRoutingError> {
+    let normalized = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let valid = normalized.len() <= 253
+        && normalized.contains('.')
+        && normalized.split('.').all(|label| {
+            !label.is_empty() && label.len() <= 63 && !label.starts_with('-') && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if valid {
+        Ok(normalized)
+    } else {
+        Err(PolicyRoutingError::DomainRouteError(format!("Invalid domain name: {}", domain)))
+    }
+}
+
+// Domain route rule selector: iif <lan_interface> to <address>/32 lookup <peer_table>
+fn is_domain_route_rule(rule: &Rule) -> bool {
+    (DOMAIN_ROUTE_PRIORITY_BASE..DOMAIN_ROUTE_PRIORITY_BASE + 1000).contains(&rule.priority)
+        && is_peer_table(rule.table)
+        && rule.iif.is_some()
+        && rule.from.is_none()
+        && rule.to.is_some_and(|to| to.prefix_len() == 32)
+}
+
+// Remember the addresses a domain resolved to and forget the ones not seen for DOMAIN_ROUTE_ADDRESS_TTL_SECS,
+// or the least recently seen ones past DOMAIN_ROUTE_MAX_ADDRESSES
+fn record_domain_addresses(domain: &str, addresses: &[Ipv4Addr], now: u64) {
+    let mut cache = DOMAIN_ROUTE_ADDRESSES.write().unwrap();
+    let seen = cache.entry(domain.to_string()).or_default();
+    for address in addresses {
+        seen.insert(*address, now);
+    }
+    seen.retain(|_, last_seen| now.saturating_sub(*last_seen) <= DOMAIN_ROUTE_ADDRESS_TTL_SECS);
+    if seen.len() > DOMAIN_ROUTE_MAX_ADDRESSES {
+        let mut by_age: Vec<(Ipv4Addr, u64)> = seen.iter().map(|(address, last_seen)| (*address, *last_seen)).collect();
+        by_age.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        log::warn!("{} resolved to {} addresses, routing the {} most recently seen", domain, by_age.len(), DOMAIN_ROUTE_MAX_ADDRESSES);
+        for (address, _) in &by_age[DOMAIN_ROUTE_MAX_ADDRESSES..] {
+            seen.remove(address);
+        }
+    }
+}
+
+// Addresses currently routed for a domain
+pub fn get_domain_route_addresses(domain: &str) -> Vec<Ipv4Addr> {
+    let mut addresses: Vec<Ipv4Addr> = DOMAIN_ROUTE_ADDRESSES.read().unwrap()
+        .get(domain)
+        .map(|seen| seen.keys().copied().collect())
+        .unwrap_or_default();
+    addresses.sort();
+    addresses
+}
+
+// Destination -> (exit node, table) for every resolved address
+// Domains are visited in order, so an address shared by two domains always goes to the same exit node
+fn plan_domain_routes(state: &super::persist::ModeState, network: &Network) -> BTreeMap<Ipv4Net, (Uuid, u32)> {
+    let exit_candidates = get_peers_with_default_route(network);
+    let cache = DOMAIN_ROUTE_ADDRESSES.read().unwrap();
+    let domains: BTreeMap<&String, &String> = state.domain_routes.iter().collect();
+    let mut plan = BTreeMap::new();
+    for (domain, peer_id_str) in domains {
+        let (Ok(peer_id), Some(table_id)) = (Uuid::parse_str(peer_id_str), state.peer_table_ids.get(peer_id_str)) else {
+            continue; // Peer is not (yet) in Router Mode
+        };
+        if !exit_candidates.contains(&peer_id) {
+            continue; // Its table has no default route to send the traffic to
+        }
+        for address in cache.get(domain).into_iter().flat_map(|seen| seen.keys()) {
+            plan.entry(Ipv4Net::from(*address)).or_insert((peer_id, *table_id));
+        }
+    }
+    plan
+}
+
+// Bring domain route rules and AllowedIPs in line with the resolved addresses
+// AllowedIPs are changed incrementally (+/-), so the rest of the peer's AllowedIPs is left alone
+pub fn apply_domain_routes(network: &Network) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let Some(state) = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))? else {
+        return Ok(());
+    };
+    if state.last_mode != SystemMode::Router {
+        return Ok(());
+    }
+    
+    let plan = plan_domain_routes(&state, network);
+    let backend = routing_backend();
+    let rules = get_ip_rules()?;
+    
+    // Withdraw addresses that expired, moved to another exit node or whose domain was removed
+    let mut withdrawn: Vec<(u32, Ipv4Net)> = Vec::new();
+    for rule in rules.iter().filter(|rule| is_domain_route_rule(rule)) {
+        let destination = rule.to.unwrap();
+        if plan.get(&destination).map(|(_, table_id)| *table_id) == Some(rule.table) {
+            continue;
+        }
+        if let Err(e) = backend.delete_rule(rule) {
+            log::warn!("Failed to delete domain route rule {}: {}", rule, e);
+        }
+        if !plan.contains_key(&destination) {
+            withdrawn.push((rule.table, destination));
+        }
+    }
+    
+    if plan.is_empty() && withdrawn.is_empty() {
+        return Ok(());
+    }
+    let lan_interface = find_lan_interface()?;
+    for (destination, (_, table_id)) in &plan {
+        let rule = Rule {
+            priority: DOMAIN_ROUTE_PRIORITY_BASE + (table_id % 1000),
+            iif: Some(lan_interface.clone()),
+            to: Some(*destination),
+            table: *table_id,
+            ..Default::default()
+        };
+        backend.add_rule(&rule).map_err(|e| PolicyRoutingError::IpRuleError(
+            format!("Failed to install domain route rule for {} -> {}: {}", destination, table_id, e)
+        ))?;
+    }
+    
+    // Adding an address to one peer takes it away from any other, so only expired ones need an explicit removal
+    let mut changes: HashMap<Uuid, Vec<String>> = HashMap::new();
+    for (table_id, destination) in withdrawn {
+        let Some(peer_id) = state.peer_table_ids.iter()
+            .find(|(_, id)| **id == table_id)
+            .and_then(|(peer_id, _)| Uuid::parse_str(peer_id).ok()) else {
+            continue;
+        };
+        if !router_allowed_ips(&peer_id, network).contains(&destination.to_string()) {
+            changes.entry(peer_id).or_default().push(format!("-{}", destination));
+        }
+    }
+    for (destination, (peer_id, _)) in &plan {
+        changes.entry(*peer_id).or_default().push(format!("+{}", destination));
+    }
+    // An exit node pinned to its own interface already accepts 0.0.0.0/0 there
+    let pinned = pinned_exit_interfaces(&state);
+    for (peer_id, allowed_ips) in changes {
+        let Some(peer) = network.peers.get(&peer_id) else {
+            continue;
+        };
+        if pinned.contains_key(&peer_id) {
+            continue;
+        }
+        let public_key_b64 = wg_public_key_from_private_key(&peer.private_key).to_base64();
+        if let Err(e) = shell_cmd(&["wg", "set", &network.name, "peer", &public_key_b64, 
+                                    "allowed-ips", &allowed_ips.join(",")]) {
+            log::warn!("Failed to update domain route allowed IPs for exit node {}: {}", peer.name, e);
+        }
+    }
+    
+    log::debug!("Routing {} domain address(es) through {} exit node(s)", plan.len(),
+        plan.values().map(|(peer_id, _)| peer_id).collect::<std::collections::HashSet<_>>().len());
+    Ok(())
+}
+
+// Resolve every routed domain once (blocking, uses the system resolver) and snapshot the addresses
+fn resolve_domain_routes(state: &super::persist::ModeState) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for domain in state.domain_routes.keys() {
+        let addresses: Vec<Ipv4Addr> = match (domain.as_str(), 0).to_socket_addrs() {
+            Ok(resolved) => resolved
+                .filter_map(|address| match address.ip() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                })
+                .collect(),
+            Err(e) => {
+                log::warn!("Failed to resolve {} for domain routing: {}", domain, e);
+                Vec::new()
+            }
+        };
+        record_domain_addresses(domain, &addresses, now);
+    }
+    snapshot_domain_route_addresses();
+}
+
+// Get routed domains (domain -> exit node peer ID)
+pub fn get_domain_routes() -> Result<HashMap<String, String>, PolicyRoutingError> {
+    let state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) => s,
+        None => return Ok(HashMap::new()),
+    };
+    
+    Ok(state.domain_routes)
+}
+
+// Replace the routed domains (domain -> exit node peer ID), resolve them and apply the routes
+// network: Optional network config to avoid deadlock (if None, will load config)
+pub fn set_domain_routes(routes: HashMap<String, String>, network: Option<&Network>) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let loaded_config = match network {
+        Some(_) => None,
+        None => Some(crate::conf::util::get_config()
+            .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?),
+    };
+    let network = network.or(loaded_config.as_ref().map(|c| &c.network)).unwrap();
+    
+    let exit_candidates = get_peers_with_default_route(network);
+    let mut domain_routes = HashMap::new();
+    for (domain, peer_id) in routes {
+        let domain = normalize_domain(&domain)?;
+        let peer_uuid = Uuid::parse_str(&peer_id)
+            .map_err(|e| PolicyRoutingError::DomainRouteError(format!("Invalid peer ID {}: {}", peer_id, e)))?;
+        if !exit_candidates.contains(&peer_uuid) {
+            return Err(PolicyRoutingError::DomainRouteError(
+                format!("Peer {} does not advertise a default route and cannot carry domain {}", peer_id, domain)
+            ));
+        }
+        domain_routes.insert(domain, peer_uuid.to_string());
+    }
+    
+    let mut state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) => s,
+        None => return Err(PolicyRoutingError::PersistenceError("No mode state found - enable Router Mode first".to_string())),
+    };
+    state.domain_routes = domain_routes;
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    log::info!("Domain routes set to: {:?}", state.domain_routes);
+    
+    DOMAIN_ROUTE_ADDRESSES.write().unwrap().retain(|domain, _| state.domain_routes.contains_key(domain));
+    resolve_domain_routes(&state);
+    apply_domain_routes(network)
+}
+
+// Background task re-resolving routed domains, so the routes follow DNS changes
+pub async fn start_domain_route_resolver() -> std::io::Result<()> {
+    let mut ticker = interval(Duration::from_secs(DOMAIN_ROUTE_REFRESH_SECS));
     
     loop {
         ticker.tick().await;
         
-        // Only monitor if in Router Mode
-        if let Ok(Some(state)) = load_mode_state() {
-            if state.last_mode == SystemMode::Router {
-                if let Ok(config) = crate::conf::util::get_config() {
-                    let wg_interface = config.network.name.clone();
-                    let peers_with_default = get_peers_with_default_route(&config.network);
-                    let network = config.network.clone();
-                    
-                    // Monitor each peer concurrently (spawn tasks to avoid blocking)
-                    for peer_id in peers_with_default {
-                        if let Some(peer) = network.peers.get(&peer_id) {
-                            let peer_id_clone = peer_id;
-                            let peer_clone = peer.clone();
-                            let wg_interface_clone = wg_interface.clone();
-                            let network_clone = network.clone();
-                            let cache = EXIT_NODE_HEALTH_CACHE.clone();
-                            
-                            // Clone peer name for logging
-                            let peer_name = peer.name.clone();
-                            
-                            // Spawn async task to check health (non-blocking)
-                            tokio::spawn(async move {
-                                let health = check_peer_health_impl_async(
-                                    &network_clone,
-                                    peer_id_clone,
-                                    &peer_clone,
-                                    &wg_interface_clone,
-                                ).await;
-                                
-                                // Check for status transition before updating cache
-                                let mut cache = cache.write().unwrap();
-                                let old_health = cache.get(&peer_id_clone);
-                                
-                                // Log status transitions
-                                if let Some(old) = old_health {
-                                    if old.is_online != health.is_online {
-                                        let peer_id_short = &peer_id_clone.to_string()[..8];
-                                        if health.is_online {
-                                            // Offline → Online
-                                            let handshake_info = health.last_handshake
-                                                .and_then(|ts| {
-                                                    // Only show handshake time if it's valid (non-zero and within last year)
-                                                    if ts == 0 {
-                                                        return None;
-                                                    }
-                                                    let now = std::time::SystemTime::now()
-                                                        .duration_since(std::time::UNIX_EPOCH)
-                                                        .map(|d| d.as_secs())
-                                                        .unwrap_or(0);
-                                                    let ago = now.saturating_sub(ts);
-                                                    // If more than a year ago, timestamp is likely invalid
-                                                    if ago > 31536000 {
-                                                        return None;
-                                                    }
-                                                    Some(format!("handshake {}s ago", ago))
-                                                })
-                                                .unwrap_or_else(|| "ping successful".to_string());
-                                            let latency_info = health.latency_ms
-                                                .map(|l| format!(", latency {}ms", l))
-                                                .unwrap_or_default();
-                                            log::info!(
-                                                "Peer {} ({}) status changed: Offline → Online ({}{})",
-                                                peer_name, peer_id_short, handshake_info, latency_info
-                                            );
-                                            
-                                            // Smart Gateway fail-back: Track when primary comes back online
-                                            if let Ok(true) = get_auto_failover() {
-                                                if let Ok(Some(primary_id)) = get_primary_exit_node() {
-                                                    if primary_id == peer_id_clone {
-                                                        let now = std::time::SystemTime::now()
-                                                            .duration_since(std::time::UNIX_EPOCH)
-                                                            .map(|d| d.as_secs())
-                                                            .unwrap_or(0);
-                                                        // Persist the fail-back timer (Bug 1 fix)
-                                                        persist_primary_online_since(Some(peer_id_clone), Some(now));
-                                                        log::info!(
-                                                            "Smart Gateway: Primary {} came back online, will fail-back in {}s if stable",
-                                                            peer_name, FAILBACK_STABILITY_SECS
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        } else {
-                                            // Online → Offline
-                                            let handshake_info = old.last_handshake
+        if let Ok(Some(state)) = load_mode_state()
+            && state.last_mode == SystemMode::Router
+            && !state.domain_routes.is_empty() {
+                let refresh = tokio::task::spawn_blocking(move || {
+                    resolve_domain_routes(&state);
+                    let config = crate::conf::util::get_config()
+                        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?;
+                    apply_domain_routes(&config.network)
+                });
+                match refresh.await {
+                    Ok(Err(e)) => log::warn!("Failed to refresh domain routes: {}", e),
+                    Err(e) => log::warn!("Domain route resolver task failed: {}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+    }
+}
+
+// Get all peers that advertise default route
+// Optimized: Cache routes per peer to avoid redundant computation
+pub fn get_peers_with_default_route(network: &Network) -> Vec<Uuid> {
+    let mut peers = Vec::new();
+    // Cache routes per peer to avoid recomputing for the same peer
+    let mut route_cache: std::collections::HashMap<Uuid, Vec<String>> = std::collections::HashMap::new();
+    
+    for peer_id in network.peers.keys() {
+        // Skip this router itself - it can't be an exit node
+        if *peer_id == network.this_peer {
+            continue;
+        }
+        
+        // Check cache first
+        let routes = route_cache.entry(*peer_id).or_insert_with(|| {
+            get_peer_advertised_routes(peer_id, network)
+        });
+        
+        if routes.contains(&"0.0.0.0/0".to_string()) || routes.contains(&"default".to_string()) {
+            peers.push(*peer_id);
+        }
+    }
+    
+    peers
+}
+
+// Health status for an exit node
+#[derive(Debug, Clone)]
+pub struct ExitNodeHealth {
+    pub peer_id: Uuid,
+    pub is_online: bool,
+    pub last_handshake: Option<u64>, // Unix timestamp in seconds
+    pub first_handshake: Option<u64>, // Unix timestamp in seconds (when peer first came online via ping - used for "Up Since")
+    pub latency_ms: Option<u64>,     // Latency in milliseconds (current/average)
+    pub packet_loss_percent: Option<f64>, // Packet loss percentage (0.0-100.0)
+    pub jitter_ms: Option<u64>,      // Jitter in milliseconds (latency variation)
+    pub transfer_rx: u64,           // Bytes received
+    pub transfer_tx: u64,           // Bytes sent
+    pub endpoint: Option<String>,   // Endpoint address:port
+    pub degraded_reason: Option<String>, // SLA threshold exceeded for sla_hold_secs (online, but down for failover)
+    pub internet_reachable: Option<bool>, // Internet probes through the exit node (None without probes or tunnel)
+}
+
+impl ExitNodeHealth {
+    // Usable for failover and fail-back decisions: reachable and within the SLA thresholds
+    pub fn is_healthy(&self) -> bool {
+        self.is_online && self.degraded_reason.is_none()
+    }
+
+    // As reported by the API (GET /api/mode/exit-node and the live stream)
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "peer_id": self.peer_id.to_string(),
+            "packet_loss_percent": self.packet_loss_percent,
+            "jitter_ms": self.jitter_ms,
+            "is_online": self.is_online,
+            "degraded": self.degraded_reason.is_some(),
+            "degraded_reason": self.degraded_reason,
+            "internet_reachable": self.internet_reachable,
+            "last_handshake": self.last_handshake,
+            "first_handshake": self.first_handshake,
+            "latency_ms": self.latency_ms,
+            "transfer_rx": self.transfer_rx,
+            "transfer_tx": self.transfer_tx,
+            "endpoint": self.endpoint
+        })
+    }
+}
+
+// Get health status for exit nodes (reads from cache, updated by background monitor)
+pub fn get_exit_node_health(network: &Network, _wg_interface: &str) -> Vec<ExitNodeHealth> {
+    let peers_with_default = get_peers_with_default_route(network);
+    let cache = EXIT_NODE_HEALTH_CACHE.read().unwrap();
+    
+    // Return cached health status for peers with default routes (and the local breakout while it is monitored)
+    peers_with_default
+        .iter()
+        .chain(std::iter::once(&LOCAL_BREAKOUT_EXIT_ID))
+        .filter_map(|peer_id| cache.get(peer_id).cloned())
+        .collect()
+}
+
+// Cached health of any monitored peer (exit node candidates and overlapping prefix peers)
+pub fn get_peer_health(peer_id: &Uuid) -> Option<ExitNodeHealth> {
+    EXIT_NODE_HEALTH_CACHE.read().unwrap().get(peer_id).cloned()
+}
+
+// Background health monitoring task (runs continuously, updates cache)
+// Matches OPNsense dpinger behavior: single lightweight ping per exit node every interval (1 second by default)
+pub async fn start_health_monitor() -> std::io::Result<()> {
+    let mut tick_secs = FAILOVER_TUNING.read().unwrap().health_monitor_interval_secs;
+    let mut ticker = interval(Duration::from_secs(tick_secs));
+    // Internet probe targets currently in the exit nodes' AllowedIPs
+    let mut applied_probe_targets: Option<BTreeMap<Uuid, Vec<String>>> = None;
+    
+    loop {
+        ticker.tick().await;
+        
+        // Pick up failover tuning edits in conf.yml (API, CLI or by hand) without a restart
+        match crate::conf::util::reload_router_failover() {
+            Ok(Some(failover)) => {
+                log::debug!("Loaded failover tuning from conf.yml");
+                *FAILOVER_TUNING.write().unwrap() = failover;
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Ignoring failover tuning in conf.yml: {}", e),
+        }
+        super::notify::reload();
+        
+        // Tick as often as the most frequently probed exit node needs
+        let (new_tick_secs, tolerance) = {
+            let tuning = FAILOVER_TUNING.read().unwrap();
+            let secs = tuning.exit_nodes.values()
+                .filter_map(|o| o.health_monitor_interval_secs)
+                .fold(tuning.health_monitor_interval_secs, u64::min);
+            (secs, Duration::from_millis(secs * 500))
+        };
+        if new_tick_secs != tick_secs {
+            log::info!("Health monitor interval changed from {}s to {}s", tick_secs, new_tick_secs);
+            tick_secs = new_tick_secs;
+            ticker = interval(Duration::from_secs(tick_secs));
+            ticker.tick().await;
+        }
+        
+        // Only monitor if in Router Mode
+        if let Ok(Some(state)) = load_mode_state()
+            && state.last_mode == SystemMode::Router
+                && let Ok(config) = crate::conf::util::get_config() {
+                    let wg_interface = config.network.name.clone();
+                    let peers_with_default = get_peers_with_default_route(&config.network);
+                    let network = config.network.clone();
+                    let load_balance = state.load_balance;
+                    let exit_interfaces = pinned_exit_interfaces(&state);
+                    let local_breakout_fallback = state.local_breakout_fallback;
+                    
+                    // Route internet probe targets through their exit node (on start and after tuning changes)
+                    let probe_targets = plan_probe_targets(&network);
+                    if applied_probe_targets.as_ref() != Some(&probe_targets) {
+                        apply_probe_targets(&network, applied_probe_targets.as_ref(), &probe_targets);
+                        applied_probe_targets = Some(probe_targets);
+                    }
+                    
+                    // Exit node candidates plus peers taking part in overlapping prefix active/backup
+                    let mut monitored_peers = peers_with_default.clone();
+                    for peer_id in prefix_arbitration_peers(&state) {
+                        if !monitored_peers.contains(&peer_id) {
+                            monitored_peers.push(peer_id);
+                        }
+                    }
+                    
+                    // The local breakout is probed while it is (or may become) the exit node
+                    let local_breakout_id = LOCAL_BREAKOUT_EXIT_ID.to_string();
+                    let local_breakout_in_use = local_breakout_fallback
+                        || active_gateway_group(&state)
+                            .is_some_and(|(_, group)| group.tiers.iter().flatten().any(|id| *id == local_breakout_id))
+                        || state.prefix_active_backup.get("0.0.0.0/0")
+                            .is_some_and(|ps| ps.active_peer_id == local_breakout_id);
+                    if local_breakout_in_use {
+                        monitored_peers.push(LOCAL_BREAKOUT_EXIT_ID);
+                    } else {
+                        EXIT_NODE_HEALTH_CACHE.write().unwrap().remove(&LOCAL_BREAKOUT_EXIT_ID);
+                    }
+                    
+                    // Monitor each peer concurrently (spawn tasks to avoid blocking)
+                    for peer_id in monitored_peers {
+                        if !probe_due(peer_id, failover_tuning(&peer_id).health_monitor_interval_secs, tolerance) {
+                            continue;
+                        }
+                        if network.peers.contains_key(&peer_id) || peer_id == LOCAL_BREAKOUT_EXIT_ID {
+                            let peer_id_clone = peer_id;
+                            let exit_candidates = peers_with_default.clone();
+                            let gateway_group = active_gateway_group(&state);
+                            let peer_clone = network.peers.get(&peer_id).cloned();
+                            let wg_interface_clone = exit_interfaces.get(&peer_id).cloned().unwrap_or_else(|| wg_interface.clone());
+                            let pinned = exit_interfaces.contains_key(&peer_id);
+                            let network_clone = network.clone();
+                            let cache = EXIT_NODE_HEALTH_CACHE.clone();
+                            
+                            // Clone peer name for logging
+                            let peer_name = exit_node_name(&network, &peer_id);
+                            
+                            // Spawn async task to check health (non-blocking)
+                            tokio::spawn(async move {
+                                let health = match &peer_clone {
+                                    Some(peer) => check_peer_health_impl_async(
+                                        &network_clone,
+                                        peer_id_clone,
+                                        peer,
+                                        &wg_interface_clone,
+                                    ).await,
+                                    None => check_local_breakout_health_async().await,
+                                };
+                                super::history::record(&health);
+                                
+                                // Store the result and work on a snapshot: failover below switches exit nodes,
+                                // which reads the cache again (health_snapshot) and must not wait for this task
+                                let (old_health, cache) = {
+                                    let mut cache = cache.write().unwrap();
+                                    let old_health = cache.insert(peer_id_clone, health.clone());
+                                    (old_health, cache.clone())
+                                };
+                                crate::web::stream::publish(StreamMessage::Health { health: health.to_json() });
+                                let old_health = old_health.as_ref();
+                                // A degraded peer (SLA threshold exceeded) counts as down, like an offline one
+                                let transitioned = old_health.is_some_and(|old| old.is_healthy() != health.is_healthy());
+                                
+                                // Journal every status change, Degraded ↔ Offline included
+                                if let Some(old) = old_health
+                                    && (transitioned || old.is_online != health.is_online) {
+                                        let kind = if health.is_healthy() {
+                                            EventKind::Online
+                                        } else if health.is_online {
+                                            EventKind::Degraded
+                                        } else {
+                                            EventKind::Offline
+                                        };
+                                        events::record(kind, Initiator::Monitor, EventDetails {
+                                            peer_id: Some(peer_id_clone),
+                                            reason: health.degraded_reason.clone(),
+                                            metrics: Some((&health).into()),
+                                            ..Default::default()
+                                        });
+                                    }
+                                
+                                // Degraded ↔ Offline changes nothing for failover, just log them
+                                if let Some(old) = old_health
+                                    && !transitioned && old.is_online != health.is_online {
+                                        log::info!(
+                                            "Peer {} status changed: {} → {}",
+                                            peer_name,
+                                            if old.is_online { "Degraded" } else { "Offline" },
+                                            if health.is_online { "Degraded" } else { "Offline" }
+                                        );
+                                    }
+                                
+                                // Log status transitions
+                                if let Some(old) = old_health
+                                    && transitioned {
+                                        let peer_id_short = &peer_id_clone.to_string()[..8];
+                                        if health.is_healthy() {
+                                            // Offline/Degraded → Online
+                                            let handshake_info = health.last_handshake
                                                 .and_then(|ts| {
                                                     // Only show handshake time if it's valid (non-zero and within last year)
                                                     if ts == 0 {
@@ -1448,25 +2797,65 @@ pub async fn start_health_monitor() -> std::io::Result<()> {
                                                     if ago > 31536000 {
                                                         return None;
                                                     }
-                                                    Some(format!("last handshake was {}s ago", ago))
+                                                    Some(format!("handshake {}s ago", ago))
                                                 })
-                                                .unwrap_or_else(|| "no handshake".to_string());
-                                            let loss_info = health.packet_loss_percent
-                                                .map(|l| format!(", {:.0}% packet loss", l))
+                                                .unwrap_or_else(|| "ping successful".to_string());
+                                            let latency_info = health.latency_ms
+                                                .map(|l| format!(", latency {}ms", l))
                                                 .unwrap_or_default();
-                                            log::warn!(
-                                                "Peer {} ({}) status changed: Online → Offline ({}{})",
-                                                peer_name, peer_id_short, handshake_info, loss_info
+                                            log::info!(
+                                                "Peer {} ({}) status changed: {} → Online ({}{})",
+                                                peer_name, peer_id_short,
+                                                if old.is_online { "Degraded" } else { "Offline" },
+                                                handshake_info, latency_info
                                             );
+
+                                        } else {
+                                            if let Some(reason) = health.degraded_reason.as_ref().filter(|_| health.is_online) {
+                                                // Online → Degraded
+                                                log::warn!(
+                                                    "Peer {} ({}) status changed: Online → Degraded ({})",
+                                                    peer_name, peer_id_short, reason
+                                                );
+                                            } else {
+                                                // Online → Offline
+                                                let handshake_info = old.last_handshake
+                                                    .and_then(|ts| {
+                                                        // Only show handshake time if it's valid (non-zero and within last year)
+                                                        if ts == 0 {
+                                                            return None;
+                                                        }
+                                                        let now = std::time::SystemTime::now()
+                                                            .duration_since(std::time::UNIX_EPOCH)
+                                                            .map(|d| d.as_secs())
+                                                            .unwrap_or(0);
+                                                        let ago = now.saturating_sub(ts);
+                                                        // If more than a year ago, timestamp is likely invalid
+                                                        if ago > 31536000 {
+                                                            return None;
+                                                        }
+                                                        Some(format!("last handshake was {}s ago", ago))
+                                                    })
+                                                    .unwrap_or_else(|| "no handshake".to_string());
+                                                let loss_info = health.packet_loss_percent
+                                                    .map(|l| format!(", {:.0}% packet loss", l))
+                                                    .unwrap_or_default();
+                                                log::warn!(
+                                                    "Peer {} ({}) status changed: Online → Offline ({}{})",
+                                                    peer_name, peer_id_short, handshake_info, loss_info
+                                                );
+                                            }
                                             
                                             // Smart Gateway: Check if this peer is the current exit node and auto-failover is enabled
-                                            if let Ok(Some(current_exit)) = get_exit_node() {
-                                                if current_exit == peer_id_clone {
-                                                    if let Ok(true) = get_auto_failover() {
+                                            // (in active-active mode the rebalance below takes care of it)
+                                            if !load_balance
+                                                && let Ok(Some(current_exit)) = get_exit_node()
+                                                && current_exit == peer_id_clone
+                                                    && let Ok(true) = get_auto_failover() {
                                                         // Bug 3 fix: Don't failover during startup grace period
                                                         if is_in_startup_grace_period() {
                                                             log::info!("Smart Gateway: Skipping failover during startup grace period ({}s remaining)", 
-                                                                STARTUP_GRACE_PERIOD_SECS.saturating_sub(
+                                                                FAILOVER_TUNING.read().unwrap().startup_grace_period_secs.saturating_sub(
                                                                     std::time::SystemTime::now()
                                                                         .duration_since(std::time::UNIX_EPOCH)
                                                                         .map(|d| d.as_secs())
@@ -1474,37 +2863,64 @@ pub async fn start_health_monitor() -> std::io::Result<()> {
                                                                         .saturating_sub(*SERVICE_START_TIME.read().unwrap())
                                                                 ));
                                                         } else {
-                                                        log::info!("Smart Gateway: Current exit node {} went offline, triggering failover...", peer_name);
+                                                        log::info!("Smart Gateway: Current exit node {} went {}, triggering failover...",
+                                                            peer_name, if health.is_online { "degraded" } else { "offline" });
                                                         
-                                                        // Find best healthy alternative from cache
-                                                        let best_alternative = cache.iter()
-                                                            .filter(|(id, h)| **id != peer_id_clone && h.is_online)
-                                                            .min_by_key(|(_, h)| h.latency_ms.unwrap_or(u64::MAX))
-                                                            .map(|(id, h)| (*id, h.latency_ms));
+                                                        // Best healthy alternative from cache: the highest healthy tier of the
+                                                        // active gateway group, otherwise the healthiest exit node
+                                                        let best_alternative = gateway_group.as_ref()
+                                                            .and_then(|(_, group)| best_group_member(group, &cache, Some(&peer_id_clone)))
+                                                            .map(|(_, id)| (id, cache.get(&id).and_then(|h| h.latency_ms)))
+                                                            .or_else(|| cache.iter()
+                                                                .filter(|(id, h)| **id != peer_id_clone && h.is_healthy() && exit_candidates.contains(id))
+                                                                .min_by_key(|(_, h)| h.latency_ms.unwrap_or(u64::MAX))
+                                                                .map(|(id, h)| (*id, h.latency_ms)))
+                                                            .or_else(|| {
+                                                                // Last resort: the local uplink, unless it is known to be down as well
+                                                                let local = cache.get(&LOCAL_BREAKOUT_EXIT_ID);
+                                                                (local_breakout_fallback
+                                                                    && peer_id_clone != LOCAL_BREAKOUT_EXIT_ID
+                                                                    && local.is_none_or(|h| h.is_healthy()))
+                                                                    .then(|| (LOCAL_BREAKOUT_EXIT_ID, local.and_then(|h| h.latency_ms)))
+                                                            });
                                                         
                                                         if let Some((new_exit_id, latency)) = best_alternative {
                                                             // Load config for set_exit_node
                                                             if let Ok(config) = crate::conf::util::get_config() {
-                                                                let new_peer_name = config.network.peers.get(&new_exit_id)
-                                                                    .map(|p| p.name.clone())
-                                                                    .unwrap_or_else(|| new_exit_id.to_string());
+                                                                let new_peer_name = exit_node_name(&config.network, &new_exit_id);
                                                                 
-                                                                // Save current exit as primary before switching (for fail-back)
-                                                                if let Err(e) = set_primary_exit_node(Some(peer_id_clone)) {
-                                                                    log::warn!("Smart Gateway: Failed to save primary exit node: {}", e);
-                                                                }
-                                                                
-                                                                match set_exit_node(&new_exit_id, Some(&config.network)) {
-                                                                    Ok(_) => {
+                                                                // Mode state and kernel changes block: keep them off the async workers
+                                                                let switched = tokio::task::spawn_blocking(move || {
+                                                                    // Without a gateway group, fail back to the current exit node later
+                                                                    if let Err(e) = start_auto_gateway_group(&peer_id_clone) {
+                                                                        log::warn!("Smart Gateway: Failed to remember exit node for fail-back: {}", e);
+                                                                    }
+                                                                    set_exit_node(&new_exit_id, Some(&config.network))
+                                                                }).await;
+                                                                match switched {
+                                                                    Ok(Ok(_)) => {
                                                                         let latency_info = latency.map(|l| format!(" ({}ms)", l)).unwrap_or_default();
                                                                         log::info!(
                                                                             "Smart Gateway: Switched from {} to {}{} (will fail-back after {}s)",
-                                                                            peer_name, new_peer_name, latency_info, FAILBACK_STABILITY_SECS
+                                                                            peer_name, new_peer_name, latency_info, failover_tuning(&peer_id_clone).failback_stability_secs
                                                                         );
+                                                                        events::record(EventKind::Failover, Initiator::Monitor, EventDetails {
+                                                                            old_peer: Some(peer_id_clone),
+                                                                            new_peer: Some(new_exit_id),
+                                                                            reason: Some(match &health.degraded_reason {
+                                                                                Some(reason) if health.is_online => format!("degraded: {}", reason),
+                                                                                _ => "offline".to_string(),
+                                                                            }),
+                                                                            metrics: Some((&health).into()),
+                                                                            ..Default::default()
+                                                                        });
                                                                     }
-                                                                    Err(e) => {
+                                                                    Ok(Err(e)) => {
                                                                         log::error!("Smart Gateway: Failed to switch to {}: {}", new_peer_name, e);
                                                                     }
+                                                                    Err(e) => {
+                                                                        log::error!("Smart Gateway: Switch to {} did not complete: {}", new_peer_name, e);
+                                                                    }
                                                                 }
                                                             }
                                                         } else {
@@ -1512,922 +2928,2858 @@ pub async fn start_health_monitor() -> std::io::Result<()> {
                                                         }
                                                         } // Close the else block for startup grace period check
                                                     }
-                                                }
-                                            }
                                             
-                                            // Clear fail-back tracking if primary went offline
-                                            {
-                                                let tracker = PRIMARY_ONLINE_SINCE.read().unwrap();
-                                                if let Some((tracked_id, _)) = *tracker {
-                                                    if tracked_id == peer_id_clone {
-                                                        drop(tracker);
-                                                        // Persist the cleared timer (Bug 1 fix)
-                                                        persist_primary_online_since(None, None);
-                                                        log::debug!("Smart Gateway: Primary {} went offline, resetting fail-back timer", peer_name);
-                                                    }
-                                                }
+                                            // Overlapping prefixes this peer is active for move to a healthy backup
+                                            if is_in_startup_grace_period() {
+                                                log::debug!("Skipping prefix failover for {} during startup grace period", peer_name);
+                                            } else {
+                                                failover_prefixes_for_peer(&peer_id_clone, &cache);
                                             }
                                         }
                                     }
+
+                                
+                                // Active-active: an exit node joining or leaving changes everyone's share
+                                if load_balance && transitioned && exit_candidates.contains(&peer_id_clone) {
+                                    let online: HashMap<Uuid, bool> = cache.iter().map(|(id, h)| (*id, h.is_healthy())).collect();
+                                    if let Err(e) = apply_load_balance(&network_clone, &online) {
+                                        log::error!("Failed to rebalance exit nodes after {} changed status: {}", peer_name, e);
+                                    }
                                 }
                                 
-                                // Update cache
-                                cache.insert(peer_id_clone, health.clone());
+                                // Clients pinned to this exit node use the exit node while it is offline
+                                if pinned && transitioned {
+                                    let online: HashMap<Uuid, bool> = cache.iter().map(|(id, h)| (*id, h.is_online)).collect();
+                                    if let Err(e) = apply_client_exits(&network_clone, &online) {
+                                        log::error!("Failed to update client exit rules after {} changed status: {}", peer_name, e);
+                                    }
+                                }
                                 
-                                // Smart Gateway fail-back: Check if primary has been online long enough
-                                if let Ok(true) = get_auto_failover() {
-                                    let tracker = PRIMARY_ONLINE_SINCE.read().unwrap();
-                                    if let Some((primary_id, online_since)) = *tracker {
-                                        drop(tracker); // Release lock before doing work
-                                        
-                                        // Only check if this health update is for the primary
-                                        if peer_id_clone == primary_id && health.is_online {
-                                            let now = std::time::SystemTime::now()
-                                                .duration_since(std::time::UNIX_EPOCH)
-                                                .map(|d| d.as_secs())
-                                                .unwrap_or(0);
-                                            let online_duration = now.saturating_sub(online_since);
-                                            
-                                            // Check if stable for FAILBACK_STABILITY_SECS
-                                            if online_duration >= FAILBACK_STABILITY_SECS {
-                                                // Check if we're on a different exit node
-                                                if let Ok(Some(current_exit)) = get_exit_node() {
-                                                    if current_exit != primary_id {
-                                                        log::info!(
-                                                            "Smart Gateway: Primary {} has been online for {}s, triggering fail-back...",
-                                                            peer_name, online_duration
-                                                        );
-                                                        
-                                                        // Load config and switch back
-                                                        if let Ok(config) = crate::conf::util::get_config() {
-                                                            match set_exit_node(&primary_id, Some(&config.network)) {
-                                                                Ok(_) => {
-                                                                    log::info!(
-                                                                        "Smart Gateway: Switched back to primary {}",
-                                                                        peer_name
-                                                                    );
-                                                                    // Clear primary tracking - we're back on primary
-                                                                    let _ = set_primary_exit_node(None);
-                                                                    // Persist the cleared timer (Bug 1 fix)
-                                                                    persist_primary_online_since(None, None);
-                                                                }
-                                                                Err(e) => {
-                                                                    log::error!(
-                                                                        "Smart Gateway: Failed to switch back to primary {}: {}",
-                                                                        peer_name, e
-                                                                    );
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
+                                let now = std::time::SystemTime::now()
+                                    .duration_since(std::time::UNIX_EPOCH)
+                                    .map(|d| d.as_secs())
+                                    .unwrap_or(0);
+                                
+                                // Overlapping prefixes go back to the peer they failed over from once it is stable
+                                let prefix_health = cache.clone();
+                                if let Err(e) = tokio::task::spawn_blocking(move || check_prefix_failback(&prefix_health, now)).await {
+                                    log::error!("Prefix fail-back check did not complete: {}", e);
+                                }
+                                
+                                // Smart Gateway fail-back: climb back to the highest tier that has been healthy long enough
+                                // (the fail-back timer persists across restarts)
+                                if !load_balance {
+                                    match tokio::task::spawn_blocking(move || check_gateway_group_failback(&cache, &network_clone, now)).await {
+                                        Ok(Ok(_)) => {}
+                                        Ok(Err(e)) => log::error!("Smart Gateway: Fail-back failed: {}", e),
+                                        Err(e) => log::error!("Smart Gateway: Fail-back check did not complete: {}", e),
                                     }
                                 }
                             });
                         }
                     }
+                } else {
+                    // Leaving Router Mode resets AllowedIPs, so targets are applied again when it comes back
+                    applied_probe_targets = None;
                 }
+        
+        // Small delay to prevent tight loop
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Async version of check_peer_health_impl (non-blocking)
+async fn check_peer_health_impl_async(
+    _network: &Network,
+    peer_id: Uuid,
+    peer: &wg_quickrs_lib::types::network::Peer,
+    wg_interface: &str,
+) -> ExitNodeHealth {
+    let public_key = wg_public_key_from_private_key(&peer.private_key);
+    let public_key_b64 = public_key.to_base64();
+    
+    // Get WireGuard dump output (async, non-blocking)
+    use tokio::process::Command as TokioCommand;
+    let wg_dump = match TokioCommand::new("wg")
+        .arg("show")
+        .arg(wg_interface)
+        .arg("dump")
+        .output()
+        .await
+    {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).to_string(),
+        _ => {
+            // Return default health if can't get dump
+            // Calculate loss/jitter from existing history if available
+            let ping_history = PING_HISTORY.clone();
+            let history = ping_history.read().unwrap();
+            let (packet_loss_percent, jitter_ms) = if let Some(peer_history) = history.get(&peer_id) {
+                calculate_loss_and_jitter(peer_history)
+            } else {
+                (None, None)
+            };
+            
+            return ExitNodeHealth {
+                peer_id,
+                is_online: false,
+                last_handshake: None,
+                first_handshake: None,
+                latency_ms: None,
+                packet_loss_percent,
+                jitter_ms,
+                transfer_rx: 0,
+                transfer_tx: 0,
+                endpoint: None,
+                degraded_reason: None,
+                internet_reachable: None,
+            };
+        }
+    };
+    
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    
+    // Find this peer in WireGuard dump
+    let mut last_handshake: Option<u64> = None;
+    let mut transfer_rx: u64 = 0;
+    let mut transfer_tx: u64 = 0;
+    let mut endpoint: Option<String> = None;
+    
+    for line in wg_dump.lines().skip(1) {
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() < 8 {
+            continue;
+        }
+        
+        if parts[0] == public_key_b64 {
+            // Parse endpoint (column 2)
+            if parts.len() > 2 && parts[2] != "(none)" && !parts[2].is_empty() && parts[2] != "off" {
+                endpoint = Some(parts[2].to_string());
+            }
+            
+            // Parse last handshake (column 4, in seconds since epoch)
+            if parts.len() > 4 {
+                last_handshake = parts[4].parse::<u64>().ok();
+            }
+            
+            // Parse transfer stats (columns 5 and 6)
+            if parts.len() > 6 {
+                transfer_rx = parts[5].parse::<u64>().unwrap_or(0);
+                transfer_tx = parts[6].parse::<u64>().unwrap_or(0);
+            }
+            break;
+        }
+    }
+    
+    // Tunnel liveness: ping the peer's tunnel IP (peer.address) via the WireGuard interface,
+    // and/or check that it keeps handshaking and sending data (for peers that drop ICMP)
+    let tuning = failover_tuning(&peer_id);
+    let handshake_ok = handshake_alive(peer_id, last_handshake, transfer_rx, tuning.max_handshake_age_secs, now);
+    let (tunnel_up, latency_ms) = match tuning.liveness {
+        Liveness::Ping => check_peer_connectivity_async(&peer.address.to_string(), wg_interface).await,
+        Liveness::Handshake => (handshake_ok, None),
+        Liveness::Hybrid => {
+            let (ping_succeeded, latency_ms) = check_peer_connectivity_async(&peer.address.to_string(), wg_interface).await;
+            (ping_succeeded || handshake_ok, latency_ms)
+        }
+    };
+    
+    // A tunnel without internet behind it counts as a failed probe
+    let internet_reachable = if tunnel_up { check_internet_async(peer_id).await } else { None };
+    let probe_succeeded = tunnel_up && internet_reachable != Some(false);
+    
+    let ProbeOutcome { is_online, first_handshake, packet_loss_percent, jitter_ms, degraded_reason } =
+        record_probe_result(peer_id, probe_succeeded, latency_ms.filter(|_| probe_succeeded), now);
+    
+    // Over a traffic quota that blocks exit use (block_exit, disconnect): down for failover like an SLA breach
+    let degraded_reason = degraded_reason.or_else(|| crate::wireguard::usage::exit_blocked(&peer_id));
+    
+    ExitNodeHealth {
+        peer_id,
+        is_online,
+        last_handshake,
+        first_handshake,
+        latency_ms,
+        packet_loss_percent,
+        jitter_ms,
+        transfer_rx,
+        transfer_tx,
+        endpoint,
+        degraded_reason,
+        internet_reachable,
+    }
+}
+
+// Health of one probe after the offline threshold, with loss/jitter over the ping history, "Up Since"
+// and the SLA threshold exceeded for longer than sla_hold_secs (if any)
+struct ProbeOutcome {
+    is_online: bool,
+    first_handshake: Option<u64>,
+    packet_loss_percent: Option<f64>,
+    jitter_ms: Option<u64>,
+    degraded_reason: Option<String>,
+}
+
+// Record a probe result of a monitored peer (or the local breakout): failure threshold, ping history,
+// session "Up Since" and the last online state / last successful ping (in the runtime health store)
+fn record_probe_result(peer_id: Uuid, ping_succeeded: bool, latency_ms: Option<u64>, now: u64) -> ProbeOutcome {
+    // first_handshake is session-only (resets on restart)
+    let previous = super::runtime::peer(&peer_id);
+    let tuning = failover_tuning(&peer_id);
+    
+    // Apply consecutive failures threshold for offline detection
+    // Peer is only marked offline after `consecutive_failures` consecutive failures
+    let consecutive_failures = CONSECUTIVE_FAILURES.clone();
+    let is_online = {
+        let mut failures = consecutive_failures.write().unwrap();
+        let failure_count = failures.entry(peer_id).or_insert(0);
+        
+        if ping_succeeded {
+            // Ping succeeded - reset failure counter, peer is online
+            *failure_count = 0;
+            true
+        } else {
+            // Ping failed - increment counter
+            *failure_count = failure_count.saturating_add(1);
+            // Only mark offline after threshold consecutive failures
+            *failure_count < tuning.consecutive_failures
+        }
+    };
+    
+    // Track ping history for loss and jitter calculation (like OPNsense dpinger)
+    // Note: Uses ping_succeeded (actual ping result) not is_online (threshold-based status)
+    let ping_history = PING_HISTORY.clone();
+    let mut history = ping_history.write().unwrap();
+    let peer_history = history.entry(peer_id).or_insert_with(|| VecDeque::with_capacity(tuning.ping_history_size));
+    
+    // Add current ping result to history
+    peer_history.push_back(PingResult {
+        timestamp: now,
+        latency_ms, // None if ping_succeeded is false
+    });
+    
+    // Keep only the last `ping_history_size` results (60 by default, like OPNsense)
+    while peer_history.len() > tuning.ping_history_size {
+        peer_history.pop_front();
+    }
+    
+    // Calculate packet loss and jitter from history (like OPNsense dpinger)
+    let (packet_loss_percent, jitter_ms) = calculate_loss_and_jitter(peer_history);
+    let average_latency_ms = average_latency(peer_history);
+    
+    // Release lock before continuing
+    drop(history);
+    
+    // SLA: an online peer exceeding a threshold for sla_hold_secs is degraded (down for failover)
+    let degraded_reason = {
+        let mut violation_since = SLA_VIOLATION_SINCE.write().unwrap();
+        match sla_violation(&tuning, average_latency_ms, packet_loss_percent, jitter_ms).filter(|_| is_online) {
+            Some(reason) => {
+                let since = *violation_since.entry(peer_id).or_insert(now);
+                (now.saturating_sub(since) >= tuning.sla_hold_secs).then_some(reason)
+            }
+            None => {
+                violation_since.remove(&peer_id);
+                None
+            }
+        }
+    };
+    
+    // Track "Up Since" based on ping-based online status
+    // "Up Since" resets on restart and tracks when peer came online in current session
+    let was_online_previously = previous.online;
+    
+    // Get session-only "Up Since" tracking (in-memory, resets on restart)
+    let session_up_since = SESSION_UP_SINCE.clone();
+    let first_handshake = {
+        if is_online {
+            // Track when peer first came online in this session (after restart or offline→online transition)
+            let mut session_up_since_map = session_up_since.write().unwrap();
+            if !was_online_previously {
+                // Peer transitioned from offline to online - set "Up Since" to now
+                session_up_since_map.insert(peer_id, now);
+                Some(now)
+            } else {
+                // Peer was already online - get existing "Up Since" from session, or set to now if not tracked
+                session_up_since_map.get(&peer_id).copied().or_else(|| {
+                    // First time seeing this peer online in this session (e.g., after restart)
+                    session_up_since_map.insert(peer_id, now);
+                    Some(now)
+                })
+            }
+        } else {
+            // Peer is offline - remove from SESSION_UP_SINCE so next online transition gets fresh timestamp
+            let mut session_up_since_map = session_up_since.write().unwrap();
+            session_up_since_map.remove(&peer_id);
+            
+            // Return last successful ping time (when it was last seen online) for "Down Since" display
+            previous.last_successful_ping
+        }
+    };
+    
+    // Update the last known online state and, while online, the last successful ping time
+    // (in memory; the store writes batched snapshots instead of router_mode_state.json)
+    super::runtime::update(peer_id, super::runtime::PeerRuntime {
+        online: is_online,
+        last_successful_ping: if is_online { Some(now) } else { previous.last_successful_ping },
+    });
+    
+    ProbeOutcome {
+        is_online,
+        first_handshake,
+        packet_loss_percent,
+        jitter_ms,
+        degraded_reason,
+    }
+}
+
+// Health of the local breakout: ping a public address out of the uplink
+async fn check_local_breakout_health_async() -> ExitNodeHealth {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    
+    let uplink = uplink_default_route().ok();
+    let (ping_succeeded, latency_ms) = match &uplink {
+        Some(route) => check_peer_connectivity_async(LOCAL_BREAKOUT_PROBE_TARGET, &route.device).await,
+        None => (false, None),
+    };
+    let internet_reachable = if ping_succeeded { check_internet_async(LOCAL_BREAKOUT_EXIT_ID).await } else { None };
+    let probe_succeeded = ping_succeeded && internet_reachable != Some(false);
+    let ProbeOutcome { is_online, first_handshake, packet_loss_percent, jitter_ms, degraded_reason } =
+        record_probe_result(LOCAL_BREAKOUT_EXIT_ID, probe_succeeded, latency_ms.filter(|_| probe_succeeded), now);
+    
+    ExitNodeHealth {
+        peer_id: LOCAL_BREAKOUT_EXIT_ID,
+        is_online,
+        last_handshake: None,
+        first_handshake,
+        latency_ms,
+        packet_loss_percent,
+        jitter_ms,
+        transfer_rx: 0,
+        transfer_tx: 0,
+        endpoint: uplink.map(|route| match route.gateway {
+            Some(gateway) => format!("{} via {}", route.device, gateway),
+            None => route.device,
+        }),
+        degraded_reason,
+        internet_reachable,
+    }
+}
+
+// Internet probes (HTTP, DNS, TCP) through an exit node
+// The probe sockets carry the exit node's table ID as SO_MARK, and a `fwmark <table> lookup <table>` rule
+// (14000+) sends them into its table and so into the tunnel. WireGuard then picks the peer by destination,
+// so every probe target is also added to that exit node's AllowedIPs as a /32 (like a domain route):
+// LAN traffic to a probe target leaves through that exit node as well.
+const PROBE_RULE_PRIORITY_BASE: u32 = 14000;
+const INTERNET_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn probe_rule(table_id: u32) -> Rule {
+    Rule {
+        priority: PROBE_RULE_PRIORITY_BASE + (table_id % 1000),
+        fwmark: Some(table_id),
+        table: table_id,
+        ..Default::default()
+    }
+}
+
+// Probe targets routed through a tunnel exit node, as AllowedIPs entries
+fn probe_allowed_ips(peer_id: &Uuid) -> Vec<String> {
+    if *peer_id == LOCAL_BREAKOUT_EXIT_ID {
+        return Vec::new();
+    }
+    let tuning = FAILOVER_TUNING.read().unwrap();
+    let mut targets: Vec<String> = tuning.probes_for(peer_id).iter()
+        .filter_map(|probe| probe.target())
+        .map(|target| Ipv4Net::from(target).to_string())
+        .collect();
+    targets.sort();
+    targets.dedup();
+    targets
+}
+
+// Probe targets of every exit node candidate
+fn plan_probe_targets(network: &Network) -> BTreeMap<Uuid, Vec<String>> {
+    get_peers_with_default_route(network).into_iter()
+        .map(|peer_id| (peer_id, probe_allowed_ips(&peer_id)))
+        .filter(|(_, targets)| !targets.is_empty())
+        .collect()
+}
+
+// Move probe targets in and out of the exit nodes' AllowedIPs after the tuning changed
+// (`previous` is None the first time, when nothing is known to be installed)
+fn apply_probe_targets(network: &Network, previous: Option<&BTreeMap<Uuid, Vec<String>>>, current: &BTreeMap<Uuid, Vec<String>>) {
+    let empty = BTreeMap::new();
+    let previous = previous.unwrap_or(&empty);
+    let peers: std::collections::BTreeSet<&Uuid> = previous.keys().chain(current.keys()).collect();
+    for peer_id in peers {
+        let before = previous.get(peer_id).cloned().unwrap_or_default();
+        let after = current.get(peer_id).cloned().unwrap_or_default();
+        // router_allowed_ips already holds the current targets, so only stale ones are removed
+        let keep = router_allowed_ips(peer_id, network);
+        let mut changes: Vec<String> = after.iter().filter(|t| !before.contains(t)).map(|t| format!("+{}", t)).collect();
+        changes.extend(before.iter().filter(|t| !keep.contains(t)).map(|t| format!("-{}", t)));
+        if after.is_empty()
+            && let Ok(Some(table_id)) = get_peer_table_id(peer_id) {
+                let _ = routing_backend().delete_rule(&probe_rule(table_id));
+            }
+        if changes.is_empty() {
+            continue;
+        }
+        let Some(peer) = network.peers.get(peer_id) else {
+            continue;
+        };
+        let public_key_b64 = wg_public_key_from_private_key(&peer.private_key).to_base64();
+        if let Err(e) = shell_cmd(&["wg", "set", &network.name, "peer", &public_key_b64,
+                                    "allowed-ips", &changes.join(",")]) {
+            log::warn!("Failed to update probe target allowed IPs for exit node {}: {}", peer.name, e);
+        } else {
+            log::info!("Internet probe targets through exit node {}: {}", peer.name,
+                if after.is_empty() { "none".to_string() } else { after.join(", ") });
+        }
+    }
+}
+
+// Run the internet probes of an exit node (or the local breakout) through its table
+// None without probes; Some(true) as soon as one target answers
+async fn check_internet_async(peer_id: Uuid) -> Option<bool> {
+    let probes = FAILOVER_TUNING.read().unwrap().probes_for(&peer_id).to_vec();
+    if probes.is_empty() {
+        return None;
+    }
+    let table_id = if peer_id == LOCAL_BREAKOUT_EXIT_ID {
+        LOCAL_BREAKOUT_TABLE
+    } else {
+        match get_peer_table_id(&peer_id) {
+            Ok(Some(table_id)) => table_id,
+            _ => return Some(false),
+        }
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        // Idempotent, and survives the table being recreated
+        if let Err(e) = routing_backend().add_rule(&probe_rule(table_id)) {
+            log::warn!("Failed to install internet probe rule for table {}: {}", table_id, e);
+        }
+        probes.iter().any(|probe| match super::prober::probe_internet(probe, table_id, INTERNET_PROBE_TIMEOUT) {
+            Ok(_) => true,
+            Err(e) => {
+                log::debug!("Internet probe {} through {} failed: {}", probe, peer_id, e);
+                false
+            }
+        })
+    }).await;
+    Some(result.unwrap_or(false))
+}
+
+// Reply timeout of a single probe (like `ping -W 1`)
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Async version: Check peer connectivity and measure latency (non-blocking)
+// Returns (is_online, latency_ms)
+// Probes the peer's tunnel IP (peer.address) via the WireGuard interface with the native prober
+// Uses the WireGuard interface directly to avoid routing table issues when switching gateways
+// Matches OPNsense dpinger: single lightweight probe with a short timeout
+async fn check_peer_connectivity_async(peer_address: &str, wg_interface: &str) -> (bool, Option<u64>) {
+    let Ok(target) = peer_address.parse::<std::net::Ipv4Addr>() else {
+        return (false, None);
+    };
+    let interface = wg_interface.to_string();
+    let result = tokio::task::spawn_blocking(move || {
+        super::prober::probe(target, &interface, PROBE_TIMEOUT)
+    }).await;
+    
+    match result {
+        // Microsecond RTT, reported to the nearest millisecond
+        Ok(Ok(Some(rtt))) => (true, Some((rtt.as_micros() as u64 + 500) / 1000)),
+        Ok(Ok(None)) => (false, None),
+        Ok(Err(e)) => {
+            log::debug!("Probe of {} via {} failed: {}", peer_address, wg_interface, e);
+            (false, None)
+        }
+        Err(e) => {
+            log::warn!("Probe task for {} failed: {}", peer_address, e);
+            (false, None)
+        }
+    }
+}
+
+// Helper: Find LAN interface (cached)
+// Picks the interface holding an address inside the LAN CIDR(s), falling back to common interface names
+pub fn find_lan_interface() -> Result<String, PolicyRoutingError> {
+    // Check cache first
+    {
+        let cache = LAN_INTERFACE_CACHE.lock().unwrap();
+        if let Some(ref cached) = *cache {
+            return Ok(cached.clone());
+        }
+    }
+    
+    // Not cached, detect interface
+    let lan_cidr = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(state) => state.lan_cidr,
+        None => None,
+    };
+    
+    let backend = routing_backend();
+    let mut interface = None;
+    
+    if let Some(cidr) = lan_cidr {
+        let lan_nets = parse_lan_nets(&cidr);
+        let addresses = backend.list_interface_addresses()
+            .map_err(|e| PolicyRoutingError::IpRuleError(format!("Failed to list interfaces: {}", e)))?;
+        
+        interface = addresses.into_iter()
+            .filter(|a| a.interface != "lo")
+            .find(|a| lan_nets.iter().any(|net| net.contains(&a.address.addr())))
+            .map(|a| a.interface);
+    }
+    
+    // Fallback to common interface names
+    if interface.is_none() {
+        interface = ["eth0", "ens3", "enp0s3", "enp1s0"].iter()
+            .find(|iface| backend.interface_exists(iface).unwrap_or(false))
+            .map(|iface| iface.to_string());
+    }
+    
+    // Default
+    let interface = interface.unwrap_or_else(|| "eth0".to_string());
+    
+    // Cache the result
+    {
+        let mut cache = LAN_INTERFACE_CACHE.lock().unwrap();
+        *cache = Some(interface.clone());
+    }
+    
+    Ok(interface)
+}
+
+// Set active peer for overlapping prefix
+// The default route is handled by the exit node logic, any other prefix by active/backup arbitration
+pub fn set_active_peer_for_prefix(
+    prefix: &str,
+    active_peer_id: &str,
+    backup_peer_ids: &[String],
+) -> Result<(), PolicyRoutingError> {
+    let active_peer = Uuid::parse_str(active_peer_id)
+        .map_err(|e| PolicyRoutingError::PrefixError(format!("Invalid peer ID {}: {}", active_peer_id, e)))?;
+    
+    if prefix == "0.0.0.0/0" || prefix == "default" {
+        return set_exit_node(&active_peer, None); // Load config if needed
+    }
+    
+    let config = crate::conf::util::get_config()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?;
+    set_active_peer_for_prefix_impl(prefix, &active_peer, backup_peer_ids, &config.network)
+}
+
+// Internal implementation that accepts network reference to avoid deadlock
+fn set_active_peer_for_prefix_impl(
+    prefix: &str,
+    active_peer: &Uuid,
+    backup_peer_ids: &[String],
+    network: &Network,
+) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let prefix_net = Ipv4Net::from_str(prefix)
+        .map_err(|e| PolicyRoutingError::PrefixError(format!("Invalid prefix {}: {}", prefix, e)))?
+        .trunc();
+    
+    let overlapping = get_overlapping_prefixes(network);
+    let advertisers = overlapping.get(&prefix_net).ok_or_else(|| PolicyRoutingError::PrefixError(
+        format!("Prefix {} is not advertised by more than one peer", prefix_net)
+    ))?;
+    if !advertisers.contains(active_peer) {
+        return Err(PolicyRoutingError::PrefixError(
+            format!("Peer {} does not advertise prefix {}", active_peer, prefix_net)
+        ));
+    }
+    
+    // Requested backups first (in the given order), then every other advertiser
+    let mut backups = Vec::new();
+    for backup_peer_id in backup_peer_ids {
+        let backup_peer = Uuid::parse_str(backup_peer_id)
+            .map_err(|e| PolicyRoutingError::PrefixError(format!("Invalid peer ID {}: {}", backup_peer_id, e)))?;
+        if !advertisers.contains(&backup_peer) {
+            return Err(PolicyRoutingError::PrefixError(
+                format!("Peer {} does not advertise prefix {}", backup_peer, prefix_net)
+            ));
+        }
+        if backup_peer != *active_peer && !backups.contains(&backup_peer) {
+            backups.push(backup_peer);
+        }
+    }
+    for advertiser in advertisers {
+        if advertiser != active_peer && !backups.contains(advertiser) {
+            backups.push(*advertiser);
+        }
+    }
+    
+    let mut state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    
+    state.prefix_active_backup.insert(prefix_net.to_string(), super::persist::PrefixState {
+        active_peer_id: active_peer.to_string(),
+        backup_peer_ids: backups.iter().map(|id| id.to_string()).collect(),
+        ..Default::default()
+    });
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    
+    apply_prefix_active_backup(&state, prefix_net)?;
+    log::info!("Set peer {} as active for prefix {} (backups: {:?})", active_peer, prefix_net, backups);
+    Ok(())
+}
+
+// Non-default prefixes advertised by more than one peer, with the peers advertising them
+// Peers are ordered by routing table (the peer that joined Router Mode first comes first)
+pub fn get_overlapping_prefixes(network: &Network) -> std::collections::BTreeMap<Ipv4Net, Vec<Uuid>> {
+    let table_ids = load_mode_state().ok().flatten()
+        .map(|s| s.peer_table_ids)
+        .unwrap_or_default();
+    
+    let mut advertisers: std::collections::BTreeMap<Ipv4Net, Vec<Uuid>> = std::collections::BTreeMap::new();
+    for peer_id in network.peers.keys() {
+        if *peer_id == network.this_peer {
+            continue;
+        }
+        for route in get_peer_advertised_routes(peer_id, network) {
+            if route == "0.0.0.0/0" || route == "default" {
+                continue;
+            }
+            if let Ok(net) = Ipv4Net::from_str(&route) {
+                advertisers.entry(net.trunc()).or_default().push(*peer_id);
+            }
+        }
+    }
+    
+    advertisers.retain(|_, peers| peers.len() > 1);
+    for peers in advertisers.values_mut() {
+        peers.sort_by_key(|id| table_ids.get(&id.to_string()).copied().unwrap_or(u32::MAX));
+    }
+    advertisers
+}
+
+// Peers with a non-default prefix entry in the active/backup state (active and backups)
+fn prefix_arbitration_peers(state: &super::persist::ModeState) -> Vec<Uuid> {
+    let mut peers = Vec::new();
+    for (prefix, prefix_state) in &state.prefix_active_backup {
+        if prefix == "0.0.0.0/0" {
+            continue;
+        }
+        for peer_id in std::iter::once(&prefix_state.active_peer_id).chain(&prefix_state.backup_peer_ids) {
+            if let Ok(id) = Uuid::parse_str(peer_id)
+                && !peers.contains(&id) {
+                    peers.push(id);
+                }
+        }
+    }
+    peers
+}
+
+// Prefixes for which the peer is a backup (its PBR rule must not be installed)
+fn backup_prefixes_for_peer(state: &super::persist::ModeState, peer_id: &str) -> Vec<String> {
+    state.prefix_active_backup.iter()
+        .filter(|(prefix, prefix_state)| {
+            prefix.as_str() != "0.0.0.0/0" && prefix_state.backup_peer_ids.iter().any(|id| id == peer_id)
+        })
+        .map(|(prefix, _)| prefix.clone())
+        .collect()
+}
+
+// Program the kernel for one overlapping prefix: PBR rule to the active peer's table,
+// none to the backups' tables. The new rule goes in before the old ones are removed.
+fn apply_prefix_active_backup(state: &super::persist::ModeState, prefix: Ipv4Net) -> Result<(), PolicyRoutingError> {
+    let Some(prefix_state) = state.prefix_active_backup.get(&prefix.to_string()) else {
+        return Ok(());
+    };
+    let active_table = state.peer_table_ids.get(&prefix_state.active_peer_id).copied()
+        .ok_or_else(|| PolicyRoutingError::TableIdError(
+            format!("No routing table for active peer {} of prefix {}", prefix_state.active_peer_id, prefix)
+        ))?;
+    let backup_tables: Vec<u32> = prefix_state.backup_peer_ids.iter()
+        .filter_map(|id| state.peer_table_ids.get(id).copied())
+        .collect();
+    
+    let lan_interface = find_lan_interface()?;
+    let active_rule = Rule {
+        priority: 10000 + (active_table % 1000),
+        iif: Some(lan_interface.clone()),
+        to: Some(prefix),
+        table: active_table,
+        ..Default::default()
+    };
+    routing_backend().add_rule(&active_rule).map_err(|e| PolicyRoutingError::IpRuleError(
+        format!("Failed to install PBR rule for {} -> {}: {}", prefix, active_table, e)
+    ))?;
+    
+    let rules = get_ip_rules()?;
+    let removed = delete_matching_rules(&rules, |rule| {
+        rule.iif.as_deref() == Some(lan_interface.as_str())
+            && rule.to == Some(prefix)
+            && backup_tables.contains(&rule.table)
+    });
+    log::debug!("Prefix {} -> table {} (removed {} backup rule(s))", prefix, active_table, removed);
+    Ok(())
+}
+
+// Reconcile active/backup state for overlapping prefixes with the current network:
+// new overlaps get an active peer (first healthy advertiser), gone advertisers are dropped,
+// and prefixes that no longer overlap get their remaining peer's rule back
+pub fn sync_prefix_active_backup(network: &Network) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let mut state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    
+    let overlapping = get_overlapping_prefixes(network);
+    let health: HashMap<Uuid, bool> = EXIT_NODE_HEALTH_CACHE.read().unwrap().iter()
+        .map(|(id, h)| (*id, h.is_healthy()))
+        .collect();
+    
+    // Entries for prefixes that no longer overlap
+    let released: Vec<Ipv4Net> = state.prefix_active_backup.keys()
+        .filter(|prefix| prefix.as_str() != "0.0.0.0/0")
+        .filter_map(|prefix| Ipv4Net::from_str(prefix).ok())
+        .filter(|prefix| !overlapping.contains_key(prefix))
+        .collect();
+    for prefix in &released {
+        state.prefix_active_backup.remove(&prefix.to_string());
+    }
+    
+    for (prefix, advertisers) in &overlapping {
+        // Only peers with a routing table can be selected
+        let candidates: Vec<Uuid> = advertisers.iter()
+            .filter(|id| state.peer_table_ids.contains_key(&id.to_string()))
+            .copied()
+            .collect();
+        if candidates.len() < 2 {
+            continue;
+        }
+        
+        let existing = state.prefix_active_backup.get(&prefix.to_string());
+        let current_active = existing
+            .and_then(|ps| Uuid::parse_str(&ps.active_peer_id).ok())
+            .filter(|id| candidates.contains(id));
+        let active = current_active.unwrap_or_else(|| {
+            // Unknown health counts as healthy (monitor may not have run yet)
+            candidates.iter()
+                .find(|id| health.get(id).copied().unwrap_or(true))
+                .copied()
+                .unwrap_or(candidates[0])
+        });
+        
+        // Keep the existing order (a replaced active peer first), then append new advertisers
+        let previous = existing.into_iter()
+            .flat_map(|ps| std::iter::once(&ps.active_peer_id).chain(&ps.backup_peer_ids))
+            .filter_map(|id| Uuid::parse_str(id).ok());
+        let mut backups: Vec<Uuid> = Vec::new();
+        for candidate in previous.chain(candidates.iter().copied()) {
+            if candidate != active && candidates.contains(&candidate) && !backups.contains(&candidate) {
+                backups.push(candidate);
+            }
+        }
+        
+        if current_active.is_none() {
+            log::info!("Overlapping prefix {}: peer {} is active, {} backup(s)", prefix, active, backups.len());
+        }
+        // A pending fail-back holds while the peer it goes back to still advertises the prefix
+        let failback = existing
+            .filter(|ps| ps.failed_over_from.as_ref()
+                .is_some_and(|id| backups.iter().any(|backup| backup.to_string() == *id)))
+            .map(|ps| (ps.failed_over_from.clone(), ps.failback_since))
+            .unwrap_or_default();
+        state.prefix_active_backup.insert(prefix.to_string(), super::persist::PrefixState {
+            active_peer_id: active.to_string(),
+            backup_peer_ids: backups.iter().map(|id| id.to_string()).collect(),
+            failed_over_from: failback.0,
+            failback_since: failback.1,
+        });
+    }
+    
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    
+    for prefix in overlapping.keys() {
+        if let Err(e) = apply_prefix_active_backup(&state, *prefix) {
+            log::warn!("Failed to apply active/backup for prefix {}: {}", prefix, e);
+        }
+    }
+    
+    // A released prefix is advertised by at most one peer now; restore its rule
+    if !released.is_empty() {
+        let lan_interface = find_lan_interface()?;
+        for prefix in &released {
+            for peer_id in network.peers.keys().filter(|id| **id != network.this_peer) {
+                let routes = get_peer_advertised_routes(peer_id, network);
+                if let Some(&table_id) = state.peer_table_ids.get(&peer_id.to_string())
+                    && routes.iter().any(|r| Ipv4Net::from_str(r).is_ok_and(|net| net.trunc() == *prefix)) {
+                        install_pbr_rules_for_peer(peer_id, table_id, &[prefix.to_string()], &lan_interface)?;
+                    }
+            }
+            log::info!("Prefix {} no longer overlaps, released active/backup state", prefix);
+        }
+    }
+    
+    Ok(())
+}
+
+// Health-driven failover for overlapping prefixes: move every prefix the offline peer is active for
+// to its first healthy backup. The failed peer becomes the first backup, and the prefix fails back to the
+// peer it first failed over from once that one is stable again (check_prefix_failback).
+// Called by the health monitor with a snapshot of the health cache
+fn failover_prefixes_for_peer(peer_id: &Uuid, health: &HashMap<Uuid, ExitNodeHealth>) {
+    let _routing = routing_lock();
+    let Ok(Some(mut state)) = load_mode_state() else {
+        return;
+    };
+    let peer_id_str = peer_id.to_string();
+    
+    let mut switched = Vec::new();
+    for (prefix, prefix_state) in state.prefix_active_backup.iter_mut() {
+        if prefix == "0.0.0.0/0" || prefix_state.active_peer_id != peer_id_str {
+            continue;
+        }
+        let healthy_backup = prefix_state.backup_peer_ids.iter()
+            .position(|id| Uuid::parse_str(id).ok()
+                .and_then(|uuid| health.get(&uuid))
+                .is_some_and(|h| h.is_healthy()));
+        match healthy_backup {
+            Some(index) => {
+                let new_active = prefix_state.backup_peer_ids.remove(index);
+                prefix_state.backup_peer_ids.insert(0, peer_id_str.clone());
+                log::info!("Prefix {}: active peer {} went down, failing over to {}", prefix, peer_id_str, new_active);
+                events::record(EventKind::PrefixFailover, Initiator::Monitor, EventDetails {
+                    old_peer: Some(*peer_id),
+                    new_peer: Uuid::parse_str(&new_active).ok(),
+                    reason: Some(format!("prefix {}", prefix)),
+                    metrics: health.get(peer_id).map(EventMetrics::from),
+                    ..Default::default()
+                });
+                prefix_state.active_peer_id = new_active;
+                // Failing over again (from the backup) still goes back to the original peer
+                if prefix_state.failed_over_from.is_none() {
+                    prefix_state.failed_over_from = Some(peer_id_str.clone());
+                }
+                prefix_state.failback_since = None;
+                if let Ok(net) = Ipv4Net::from_str(prefix) {
+                    switched.push(net);
+                }
+            }
+            None => log::warn!("Prefix {}: active peer {} went down and no healthy backup is available", prefix, peer_id_str),
+        }
+    }
+    
+    if switched.is_empty() {
+        return;
+    }
+    if let Err(e) = save_mode_state(&state) {
+        log::warn!("Failed to save prefix failover state: {}", e);
+        return;
+    }
+    for prefix in switched {
+        if let Err(e) = apply_prefix_active_backup(&state, prefix) {
+            log::error!("Failed to fail over prefix {}: {}", prefix, e);
+        }
+    }
+}
+
+// Prefix fail-back: once the peer a prefix failed over from has been healthy for failback_stability_secs,
+// it is active again and the peer it failed over to becomes the first backup
+// Called by the health monitor after every health update (with a snapshot of the health cache)
+fn check_prefix_failback(health: &HashMap<Uuid, ExitNodeHealth>, now: u64) {
+    let _routing = routing_lock();
+    let Ok(Some(mut state)) = load_mode_state() else {
+        return;
+    };
+    
+    let mut changed = false;
+    let mut switched = Vec::new();
+    for (prefix, prefix_state) in state.prefix_active_backup.iter_mut() {
+        let Some(original) = prefix_state.failed_over_from.as_deref().and_then(|id| Uuid::parse_str(id).ok()) else {
+            continue;
+        };
+        if !health.get(&original).is_some_and(|h| h.is_healthy()) {
+            changed |= prefix_state.failback_since.take().is_some();
+            continue;
+        }
+        let Some(since) = prefix_state.failback_since else {
+            log::debug!("Prefix {}: peer {} is healthy again, starting fail-back timer", prefix, original);
+            prefix_state.failback_since = Some(now);
+            changed = true;
+            continue;
+        };
+        let online_duration = now.saturating_sub(since);
+        if online_duration < failover_tuning(&original).failback_stability_secs {
+            continue;
+        }
+        
+        let original_str = original.to_string();
+        let previous = std::mem::replace(&mut prefix_state.active_peer_id, original_str.clone());
+        prefix_state.backup_peer_ids.retain(|id| *id != original_str);
+        prefix_state.backup_peer_ids.insert(0, previous.clone());
+        prefix_state.failed_over_from = None;
+        prefix_state.failback_since = None;
+        changed = true;
+        log::info!("Prefix {}: peer {} has been healthy for {}s, failing back from {}", prefix, original, online_duration, previous);
+        events::record(EventKind::PrefixFailback, Initiator::Monitor, EventDetails {
+            old_peer: Uuid::parse_str(&previous).ok(),
+            new_peer: Some(original),
+            reason: Some(format!("prefix {}", prefix)),
+            metrics: health.get(&original).map(EventMetrics::from),
+            ..Default::default()
+        });
+        if let Ok(net) = Ipv4Net::from_str(prefix) {
+            switched.push(net);
+        }
+    }
+    
+    if !changed {
+        return;
+    }
+    if let Err(e) = save_mode_state(&state) {
+        log::warn!("Failed to save prefix fail-back state: {}", e);
+        return;
+    }
+    for prefix in switched {
+        if let Err(e) = apply_prefix_active_backup(&state, prefix) {
+            log::error!("Failed to fail back prefix {}: {}", prefix, e);
+        }
+    }
+}
+
+// Remove peer routing table and clean up
+// Remove peer routing table (public wrapper - loads config internally if needed)
+pub fn remove_peer_routing_table(peer_id: &Uuid, table_id: u32) -> Result<(), PolicyRoutingError> {
+    remove_peer_routing_table_impl(peer_id, table_id, None)
+}
+
+// Internal implementation that accepts network reference to avoid deadlock
+pub fn remove_peer_routing_table_impl(peer_id: &Uuid, table_id: u32, network: Option<&Network>) -> Result<(), PolicyRoutingError> {
+    let peer_id_str = peer_id.to_string();
+    
+    log::info!("Removing routing table {} for peer {}", table_id, peer_id_str);
+    
+    // Flush all routes from the table
+    if let Err(e) = routing_backend().flush_table(table_id) {
+        log::warn!("Failed to flush table {}: {} (continuing anyway)", table_id, e);
+        // Continue with cleanup even if flush fails
+    }
+    
+    // Remove PBR rules for this peer
+    if let Err(e) = remove_pbr_rules_for_peer(peer_id, table_id) {
+        log::warn!("Failed to remove PBR rules for peer {}: {} (continuing anyway)", peer_id_str, e);
+    }
+    
+    // Remove from persisted state and handle exit node
+    let mut state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    
+    let lan_nets = state.lan_cidr.as_deref().map(parse_lan_nets).unwrap_or_default();
+    
+    // Remove this peer's own LAN access rules (they would otherwise outlive the peer) and move the peers after it
+    if let Some(network) = network.filter(|n| n.peers.contains_key(peer_id)) {
+        let mut remaining = network.clone();
+        remaining.peers.remove(peer_id);
+        if let Err(e) = sync_peer_lan_access_rules(&remaining) {
+            log::warn!("Failed to update LAN access rules after removing peer {}: {} (continuing anyway)", peer_id, e);
+        }
+    }
+    
+    // Check if this peer was the exit node
+    let was_exit_node = state.prefix_active_backup
+        .get("0.0.0.0/0")
+        .is_some_and(|prefix_state| prefix_state.active_peer_id == peer_id_str);
+    
+    // Clients pinned to it fall back to the exit node
+    let was_pinned = state.client_exits.iter().any(|entry| entry.exit_peer_id == peer_id_str);
+    state.client_exits.retain(|entry| entry.exit_peer_id != peer_id_str);
+    
+    // Load config if network not provided (for backward compatibility)
+    let loaded_config = if (was_exit_node || was_pinned) && network.is_none() {
+        Some(crate::conf::util::get_config()
+            .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?)
+    } else {
+        None
+    };
+    let network = network.or(loaded_config.as_ref().map(|c| &c.network));
+    
+    let mut new_exit_node = None;
+    if was_exit_node {
+        // This peer was the exit node, remove exit node rules
+        log::info!("Removing exit node rules for peer {}", peer_id_str);
+        
+        // Dump rules once and remove exit node rules
+        if let Ok(rules) = get_ip_rules() {
+            let removed = delete_matching_rules(&rules, |rule| {
+                rule.table == table_id && rule.priority >= 20000 && rule.is_default_destination()
+            });
+            log::info!("Removed {} exit node rule(s) for table {}", removed, table_id);
+            
+            // Remove LAN exception rules if they exist (supports multiple comma-separated CIDRs)
+            let lan_interface = find_lan_interface().ok();
+            remove_lan_exception_rules(&rules, &lan_nets, lan_interface.as_deref(), network);
+        }
+        
+        // This peer was the exit node, remove it from state
+        state.prefix_active_backup.remove("0.0.0.0/0");
+        
+        // Try to find a new exit node from remaining peers (filter out the peer being deleted)
+        if let Some(network_ref) = network {
+            new_exit_node = get_peers_with_default_route(network_ref)
+                .into_iter()
+                .find(|&p| p != *peer_id);
+        }
+    }
+    
+    // Drop the peer from overlapping prefixes, promoting a backup where it was active
+    let health = EXIT_NODE_HEALTH_CACHE.read().unwrap().clone();
+    let mut promoted = Vec::new();
+    state.prefix_active_backup.retain(|prefix, prefix_state| {
+        if prefix == "0.0.0.0/0" {
+            return true;
+        }
+        prefix_state.backup_peer_ids.retain(|id| *id != peer_id_str);
+        if prefix_state.active_peer_id == peer_id_str {
+            let Some(first) = prefix_state.backup_peer_ids.first().cloned() else {
+                return false;
+            };
+            let new_active = prefix_state.backup_peer_ids.iter()
+                .find(|id| Uuid::parse_str(id).ok()
+                    .and_then(|uuid| health.get(&uuid))
+                    .is_some_and(|h| h.is_healthy()))
+                .cloned()
+                .unwrap_or(first);
+            prefix_state.backup_peer_ids.retain(|id| *id != new_active);
+            log::info!("Prefix {}: active peer {} removed, promoting {}", prefix, peer_id_str, new_active);
+            prefix_state.active_peer_id = new_active.clone();
+            if let Ok(net) = Ipv4Net::from_str(prefix) {
+                promoted.push((net, new_active));
+            }
+        }
+        // A single remaining peer is no longer an overlap
+        !prefix_state.backup_peer_ids.is_empty()
+    });
+    
+    state.domain_routes.retain(|_, exit_peer_id| *exit_peer_id != peer_id_str);
+    state.remove_from_gateway_groups(&peer_id_str);
+    state.peer_table_ids.remove(&peer_id_str);
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    
+    if was_pinned {
+        let exit_interface = exit_interface_name(table_id);
+        if let Some(network_ref) = network {
+            exit_interface_firewall(network_ref, &state, peer_id, &exit_interface, false);
+        }
+        if let Err(e) = routing_backend().delete_interface(&exit_interface) {
+            log::debug!("Failed to delete {}: {}", exit_interface, e);
+        }
+        if let Some(network_ref) = network
+            && let Err(e) = apply_client_exits(network_ref, &health_snapshot()) {
+                log::warn!("Failed to update client exit rules after removing peer {}: {}", peer_id_str, e);
+            }
+    }
+    
+    for (prefix, new_active) in promoted {
+        let result = if state.prefix_active_backup.contains_key(&prefix.to_string()) {
+            apply_prefix_active_backup(&state, prefix)
+        } else if let (Some(&new_table_id), Ok(new_active_id)) = (state.peer_table_ids.get(&new_active), Uuid::parse_str(&new_active)) {
+            find_lan_interface().and_then(|lan_interface| {
+                install_pbr_rules_for_peer(&new_active_id, new_table_id, &[prefix.to_string()], &lan_interface)
+            })
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to promote peer {} for prefix {}: {}", new_active, prefix, e);
+        }
+    }
+    
+    // Promote the new exit node only after our removal is persisted:
+    // set_exit_node saves its own state, which must not be overwritten by the copy loaded above
+    if was_exit_node {
+        match (new_exit_node, network) {
+            (Some(new_exit_node), Some(network_ref)) => {
+                log::info!("Selecting new exit node: {}", new_exit_node);
+                // The caller removes the peer from the network afterwards; leave it out now so
+                // no LAN access rules are installed for it
+                let mut remaining = network_ref.clone();
+                remaining.peers.remove(peer_id);
+                remaining.connections.retain(|connection_id, _| !connection_id.contains(peer_id));
+                if let Err(e) = set_exit_node(&new_exit_node, Some(&remaining)) {
+                    log::warn!("Failed to set new exit node: {}", e);
+                }
+            }
+            _ => log::info!("No other peers with default route, exit node removed"),
+        }
+    } else if state.load_balance
+        && let Some(network_ref) = network
+        && get_peers_with_default_route(network_ref).contains(peer_id) {
+            // Hand the removed exit node's share to the remaining ones
+            let mut remaining = network_ref.clone();
+            remaining.peers.remove(peer_id);
+            remaining.connections.retain(|connection_id, _| !connection_id.contains(peer_id));
+            if let Err(e) = apply_load_balance(&remaining, &health_snapshot()) {
+                log::warn!("Failed to rebalance exit nodes after removing peer {}: {}", peer_id_str, e);
+            }
+        }
+    
+    log::info!("Successfully removed routing table {} for peer {}", table_id, peer_id_str);
+    Ok(())
+}
+
+// Get table ID for a peer
+pub fn get_peer_table_id(peer_id: &Uuid) -> Result<Option<u32>, PolicyRoutingError> {
+    let peer_id_str = peer_id.to_string();
+    let state = match load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+    {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    
+    Ok(state.peer_table_ids.get(&peer_id_str).copied())
+}
+
+// Get all routes for a peer from connections
+// Returns a deduplicated list of CIDR strings including:
+// 1. Routes the peer can reach through connections
+// 2. The peer's own address/32 route
+pub fn get_peer_advertised_routes(peer_id: &Uuid, network: &Network) -> Vec<String> {
+    log::debug!("[get_peer_advertised_routes] Getting routes for peer {}", peer_id);
+    let mut routes = std::collections::HashSet::new();
+    
+    // First, add the peer's own address/32 route
+    if let Some(peer) = network.peers.get(peer_id) {
+        let peer_address = format!("{}/32", peer.address);
+        log::debug!("[get_peer_advertised_routes] Adding peer's own address: {}", peer_address);
+        routes.insert(peer_address);
+    } else {
+        log::warn!("[get_peer_advertised_routes] Peer {} not found in network peers", peer_id);
+    }
+    
+    // Iterate through all connections to get routes the peer can reach
+    log::debug!("[get_peer_advertised_routes] Checking {} connections...", network.connections.len());
+    for (connection_id, connection) in &network.connections {
+        // Check if this peer is involved in the connection
+        if connection_id.a == *peer_id {
+            // This peer is peer A, so routes it can reach are in allowed_ips_b_to_a
+            // (what peer B advertises to peer A)
+            log::debug!("[get_peer_advertised_routes] Peer {} is peer A in connection {}", peer_id, connection_id);
+            for cidr in &connection.allowed_ips_b_to_a {
+                log::debug!("[get_peer_advertised_routes] Adding route from allowed_ips_b_to_a: {}", cidr);
+                routes.insert(cidr.to_string());
+            }
+        } else if connection_id.b == *peer_id {
+            // This peer is peer B, so routes it can reach are in allowed_ips_a_to_b
+            // (what peer A advertises to peer B)
+            log::debug!("[get_peer_advertised_routes] Peer {} is peer B in connection {}", peer_id, connection_id);
+            for cidr in &connection.allowed_ips_a_to_b {
+                log::debug!("[get_peer_advertised_routes] Adding route from allowed_ips_a_to_b: {}", cidr);
+                routes.insert(cidr.to_string());
+            }
+        }
+    }
+    
+    let mut result: Vec<String> = routes.into_iter().collect();
+    result.sort();
+    log::debug!("[get_peer_advertised_routes] Returning {} routes for peer {}: {:?}", result.len(), peer_id, result);
+    result
+}
+
+// Update routes for a peer (flush old routes and install new ones)
+// This is called when connections are modified
+pub fn update_peer_routes(peer_id: &Uuid, network: &Network, wg_interface: &str) -> Result<(), PolicyRoutingError> {
+    // Get the table ID for this peer
+    let table_id = match get_peer_table_id(peer_id)? {
+        Some(id) => id,
+        None => {
+            log::debug!("No routing table found for peer {}, skipping route update", peer_id);
+            return Ok(());
+        }
+    };
+    
+    // Flush existing routes from the table
+    if let Err(e) = routing_backend().flush_table(table_id) {
+        log::warn!("Failed to flush table {} for peer {}: {} (continuing anyway)", table_id, peer_id, e);
+    }
+    
+    // Get updated routes
+    let routes = get_peer_advertised_routes(peer_id, network);
+    
+    // Install new routes
+    install_peer_routes(peer_id, table_id, &routes, wg_interface)?;
+    
+    // Update PBR rules for this peer
+    let lan_interface = find_lan_interface()?;
+    if let Err(e) = update_pbr_rules_for_peer(peer_id, network, &lan_interface) {
+        log::warn!("Failed to update PBR rules for peer {}: {} (continuing anyway)", peer_id, e);
+    }
+    
+    // Check if this peer has default route and handle exit node logic
+    if routes.contains(&"0.0.0.0/0".to_string()) || routes.contains(&"default".to_string()) {
+        // If no exit node is set, set this peer as exit node
+        // Pass network to avoid deadlock
+        if get_exit_node()?.is_none() {
+            log::info!("No exit node set, setting peer {} as exit node", peer_id);
+            if let Err(e) = set_exit_node(peer_id, Some(network)) {
+                log::warn!("Failed to set peer {} as exit node: {}", peer_id, e);
+            }
+        }
+    }
+    
+    // Ensure LAN access rules exist for this peer, and every other peer is in its place (if an exit node is set)
+    if let Ok(Some(_exit_node)) = get_exit_node()
+        && let Err(e) = sync_peer_lan_access_rules(network) {
+            log::warn!("Failed to update LAN access rules after updating peer {}: {} (continuing anyway)", peer_id, e);
+        }
+    
+    // The peer may have started or stopped advertising a default route
+    if let Err(e) = apply_load_balance(network, &health_snapshot()) {
+        log::warn!("Failed to rebalance exit nodes after updating peer {}: {} (continuing anyway)", peer_id, e);
+    }
+    
+    // The peer's routes may have started or stopped overlapping with another peer's
+    if let Err(e) = sync_prefix_active_backup(network) {
+        log::warn!("Failed to sync overlapping prefixes after updating peer {}: {} (continuing anyway)", peer_id, e);
+    }
+    
+    // Domain route rules went with the PBR rules above, and the peer may have stopped being an exit node
+    if let Err(e) = apply_domain_routes(network) {
+        log::warn!("Failed to apply domain routes after updating peer {}: {} (continuing anyway)", peer_id, e);
+    }
+    
+    log::info!("Updated routes for peer {} in table {} ({} routes)", peer_id, table_id, routes.len());
+    Ok(())
+}
+
+// Priority of the LAN interface exception rule for the first LAN CIDR: one below the exit node rule
+// (None without an exit node, when there are no LAN exceptions)
+fn lan_exception_priority(state: &super::persist::ModeState) -> Option<u32> {
+    state.prefix_active_backup.get("0.0.0.0/0")
+        .and_then(|ps| exit_table_id(state, &ps.active_peer_id))
+        .map(|table_id| 20000 + (table_id % 1000) - 1)
+}
+
+// Position of a peer among the peers other than the router (orders the per-peer LAN access rules)
+fn peer_lan_index(network: &Network, peer_id: &Uuid) -> u32 {
+    network.peers.keys()
+        .filter(|pid| **pid != network.this_peer)
+        .take_while(|pid| *pid != peer_id)
+        .count() as u32
+}
+
+// Per-peer LAN access rule, 100 below the LAN interface exception
+// Unique priority: base + (cidr_index * 100) + peer_index
+fn peer_lan_rule(exception_priority: u32, cidr_idx: usize, peer_index: u32, peer: &Peer, wg_interface: &str, lan_net: Ipv4Net) -> Rule {
+    Rule {
+        priority: exception_priority - 100 + (cidr_idx as u32 * 100) + peer_index,
+        from: Some(Ipv4Net::from(peer.address)),
+        iif: Some(wg_interface.to_string()),
+        to: Some(lan_net),
+        table: MAIN_TABLE,
+        ..Default::default()
+    }
+}
+
+/// Set LAN access for a specific peer
+/// Returns the new LAN access state
+pub fn set_peer_lan_access(peer_id: &Uuid, has_lan_access: bool, network: &Network) -> Result<bool, PolicyRoutingError> {
+    let _routing = routing_lock();
+    let peer_id_str = peer_id.to_string();
+    
+    // Load current state
+    let mut state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    
+    // Update the peer's LAN access setting
+    state.peer_lan_access.insert(peer_id_str.clone(), has_lan_access);
+    
+    // Save the state
+    save_mode_state(&state)
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
+    
+    let peer = network.peers.get(peer_id)
+        .ok_or_else(|| PolicyRoutingError::PersistenceError(format!("Peer {} not found", peer_id)))?;
+    
+    // A pinned exit node reaches the LAN from its own interface, where the firewall decides
+    if let Some(exit_interface) = pinned_exit_interfaces(&state).get(peer_id) {
+        exit_interface_firewall(network, &state, peer_id, exit_interface, true);
+    }
+    
+    if state.lan_cidr.is_none() {
+        return Err(PolicyRoutingError::PersistenceError("No LAN CIDR configured".to_string()));
+    }
+    // The rule follows the exit node's LAN exception (none without an exit node)
+    if lan_exception_priority(&state).is_none() {
+        log::debug!("No exit node set, the LAN access rule for peer {} is installed with the next exit node", peer.name);
+        return Ok(has_lan_access);
+    }
+    sync_peer_lan_access_rules(network)?;
+    log::info!("{} LAN access for peer {} ({})", if has_lan_access { "Allowed" } else { "Denied" }, peer.name, peer.address);
+    
+    Ok(has_lan_access)
+}
+
+/// Plan of set_peer_lan_access: the changes it would make, without making them (see change_plan.rs)
+pub fn plan_set_peer_lan_access(peer_id: &Uuid, has_lan_access: bool, network: &Network) -> Result<ChangePlan, PolicyRoutingError> {
+    let current = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    if !network.peers.contains_key(peer_id) {
+        return Err(PolicyRoutingError::PersistenceError(format!("Peer {} not found", peer_id)));
+    }
+
+    let mut target = current.clone();
+    target.peer_lan_access.insert(peer_id.to_string(), has_lan_access);
+
+    let mut plan = ChangePlan::new(format!("set_peer_lan_access {} {}", peer_id, has_lan_access));
+    plan.transition(Some(&current), Some(&target), network)
+        .map_err(|e| PolicyRoutingError::IpRuleError(format!("Failed to plan the change: {}", e)))?;
+    Ok(plan)
+}
+
+// Per-peer LAN access rules of a state, one per peer with LAN access and LAN CIDR (none without an exit node)
+fn planned_peer_lan_rules(state: &super::persist::ModeState, network: &Network) -> Vec<Rule> {
+    let Some(exception_priority) = lan_exception_priority(state) else {
+        return Vec::new();
+    };
+    let mut rules = Vec::new();
+    for (cidr_idx, lan_net) in lan_nets(state).iter().enumerate() {
+        for (peer_id, peer) in network.peers.iter().filter(|(peer_id, _)| **peer_id != network.this_peer) {
+            if state.peer_lan_access.get(&peer_id.to_string()).copied().unwrap_or(true) {
+                let peer_index = peer_lan_index(network, peer_id);
+                rules.push(peer_lan_rule(exception_priority, cidr_idx, peer_index, peer, &network.name, *lan_net));
             }
         }
-        
-        // Small delay to prevent tight loop
-        sleep(Duration::from_millis(100)).await;
     }
+    rules
 }
 
-// Async version of check_peer_health_impl (non-blocking)
-async fn check_peer_health_impl_async(
-    _network: &Network,
-    peer_id: Uuid,
-    peer: &wg_quickrs_lib::types::network::Peer,
-    wg_interface: &str,
-) -> ExitNodeHealth {
-    // Load persisted state (for last_online_state and last_successful_ping only)
-    // first_handshake is now session-only (resets on restart)
-    let mut mode_state = load_mode_state().unwrap_or(None);
-    let mut last_online_state_map = mode_state.as_mut()
-        .map(|s| std::mem::take(&mut s.peer_last_online_state))
-        .unwrap_or_default();
-    let mut last_successful_ping_map = mode_state.as_mut()
-        .map(|s| std::mem::take(&mut s.peer_last_successful_ping))
-        .unwrap_or_default();
-    
-    let peer_id_str = peer_id.to_string();
-    let public_key = wg_public_key_from_private_key(&peer.private_key);
-    let public_key_b64 = public_key.to_base64();
+/// Converge the LAN access rules of every peer to their settings, as plan_routing has them
+/// Called when a peer joins or leaves (the peers after it move, as priorities follow the position) and when a
+/// setting changes
+pub fn sync_peer_lan_access_rules(network: &Network) -> Result<(), PolicyRoutingError> {
+    let _routing = routing_lock();
+    let state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
+        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
+    let planned = planned_peer_lan_rules(&state, network);
+    let lan_nets = lan_nets(&state);
+    
+    let backend = routing_backend();
+    let rules = get_ip_rules()?;
+    for rule in planned.iter().filter(|rule| !rules.contains(rule)) {
+        if let Err(e) = backend.add_rule(rule) {
+            log::warn!("Failed to install LAN access rule {}: {}", rule, e);
+        } else {
+            log::debug!("Installed LAN access rule {}", rule);
+        }
+    }
+    // Then the rules of peers that lost LAN access, left or moved
+    delete_matching_rules(&rules, |rule| {
+        rule.table == MAIN_TABLE
+            && rule.from.is_some()
+            && rule.iif.as_deref() == Some(network.name.as_str())
+            && rule.to.is_some_and(|to| lan_nets.contains(&to))
+            && !planned.contains(rule)
+    });
+    Ok(())
+}
+
+/// Get LAN access status for all peers
+pub fn get_all_peer_lan_access() -> Result<HashMap<String, bool>, PolicyRoutingError> {
+    let state = load_mode_state()
+        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?;
     
-    // Get WireGuard dump output (async, non-blocking)
-    use tokio::process::Command as TokioCommand;
-    let wg_dump = match TokioCommand::new("wg")
-        .arg("show")
-        .arg(wg_interface)
-        .arg("dump")
-        .output()
-        .await
-    {
-        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).to_string(),
-        _ => {
-            // Return default health if can't get dump
-            // Calculate loss/jitter from existing history if available
-            let ping_history = PING_HISTORY.clone();
-            let history = ping_history.read().unwrap();
-            let (packet_loss_percent, jitter_ms) = if let Some(peer_history) = history.get(&peer_id) {
-                calculate_loss_and_jitter(peer_history)
-            } else {
-                (None, None)
-            };
-            
-            return ExitNodeHealth {
-                peer_id,
-                is_online: false,
-                last_handshake: None,
-                first_handshake: None,
-                latency_ms: None,
-                packet_loss_percent,
-                jitter_ms,
-                transfer_rx: 0,
-                transfer_tx: 0,
-                endpoint: None,
-            };
+    match state {
+        Some(s) => Ok(s.peer_lan_access),
+        None => Ok(HashMap::new()),
+    }
+}
+
+
+// Desired kernel state (for the reconciler)
+// Everything the call sites above install piece by piece, derived in one place from the mode state and the network
+#[derive(Debug, Default)]
+pub struct RoutingPlan {
+    pub rules: Vec<Rule>,
+    pub routes: BTreeMap<u32, Vec<Route>>, // Every table wg-quickrs owns, with the routes it should hold
+    pub uplink: Option<String>,            // Uplink interface while the local breakout is the exit node
+    pub client_exits: crate::firewall::ClientExitFirewall, // Exit node interfaces and client marks of the pinned clients
+}
+
+impl RoutingPlan {
+    fn add_rule(&mut self, rule: Rule) {
+        if !self.rules.contains(&rule) {
+            self.rules.push(rule);
         }
-    };
+    }
+}
+
+pub fn plan_routing(state: &super::persist::ModeState, network: &Network, lan_interface: &str) -> RoutingPlan {
+    let mut plan = RoutingPlan::default();
+    let wg_interface = network.name.as_str();
+    let tuning = FAILOVER_TUNING.read().unwrap().clone();
+    let attached = attached_exit_interfaces(state);
+    
+    // Peer tables: advertised routes (via the exit node's own interface if clients are pinned to it),
+    // a PBR rule per specific route (unless only a backup for it), the probe rule
+    let peer_tables: BTreeMap<&String, &u32> = state.peer_table_ids.iter().collect();
+    for (peer_id_str, table_id) in peer_tables {
+        let routes = plan.routes.entry(*table_id).or_default();
+        let Some(peer_id) = Uuid::parse_str(peer_id_str).ok().filter(|id| network.peers.contains_key(id)) else {
+            continue; // Left behind by a removed peer: the table should be empty
+        };
+        let advertised = get_peer_advertised_routes(&peer_id, network);
+        let device = attached.get(&peer_id).map(String::as_str).unwrap_or(wg_interface);
+        for route in advertised.iter().filter(|route| validate_route(route)) {
+            let route = if route == "default" { "0.0.0.0/0" } else { route };
+            if let Ok(destination) = Ipv4Net::from_str(route) {
+                routes.push(Route { table: *table_id, destination, device: device.to_string(), gateway: None });
+            }
+        }
+        let backup_prefixes = backup_prefixes_for_peer(state, peer_id_str);
+        for destination in advertised.iter()
+            .filter(|route| *route != "0.0.0.0/0" && *route != "default")
+            .filter_map(|route| Ipv4Net::from_str(route).ok())
+            .filter(|destination| !backup_prefixes.contains(&destination.trunc().to_string())) {
+            plan.add_rule(Rule {
+                priority: 10000 + (table_id % 1000),
+                iif: Some(lan_interface.to_string()),
+                to: Some(destination.trunc()),
+                table: *table_id,
+                ..Default::default()
+            });
+        }
+        if !tuning.probes_for(&peer_id).is_empty() {
+            plan.add_rule(probe_rule(*table_id));
+        }
+    }
     
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
+    // Exit node: exit rules, LAN exceptions and per-peer LAN access rules
+    let exit_node = state.prefix_active_backup.get("0.0.0.0/0")
+        .and_then(|ps| exit_table_id(state, &ps.active_peer_id));
+    if exit_node == Some(LOCAL_BREAKOUT_TABLE) {
+        let routes = match uplink_default_route() {
+            Ok(route) => vec![Route { table: LOCAL_BREAKOUT_TABLE, ..route }],
+            // Nothing to copy: leave the table as it is
+            Err(_) => routing_backend().list_routes(LOCAL_BREAKOUT_TABLE).unwrap_or_default(),
+        };
+        plan.uplink = routes.first().map(|route| route.device.clone());
+        plan.routes.insert(LOCAL_BREAKOUT_TABLE, routes);
+    } else {
+        plan.routes.insert(LOCAL_BREAKOUT_TABLE, Vec::new());
+    }
+    if !tuning.probes_for(&LOCAL_BREAKOUT_EXIT_ID).is_empty() {
+        plan.add_rule(probe_rule(LOCAL_BREAKOUT_TABLE));
+    }
     
-    // Find this peer in WireGuard dump
-    let mut last_handshake: Option<u64> = None;
-    let mut transfer_rx: u64 = 0;
-    let mut transfer_tx: u64 = 0;
-    let mut endpoint: Option<String> = None;
+    if let (Some(table_id), Some(exception_priority)) = (exit_node, lan_exception_priority(state)) {
+        plan.add_rule(Rule {
+            priority: 20000 + (table_id % 1000),
+            iif: Some(lan_interface.to_string()),
+            table: table_id,
+            ..Default::default()
+        });
+        plan.add_rule(Rule {
+            priority: 20000 + (table_id % 1000) + 1,
+            from: Some(network.subnet.trunc()),
+            iif: Some(wg_interface.to_string()),
+            table: table_id,
+            ..Default::default()
+        });
+        for (cidr_idx, lan_net) in lan_nets(state).iter().enumerate() {
+            plan.add_rule(Rule {
+                priority: exception_priority - (cidr_idx as u32),
+                iif: Some(lan_interface.to_string()),
+                to: Some(*lan_net),
+                table: MAIN_TABLE,
+                ..Default::default()
+            });
+        }
+        for rule in planned_peer_lan_rules(state, network) {
+            plan.add_rule(rule);
+        }
+    }
     
-    for line in wg_dump.lines().skip(1) {
-        let parts: Vec<&str> = line.split('\t').collect();
-        if parts.len() < 8 {
-            continue;
+    // Domain routes, and the rules of the pinned clients
+    if state.last_mode == SystemMode::Router {
+        for (destination, (_, table_id)) in plan_domain_routes(state, network) {
+            plan.add_rule(Rule {
+                priority: DOMAIN_ROUTE_PRIORITY_BASE + (table_id % 1000),
+                iif: Some(lan_interface.to_string()),
+                to: Some(destination),
+                table: table_id,
+                ..Default::default()
+            });
         }
-        
-        if parts[0] == public_key_b64 {
-            // Parse endpoint (column 2)
-            if parts.len() > 2 && parts[2] != "(none)" && !parts[2].is_empty() && parts[2] != "off" {
-                endpoint = Some(parts[2].to_string());
-            }
-            
-            // Parse last handshake (column 4, in seconds since epoch)
-            if parts.len() > 4 {
-                last_handshake = parts[4].parse::<u64>().ok();
-            }
-            
-            // Parse transfer stats (columns 5 and 6)
-            if parts.len() > 6 {
-                transfer_rx = parts[5].parse::<u64>().unwrap_or(0);
-                transfer_tx = parts[6].parse::<u64>().unwrap_or(0);
+        for rule in plan_client_exit_rules(state, network, &client_exit_health(state, &health_snapshot()), lan_interface) {
+            plan.add_rule(rule);
+        }
+        plan.client_exits = client_exit_firewall(state);
+    }
+    
+    plan
+}
+
+// Rules wg-quickrs owns, so the reconciler may delete them when they are not planned:
+// anything looking up a peer table or the local breakout table, LAN exceptions into the main table
+// and the rules of the pinned clients
+pub fn is_managed_rule(rule: &Rule, lan_nets: &[Ipv4Net], lan_interface: &str, wg_interface: &str) -> bool {
+    is_exit_table(rule.table)
+        || is_client_exit_rule(rule)
+        || (rule.table == MAIN_TABLE
+            && rule.to.is_some_and(|to| lan_nets.contains(&to))
+            && (rule.iif.as_deref() == Some(lan_interface) || rule.iif.as_deref() == Some(wg_interface)))
+}
+
+// Parsed LAN networks of the mode state
+pub fn lan_nets(state: &super::persist::ModeState) -> Vec<Ipv4Net> {
+    parse_lan_nets(state.lan_cidr.as_deref().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mode::backend::{set_routing_backend, RoutingBackend};
+    use crate::mode::change_plan::{ChangeAction, ChangeKind};
+    use crate::mode::persist::{clear_mode_state, ModeState};
+    use crate::mode::reconcile::{reconcile_routing, DriftReport};
+    use crate::mode::simulated::SimulatedKernel;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use wg_quickrs_lib::types::network::{Connection, ConnectionId, Peer};
+
+    const WG_INTERFACE: &str = "wg0";
+    const LAN_CIDR: &str = "192.168.1.0/24, 10.20.0.0/16";
+
+    static CONFIG_FOLDER: Lazy<tempfile::TempDir> = Lazy::new(|| tempfile::tempdir().unwrap());
+
+    // Fresh Router Mode state on a fresh simulated kernel
+    fn router_mode() -> (std::sync::MutexGuard<'static, ()>, Arc<SimulatedKernel>) {
+        let guard = crate::mode::simulated::test_lock();
+        let _ = crate::WG_QUICKRS_CONFIG_FOLDER.set(CONFIG_FOLDER.path().to_path_buf());
+        clear_mode_state().unwrap();
+        save_mode_state(&ModeState::new(SystemMode::Router, Some(LAN_CIDR.to_string()))).unwrap();
+
+        let kernel = Arc::new(SimulatedKernel::new()
+            .with_interface("eth0", "192.168.1.10/24")
+            .with_interface(WG_INTERFACE, "10.0.34.1/24"));
+        set_routing_backend(kernel.clone());
+        *LAN_INTERFACE_CACHE.lock().unwrap() = None;
+        EXIT_NODE_HEALTH_CACHE.write().unwrap().clear();
+        (guard, kernel)
+    }
+
+    fn generate_peer(name: &str, address: &str) -> Peer {
+        Peer {
+            name: name.to_string(),
+            address: address.parse().unwrap(),
+            endpoint: Default::default(),
+            kind: Default::default(),
+            icon: Default::default(),
+            dns: Default::default(),
+            mtu: Default::default(),
+            scripts: Default::default(),
+            private_key: Default::default(),
+            created_at: Default::default(),
+            updated_at: Default::default(),
+        }
+    }
+
+    // Router plus `exit_nodes` peers advertising a default route and `clients` peers that don't
+    fn generate_network(exit_nodes: usize, clients: usize) -> Network {
+        let this_peer = Uuid::new_v4();
+        let mut peers = BTreeMap::new();
+        let mut connections = BTreeMap::new();
+        peers.insert(this_peer, generate_peer("router", "10.0.34.1"));
+        for i in 0..exit_nodes + clients {
+            let peer_id = Uuid::new_v4();
+            let address = format!("10.0.34.{}", i + 2);
+            peers.insert(peer_id, generate_peer(&format!("peer-{}", i), &address));
+            let mut advertised = vec![format!("{}/32", address).parse().unwrap()];
+            if i < exit_nodes {
+                advertised.push("0.0.0.0/0".parse().unwrap());
+                advertised.push(format!("172.16.{}.0/24", i).parse().unwrap());
             }
-            break;
+            connections.insert(ConnectionId { a: this_peer, b: peer_id }, Connection {
+                enabled: true,
+                pre_shared_key: Default::default(),
+                persistent_keepalive: Default::default(),
+                allowed_ips_a_to_b: advertised,
+                allowed_ips_b_to_a: vec!["10.0.34.1/32".parse().unwrap()],
+            });
+        }
+        Network {
+            name: WG_INTERFACE.to_string(),
+            subnet: "10.0.34.0/24".parse().unwrap(),
+            this_peer,
+            peers,
+            connections,
+            defaults: Default::default(),
+            reservations: Default::default(),
+            updated_at: Default::default(),
+        }
+    }
+
+    // Make a peer advertise an extra prefix to the router
+    fn advertise(network: &mut Network, peer_id: &Uuid, prefix: &str) {
+        let connection = network.connections.iter_mut()
+            .find(|(connection_id, _)| connection_id.b == *peer_id)
+            .map(|(_, connection)| connection)
+            .unwrap();
+        connection.allowed_ips_a_to_b.push(prefix.parse().unwrap());
+    }
+
+    fn set_online(peer_id: &Uuid, is_online: bool) {
+        EXIT_NODE_HEALTH_CACHE.write().unwrap().insert(*peer_id, ExitNodeHealth {
+            peer_id: *peer_id,
+            is_online,
+            last_handshake: None,
+            first_handshake: None,
+            latency_ms: None,
+            packet_loss_percent: None,
+            jitter_ms: None,
+            transfer_rx: 0,
+            transfer_tx: 0,
+            endpoint: None,
+            degraded_reason: None,
+            internet_reachable: None,
+        });
+    }
+
+    // A snapshot of the health cache, as the monitor hands it out (no guard held while the callee runs)
+    fn health_cache() -> HashMap<Uuid, ExitNodeHealth> {
+        EXIT_NODE_HEALTH_CACHE.read().unwrap().clone()
+    }
+
+    // Tables holding a rule for the prefix
+    fn prefix_rule_tables(kernel: &SimulatedKernel, prefix: &str) -> Vec<u32> {
+        let prefix: Ipv4Net = prefix.parse().unwrap();
+        kernel.rules().iter().filter(|rule| rule.to == Some(prefix)).map(|rule| rule.table).collect()
+    }
+
+    fn table_of(peer_id: &Uuid) -> u32 {
+        get_peer_table_id(peer_id).unwrap().unwrap()
+    }
+
+    fn active_for(prefix: &str) -> Option<String> {
+        load_mode_state().unwrap().unwrap().prefix_active_backup.get(prefix).map(|ps| ps.active_peer_id.clone())
+    }
+
+    // Three sites advertising 10.50.0.0/16, set up in peer order
+    fn overlapping_sites(kernel: &SimulatedKernel) -> (Network, Vec<Uuid>) {
+        let mut network = generate_network(0, 3);
+        let peers = client_peers(&network);
+        for peer_id in &peers {
+            advertise(&mut network, peer_id, "10.50.0.0/16");
+        }
+        for peer_id in &peers {
+            add_peer(peer_id, &network);
+        }
+        // Before arbitration every site has its rule
+        assert_eq!(prefix_rule_tables(kernel, "10.50.0.0/16").len(), 3);
+        sync_prefix_active_backup(&network).unwrap();
+        (network, peers)
+    }
+
+    // Same steps Router Mode takes for every peer when it is enabled
+    fn add_peer(peer_id: &Uuid, network: &Network) {
+        let table_id = create_peer_routing_table(peer_id).unwrap();
+        let routes = get_peer_advertised_routes(peer_id, network);
+        install_peer_routes(peer_id, table_id, &routes, WG_INTERFACE).unwrap();
+        install_pbr_rules_for_peer(peer_id, table_id, &routes, &find_lan_interface().unwrap()).unwrap();
+    }
+
+    // Same order as peer removal in respond.rs: tear down the table, then drop the peer from the network
+    fn remove_peer(peer_id: &Uuid, network: &mut Network) {
+        let table_id = get_peer_table_id(peer_id).unwrap().unwrap();
+        remove_peer_routing_table_impl(peer_id, table_id, Some(network)).unwrap();
+        network.peers.remove(peer_id);
+        network.connections.retain(|connection_id, _| !connection_id.contains(peer_id));
+    }
+
+    fn client_peers(network: &Network) -> Vec<Uuid> {
+        network.peers.keys().filter(|id| **id != network.this_peer).copied().collect()
+    }
+
+    // Rules sending default traffic to a peer table
+    fn exit_rule_tables(kernel: &SimulatedKernel) -> Vec<u32> {
+        kernel.rules().iter()
+            .filter(|rule| rule.priority >= 20000 && is_peer_table(rule.table))
+            .map(|rule| rule.table)
+            .collect()
+    }
+
+    #[test]
+    fn test_lan_interface_detected_from_addresses() {
+        let (_guard, _kernel) = router_mode();
+        assert_eq!(find_lan_interface().unwrap(), "eth0");
+    }
+
+    #[test]
+    fn test_peer_tables_are_unique_and_stable() {
+        let (_guard, _kernel) = router_mode();
+        let peers: Vec<Uuid> = (0..20).map(|_| Uuid::new_v4()).collect();
+        let tables: Vec<u32> = peers.iter().map(|id| create_peer_routing_table(id).unwrap()).collect();
+
+        let unique: std::collections::HashSet<u32> = tables.iter().copied().collect();
+        assert_eq!(unique.len(), tables.len());
+        assert!(tables.iter().all(|table| is_peer_table(*table)));
+        for (peer_id, table_id) in peers.iter().zip(&tables) {
+            assert_eq!(create_peer_routing_table(peer_id).unwrap(), *table_id);
         }
     }
-    
-    // Check connectivity using ping (non-blocking async version)
-    // Ping the peer's tunnel IP (peer.address) via the WireGuard interface
-    let (ping_succeeded, latency_ms) = check_peer_connectivity_async(&peer.address.to_string(), wg_interface).await;
-    
-    // Apply consecutive failures threshold for offline detection
-    // Peer is only marked offline after CONSECUTIVE_FAILURES_THRESHOLD consecutive failures
-    let consecutive_failures = CONSECUTIVE_FAILURES.clone();
-    let is_online = {
-        let mut failures = consecutive_failures.write().unwrap();
-        let failure_count = failures.entry(peer_id).or_insert(0);
-        
-        if ping_succeeded {
-            // Ping succeeded - reset failure counter, peer is online
-            *failure_count = 0;
-            true
-        } else {
-            // Ping failed - increment counter
-            *failure_count = failure_count.saturating_add(1);
-            // Only mark offline after threshold consecutive failures
-            *failure_count < CONSECUTIVE_FAILURES_THRESHOLD
+
+    #[test]
+    fn test_peer_routes_and_pbr_rules_installed() {
+        let (_guard, kernel) = router_mode();
+        let network = generate_network(1, 0);
+        let peer_id = client_peers(&network)[0];
+        add_peer(&peer_id, &network);
+
+        let table_id = get_peer_table_id(&peer_id).unwrap().unwrap();
+        let destinations: Vec<String> = kernel.routes(table_id).iter().map(|r| r.destination.to_string()).collect();
+        assert_eq!(destinations, ["0.0.0.0/0", "10.0.34.2/32", "172.16.0.0/24"]);
+
+        // The default route is left to set_exit_node, specific routes get a PBR rule each
+        let pbr_rules: Vec<Rule> = kernel.rules().into_iter().filter(|rule| rule.table == table_id).collect();
+        assert_eq!(pbr_rules.len(), 2);
+        assert!(pbr_rules.iter().all(|rule| rule.priority == 10000 + table_id % 1000));
+    }
+
+    #[test]
+    fn test_route_to_missing_interface_is_skipped() {
+        let (_guard, kernel) = router_mode();
+        let peer_id = Uuid::new_v4();
+        install_peer_routes(&peer_id, 1000, &["10.1.0.0/16".to_string()], "wg9").unwrap();
+        assert!(kernel.routes(1000).is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_prefix_routes_to_first_peer_only() {
+        let (_guard, kernel) = router_mode();
+        let (network, peers) = overlapping_sites(&kernel);
+
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[0])]);
+        assert_eq!(active_for("10.50.0.0/16"), Some(peers[0].to_string()));
+        assert_eq!(get_overlapping_prefixes(&network).len(), 1);
+
+        // Reinstalling a backup's rules (e.g. after a connection edit) keeps the prefix with the active peer
+        install_pbr_rules_for_peer(&peers[1], table_of(&peers[1]), &["10.50.0.0/16".to_string()], "eth0").unwrap();
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[0])]);
+    }
+
+    #[test]
+    fn test_set_active_peer_for_prefix() {
+        let (_guard, kernel) = router_mode();
+        let (network, peers) = overlapping_sites(&kernel);
+
+        set_active_peer_for_prefix_impl("10.50.0.0/16", &peers[2], &[peers[1].to_string()], &network).unwrap();
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[2])]);
+        let state = load_mode_state().unwrap().unwrap();
+        assert_eq!(state.prefix_active_backup["10.50.0.0/16"].backup_peer_ids, [peers[1].to_string(), peers[0].to_string()]);
+
+        // Not an overlapping prefix, or a peer that doesn't advertise it
+        assert!(matches!(
+            set_active_peer_for_prefix_impl("10.60.0.0/16", &peers[0], &[], &network),
+            Err(PolicyRoutingError::PrefixError(_))
+        ));
+        assert!(matches!(
+            set_active_peer_for_prefix_impl("10.50.0.0/16", &Uuid::new_v4(), &[], &network),
+            Err(PolicyRoutingError::PrefixError(_))
+        ));
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[2])]);
+    }
+
+    #[test]
+    fn test_prefix_fails_over_to_healthy_backup() {
+        let (_guard, kernel) = router_mode();
+        let (_network, peers) = overlapping_sites(&kernel);
+
+        // First backup is offline too, so the second one takes over
+        set_online(&peers[0], false);
+        set_online(&peers[1], false);
+        set_online(&peers[2], true);
+        failover_prefixes_for_peer(&peers[0], &health_cache());
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[2])]);
+        assert_eq!(active_for("10.50.0.0/16"), Some(peers[2].to_string()));
+
+        // Nothing healthy left: stay put
+        set_online(&peers[0], false);
+        set_online(&peers[2], false);
+        failover_prefixes_for_peer(&peers[2], &health_cache());
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[2])]);
+    }
+
+    #[test]
+    fn test_prefix_fails_back_once_original_peer_is_stable() {
+        let (_guard, kernel) = router_mode();
+        let (network, peers) = overlapping_sites(&kernel);
+        let stability = AgentRouterFailover::default().failback_stability_secs;
+        let prefix_state = || load_mode_state().unwrap().unwrap().prefix_active_backup["10.50.0.0/16"].clone();
+
+        set_online(&peers[0], false);
+        set_online(&peers[1], true);
+        set_online(&peers[2], true);
+        failover_prefixes_for_peer(&peers[0], &health_cache());
+        assert_eq!(active_for("10.50.0.0/16"), Some(peers[1].to_string()));
+        assert_eq!(prefix_state().failed_over_from, Some(peers[0].to_string()));
+
+        // Still down: nothing to time
+        check_prefix_failback(&health_cache(), 1000);
+        assert_eq!(prefix_state().failback_since, None);
+
+        // Healthy again: the timer starts, a flap resets it
+        set_online(&peers[0], true);
+        check_prefix_failback(&health_cache(), 1000);
+        assert_eq!(prefix_state().failback_since, Some(1000));
+        set_online(&peers[0], false);
+        check_prefix_failback(&health_cache(), 1010);
+        assert_eq!(prefix_state().failback_since, None);
+        set_online(&peers[0], true);
+        check_prefix_failback(&health_cache(), 1020);
+        check_prefix_failback(&health_cache(), 1020 + stability - 1);
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[1])]);
+
+        // Stable for failback_stability_secs: back to the original peer, the one it failed over to is first backup
+        check_prefix_failback(&health_cache(), 1020 + stability);
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[0])]);
+        let state = prefix_state();
+        assert_eq!(state.active_peer_id, peers[0].to_string());
+        assert_eq!(state.backup_peer_ids, [peers[1].to_string(), peers[2].to_string()]);
+        assert_eq!((state.failed_over_from, state.failback_since), (None, None));
+        let filter = events::EventFilter { kinds: vec![EventKind::PrefixFailback], ..Default::default() };
+        let failback = events::query(&filter, 1).unwrap();
+        assert_eq!(failback[0].details.new_peer, Some(peers[0]));
+
+        // A chain of failovers still goes back to the first peer; a manual choice ends the fail-back
+        set_online(&peers[0], false);
+        failover_prefixes_for_peer(&peers[0], &health_cache());
+        set_online(&peers[1], false);
+        failover_prefixes_for_peer(&peers[1], &health_cache());
+        assert_eq!(active_for("10.50.0.0/16"), Some(peers[2].to_string()));
+        assert_eq!(prefix_state().failed_over_from, Some(peers[0].to_string()));
+        set_active_peer_for_prefix_impl("10.50.0.0/16", &peers[2], &[], &network).unwrap();
+        assert_eq!(prefix_state().failed_over_from, None);
+    }
+
+    #[test]
+    fn test_removing_active_peer_promotes_backup() {
+        let (_guard, kernel) = router_mode();
+        let (mut network, peers) = overlapping_sites(&kernel);
+
+        remove_peer(&peers[0], &mut network);
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[1])]);
+        assert_eq!(active_for("10.50.0.0/16"), Some(peers[1].to_string()));
+
+        // Only one site left: no longer an overlap, its rule stays
+        remove_peer(&peers[1], &mut network);
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[2])]);
+        assert_eq!(active_for("10.50.0.0/16"), None);
+    }
+
+    #[test]
+    fn test_prefix_released_when_overlap_ends() {
+        let (_guard, kernel) = router_mode();
+        let mut network = generate_network(0, 2);
+        let peers = client_peers(&network);
+        for peer_id in &peers {
+            advertise(&mut network, peer_id, "10.50.0.0/16");
+            add_peer(peer_id, &network);
         }
-    };
-    
-    // Track ping history for loss and jitter calculation (like OPNsense dpinger)
-    // Note: Uses ping_succeeded (actual ping result) not is_online (threshold-based status)
-    let ping_history = PING_HISTORY.clone();
-    let mut history = ping_history.write().unwrap();
-    let peer_history = history.entry(peer_id).or_insert_with(|| VecDeque::with_capacity(PING_HISTORY_SIZE));
-    
-    // Add current ping result to history
-    peer_history.push_back(PingResult {
-        timestamp: now,
-        latency_ms, // None if ping_succeeded is false
-    });
-    
-    // Keep only last 60 results (60 second window, like OPNsense)
-    while peer_history.len() > PING_HISTORY_SIZE {
-        peer_history.pop_front();
+        sync_prefix_active_backup(&network).unwrap();
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[0])]);
+
+        // The active site stops advertising the prefix
+        let connection = network.connections.iter_mut().find(|(id, _)| id.b == peers[0]).unwrap().1;
+        connection.allowed_ips_a_to_b.retain(|net| net.to_string() != "10.50.0.0/16");
+        update_peer_routes(&peers[0], &network, WG_INTERFACE).unwrap();
+
+        assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), [table_of(&peers[1])]);
+        assert_eq!(active_for("10.50.0.0/16"), None);
     }
-    
-    // Calculate packet loss and jitter from history (like OPNsense dpinger)
-    let (packet_loss_percent, jitter_ms) = calculate_loss_and_jitter(peer_history);
-    
-    // Release lock before continuing
-    drop(history);
-    
-    // Track "Up Since" based on ping-based online status
-    // "Up Since" resets on restart and tracks when peer came online in current session
-    let was_online_previously = last_online_state_map.get(&peer_id_str).copied().unwrap_or(false);
-    
-    // Get session-only "Up Since" tracking (in-memory, resets on restart)
-    let session_up_since = SESSION_UP_SINCE.clone();
-    let first_handshake = {
-        if is_online {
-            // Peer is currently online - update last successful ping time
-            last_successful_ping_map.insert(peer_id_str.clone(), now);
-            
-            // Track when peer first came online in this session (after restart or offline→online transition)
-            let mut session_up_since_map = session_up_since.write().unwrap();
-            if !was_online_previously {
-                // Peer transitioned from offline to online - set "Up Since" to now
-                session_up_since_map.insert(peer_id, now);
-                Some(now)
-            } else {
-                // Peer was already online - get existing "Up Since" from session, or set to now if not tracked
-                session_up_since_map.get(&peer_id).copied().or_else(|| {
-                    // First time seeing this peer online in this session (e.g., after restart)
-                    session_up_since_map.insert(peer_id, now);
-                    Some(now)
-                })
-            }
-        } else {
-            // Peer is offline - remove from SESSION_UP_SINCE so next online transition gets fresh timestamp
-            let mut session_up_since_map = session_up_since.write().unwrap();
-            session_up_since_map.remove(&peer_id);
-            
-            // Return last successful ping time (when it was last seen online) for "Down Since" display
-            last_successful_ping_map.get(&peer_id_str).copied()
-        }
-    };
-    
-    // Update the last known online state
-    last_online_state_map.insert(peer_id_str.clone(), is_online);
-    
-    // Save updated state back to persistence (but NOT first_handshake - it's session-only)
-    // IMPORTANT: Reload the state fresh before saving to avoid overwriting concurrent changes (e.g., lan_cidr updates)
-    if let Ok(Some(mut fresh_state)) = load_mode_state() {
-        // Only update the health-related fields we manage
-        fresh_state.peer_last_online_state = last_online_state_map;
-        fresh_state.peer_last_successful_ping = last_successful_ping_map;
-        let _ = save_mode_state(&fresh_state);
+
+    // Default route shares per exit node in a load balancing plan
+    fn planned_shares(plan: &[(Uuid, Vec<String>)], peer_id: &Uuid) -> Vec<String> {
+        plan.iter().find(|(id, _)| id == peer_id).unwrap().1.iter()
+            .filter(|ip| ip.ends_with("/6") || *ip == "0.0.0.0/0")
+            .cloned()
+            .collect()
     }
-    
-    ExitNodeHealth {
-        peer_id,
-        is_online,
-        last_handshake,
-        first_handshake,
-        latency_ms,
-        packet_loss_percent,
-        jitter_ms,
-        transfer_rx,
-        transfer_tx,
-        endpoint,
+
+    #[test]
+    fn test_load_balance_plan_follows_health_and_weights() {
+        let (_guard, _kernel) = router_mode();
+        let network = generate_network(3, 1);
+        let exit_nodes = get_peers_with_default_route(&network);
+        set_exit_node(&exit_nodes[0], Some(&network)).unwrap();
+        let mut state = load_mode_state().unwrap().unwrap();
+        state.load_balance = true;
+        state.exit_node_weights.insert(exit_nodes[1].to_string(), 3);
+
+        // Weights 1:3:1 over 64 buckets
+        let plan = plan_load_balance(&state, &network, &HashMap::new());
+        assert_eq!(plan.len(), 3);
+        let counts: Vec<usize> = exit_nodes.iter().map(|id| planned_shares(&plan, id).len()).collect();
+        assert_eq!(counts.iter().sum::<usize>(), 64);
+        assert!(counts[1] >= 38 && counts[1] <= 39, "{:?}", counts);
+        // Shares come on top of the peer's own addresses
+        assert!(plan[0].1.contains(&format!("{}/32", network.peers[&exit_nodes[0]].address)));
+
+        // Offline and zero-weight exit nodes drop out
+        state.exit_node_weights.insert(exit_nodes[2].to_string(), 0);
+        let online = HashMap::from([(exit_nodes[1], false)]);
+        let plan = plan_load_balance(&state, &network, &online);
+        assert_eq!(planned_shares(&plan, &exit_nodes[0]), ["0.0.0.0/0"]);
+        assert!(planned_shares(&plan, &exit_nodes[1]).is_empty());
+        assert!(planned_shares(&plan, &exit_nodes[2]).is_empty());
+
+        // Nobody healthy: the exit node keeps the default route
+        let online = HashMap::from([(exit_nodes[0], false), (exit_nodes[1], false)]);
+        let plan = plan_load_balance(&state, &network, &online);
+        assert_eq!(planned_shares(&plan, &exit_nodes[0]), ["0.0.0.0/0"]);
     }
-}
 
-// Async version: Check peer connectivity and measure latency using ping (non-blocking)
-// Returns (is_online, latency_ms)
-// Pings the peer's tunnel IP (peer.address) via the WireGuard interface
-// Uses 3 packets with 2 second timeout per packet, total timeout 10 seconds
-// Uses WireGuard interface directly to avoid routing table issues when switching gateways
-async fn check_peer_connectivity_async(peer_address: &str, wg_interface: &str) -> (bool, Option<u64>) {
-    use tokio::process::Command;
-    
-    // Use async Command to avoid blocking the runtime
-    // Format: ping -I <wg_interface> -c 1 -W 1 -w 2 <peer_tunnel_ip>
-    // Matches OPNsense dpinger: single lightweight ping with short timeout
-    // -I forces ping to use the WireGuard interface directly, bypassing routing table issues
-    // peer_address is the peer's tunnel IP (e.g., 10.100.105.2)
-    // -c 1: single packet (like dpinger)
-    // -W 1: 1 second timeout per packet
-    // -w 2: 2 second total timeout (fails fast)
-    let output = match Command::new("ping")
-        .arg("-I")
-        .arg(wg_interface)
-        .arg("-c")
-        .arg("1")
-        .arg("-W")
-        .arg("1")
-        .arg("-w")
-        .arg("2")
-        .arg(peer_address)
-        .output()
-        .await
-    {
-        Ok(output) if output.status.success() => output,
-        _ => return (false, None),
-    };
-    
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    
-    // Parse ping output for all time=XX.X ms values
-    // Example: "64 bytes from 10.100.105.2: icmp_seq=1 ttl=64 time=42.3 ms"
-    let mut latencies = Vec::new();
-    for line in output_str.lines() {
-        if let Some(time_pos) = line.find("time=") {
-            let time_part = &line[time_pos + 5..];
-            if let Some(ms_pos) = time_part.find(" ms") {
-                let time_str = &time_part[..ms_pos];
-                if let Ok(time_val) = time_str.parse::<f64>() {
-                    latencies.push(time_val);
-                }
-            }
+    #[test]
+    fn test_load_balance_weight_validation() {
+        let (_guard, _kernel) = router_mode();
+        let network = generate_network(2, 0);
+        let exit_node = get_peers_with_default_route(&network)[0];
+        let too_heavy = HashMap::from([(exit_node.to_string(), MAX_EXIT_NODE_WEIGHT + 1)]);
+        assert!(matches!(
+            set_load_balance(true, Some(too_heavy), Some(&network)),
+            Err(PolicyRoutingError::LoadBalanceError(_))
+        ));
+        assert!(matches!(
+            set_load_balance(true, Some(HashMap::from([("not-a-uuid".to_string(), 1)])), Some(&network)),
+            Err(PolicyRoutingError::LoadBalanceError(_))
+        ));
+        assert_eq!(get_load_balance().unwrap(), (false, HashMap::new()));
+
+        set_load_balance(true, Some(HashMap::from([(exit_node.to_string(), 5)])), Some(&network)).unwrap();
+        assert_eq!(get_load_balance().unwrap(), (true, HashMap::from([(exit_node.to_string(), 5)])));
+    }
+
+    // Exit nodes the router can reach from a fresh port (client exits need an endpoint)
+    fn with_endpoints(network: &mut Network, peer_ids: &[Uuid]) {
+        for (i, peer_id) in peer_ids.iter().enumerate() {
+            let peer = network.peers.get_mut(peer_id).unwrap();
+            peer.endpoint.enabled = true;
+            peer.endpoint.address = EndpointAddress::Ipv4AndPort(wg_quickrs_lib::types::network::Ipv4AndPort {
+                ipv4: format!("203.0.113.{}", i + 1).parse().unwrap(),
+                port: 51820,
+            });
         }
     }
-    
-    // If we got at least one successful ping, peer is online
-    if !latencies.is_empty() {
-        // Calculate average latency
-        let sum: f64 = latencies.iter().sum();
-        let avg = sum / latencies.len() as f64;
-        (true, Some(avg.round() as u64))
-    } else {
-        // No successful pings, peer is offline
-        (false, None)
+
+    fn client_exit(source: &str, exit_peer_id: &Uuid) -> crate::mode::persist::ClientExit {
+        crate::mode::persist::ClientExit { source: source.to_string(), exit_peer_id: exit_peer_id.to_string() }
     }
-}
 
+    fn client_exit_rules(kernel: &SimulatedKernel) -> Vec<Rule> {
+        kernel.rules().into_iter().filter(is_client_exit_rule).collect()
+    }
 
+    #[test]
+    fn test_client_source_parsing() {
+        assert_eq!(parse_client_source("192.168.1.50").unwrap(), ClientSource::Net("192.168.1.50/32".parse().unwrap()));
+        assert_eq!(parse_client_source(" 192.168.1.77/28 ").unwrap(), ClientSource::Net("192.168.1.64/28".parse().unwrap()));
+        assert_eq!(parse_client_source("aa-bb-cc-dd-ee-0f").unwrap(), ClientSource::Mac("AA:BB:CC:DD:EE:0F".to_string()));
+        assert!(matches!(parse_client_source("aa:bb:cc:dd:ee"), Err(PolicyRoutingError::ClientExitError(_))));
+        assert!(matches!(parse_client_source("laptop"), Err(PolicyRoutingError::ClientExitError(_))));
+    }
 
-// Helper: Find LAN interface (cached)
-pub fn find_lan_interface() -> Result<String, PolicyRoutingError> {
-    // Check cache first
-    {
-        let cache = LAN_INTERFACE_CACHE.lock().unwrap();
-        if let Some(ref cached) = *cache {
-            return Ok(cached.clone());
+    #[test]
+    fn test_client_exit_rules_plan() {
+        let (_guard, _kernel) = router_mode();
+        let network = generate_network(2, 0);
+        let exit_nodes = get_peers_with_default_route(&network);
+        for peer_id in &exit_nodes {
+            add_peer(peer_id, &network);
         }
+        let mut state = load_mode_state().unwrap().unwrap();
+        state.client_exits = vec![
+            client_exit("192.168.1.50/32", &exit_nodes[1]),
+            client_exit("AA:BB:CC:DD:EE:FF", &exit_nodes[0]),
+        ];
+
+        let rules = plan_client_exit_rules(&state, &network, &HashMap::new(), "eth0");
+        // Two LAN CIDRs and the WireGuard subnet stay local, then the exit rule
+        assert_eq!(rules.len(), 8);
+        let client: Ipv4Net = "192.168.1.50/32".parse().unwrap();
+        assert!(rules[..3].iter().all(|rule| rule.from == Some(client) && rule.table == MAIN_TABLE));
+        assert_eq!(rules[2].to, Some(network.subnet));
+        assert_eq!(rules[3], Rule {
+            priority: CLIENT_EXIT_PRIORITY + 9,
+            from: Some(client),
+            iif: Some("eth0".to_string()),
+            table: table_of(&exit_nodes[1]),
+            ..Default::default()
+        });
+        // The MAC entry is matched by its mark, one slot further down
+        assert!(rules[4..].iter().all(|rule| rule.fwmark == Some(CLIENT_EXIT_FWMARK + 1) && rule.from.is_none()));
+        assert_eq!(rules[7].priority, CLIENT_EXIT_PRIORITY + 19);
+        assert_eq!(rules[7].table, table_of(&exit_nodes[0]));
+        // Between the prefix rules and the exit node rules
+        assert!(rules.iter().all(|rule| rule.priority > 10999 && rule.priority < 19000));
+
+        // An offline exit node leaves its clients to the exit node
+        let online = HashMap::from([(exit_nodes[1], false)]);
+        let rules = plan_client_exit_rules(&state, &network, &online, "eth0");
+        assert_eq!(rules.len(), 4);
+        assert!(rules.iter().all(|rule| rule.fwmark.is_some()));
     }
-    
-    // Not cached, detect interface
-    let lan_cidr = match load_mode_state()
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
-    {
-        Some(state) => state.lan_cidr,
-        None => None,
-    };
-    
-    let interface = if let Some(cidr) = lan_cidr {
-        // Use similar logic as firewall.rs
-        let parts: Vec<&str> = cidr.split('/').collect();
-        if parts.len() == 2 {
-            let network = parts[0];
-            let network_parts: Vec<&str> = network.split('.').collect();
-            if network_parts.len() >= 3 {
-                let network_prefix = format!("{}.{}.{}", network_parts[0], network_parts[1], network_parts[2]);
-                
-                let ip_output = shell_cmd(&["ip", "-4", "addr", "show"])
-                    .map_err(|e| PolicyRoutingError::IpRuleError(format!("Failed to list interfaces: {}", e)))?;
-                
-                let ip_output_str = String::from_utf8_lossy(&ip_output.stdout);
-                let mut current_interface: Option<String> = None;
-                
-                for line in ip_output_str.lines() {
-                    if line.contains(':') && !line.starts_with("    ") && !line.starts_with(" ") {
-                        let iface_part = line.split(':').nth(1);
-                        if let Some(iface) = iface_part {
-                            let iface_name = iface.split('@').next().unwrap_or("").trim();
-                            if !iface_name.is_empty() && iface_name != "lo" {
-                                current_interface = Some(iface_name.to_string());
-                            }
-                        }
-                    } else if let Some(iface) = &current_interface {
-                        if line.contains("inet") && line.contains(&network_prefix) {
-                            let result = iface.clone();
-                            // Cache the result
-                            {
-                                let mut cache = LAN_INTERFACE_CACHE.lock().unwrap();
-                                *cache = Some(result.clone());
-                            }
-                            return Ok(result);
-                        }
-                    }
-                }
-            }
+
+    #[test]
+    fn test_client_exit_validation() {
+        let (_guard, _kernel) = router_mode();
+        let mut network = generate_network(2, 1);
+        let exit_nodes = get_peers_with_default_route(&network);
+        let client = *client_peers(&network).iter().find(|id| !exit_nodes.contains(id)).unwrap();
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
         }
-        
-        // Fallback to common interface names
-        for iface in &["eth0", "ens3", "enp0s3", "enp1s0"] {
-            if shell_cmd(&["ip", "addr", "show", iface]).is_ok() {
-                let result = iface.to_string();
-                // Cache the result
-                {
-                    let mut cache = LAN_INTERFACE_CACHE.lock().unwrap();
-                    *cache = Some(result.clone());
-                }
-                return Ok(result);
-            }
+        with_endpoints(&mut network, &exit_nodes[..1]);
+
+        let rejected = [
+            vec![client_exit("172.30.0.5", &exit_nodes[0])],                   // outside the LAN
+            vec![client_exit("192.168.1.5", &exit_nodes[1])],                  // no endpoint
+            vec![client_exit("192.168.1.5", &client)],                         // not an exit node
+            vec![client_exit("192.168.1.5", &exit_nodes[0]), client_exit("192.168.1.5/32", &exit_nodes[0])],
+        ];
+        for entries in rejected {
+            assert!(matches!(set_client_exits(&entries, Some(&network)), Err(PolicyRoutingError::ClientExitError(_))), "{:?}", entries);
         }
-        
-        // Default
-        "eth0".to_string()
-    } else {
-        // Fallback to common interface names
-        for iface in &["eth0", "ens3", "enp0s3", "enp1s0"] {
-            if shell_cmd(&["ip", "addr", "show", iface]).is_ok() {
-                let result = iface.to_string();
-                // Cache the result
-                {
-                    let mut cache = LAN_INTERFACE_CACHE.lock().unwrap();
-                    *cache = Some(result.clone());
-                }
-                return Ok(result);
-            }
+        assert!(get_client_exits().unwrap().is_empty());
+
+        let saved = set_client_exits(&[client_exit("10.20.3.4", &exit_nodes[0]), client_exit("aa-bb-cc-dd-ee-ff", &exit_nodes[0])], Some(&network)).unwrap();
+        assert_eq!(saved, vec![client_exit("10.20.3.4/32", &exit_nodes[0]), client_exit("AA:BB:CC:DD:EE:FF", &exit_nodes[0])]);
+        assert_eq!(get_client_exits().unwrap(), saved);
+
+        // Active-active and client exits exclude each other
+        assert!(matches!(set_load_balance(true, None, Some(&network)), Err(PolicyRoutingError::LoadBalanceError(_))));
+        set_client_exits(&[], Some(&network)).unwrap();
+        set_load_balance(true, None, Some(&network)).unwrap();
+        assert!(matches!(
+            set_client_exits(&[client_exit("10.20.3.4", &exit_nodes[0])], Some(&network)),
+            Err(PolicyRoutingError::ClientExitError(_))
+        ));
+    }
+
+    #[test]
+    fn test_pinned_exit_node_runs_on_own_interface() {
+        let (_guard, kernel) = router_mode();
+        let mut network = generate_network(2, 0);
+        let exit_nodes = get_peers_with_default_route(&network);
+        for peer_id in &exit_nodes {
+            add_peer(peer_id, &network);
         }
-        "eth0".to_string()
-    };
-    
-    // Cache the result
-    {
-        let mut cache = LAN_INTERFACE_CACHE.lock().unwrap();
-        *cache = Some(interface.clone());
+        with_endpoints(&mut network, &exit_nodes);
+        set_exit_node(&exit_nodes[0], Some(&network)).unwrap();
+        set_client_exits(&[client_exit("192.168.1.50", &exit_nodes[1])], Some(&network)).unwrap();
+
+        let state = load_mode_state().unwrap().unwrap();
+        let table_id = table_of(&exit_nodes[1]);
+        let interface = exit_interface_name(table_id);
+        assert_eq!(pinned_exit_interfaces(&state), HashMap::from([(exit_nodes[1], interface.clone())]));
+        assert_eq!(peer_interface(&network, &exit_nodes[1]), interface);
+        assert_eq!(peer_interface(&network, &exit_nodes[0]), WG_INTERFACE);
+        // The main interface no longer carries the pinned exit node
+        let main = main_interface_network(&network);
+        assert!(!main.connections.keys().any(|id| id.contains(&exit_nodes[1])));
+        assert!(main.connections.keys().any(|id| id.contains(&exit_nodes[0])));
+
+        // Without `wg` the interface can't be configured: nothing is half-moved and the client keeps the exit node
+        assert!(!kernel.interface_exists(&interface).unwrap());
+        assert!(client_exit_rules(&kernel).is_empty());
+
+        // Once it is up, its table and routes point there and the client follows it
+        kernel.add_wireguard_interface(&interface, "10.0.34.1/32".parse().unwrap(), None).unwrap();
+        route_peer_via(&exit_nodes[1], &network, &state, &interface);
+        assert!(kernel.routes(table_id).iter().all(|route| route.device == interface));
+        let main_routes: Vec<(String, String)> = kernel.routes(MAIN_TABLE).iter()
+            .map(|route| (route.destination.to_string(), route.device.clone()))
+            .collect();
+        let address = network.peers[&exit_nodes[1]].address;
+        assert!(main_routes.contains(&(format!("{}/32", address), interface.clone())));
+        assert!(main_routes.contains(&(format!("172.16.{}.0/24", address.octets()[3] - 2), interface.clone())));
+        apply_client_exits(&network, &HashMap::new()).unwrap();
+        let rules = client_exit_rules(&kernel);
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules.last().unwrap().table, table_id);
+        assert_planned(&network, "pinned client");
+
+        // Offline: back to the exit node, and again once it recovers
+        set_online(&exit_nodes[1], false);
+        apply_client_exits(&network, &health_snapshot()).unwrap();
+        assert!(client_exit_rules(&kernel).is_empty());
+        assert_planned(&network, "pinned exit node offline");
+        set_online(&exit_nodes[1], true);
+        apply_client_exits(&network, &health_snapshot()).unwrap();
+        assert_eq!(client_exit_rules(&kernel).len(), 4);
+
+        // Re-adding peer PBR rules leaves the client rules alone
+        update_pbr_rules_for_peer(&exit_nodes[1], &network, "eth0").unwrap();
+        assert_eq!(client_exit_rules(&kernel).len(), 4);
+
+        // Removing the exit node unpins its clients and drops its interface
+        remove_peer(&exit_nodes[1], &mut network);
+        assert!(get_client_exits().unwrap().is_empty());
+        assert!(client_exit_rules(&kernel).is_empty());
+        assert!(!kernel.interface_exists(&interface).unwrap());
     }
-    
-    Ok(interface)
-}
 
-// Set active peer for overlapping prefix (for future use with other prefixes)
-pub fn set_active_peer_for_prefix(
-    prefix: &str,
-    active_peer_id: &str,
-    _backup_peer_ids: &[String],
-) -> Result<(), PolicyRoutingError> {
-    // For now, only handle default route
-    if prefix == "0.0.0.0/0" || prefix == "default" {
-        if let Ok(peer_id) = Uuid::parse_str(active_peer_id) {
-            return set_exit_node(&peer_id, None); // Load config if needed
+    // Domain route rules by destination address -> table
+    fn domain_rule_tables(kernel: &SimulatedKernel) -> BTreeMap<String, u32> {
+        kernel.rules().iter()
+            .filter(|rule| is_domain_route_rule(rule))
+            .map(|rule| (rule.to.unwrap().addr().to_string(), rule.table))
+            .collect()
+    }
+
+    #[test]
+    fn test_domain_routes_follow_resolved_addresses() {
+        let (_guard, kernel) = router_mode();
+        DOMAIN_ROUTE_ADDRESSES.write().unwrap().clear();
+        let mut network = generate_network(2, 1);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
         }
+        let exit_nodes = get_peers_with_default_route(&network);
+        set_exit_node(&exit_nodes[0], Some(&network)).unwrap();
+
+        let mut state = load_mode_state().unwrap().unwrap();
+        state.domain_routes.insert("video.example".to_string(), exit_nodes[1].to_string());
+        save_mode_state(&state).unwrap();
+        record_domain_addresses("video.example", &["203.0.113.10".parse().unwrap(), "203.0.113.11".parse().unwrap()], 1000);
+        apply_domain_routes(&network).unwrap();
+        let expected_table = table_of(&exit_nodes[1]);
+        assert_eq!(domain_rule_tables(&kernel), BTreeMap::from([
+            ("203.0.113.10".to_string(), expected_table),
+            ("203.0.113.11".to_string(), expected_table),
+        ]));
+        // Rules sit between the prefix rules and the exit node rules
+        assert!(kernel.rules().iter().filter(|rule| is_domain_route_rule(rule))
+            .all(|rule| rule.priority > 10999 && rule.priority < 20000 && rule.iif.as_deref() == Some("eth0")));
+
+        // Updating the peer rebuilds its PBR rules without losing the domain routes
+        update_peer_routes(&exit_nodes[1], &network, WG_INTERFACE).unwrap();
+        assert_eq!(domain_rule_tables(&kernel).len(), 2);
+
+        // An address not seen for longer than the TTL is withdrawn
+        record_domain_addresses("video.example", &["203.0.113.10".parse().unwrap()], 1000 + DOMAIN_ROUTE_ADDRESS_TTL_SECS + 1);
+        apply_domain_routes(&network).unwrap();
+        assert_eq!(domain_rule_tables(&kernel), BTreeMap::from([("203.0.113.10".to_string(), expected_table)]));
+        assert_eq!(get_domain_route_addresses("video.example"), ["203.0.113.10".parse::<Ipv4Addr>().unwrap()]);
+
+        // Removing the exit node drops its domains and their rules
+        remove_peer(&exit_nodes[1], &mut network);
+        assert!(domain_rule_tables(&kernel).is_empty());
+        assert!(get_domain_routes().unwrap().is_empty());
     }
-    
-    // TODO: Handle other overlapping prefixes in the future
-    log::debug!("set_active_peer_for_prefix not yet implemented for prefix: {}", prefix);
-    Ok(())
-}
 
-// Remove peer routing table and clean up
-// Remove peer routing table (public wrapper - loads config internally if needed)
-pub fn remove_peer_routing_table(peer_id: &Uuid, table_id: u32) -> Result<(), PolicyRoutingError> {
-    remove_peer_routing_table_impl(peer_id, table_id, None)
-}
+    #[test]
+    fn test_domain_route_addresses_capped_and_persisted() {
+        let (_guard, _kernel) = router_mode();
+        DOMAIN_ROUTE_ADDRESSES.write().unwrap().clear();
+        let old: Vec<Ipv4Addr> = (0..DOMAIN_ROUTE_MAX_ADDRESSES as u8).map(|i| Ipv4Addr::new(198, 51, 100, i)).collect();
+        record_domain_addresses("cdn.example", &old, 1000);
+        assert_eq!(get_domain_route_addresses("cdn.example").len(), DOMAIN_ROUTE_MAX_ADDRESSES);
 
-// Internal implementation that accepts network reference to avoid deadlock
-pub fn remove_peer_routing_table_impl(peer_id: &Uuid, table_id: u32, network: Option<&Network>) -> Result<(), PolicyRoutingError> {
-    let peer_id_str = peer_id.to_string();
-    
-    log::info!("Removing routing table {} for peer {}", table_id, peer_id_str);
-    
-    // Flush all routes from the table
-    let flush_cmd = &["ip", "route", "flush", "table", &table_id.to_string()];
-    if let Err(e) = shell_cmd(flush_cmd) {
-        log::warn!("Failed to flush table {}: {} (continuing anyway)", table_id, e);
-        // Continue with cleanup even if flush fails
+        // New answers push out the least recently seen addresses
+        let new = [Ipv4Addr::new(203, 0, 113, 1), Ipv4Addr::new(203, 0, 113, 2)];
+        record_domain_addresses("cdn.example", &new, 1060);
+        let addresses = get_domain_route_addresses("cdn.example");
+        assert_eq!(addresses.len(), DOMAIN_ROUTE_MAX_ADDRESSES);
+        assert!(new.iter().all(|address| addresses.contains(address)));
+
+        // The snapshot reads back as it was written
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DOMAIN_ROUTE_ADDRESSES_FILE);
+        write_domain_route_addresses(&path, &DOMAIN_ROUTE_ADDRESSES.read().unwrap()).unwrap();
+        assert_eq!(read_domain_route_addresses(&path), *DOMAIN_ROUTE_ADDRESSES.read().unwrap());
+        std::fs::write(&path, "{").unwrap();
+        assert!(read_domain_route_addresses(&path).is_empty());
     }
-    
-    // Remove PBR rules for this peer
-    if let Err(e) = remove_pbr_rules_for_peer(peer_id, table_id) {
-        log::warn!("Failed to remove PBR rules for peer {}: {} (continuing anyway)", peer_id_str, e);
+
+    #[test]
+    fn test_domain_route_validation() {
+        let (_guard, _kernel) = router_mode();
+        let network = generate_network(1, 1);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
+        }
+        let exit_node = get_peers_with_default_route(&network)[0];
+        let client = *client_peers(&network).iter().find(|id| **id != exit_node).unwrap();
+
+        for domain in ["", "localhost", "-bad.example", "bad_label.example", "*.example.com"] {
+            assert!(matches!(
+                set_domain_routes(HashMap::from([(domain.to_string(), exit_node.to_string())]), Some(&network)),
+                Err(PolicyRoutingError::DomainRouteError(_))
+            ), "{:?} accepted", domain);
+        }
+        // Only peers with a default route can carry domain traffic
+        assert!(matches!(
+            set_domain_routes(HashMap::from([("video.example".to_string(), client.to_string())]), Some(&network)),
+            Err(PolicyRoutingError::DomainRouteError(_))
+        ));
+        assert!(get_domain_routes().unwrap().is_empty());
+        assert_eq!(normalize_domain(" Video.Example. ").unwrap(), "video.example");
     }
-    
-    // Remove from persisted state and handle exit node
-    let mut state = load_mode_state()
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
-        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
-    
-    // Check if this peer was the exit node
-    if let Some(prefix_state) = state.prefix_active_backup.get("0.0.0.0/0") {
-        if prefix_state.active_peer_id == peer_id_str {
-            // This peer was the exit node, remove exit node rules
-            log::info!("Removing exit node rules for peer {}", peer_id_str);
-            
-            // Parse rules once and remove exit node rules
-            if let Ok(rules) = get_ip_rules_cached() {
-                for rule in &rules {
-                    // Note: `to 0.0.0.0/0` may appear as `to = None` in parsed output
-                    if rule.table_id == Some(table_id)
-                        && rule.priority >= 20000
-                        && (rule.to.is_none() || rule.to == Some("0.0.0.0/0".to_string())) {
-                        let priority_str = rule.priority.to_string();
-                        let _ = shell_cmd(&["ip", "rule", "del", "priority", &priority_str]);
-                        log::info!("Removed exit node rule with priority {}", rule.priority);
-                    }
-                }
-            }
-            
-            // Remove LAN exception rules if they exist (supports multiple comma-separated CIDRs)
-            if let Some(lan_cidr_str) = &state.lan_cidr {
-                let lan_cidrs = parse_lan_cidrs(lan_cidr_str);
-                let lan_interface = find_lan_interface().ok();
-                
-                for lan_cidr in &lan_cidrs {
-                    if let Some(ref iface) = lan_interface {
-                        // Remove eth0 LAN exception rule
-                        let _ = shell_cmd(&[
-                            "ip", "rule", "del",
-                            "iif", iface,
-                            "to", lan_cidr,
-                            "lookup", "main",
-                        ]);
-                        log::info!("Removed LAN exception rule for {}", lan_cidr);
-                    }
-                    
-                    // Remove WireGuard peer LAN exception rules
-                    // Get WireGuard interface from network if available
-                    if let Some(network_ref) = network {
-                        let wg_interface = &network_ref.name;
-                        let wg_subnet = network_ref.subnet.to_string();
-                        // Remove old subnet-wide rule (migration)
-                        let _ = shell_cmd(&[
-                            "ip", "rule", "del",
-                            "from", &wg_subnet,
-                            "iif", wg_interface,
-                            "to", lan_cidr,
-                            "lookup", "main",
-                        ]);
-                        // Remove per-peer LAN exception rules
-                        for (pid, p) in &network_ref.peers {
-                            if *pid == network_ref.this_peer {
-                                continue;
-                            }
-                            let peer_addr = format!("{}/32", p.address);
-                            let _ = shell_cmd(&[
-                                "ip", "rule", "del",
-                                "from", &peer_addr,
-                                "iif", wg_interface,
-                                "to", lan_cidr,
-                                "lookup", "main",
-                            ]);
-                        }
-                        log::info!("Removed WireGuard peer LAN exception rules for {}", lan_cidr);
-                    }
-                }
-            }
-            
-            // This peer was the exit node, remove it from state
-            state.prefix_active_backup.remove("0.0.0.0/0");
-            // Try to find a new exit node from remaining peers
-            if let Some(network_ref) = network {
-                // Use provided network reference
-                let peers_with_default = get_peers_with_default_route(network_ref);
-                // Filter out the peer being deleted
-                let remaining_peers: Vec<Uuid> = peers_with_default
-                    .into_iter()
-                    .filter(|&p| p != *peer_id)
-                    .collect();
-                if let Some(new_exit_node) = remaining_peers.first() {
-                    log::info!("Selecting new exit node: {}", new_exit_node);
-                    if let Err(e) = set_exit_node(new_exit_node, Some(network_ref)) {
-                        log::warn!("Failed to set new exit node: {}", e);
-                    }
-                } else {
-                    log::info!("No other peers with default route, exit node removed");
-                }
-            } else {
-                // Load config if network not provided (for backward compatibility)
-                let config = crate::conf::util::get_config()
-                    .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))?;
-                let peers_with_default = get_peers_with_default_route(&config.network);
-                // Filter out the peer being deleted
-                let remaining_peers: Vec<Uuid> = peers_with_default
-                    .into_iter()
-                    .filter(|&p| p != *peer_id)
-                    .collect();
-                if let Some(new_exit_node) = remaining_peers.first() {
-                    log::info!("Selecting new exit node: {}", new_exit_node);
-                    if let Err(e) = set_exit_node(new_exit_node, Some(&config.network)) {
-                        log::warn!("Failed to set new exit node: {}", e);
-                    }
-                } else {
-                    log::info!("No other peers with default route, exit node removed");
-                }
-            }
+
+    // Give the router an uplink default route in the main table
+    fn add_uplink(kernel: &SimulatedKernel) {
+        kernel.replace_route(&Route {
+            table: MAIN_TABLE,
+            destination: Ipv4Net::default(),
+            device: "eth0".to_string(),
+            gateway: Some("192.168.1.1".parse().unwrap()),
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_local_breakout_as_exit_node() {
+        let (_guard, kernel) = router_mode();
+        let network = generate_network(2, 1);
+        let peers = client_peers(&network);
+        for peer_id in &peers {
+            add_peer(peer_id, &network);
+        }
+        let exit_node = get_peers_with_default_route(&network)[0];
+        set_exit_node(&exit_node, Some(&network)).unwrap();
+
+        // Without an uplink default route there is nothing to break out through
+        assert!(matches!(
+            set_exit_node(&LOCAL_BREAKOUT_EXIT_ID, Some(&network)),
+            Err(PolicyRoutingError::RouteInstallationError(_))
+        ));
+        assert_eq!(get_exit_node().unwrap(), Some(exit_node));
+
+        add_uplink(&kernel);
+        set_exit_node(&LOCAL_BREAKOUT_EXIT_ID, Some(&network)).unwrap();
+        assert_eq!(get_exit_node().unwrap(), Some(LOCAL_BREAKOUT_EXIT_ID));
+        assert!(exit_rule_tables(&kernel).is_empty());
+        let local_rules: Vec<Rule> = kernel.rules().into_iter().filter(|rule| rule.table == LOCAL_BREAKOUT_TABLE).collect();
+        assert_eq!(local_rules.len(), 2);
+        assert!(local_rules.iter().all(|rule| rule.is_default_destination() && rule.priority >= 20000));
+        assert_eq!(kernel.routes(LOCAL_BREAKOUT_TABLE), [Route { table: LOCAL_BREAKOUT_TABLE, ..uplink_default_route().unwrap() }]);
+        // LAN exceptions and LAN access rules stay in place
+        let main_rules = kernel.rules().iter().filter(|rule| rule.table == MAIN_TABLE && rule.to.is_some()).count();
+        assert_eq!(main_rules, 2 * (1 + peers.len()));
+
+        // Switching back to a tunnel drops the local breakout table
+        set_exit_node(&exit_node, Some(&network)).unwrap();
+        let table_id = table_of(&exit_node);
+        assert_eq!(exit_rule_tables(&kernel), [table_id, table_id]);
+        assert!(kernel.rules().iter().all(|rule| rule.table != LOCAL_BREAKOUT_TABLE));
+        assert!(kernel.routes(LOCAL_BREAKOUT_TABLE).is_empty());
+    }
+
+    #[test]
+    fn test_local_breakout_removed_with_router_mode() {
+        let (_guard, kernel) = router_mode();
+        add_uplink(&kernel);
+        let network = generate_network(1, 0);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
+        }
+        set_exit_node(&LOCAL_BREAKOUT_EXIT_ID, Some(&network)).unwrap();
+        assert!(set_local_breakout(true).is_ok());
+        assert!(get_local_breakout().unwrap());
+
+        remove_local_breakout(&network).unwrap();
+        assert!(kernel.rules().iter().all(|rule| rule.table != LOCAL_BREAKOUT_TABLE));
+        assert!(kernel.rules().iter().all(|rule| !(rule.table == MAIN_TABLE && rule.to.is_some())));
+        assert!(kernel.routes(LOCAL_BREAKOUT_TABLE).is_empty());
+        assert_eq!(kernel.routes(MAIN_TABLE).len(), 1);
+    }
+
+    #[test]
+    fn test_failover_tuning_per_exit_node() {
+        let (_guard, _kernel) = router_mode();
+        let (tuned, default) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut failover = AgentRouterFailover::default();
+        failover.exit_nodes.insert(tuned, wg_quickrs_lib::types::config::ExitNodeFailover {
+            consecutive_failures: Some(5),
+            ping_history_size: Some(2),
+            ..Default::default()
+        });
+        *FAILOVER_TUNING.write().unwrap() = failover;
+
+        let offline_after = |peer_id: Uuid| (1..=10)
+            .find(|_| !record_probe_result(peer_id, false, None, 0).is_online)
+            .unwrap();
+        assert_eq!(offline_after(tuned), 5);
+        assert_eq!(offline_after(default), 3);
+        assert_eq!(PING_HISTORY.read().unwrap()[&tuned].len(), 2);
+        assert_eq!(PING_HISTORY.read().unwrap()[&default].len(), 3);
+
+        // Per exit node probe intervals
+        assert!(probe_due(tuned, 5, Duration::ZERO));
+        assert!(!probe_due(tuned, 5, Duration::ZERO));
+        assert!(probe_due(tuned, 5, Duration::from_secs(5)));
+
+        *FAILOVER_TUNING.write().unwrap() = AgentRouterFailover::default();
+    }
+
+    #[test]
+    fn test_sla_violation_degrades_after_hold() {
+        let (_guard, _kernel) = router_mode();
+        let peer_id = Uuid::new_v4();
+        *FAILOVER_TUNING.write().unwrap() = AgentRouterFailover {
+            max_latency_ms: Some(300),
+            sla_hold_secs: 10,
+            ..Default::default()
+        };
+
+        // Over the threshold, but not for sla_hold_secs yet
+        assert_eq!(record_probe_result(peer_id, true, Some(400), 1000).degraded_reason, None);
+        let outcome = record_probe_result(peer_id, true, Some(400), 1010);
+        assert!(outcome.is_online);
+        assert_eq!(outcome.degraded_reason.as_deref(), Some("latency 400ms above 300ms"));
+
+        // The average latency is back under the threshold
+        let outcome = record_probe_result(peer_id, true, Some(10), 1011);
+        assert_eq!(outcome.degraded_reason, None);
+        assert!(!SLA_VIOLATION_SINCE.read().unwrap().contains_key(&peer_id));
+
+        // Unset thresholds are not checked; offline peers are down anyway and not reported as degraded
+        let tuning = FAILOVER_TUNING.read().unwrap().clone();
+        assert_eq!(sla_violation(&tuning, None, Some(100.0), None), None);
+        for _ in 0..3 {
+            record_probe_result(peer_id, false, None, 1012);
         }
+        let outcome = record_probe_result(peer_id, true, Some(900), 1030);
+        assert!(outcome.is_online && outcome.degraded_reason.is_none());
+
+        *FAILOVER_TUNING.write().unwrap() = AgentRouterFailover::default();
     }
-    
-    state.peer_table_ids.remove(&peer_id_str);
-    save_mode_state(&state)
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
-    
-    log::info!("Successfully removed routing table {} for peer {}", table_id, peer_id_str);
-    Ok(())
-}
 
-// Get table ID for a peer
-pub fn get_peer_table_id(peer_id: &Uuid) -> Result<Option<u32>, PolicyRoutingError> {
-    let peer_id_str = peer_id.to_string();
-    let state = match load_mode_state()
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
-    {
-        Some(s) => s,
-        None => return Ok(None),
-    };
-    
-    Ok(state.peer_table_ids.get(&peer_id_str).copied())
-}
+    #[test]
+    fn test_handshake_liveness() {
+        let peer_id = Uuid::new_v4();
 
-// Get all routes for a peer from connections
-// Returns a deduplicated list of CIDR strings including:
-// 1. Routes the peer can reach through connections
-// 2. The peer's own address/32 route
-pub fn get_peer_advertised_routes(peer_id: &Uuid, network: &Network) -> Vec<String> {
-    log::debug!("[get_peer_advertised_routes] Getting routes for peer {}", peer_id);
-    let mut routes = std::collections::HashSet::new();
-    
-    // First, add the peer's own address/32 route
-    if let Some(peer) = network.peers.get(peer_id) {
-        let peer_address = format!("{}/32", peer.address);
-        log::debug!("[get_peer_advertised_routes] Adding peer's own address: {}", peer_address);
-        routes.insert(peer_address);
-    } else {
-        log::warn!("[get_peer_advertised_routes] Peer {} not found in network peers", peer_id);
+        // A fresh handshake counts as received data the first time the peer is seen
+        assert!(handshake_alive(peer_id, Some(1000), 500, 150, 1100));
+        // The handshake is still fresh, but nothing was received for longer than the maximum age
+        assert!(!handshake_alive(peer_id, Some(1100), 500, 150, 1200));
+        // Data arrives again
+        assert!(handshake_alive(peer_id, Some(1100), 800, 150, 1210));
+        // Data keeps arriving, but the handshake is too old (the peer stopped renewing it)
+        assert!(!handshake_alive(peer_id, Some(1100), 900, 150, 1300));
+        // Never handshaked
+        assert!(!handshake_alive(Uuid::new_v4(), None, 0, 150, 1300));
+        assert!(!handshake_alive(Uuid::new_v4(), Some(0), 0, 150, 1300));
     }
-    
-    // Iterate through all connections to get routes the peer can reach
-    log::debug!("[get_peer_advertised_routes] Checking {} connections...", network.connections.len());
-    for (connection_id, connection) in &network.connections {
-        // Check if this peer is involved in the connection
-        if connection_id.a == *peer_id {
-            // This peer is peer A, so routes it can reach are in allowed_ips_b_to_a
-            // (what peer B advertises to peer A)
-            log::debug!("[get_peer_advertised_routes] Peer {} is peer A in connection {}", peer_id, connection_id);
-            for cidr in &connection.allowed_ips_b_to_a {
-                log::debug!("[get_peer_advertised_routes] Adding route from allowed_ips_b_to_a: {}", cidr);
-                routes.insert(cidr.to_string());
-            }
-        } else if connection_id.b == *peer_id {
-            // This peer is peer B, so routes it can reach are in allowed_ips_a_to_b
-            // (what peer A advertises to peer B)
-            log::debug!("[get_peer_advertised_routes] Peer {} is peer B in connection {}", peer_id, connection_id);
-            for cidr in &connection.allowed_ips_a_to_b {
-                log::debug!("[get_peer_advertised_routes] Adding route from allowed_ips_a_to_b: {}", cidr);
-                routes.insert(cidr.to_string());
-            }
+
+    #[test]
+    fn test_internet_probe_targets_and_rules() {
+        let (_guard, kernel) = router_mode();
+        let mut network = generate_network(2, 0);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
         }
+        let exits = get_peers_with_default_route(&network);
+        let mut failover = AgentRouterFailover::default();
+        failover.exit_nodes.insert(exits[0], wg_quickrs_lib::types::config::ExitNodeFailover {
+            probes: vec![
+                wg_quickrs_lib::types::config::HealthProbe::Tcp { address: "1.1.1.1:443".parse().unwrap() },
+                wg_quickrs_lib::types::config::HealthProbe::Http { url: "http://1.1.1.1/".to_string(), host: None },
+            ],
+            ..Default::default()
+        });
+        *FAILOVER_TUNING.write().unwrap() = failover;
+
+        // The target stays in the exit node's AllowedIPs whichever way they are rewritten
+        assert_eq!(plan_probe_targets(&network), BTreeMap::from([(exits[0], vec!["1.1.1.1/32".to_string()])]));
+        assert!(router_allowed_ips(&exits[0], &network).contains(&"1.1.1.1/32".to_string()));
+        assert!(!router_allowed_ips(&exits[1], &network).contains(&"1.1.1.1/32".to_string()));
+
+        // The fwmark rule is neither an exit rule nor disturbed by switching exit nodes, and leaves with the table
+        let table_id = table_of(&exits[0]);
+        routing_backend().add_rule(&probe_rule(table_id)).unwrap();
+        set_exit_node(&exits[1], Some(&network)).unwrap();
+        set_exit_node(&exits[0], Some(&network)).unwrap();
+        assert_eq!(exit_rule_tables(&kernel), [table_id, table_id]);
+        assert!(kernel.rules().contains(&probe_rule(table_id)));
+        remove_peer(&exits[0], &mut network);
+        assert!(!kernel.rules().iter().any(|rule| rule.fwmark.is_some()));
+
+        *FAILOVER_TUNING.write().unwrap() = AgentRouterFailover::default();
     }
-    
-    let mut result: Vec<String> = routes.into_iter().collect();
-    result.sort();
-    log::debug!("[get_peer_advertised_routes] Returning {} routes for peer {}: {:?}", result.len(), peer_id, result);
-    result
-}
 
-// Update routes for a peer (flush old routes and install new ones)
-// This is called when connections are modified
-pub fn update_peer_routes(peer_id: &Uuid, network: &Network, wg_interface: &str) -> Result<(), PolicyRoutingError> {
-    // Get the table ID for this peer
-    let table_id = match get_peer_table_id(peer_id)? {
-        Some(id) => id,
-        None => {
-            log::debug!("No routing table found for peer {}, skipping route update", peer_id);
-            return Ok(());
-        }
-    };
-    
-    // Flush existing routes from the table
-    let flush_cmd = &["ip", "route", "flush", "table", &table_id.to_string()];
-    if let Err(e) = shell_cmd(flush_cmd) {
-        log::warn!("Failed to flush table {} for peer {}: {} (continuing anyway)", table_id, peer_id, e);
+    fn sorted_rules(kernel: &SimulatedKernel) -> Vec<String> {
+        let mut rules: Vec<String> = kernel.rules().iter().map(Rule::to_string).collect();
+        rules.sort();
+        rules
     }
-    
-    // Get updated routes
-    let routes = get_peer_advertised_routes(peer_id, network);
-    
-    // Install new routes
-    install_peer_routes(peer_id, table_id, &routes, wg_interface)?;
-    
-    // Update PBR rules for this peer
-    let lan_interface = find_lan_interface()?;
-    if let Err(e) = update_pbr_rules_for_peer(peer_id, network, &lan_interface) {
-        log::warn!("Failed to update PBR rules for peer {}: {} (continuing anyway)", peer_id, e);
+
+    #[test]
+    fn test_routing_lock_is_reentrant_and_exclusive() {
+        let _guard = crate::mode::simulated::test_lock();
+        let outer = routing_lock();
+        let inner = routing_lock();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let other = std::thread::spawn(move || {
+            let _routing = routing_lock();
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(inner);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(outer);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        other.join().unwrap();
     }
-    
-    // Check if this peer has default route and handle exit node logic
-    if routes.contains(&"0.0.0.0/0".to_string()) || routes.contains(&"default".to_string()) {
-        // If no exit node is set, set this peer as exit node
-        // Pass network to avoid deadlock
-        if get_exit_node()?.is_none() {
-            log::info!("No exit node set, setting peer {} as exit node", peer_id);
-            if let Err(e) = set_exit_node(peer_id, Some(network)) {
-                log::warn!("Failed to set peer {} as exit node: {}", peer_id, e);
-            }
+
+    #[test]
+    fn test_reconciler_converges_drifted_kernel_state() {
+        let (_guard, kernel) = router_mode();
+        let network = generate_network(2, 1);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
         }
+        let exits = get_peers_with_default_route(&network);
+        set_exit_node(&exits[0], Some(&network)).unwrap();
+        let (exit_table, other_table) = (table_of(&exits[0]), table_of(&exits[1]));
+
+        // What the call sites installed is the plan
+        let mut report = DriftReport::default();
+        reconcile_routing(&network, &mut report).unwrap().unwrap();
+        assert!(!report.has_drift(), "{:?}", report);
+        let (rules, routes) = (sorted_rules(&kernel), kernel.routes(exit_table));
+
+        // Drop the exit rule and default route, leave a stale exit rule, an extra route and an orphaned table behind
+        let backend = routing_backend();
+        let exit_rule = kernel.rules().into_iter().find(|rule| rule.table == exit_table && rule.priority >= 20000 && rule.from.is_none()).unwrap();
+        backend.delete_rule(&exit_rule).unwrap();
+        backend.delete_route(&Route { table: exit_table, destination: Ipv4Net::default(), device: WG_INTERFACE.to_string(), gateway: None }).unwrap();
+        backend.add_rule(&Rule { priority: 20000 + other_table % 1000, iif: Some("eth0".to_string()), table: other_table, ..Default::default() }).unwrap();
+        backend.replace_route(&Route { table: other_table, destination: "10.99.0.0/16".parse().unwrap(), device: WG_INTERFACE.to_string(), gateway: None }).unwrap();
+        backend.add_rule(&Rule { priority: 10500, iif: Some("eth0".to_string()), to: Some("10.98.0.0/16".parse().unwrap()), table: 1500, ..Default::default() }).unwrap();
+        backend.replace_route(&Route { table: 1500, destination: "10.98.0.0/16".parse().unwrap(), device: WG_INTERFACE.to_string(), gateway: None }).unwrap();
+
+        let mut report = DriftReport::default();
+        reconcile_routing(&network, &mut report).unwrap().unwrap();
+        assert_eq!((report.missing_rules.len(), report.extra_rules.len()), (1, 2));
+        assert_eq!((report.missing_routes.len(), report.extra_routes.len()), (1, 2));
+        assert!(report.errors.is_empty());
+        assert_eq!(sorted_rules(&kernel), rules);
+        assert_eq!(kernel.routes(exit_table), routes);
+        assert!(kernel.routes(1500).is_empty());
+
+        // LAN access rules follow the exit node's priorities whichever call site installs them
+        set_exit_node(&exits[1], Some(&network)).unwrap();
+        let client = client_peers(&network).into_iter().find(|id| !exits.contains(id)).unwrap();
+        set_peer_lan_access(&client, false, &network).unwrap();
+        set_peer_lan_access(&client, true, &network).unwrap();
+        let mut report = DriftReport::default();
+        reconcile_routing(&network, &mut report).unwrap().unwrap();
+        assert!(!report.has_drift(), "{:?}", report);
     }
-    
-    // Ensure LAN access rule exists for this peer (if an exit node is set)
-    // This ensures new peers get their LAN access rule immediately
-    if let Ok(Some(_exit_node)) = get_exit_node() {
-        if let Err(e) = ensure_peer_lan_access_rule(peer_id, network) {
-            log::warn!("Failed to ensure LAN access rule for peer {}: {} (continuing anyway)", peer_id, e);
+
+    // What an imperative call site left in the kernel is exactly what plan_routing derives from the saved state
+    fn assert_planned(network: &Network, step: &str) {
+        let mut report = DriftReport::default();
+        reconcile_routing(network, &mut report).unwrap().unwrap();
+        assert!(!report.has_drift(), "{}: {:?}", step, report);
+    }
+
+    #[test]
+    fn test_imperative_operations_match_the_plan() {
+        let (_guard, kernel) = router_mode();
+        add_uplink(&kernel);
+        let mut network = generate_network(2, 2);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
+            assert_planned(&network, "add_peer without an exit node");
         }
+        let exits = get_peers_with_default_route(&network);
+        let clients: Vec<Uuid> = client_peers(&network).into_iter().filter(|id| !exits.contains(id)).collect();
+
+        set_exit_node(&exits[0], Some(&network)).unwrap();
+        assert_planned(&network, "set_exit_node");
+        set_peer_lan_access(&clients[0], false, &network).unwrap();
+        assert_planned(&network, "set_peer_lan_access false");
+        set_exit_node(&exits[1], Some(&network)).unwrap();
+        assert_planned(&network, "set_exit_node to another tunnel");
+        set_peer_lan_access(&clients[0], true, &network).unwrap();
+        assert_planned(&network, "set_peer_lan_access true");
+        set_exit_node(&LOCAL_BREAKOUT_EXIT_ID, Some(&network)).unwrap();
+        assert_planned(&network, "set_exit_node to the local breakout");
+        set_exit_node(&exits[0], Some(&network)).unwrap();
+        assert_planned(&network, "set_exit_node back to a tunnel");
+
+        // A peer joining and leaving with an exit node set
+        // (sorting first, so it also shifts the LAN access rule order of every other peer)
+        let joined = Uuid::from_u128(1);
+        network.peers.insert(joined, generate_peer("joined", "10.0.34.20"));
+        network.connections.insert(ConnectionId { a: joined, b: network.this_peer }, Connection {
+            enabled: true,
+            pre_shared_key: Default::default(),
+            persistent_keepalive: Default::default(),
+            allowed_ips_a_to_b: vec!["10.0.34.1/32".parse().unwrap()],
+            allowed_ips_b_to_a: vec!["10.0.34.20/32".parse().unwrap()],
+        });
+        add_peer(&joined, &network);
+        sync_peer_lan_access_rules(&network).unwrap();
+        assert_planned(&network, "add_peer with an exit node");
+        advertise(&mut network, &clients[1], "172.31.0.0/24");
+        update_peer_routes(&clients[1], &network, WG_INTERFACE).unwrap();
+        assert_planned(&network, "update_peer_routes");
+        remove_peer(&clients[1], &mut network);
+        assert_planned(&network, "remove_peer");
+
+        set_load_balance(true, None, Some(&network)).unwrap();
+        assert_planned(&network, "set_load_balance true");
+        set_load_balance(false, None, Some(&network)).unwrap();
+        assert_planned(&network, "set_load_balance false");
     }
-    
-    log::info!("Updated routes for peer {} in table {} ({} routes)", peer_id, table_id, routes.len());
-    Ok(())
-}
 
-/// Set LAN access for a specific peer
-/// Returns the new LAN access state
-pub fn set_peer_lan_access(peer_id: &Uuid, has_lan_access: bool, network: &Network) -> Result<bool, PolicyRoutingError> {
-    let peer_id_str = peer_id.to_string();
-    
-    // Load current state
-    let mut state = load_mode_state()
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
-        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
-    
-    // Update the peer's LAN access setting
-    state.peer_lan_access.insert(peer_id_str.clone(), has_lan_access);
-    
-    // Save the state
-    save_mode_state(&state)
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to save mode state: {}", e)))?;
-    
-    // Get peer info
-    let peer = network.peers.get(peer_id)
-        .ok_or_else(|| PolicyRoutingError::PersistenceError(format!("Peer {} not found", peer_id)))?;
-    
-    let peer_addr = format!("{}/32", peer.address);
-    let wg_interface = &network.name;
-    
-    // Get LAN CIDRs from state (supports multiple comma-separated CIDRs)
-    let lan_cidr_str = state.lan_cidr
-        .ok_or_else(|| PolicyRoutingError::PersistenceError("No LAN CIDR configured".to_string()))?;
-    let lan_cidrs = parse_lan_cidrs(&lan_cidr_str);
-    
-    if has_lan_access {
-        // Add the LAN access rule for this peer
-        // Use a priority based on the peer's position (find index in sorted peers)
-        let mut peer_index = 0u32;
-        for (pid, _) in &network.peers {
-            if *pid == network.this_peer {
-                continue;
-            }
-            if *pid == *peer_id {
-                break;
+    #[test]
+    fn test_lan_access_rules_from_fixed_priorities_are_moved() {
+        let (_guard, kernel) = router_mode();
+        let network = generate_network(2, 1);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
+        }
+        let exit_node = get_peers_with_default_route(&network)[1];
+        set_exit_node(&exit_node, Some(&network)).unwrap();
+        let planned = sorted_rules(&kernel);
+
+        // Earlier versions put every peer's LAN access rule at 19899 + cidx * 100 + peer index,
+        // whatever the exit node's table, and also without an exit node
+        let backend = routing_backend();
+        let lan_nets = parse_lan_nets(LAN_CIDR);
+        let state = load_mode_state().unwrap().unwrap();
+        for rule in kernel.rules().into_iter().filter(|rule| rule.table == MAIN_TABLE && rule.iif.as_deref() == Some(WG_INTERFACE)) {
+            backend.delete_rule(&rule).unwrap();
+        }
+        for peer_id in client_peers(&network) {
+            let peer_index = peer_lan_index(&network, &peer_id);
+            for (cidr_idx, lan_net) in lan_nets.iter().enumerate() {
+                backend.add_rule(&peer_lan_rule(19999, cidr_idx, peer_index, &network.peers[&peer_id], WG_INTERFACE, *lan_net)).unwrap();
             }
-            peer_index += 1;
         }
-        
-        // Calculate priority (19899 - 100 = 19799 base, + peer_index)
-        let exception_priority = 20000 - 1; // eth0 exception priority
-        let wg_peer_lan_base_priority = exception_priority - 100;
-        
-        for (cidr_idx, lan_cidr) in lan_cidrs.iter().enumerate() {
-            // First remove any existing rule for this peer
-            let _ = shell_cmd(&[
-                "ip", "rule", "del",
-                "from", &peer_addr,
-                "iif", wg_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-            ]);
-            
-            // Use unique priority: base + (cidr_index * 100) + peer_index
-            let peer_priority = wg_peer_lan_base_priority + (cidr_idx as u32 * 100) + peer_index;
-            let peer_priority_str = peer_priority.to_string();
-            
-            // Add the rule
-            let cmd = &[
-                "ip", "rule", "add",
-                "from", &peer_addr,
-                "iif", wg_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-                "priority", &peer_priority_str,
-            ];
-            
-            if let Err(e) = shell_cmd(cmd) {
-                log::warn!("Failed to add LAN access rule for peer {} ({}) to {}: {}", peer.name, peer_addr, lan_cidr, e);
-            } else {
-                log::info!("Added LAN access rule for peer {} ({}) to {}: -> main table (priority {})", 
-                    peer.name, peer_addr, lan_cidr, peer_priority);
+        assert_ne!(lan_exception_priority(&state), Some(19999));
+        assert_ne!(sorted_rules(&kernel), planned);
+
+        // The first reconciler pass after the upgrade moves them to the exit node's priorities
+        let mut report = DriftReport::default();
+        reconcile_routing(&network, &mut report).unwrap().unwrap();
+        let moved = 2 * client_peers(&network).len();
+        assert_eq!((report.missing_rules.len(), report.extra_rules.len()), (moved, moved));
+        assert_eq!(sorted_rules(&kernel), planned);
+
+        // Without an exit node they are removed, like the call sites do (none are installed then)
+        for rule in kernel.rules().into_iter().filter(|rule| rule.table == MAIN_TABLE && rule.iif.as_deref() == Some(WG_INTERFACE)) {
+            backend.delete_rule(&rule).unwrap();
+            backend.add_rule(&Rule { priority: 19899 + (rule.priority % 100), ..rule }).unwrap();
+        }
+        let mut state = load_mode_state().unwrap().unwrap();
+        state.prefix_active_backup.remove("0.0.0.0/0");
+        save_mode_state(&state).unwrap();
+        reconcile_routing(&network, &mut DriftReport::default()).unwrap().unwrap();
+        assert!(kernel.rules().iter().all(|rule| !(rule.table == MAIN_TABLE && rule.iif.as_deref() == Some(WG_INTERFACE))));
+    }
+
+    #[test]
+    fn test_change_plans_match_applied_changes() {
+        let (_guard, kernel) = router_mode();
+        let network = generate_network(2, 1);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
+        }
+        let exits = get_peers_with_default_route(&network);
+        let client = client_peers(&network).into_iter().find(|id| !exits.contains(id)).unwrap();
+        let count = |plan: &ChangePlan, kind: ChangeKind, action: ChangeAction| {
+            plan.changes.iter().filter(|change| change.kind == kind && change.action == action).count()
+        };
+        // Rules the applied change added and removed
+        let rule_delta = |before: &[String], after: &[String]| (
+            after.iter().filter(|rule| !before.contains(rule)).count(),
+            before.iter().filter(|rule| !after.contains(rule)).count(),
+        );
+
+        for (peer_id, lan_access) in [(exits[0], None), (exits[1], None), (client, Some(false)), (client, Some(true))] {
+            let before = sorted_rules(&kernel);
+            let plan = match lan_access {
+                None => plan_set_exit_node(&peer_id, &network).unwrap(),
+                Some(allowed) => plan_set_peer_lan_access(&peer_id, allowed, &network).unwrap(),
+            };
+            // Planning changes nothing
+            assert_eq!(sorted_rules(&kernel), before);
+            assert!(plan.changes.iter().all(|change| change.kind != ChangeKind::Sysctl));
+            // Switching exit nodes moves 0.0.0.0/0 between AllowedIPs (wg set), which the plan leaves out
+            assert_eq!(plan.complete, lan_access.is_some(), "{:?}", plan.notes);
+
+            match lan_access {
+                None => set_exit_node(&peer_id, Some(&network)).unwrap(),
+                Some(allowed) => {
+                    set_peer_lan_access(&peer_id, allowed, &network).unwrap();
+                }
+            }
+            let after = sorted_rules(&kernel);
+            assert_eq!(
+                (count(&plan, ChangeKind::IpRule, ChangeAction::Add), count(&plan, ChangeKind::IpRule, ChangeAction::Delete)),
+                rule_delta(&before, &after),
+                "{:?}", plan,
+            );
+            if lan_access.is_none() {
+                let table_id = table_of(&peer_id);
+                let exit_rule = format!("ip rule add from all iif eth0 lookup {} priority {}", table_id, 20000 + table_id % 1000);
+                assert!(plan.changes.iter().any(|change| change.command == exit_rule), "{:?}", plan);
             }
         }
-    } else {
-        // Remove the LAN access rule for this peer (all CIDRs)
-        for lan_cidr in &lan_cidrs {
-            let _ = shell_cmd(&[
-                "ip", "rule", "del",
-                "from", &peer_addr,
-                "iif", wg_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-            ]);
+
+        // Nothing left to plan once a change is applied
+        let plan = plan_set_exit_node(&exits[1], &network).unwrap();
+        assert!(plan.changes.iter().all(|change| change.kind == ChangeKind::Iptables), "{:?}", plan);
+        assert!(plan.complete, "{:?}", plan.notes);
+        // A new LAN CIDR moves the LAN exceptions and per-peer LAN rules
+        let current = load_mode_state().unwrap().unwrap();
+        let mut target = current.clone();
+        target.lan_cidr = Some("192.168.1.0/24".to_string());
+        let mut plan = ChangePlan::new("update_lan_cidr");
+        plan.transition(Some(&current), Some(&target), &network).unwrap();
+        // The LAN exception and the per-peer LAN rules for 10.20.0.0/16 go, the 192.168.1.0/24 ones stay
+        assert_eq!(count(&plan, ChangeKind::IpRule, ChangeAction::Delete), 1 + client_peers(&network).len(), "{:?}", plan);
+        assert_eq!(count(&plan, ChangeKind::IpRule, ChangeAction::Add), 0);
+        assert!(plan.complete, "{:?}", plan.notes);
+    }
+
+    #[test]
+    fn test_gateway_group_validation_and_activation() {
+        let (_guard, kernel) = router_mode();
+        let network = generate_network(3, 1);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
         }
-        log::info!("Removed LAN access rules for peer {} ({})", peer.name, peer_addr);
+        let exits = get_peers_with_default_route(&network);
+        let client = *client_peers(&network).iter().find(|id| !exits.contains(id)).unwrap();
+        set_exit_node(&exits[2], Some(&network)).unwrap();
+        let group = |tiers: &[&[Uuid]]| GatewayGroup {
+            tiers: tiers.iter().map(|tier| tier.iter().map(Uuid::to_string).collect()).collect(),
+        };
+        let invalid = |groups: BTreeMap<String, GatewayGroup>, active: Option<&str>| matches!(
+            set_gateway_groups(groups, active.map(str::to_string), &network),
+            Err(PolicyRoutingError::GatewayGroupError(_))
+        );
+
+        assert!(invalid(BTreeMap::from([("sites".to_string(), group(&[&[exits[0]], &[]]))]), None));
+        assert!(invalid(BTreeMap::from([("sites".to_string(), group(&[&[client]]))]), None));
+        assert!(invalid(BTreeMap::from([("sites".to_string(), group(&[&[exits[0]], &[exits[0]]]))]), None));
+        assert!(invalid(BTreeMap::from([("sites".to_string(), group(&[&[exits[0]]]))]), Some("other")));
+
+        // Tier 1 is down, so activating the group moves to the faster member of tier 2
+        set_online(&exits[0], false);
+        set_online(&exits[1], true);
+        set_online(&LOCAL_BREAKOUT_EXIT_ID, true);
+        EXIT_NODE_HEALTH_CACHE.write().unwrap().get_mut(&exits[1]).unwrap().latency_ms = Some(40);
+        EXIT_NODE_HEALTH_CACHE.write().unwrap().get_mut(&LOCAL_BREAKOUT_EXIT_ID).unwrap().latency_ms = Some(5);
+        let sites = group(&[&[exits[0]], &[exits[1], LOCAL_BREAKOUT_EXIT_ID]]);
+        let health = EXIT_NODE_HEALTH_CACHE.read().unwrap().clone();
+        assert_eq!(best_group_member(&sites, &health, None), Some((1, LOCAL_BREAKOUT_EXIT_ID)));
+        assert_eq!(best_group_member(&sites, &health, Some(&LOCAL_BREAKOUT_EXIT_ID)), Some((1, exits[1])));
+        assert_eq!(group_tier(&sites, &exits[2]), 2);
+
+        add_uplink(&kernel);
+        set_gateway_groups(BTreeMap::from([("sites".to_string(), sites)]), Some("sites".to_string()), &network).unwrap();
+        assert_eq!(get_exit_node().unwrap(), Some(LOCAL_BREAKOUT_EXIT_ID));
+        let (groups, active, _) = get_gateway_groups().unwrap();
+        assert_eq!((groups.len(), active.as_deref()), (1, Some("sites")));
+
+        // Removing a peer drops it from its tier
+        let mut state = load_mode_state().unwrap().unwrap();
+        state.remove_from_gateway_groups(&exits[0].to_string());
+        assert_eq!(state.gateway_groups["sites"].tiers.len(), 1);
     }
-    
-    Ok(has_lan_access)
-}
 
-/// Ensure LAN access rule exists for a peer based on its current setting
-/// This is called when adding new peers to ensure they get their LAN access rule
-fn ensure_peer_lan_access_rule(peer_id: &Uuid, network: &Network) -> Result<(), PolicyRoutingError> {
-    let peer_id_str = peer_id.to_string();
-    
-    // Skip the router itself
-    if *peer_id == network.this_peer {
-        return Ok(());
+    #[test]
+    fn test_gateway_group_fails_back_to_highest_tier() {
+        let (_guard, _kernel) = router_mode();
+        let network = generate_network(3, 0);
+        for peer_id in client_peers(&network) {
+            add_peer(&peer_id, &network);
+        }
+        let exits = get_peers_with_default_route(&network);
+        let mut state = load_mode_state().unwrap().unwrap();
+        state.auto_failover = true;
+        state.gateway_groups.insert("sites".to_string(), GatewayGroup {
+            tiers: vec![vec![exits[0].to_string()], vec![exits[1].to_string()], vec![exits[2].to_string()]],
+        });
+        state.active_gateway_group = Some("sites".to_string());
+        save_mode_state(&state).unwrap();
+        set_exit_node(&exits[2], Some(&network)).unwrap();
+        let stability = AgentRouterFailover::default().failback_stability_secs;
+        let check = |now: u64| {
+            let health = EXIT_NODE_HEALTH_CACHE.read().unwrap().clone();
+            check_gateway_group_failback(&health, &network, now).unwrap()
+        };
+
+        // Tier 2 comes back: the timer starts, and restarts when tier 1 comes back as well
+        set_online(&exits[1], true);
+        assert_eq!(check(1000), None);
+        assert_eq!(load_mode_state().unwrap().unwrap().failback_timer.map(|t| t.tier), Some(1));
+        set_online(&exits[0], true);
+        assert_eq!(check(1000 + stability), None);
+        assert_eq!(load_mode_state().unwrap().unwrap().failback_timer.map(|t| t.tier), Some(0));
+
+        // Tier 1 going down again resets the timer
+        set_online(&exits[0], false);
+        set_online(&exits[1], false);
+        assert_eq!(check(1100 + stability), None);
+        assert!(load_mode_state().unwrap().unwrap().failback_timer.is_none());
+
+        // Stable for failback_stability_secs: climb straight to tier 1
+        set_online(&exits[0], true);
+        assert_eq!(check(2000), None);
+        assert_eq!(check(2000 + stability), Some(exits[0]));
+        assert_eq!(get_exit_node().unwrap(), Some(exits[0]));
+        // The fail-back is journaled
+        let filter = events::EventFilter { kinds: vec![EventKind::Failback], ..Default::default() };
+        let failback = events::query(&filter, 1).unwrap().remove(0);
+        assert_eq!((failback.details.new_peer, failback.initiator), (Some(exits[0]), Initiator::Monitor));
+        assert!(load_mode_state().unwrap().unwrap().failback_timer.is_none());
+        assert_eq!(check(3000), None);
+
+        // The auto group is dropped once Smart Gateway is back on the exit node it remembered
+        start_auto_gateway_group(&exits[1]).unwrap();
+        set_online(&exits[1], true);
+        assert_eq!(check(4000), None);
+        assert_eq!(get_gateway_groups().unwrap().1.as_deref(), Some("sites"));
+        release_gateway_group().unwrap();
+        start_auto_gateway_group(&exits[1]).unwrap();
+        assert_eq!(check(4000), None);
+        assert_eq!(check(4000 + stability), Some(exits[1]));
+        let (groups, active, _) = get_gateway_groups().unwrap();
+        assert!(active.is_none() && !groups.contains_key(AUTO_GATEWAY_GROUP));
     }
-    
-    // Load current state to get LAN access setting and LAN CIDR
-    let state = load_mode_state()
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?
-        .ok_or_else(|| PolicyRoutingError::PersistenceError("No mode state found".to_string()))?;
-    
-    // Get LAN CIDRs (supports multiple comma-separated CIDRs)
-    let lan_cidr_str = match &state.lan_cidr {
-        Some(cidr) => cidr.clone(),
-        None => return Ok(()), // No LAN CIDR configured, nothing to do
-    };
-    let lan_cidrs = parse_lan_cidrs(&lan_cidr_str);
-    
-    // Check if peer has LAN access (default is true)
-    let has_lan_access = state.peer_lan_access
-        .get(&peer_id_str)
-        .copied()
-        .unwrap_or(true);
-    
-    // Get peer info
-    let peer = network.peers.get(peer_id)
-        .ok_or_else(|| PolicyRoutingError::PersistenceError(format!("Peer {} not found", peer_id)))?;
-    
-    let peer_addr = format!("{}/32", peer.address);
-    let wg_interface = &network.name;
-    
-    if has_lan_access {
-        // Find peer index for priority calculation
-        let mut peer_index = 0u32;
-        for (pid, _) in &network.peers {
-            if *pid == network.this_peer {
-                continue;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(24))]
+
+        #[test]
+        fn prop_load_balance_shares_partition_address_space(weights in prop::collection::vec(0u32..=MAX_EXIT_NODE_WEIGHT, 1..6)) {
+            let members: Vec<(Uuid, u32)> = weights.iter().map(|w| (Uuid::new_v4(), *w)).collect();
+            let shares = load_balance_shares(&members);
+            let total_weight: u32 = weights.iter().sum();
+
+            let mut buckets: Vec<Ipv4Net> = shares.iter().flat_map(|(_, nets)| nets.clone()).collect();
+            if total_weight == 0 {
+                prop_assert!(buckets.is_empty());
+                return Ok(());
             }
-            if *pid == *peer_id {
-                break;
+            // Every /6 handed out exactly once
+            buckets.sort();
+            buckets.dedup();
+            prop_assert_eq!(buckets.len(), 64);
+            prop_assert!(buckets.iter().all(|net| net.prefix_len() == 6));
+
+            // Each member's share is within one bucket of its weighted share
+            for (id, weight) in members.iter().filter(|(_, w)| *w > 0) {
+                let count = shares.iter().find(|(member, _)| member == id).unwrap().1.len() as f64;
+                let expected = 64.0 * *weight as f64 / total_weight as f64;
+                prop_assert!((count - expected).abs() < 1.0 + f64::EPSILON, "{} vs {}", count, expected);
             }
-            peer_index += 1;
         }
-        
-        let exception_priority = 20000 - 1;
-        let wg_peer_lan_base_priority = exception_priority - 100;
-        
-        for (cidr_idx, lan_cidr) in lan_cidrs.iter().enumerate() {
-            // First remove any existing rule for this peer
-            let _ = shell_cmd(&[
-                "ip", "rule", "del",
-                "from", &peer_addr,
-                "iif", wg_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-            ]);
-            
-            // Use unique priority: base + (cidr_index * 100) + peer_index
-            let peer_priority = wg_peer_lan_base_priority + (cidr_idx as u32 * 100) + peer_index;
-            let peer_priority_str = peer_priority.to_string();
-            
-            // Add the rule
-            let cmd = &[
-                "ip", "rule", "add",
-                "from", &peer_addr,
-                "iif", wg_interface,
-                "to", lan_cidr,
-                "lookup", "main",
-                "priority", &peer_priority_str,
-            ];
-            
-            if let Err(e) = shell_cmd(cmd) {
-                log::warn!("Failed to ensure LAN access rule for peer {} ({}) to {}: {}", peer.name, peer_addr, lan_cidr, e);
-            } else {
-                log::debug!("Ensured LAN access rule for peer {} ({}) to {}: -> main table (priority {})", 
-                    peer.name, peer_addr, lan_cidr, peer_priority);
+
+        #[test]
+        fn prop_overlapping_prefix_has_exactly_one_rule(
+            actions in prop::collection::vec((0usize..3, any::<prop::sample::Index>(), any::<bool>()), 1..12),
+        ) {
+            let (_guard, kernel) = router_mode();
+            let (network, peers) = overlapping_sites(&kernel);
+
+            for (action, index, flag) in actions {
+                let peer_id = *index.get(&peers);
+                match action {
+                    0 => set_active_peer_for_prefix_impl("10.50.0.0/16", &peer_id, &[], &network).unwrap(),
+                    1 => set_online(&peer_id, flag),
+                    _ => failover_prefixes_for_peer(&peer_id, &health_cache()),
+                }
+
+                let active = Uuid::parse_str(&active_for("10.50.0.0/16").unwrap()).unwrap();
+                prop_assert_eq!(prefix_rule_tables(&kernel, "10.50.0.0/16"), vec![table_of(&active)]);
+                let state = load_mode_state().unwrap().unwrap();
+                let prefix_state = &state.prefix_active_backup["10.50.0.0/16"];
+                prop_assert_eq!(prefix_state.backup_peer_ids.len(), 2);
+                prop_assert!(!prefix_state.backup_peer_ids.contains(&prefix_state.active_peer_id));
             }
         }
-    }
-    // If no LAN access, we don't remove the rule here - that's handled by set_peer_lan_access
-    
-    Ok(())
-}
 
-/// Get LAN access status for all peers
-pub fn get_all_peer_lan_access() -> Result<HashMap<String, bool>, PolicyRoutingError> {
-    let state = load_mode_state()
-        .map_err(|e| PolicyRoutingError::PersistenceError(format!("Failed to load mode state: {}", e)))?;
-    
-    match state {
-        Some(s) => Ok(s.peer_lan_access),
-        None => Ok(HashMap::new()),
+        #[test]
+        fn prop_only_current_exit_node_has_exit_rules(
+            exit_nodes in 1usize..4,
+            clients in 0usize..3,
+            switches in prop::collection::vec(any::<prop::sample::Index>(), 1..8),
+        ) {
+            let (_guard, kernel) = router_mode();
+            let network = generate_network(exit_nodes, clients);
+            let peers = client_peers(&network);
+            for peer_id in &peers {
+                add_peer(peer_id, &network);
+            }
+            let candidates = get_peers_with_default_route(&network);
+            prop_assert_eq!(candidates.len(), exit_nodes);
+
+            for index in &switches {
+                let exit_node = *index.get(&candidates);
+                set_exit_node(&exit_node, Some(&network)).unwrap();
+
+                let table_id = get_peer_table_id(&exit_node).unwrap().unwrap();
+                prop_assert_eq!(get_exit_node().unwrap(), Some(exit_node));
+                // LAN interface rule and WireGuard subnet rule, both for the current exit node only
+                prop_assert_eq!(exit_rule_tables(&kernel), vec![table_id, table_id]);
+
+                // One LAN exception per LAN CIDR and one LAN access rule per peer and LAN CIDR
+                let main_rules = kernel.rules().iter()
+                    .filter(|rule| rule.table == MAIN_TABLE && rule.to.is_some())
+                    .count();
+                prop_assert_eq!(main_rules, 2 * (1 + peers.len()));
+            }
+        }
+
+        #[test]
+        fn prop_removing_all_peers_leaves_no_stale_state(
+            exit_nodes in 1usize..4,
+            clients in 0usize..3,
+            exit_index in any::<prop::sample::Index>(),
+            first_removed in any::<prop::sample::Index>(),
+        ) {
+            let (_guard, kernel) = router_mode();
+            let mut network = generate_network(exit_nodes, clients);
+            let mut peers = client_peers(&network);
+            for peer_id in &peers {
+                add_peer(peer_id, &network);
+            }
+            let exit_node = *exit_index.get(&get_peers_with_default_route(&network));
+            set_exit_node(&exit_node, Some(&network)).unwrap();
+
+            // Removing the exit node promotes another one, so the order matters
+            let rotate = first_removed.index(peers.len());
+            peers.rotate_left(rotate);
+            for peer_id in &peers {
+                remove_peer(peer_id, &mut network);
+            }
+
+            prop_assert_eq!(kernel.rules(), SimulatedKernel::default_rules());
+            prop_assert!(kernel.tables().is_empty());
+            let state = load_mode_state().unwrap().unwrap();
+            prop_assert!(state.peer_table_ids.is_empty());
+            prop_assert!(state.prefix_active_backup.is_empty());
+        }
     }
 }
-
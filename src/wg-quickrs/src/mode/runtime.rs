@@ -0,0 +1,207 @@
+// Runtime health store: volatile per peer health state, kept in memory and snapshotted
+//
+// The health monitor used to load and rewrite router_mode_state.json (with fsync) on every probe of
+// every monitored peer, just to remember whether it was online and when it last answered. That state
+// lives here instead, so router_mode_state.json is only written when a routing decision changes:
+// - reads and updates are in memory
+// - a snapshot goes to router_health_state.json at most every SNAPSHOT_INTERVAL and only after changes
+//   (temp file and rename, without fsync: a power cut costs at most the last interval of "Down Since")
+// - the snapshot is read back on the first use after a restart
+// - upgrading a state file from before the store seeds it with the health the file kept (see persist.rs)
+
+use crate::WG_QUICKRS_CONFIG_FOLDER;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub const HEALTH_STATE_FILE: &str = "router_health_state.json";
+const HEALTH_STATE_TEMP_FILE: &str = "router_health_state.json.tmp";
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum RuntimeStoreError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Config folder not initialized")]
+    NoConfigFolder,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerRuntime {
+    pub online: bool, // state after the last probe (for offline → online transitions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_successful_ping: Option<u64>, // Unix seconds, for "Down Since"
+}
+
+pub struct RuntimeStore {
+    path: PathBuf,
+    peers: HashMap<Uuid, PeerRuntime>,
+    dirty: bool,
+    last_snapshot: Option<Instant>,
+}
+
+impl RuntimeStore {
+    // Start from the snapshot at `path`; a missing or unreadable one starts empty
+    pub fn load(path: PathBuf) -> Self {
+        let peers = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                log::warn!("Health state snapshot {:?} is corrupted ({}). Starting empty.", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        RuntimeStore { path, peers, dirty: false, last_snapshot: None }
+    }
+
+    pub fn get(&self, peer_id: &Uuid) -> PeerRuntime {
+        self.peers.get(peer_id).copied().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, peer_id: Uuid, runtime: PeerRuntime) {
+        if self.peers.insert(peer_id, runtime) != Some(runtime) {
+            self.dirty = true;
+        }
+    }
+
+    pub fn remove(&mut self, peer_id: &Uuid) {
+        self.dirty |= self.peers.remove(peer_id).is_some();
+    }
+
+    // Add peers the store does not know yet
+    pub fn seed(&mut self, peers: HashMap<Uuid, PeerRuntime>) {
+        for (peer_id, runtime) in peers {
+            if let std::collections::hash_map::Entry::Vacant(entry) = self.peers.entry(peer_id) {
+                entry.insert(runtime);
+                self.dirty = true;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty |= !self.peers.is_empty();
+        self.peers.clear();
+    }
+
+    // Write a snapshot if something changed and the last one is older than `interval`
+    pub fn snapshot_if_due(&mut self, interval: Duration) -> Result<bool, RuntimeStoreError> {
+        if !self.dirty || self.last_snapshot.is_some_and(|last| last.elapsed() < interval) {
+            return Ok(false);
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = self.path.with_file_name(HEALTH_STATE_TEMP_FILE);
+        fs::write(&temp_path, serde_json::to_string(&self.peers)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        self.dirty = false;
+        self.last_snapshot = Some(Instant::now());
+        Ok(true)
+    }
+}
+
+// Loaded on first use (the config folder is known by then)
+static STORE: Lazy<Mutex<Option<RuntimeStore>>> = Lazy::new(|| Mutex::new(None));
+
+fn with_store<T>(f: impl FnOnce(&mut RuntimeStore) -> T) -> Result<T, RuntimeStoreError> {
+    let mut store = STORE.lock().unwrap();
+    if store.is_none() {
+        let config_folder = WG_QUICKRS_CONFIG_FOLDER.get().ok_or(RuntimeStoreError::NoConfigFolder)?;
+        *store = Some(RuntimeStore::load(config_folder.join(HEALTH_STATE_FILE)));
+    }
+    Ok(f(store.as_mut().unwrap()))
+}
+
+// Health state of a peer (offline and never seen when unknown)
+pub fn peer(peer_id: &Uuid) -> PeerRuntime {
+    with_store(|store| store.get(peer_id)).unwrap_or_default()
+}
+
+// Update a peer after a probe; snapshots are batched
+pub fn update(peer_id: Uuid, runtime: PeerRuntime) {
+    let result = with_store(|store| {
+        store.set(peer_id, runtime);
+        store.snapshot_if_due(SNAPSHOT_INTERVAL)
+    });
+    if let Err(e) = result.and_then(|snapshot| snapshot) {
+        log::debug!("Failed to snapshot health state: {}", e);
+    }
+}
+
+// Drop peers that are gone from the configuration
+pub fn forget(peer_id: &Uuid) {
+    let _ = with_store(|store| store.remove(peer_id));
+}
+
+// Leaving Router Mode starts health tracking over
+pub fn clear() {
+    let _ = with_store(|store| {
+        store.clear();
+        store.snapshot_if_due(Duration::ZERO)
+    });
+}
+
+// Seed the snapshot at `path` with peers it does not know yet, and write it now
+// (the loaded store if it uses that file, so the next snapshot does not drop them)
+pub fn seed(path: PathBuf, peers: HashMap<Uuid, PeerRuntime>) -> Result<(), RuntimeStoreError> {
+    let mut loaded = STORE.lock().unwrap();
+    let mut standalone = None;
+    let store = match loaded.as_mut().filter(|store| store.path == path) {
+        Some(store) => store,
+        None => standalone.insert(RuntimeStore::load(path)),
+    };
+    store.seed(peers);
+    store.snapshot_if_due(Duration::ZERO)?;
+    Ok(())
+}
+
+// Write pending changes now (on shutdown)
+pub fn flush() {
+    let result = with_store(|store| store.snapshot_if_due(Duration::ZERO));
+    if let Err(e) = result.and_then(|snapshot| snapshot) {
+        log::warn!("Failed to save health state: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_store_batches_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HEALTH_STATE_FILE);
+        let mut store = RuntimeStore::load(path.clone());
+        let peer_id = Uuid::new_v4();
+
+        // Nothing to write until something changes
+        assert!(!store.snapshot_if_due(Duration::ZERO).unwrap());
+        store.set(peer_id, PeerRuntime { online: true, last_successful_ping: Some(100) });
+        assert!(store.snapshot_if_due(SNAPSHOT_INTERVAL).unwrap());
+
+        // Further changes wait for the interval
+        store.set(peer_id, PeerRuntime { online: true, last_successful_ping: Some(101) });
+        assert!(!store.snapshot_if_due(SNAPSHOT_INTERVAL).unwrap());
+        assert_eq!(RuntimeStore::load(path.clone()).get(&peer_id).last_successful_ping, Some(100));
+        assert!(store.snapshot_if_due(Duration::ZERO).unwrap());
+        assert_eq!(RuntimeStore::load(path.clone()).get(&peer_id).last_successful_ping, Some(101));
+
+        // An unchanged update is not a change
+        store.set(peer_id, PeerRuntime { online: true, last_successful_ping: Some(101) });
+        assert!(!store.snapshot_if_due(Duration::ZERO).unwrap());
+
+        store.remove(&peer_id);
+        assert!(store.snapshot_if_due(Duration::ZERO).unwrap());
+        assert_eq!(RuntimeStore::load(path.clone()).get(&peer_id), PeerRuntime::default());
+
+        fs::write(&path, "{").unwrap();
+        assert_eq!(RuntimeStore::load(path).get(&peer_id), PeerRuntime::default());
+    }
+}
@@ -0,0 +1,243 @@
+// Simulated kernel: an in-memory RoutingBackend for tests
+//
+// Mirrors the kernel semantics routing_pbr relies on:
+// - Routes need an existing output device and replace by (table, destination)
+// - Deleting an interface drops the routes through it
+// - Only unicast routes are modelled: a device with an optional gateway
+// - Re-adding an identical rule succeeds, deleting a missing rule or route fails with NotFound
+// - A fresh kernel starts with the local/main/default rules at priorities 0/32766/32767
+// It also stands in for iptables (FirewallBackend): chains hold rule specs exactly as they were appended
+
+use super::backend::{BackendError, BackendResult, InterfaceAddress, Route, Rule, RoutingBackend, MAIN_TABLE};
+use crate::firewall::{FirewallBackend, FirewallError};
+use ipnet::Ipv4Net;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::{Mutex, MutexGuard};
+
+const LOCAL_TABLE: u32 = 255;
+const DEFAULT_TABLE: u32 = 253;
+
+#[derive(Default)]
+struct KernelState {
+    // table -> destination -> (device, gateway)
+    routes: BTreeMap<u32, BTreeMap<Ipv4Net, (String, Option<Ipv4Addr>)>>,
+    rules: Vec<Rule>,
+    interfaces: BTreeMap<String, Vec<Ipv4Net>>,
+    // (table, chain) -> rule specs in chain order
+    firewall: BTreeMap<(String, String), Vec<Vec<String>>>,
+}
+
+// The backends, the mode state and the config are process-wide: tests using any of them hold this lock
+static TEST_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+pub fn test_lock() -> MutexGuard<'static, ()> {
+    TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub struct SimulatedKernel {
+    state: Mutex<KernelState>,
+}
+
+impl SimulatedKernel {
+    pub fn new() -> Self {
+        let rules = Self::default_rules();
+        let mut interfaces = BTreeMap::new();
+        interfaces.insert("lo".to_string(), vec!["127.0.0.1/8".parse().unwrap()]);
+        SimulatedKernel {
+            state: Mutex::new(KernelState { rules, interfaces, ..Default::default() }),
+        }
+    }
+
+    // The rules every Linux kernel boots with
+    pub fn default_rules() -> Vec<Rule> {
+        vec![
+            Rule { priority: 0, table: LOCAL_TABLE, ..Default::default() },
+            Rule { priority: 32766, table: MAIN_TABLE, ..Default::default() },
+            Rule { priority: 32767, table: DEFAULT_TABLE, ..Default::default() },
+        ]
+    }
+
+    pub fn with_interface(self, name: &str, address: &str) -> Self {
+        self.state.lock().unwrap().interfaces
+            .entry(name.to_string())
+            .or_default()
+            .push(address.parse().expect("invalid interface address"));
+        self
+    }
+
+    // Add an iptables rule, e.g. with_firewall_rule("nat", "POSTROUTING", "-s 10.0.0.0/24 -o eth1 -j MASQUERADE")
+    pub fn with_firewall_rule(self, table: &str, chain: &str, spec: &str) -> Self {
+        self.state.lock().unwrap().firewall
+            .entry((table.to_string(), chain.to_string()))
+            .or_default()
+            .push(spec.split_whitespace().map(str::to_string).collect());
+        self
+    }
+
+    // Rule specs of a chain, space separated
+    pub fn firewall_rules(&self, table: &str, chain: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.firewall.get(&(table.to_string(), chain.to_string())).into_iter().flatten()
+            .map(|spec| spec.join(" "))
+            .collect()
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.state.lock().unwrap().rules.clone()
+    }
+
+    pub fn routes(&self, table: u32) -> Vec<Route> {
+        let state = self.state.lock().unwrap();
+        state.routes.get(&table).into_iter().flatten()
+            .map(|(destination, (device, gateway))| Route {
+                table,
+                destination: *destination,
+                device: device.clone(),
+                gateway: *gateway,
+            })
+            .collect()
+    }
+
+    // Tables that still hold at least one route
+    pub fn tables(&self) -> Vec<u32> {
+        let state = self.state.lock().unwrap();
+        state.routes.iter().filter(|(_, routes)| !routes.is_empty()).map(|(table, _)| *table).collect()
+    }
+}
+
+impl RoutingBackend for SimulatedKernel {
+    fn replace_route(&self, route: &Route) -> BackendResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.interfaces.contains_key(&route.device) {
+            return Err(BackendError::NoSuchInterface(route.device.clone()));
+        }
+        state.routes.entry(route.table).or_default()
+            .insert(route.destination.trunc(), (route.device.clone(), route.gateway));
+        Ok(())
+    }
+
+    fn delete_route(&self, route: &Route) -> BackendResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let routes = state.routes.entry(route.table).or_default();
+        let destination = route.destination.trunc();
+        if routes.get(&destination) != Some(&(route.device.clone(), route.gateway)) {
+            return Err(BackendError::NotFound(route.to_string()));
+        }
+        routes.remove(&destination);
+        Ok(())
+    }
+
+    fn flush_table(&self, table: u32) -> BackendResult<usize> {
+        let mut state = self.state.lock().unwrap();
+        Ok(state.routes.remove(&table).map_or(0, |routes| routes.len()))
+    }
+
+    fn list_routes(&self, table: u32) -> BackendResult<Vec<Route>> {
+        Ok(self.routes(table))
+    }
+
+    fn list_rules(&self) -> BackendResult<Vec<Rule>> {
+        Ok(self.rules())
+    }
+
+    fn add_rule(&self, rule: &Rule) -> BackendResult<()> {
+        let rule = rule.clone().normalized();
+        let mut state = self.state.lock().unwrap();
+        if let Some(iif) = &rule.iif
+            && !state.interfaces.contains_key(iif) {
+                // The kernel accepts rules for absent interfaces, they just never match
+                log::debug!("Simulated kernel: rule {} references missing interface {}", rule, iif);
+            }
+        if state.rules.contains(&rule) {
+            return Ok(());
+        }
+        // Like the kernel, keep rules ordered by priority with later inserts after equal priorities
+        let position = state.rules.partition_point(|existing| existing.priority <= rule.priority);
+        state.rules.insert(position, rule);
+        Ok(())
+    }
+
+    fn delete_rule(&self, rule: &Rule) -> BackendResult<()> {
+        let rule = rule.clone().normalized();
+        let mut state = self.state.lock().unwrap();
+        match state.rules.iter().position(|existing| *existing == rule) {
+            Some(position) => {
+                state.rules.remove(position);
+                Ok(())
+            }
+            None => Err(BackendError::NotFound(rule.to_string())),
+        }
+    }
+
+    fn interface_exists(&self, name: &str) -> BackendResult<bool> {
+        Ok(self.state.lock().unwrap().interfaces.contains_key(name))
+    }
+
+    fn list_interface_addresses(&self) -> BackendResult<Vec<InterfaceAddress>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.interfaces.iter()
+            .flat_map(|(interface, addresses)| addresses.iter().map(|address| InterfaceAddress {
+                interface: interface.clone(),
+                address: *address,
+            }))
+            .collect())
+    }
+
+    fn add_wireguard_interface(&self, name: &str, address: Ipv4Net, _mtu: Option<u16>) -> BackendResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.interfaces.contains_key(name) {
+            state.interfaces.insert(name.to_string(), vec![address]);
+        }
+        Ok(())
+    }
+
+    fn delete_interface(&self, name: &str) -> BackendResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.interfaces.remove(name).is_none() {
+            return Err(BackendError::NotFound(name.to_string()));
+        }
+        for routes in state.routes.values_mut() {
+            routes.retain(|_, (device, _)| device != name);
+        }
+        Ok(())
+    }
+}
+
+impl FirewallBackend for SimulatedKernel {
+    fn available(&self) -> bool {
+        true
+    }
+
+    fn exists(&self, table: &str, chain: &str, spec: &[String]) -> bool {
+        let state = self.state.lock().unwrap();
+        state.firewall.get(&(table.to_string(), chain.to_string()))
+            .is_some_and(|rules| rules.iter().any(|rule| rule == spec))
+    }
+
+    fn append(&self, table: &str, chain: &str, spec: &[String]) -> Result<(), FirewallError> {
+        let mut state = self.state.lock().unwrap();
+        state.firewall.entry((table.to_string(), chain.to_string())).or_default().push(spec.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, table: &str, chain: &str, spec: &[String]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(rules) = state.firewall.get_mut(&(table.to_string(), chain.to_string())) else {
+            return false;
+        };
+        match rules.iter().position(|rule| rule == spec) {
+            Some(position) => {
+                rules.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn list(&self, table: &str, chain: &str) -> Result<Vec<Vec<String>>, FirewallError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.firewall.get(&(table.to_string(), chain.to_string())).cloned().unwrap_or_default())
+    }
+}
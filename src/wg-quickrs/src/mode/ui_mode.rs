@@ -13,6 +13,7 @@ use crate::conf;
 use wg_quickrs_lib::types::network::{EndpointAddress, Network};
 use wg_quickrs_lib::helpers::wg_public_key_from_private_key;
 use uuid::Uuid;
+use super::events::{self, EventDetails, EventKind, Initiator};
 
 /// Helper function to format EndpointAddress for display
 fn format_endpoint_address(addr: &EndpointAddress) -> String {
@@ -77,11 +78,10 @@ fn get_peer_wg_params(network: &Network, peer_id: &Uuid, include_default_route:
             let ip_str = ip.to_string();
             // Skip 0.0.0.0/0 unless explicitly requested (exit node)
             if ip_str == "0.0.0.0/0" || ip_str == "default" {
-                if include_default_route {
-                    if !allowed_ips.contains(&"0.0.0.0/0".to_string()) {
+                if include_default_route
+                    && !allowed_ips.contains(&"0.0.0.0/0".to_string()) {
                         allowed_ips.push("0.0.0.0/0".to_string());
                     }
-                }
             } else if !allowed_ips.contains(&ip_str) {
                 allowed_ips.push(ip_str);
             }
@@ -116,7 +116,8 @@ pub async fn get_mode(_req: HttpRequest) -> HttpResponse {
         Ok(config) => {
             HttpResponse::Ok().json(serde_json::json!({
                 "mode": config.agent.router.mode,
-                "lan_cidr": config.agent.router.lan_cidr
+                "lan_cidr": config.agent.router.lan_cidr,
+                "pending_confirm": super::confirm::pending()
             }))
         }
         Err(_) => {
@@ -127,9 +128,11 @@ pub async fn get_mode(_req: HttpRequest) -> HttpResponse {
     }
 }
 
-// Toggle between Host and Router Mode
-pub async fn toggle_mode(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
-    use crate::mode::mode::{switch_mode, SystemMode};
+// Toggle between Host and Router Mode (or only plan it with dry_run)
+// With confirm_timeout, the change is rolled back unless confirmed in time (see mode/confirm.rs)
+pub async fn toggle_mode(_req: HttpRequest, body: actix_web::web::Bytes, dry_run: bool, confirm_timeout: Option<u64>) -> HttpResponse {
+    use crate::mode::mode::{plan_switch_mode, switch_mode, SystemMode};
+    use super::confirm::{switch_mode_with_confirm, ConfirmError};
     use serde_json::Value;
     
     // Parse request body
@@ -161,31 +164,70 @@ pub async fn toggle_mode(_req: HttpRequest, body: actix_web::web::Bytes) -> Http
         .and_then(|v| v.as_str())
         .map(|s| s.to_string());
     
-    // Switch mode
-    match switch_mode(target_mode, lan_cidr) {
-        Ok(_) => {
+    if dry_run {
+        return match plan_switch_mode(target_mode, lan_cidr) {
+            Ok(plan) => HttpResponse::Ok().json(plan),
+            Err(e @ super::mode::ModeError::PeersExist) => HttpResponse::Forbidden().json(serde_json::json!({
+                "error": e.to_string()
+            })),
+            Err(e @ super::mode::ModeError::InvalidCidr(_)) => HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            })),
+            Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            })),
+        };
+    }
+    
+    let previous_mode = conf::util::get_config().ok().map(|config| config.agent.router.mode);
+    
+    // Switch mode (blocking: sysctl, iptables and netlink calls)
+    let result = actix_web::web::block(move || match confirm_timeout {
+        Some(timeout_secs) => switch_mode_with_confirm(target_mode, lan_cidr, timeout_secs).map(Some),
+        None => switch_mode(target_mode, lan_cidr).map(|_| None).map_err(ConfirmError::from),
+    }).await;
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Mode switch failed: {}", e)
+            }));
+        }
+    };
+    match result {
+        Ok(pending) => {
             // Get updated config to return current state
             match conf::util::get_config() {
                 Ok(config) => {
+                    if let Some(previous_mode) = previous_mode.filter(|mode| *mode != config.agent.router.mode) {
+                        events::record(EventKind::ModeChange, Initiator::Api, EventDetails {
+                            reason: Some(format!("{} → {}", previous_mode, config.agent.router.mode)),
+                            ..Default::default()
+                        });
+                    }
                     HttpResponse::Ok().json(serde_json::json!({
                         "mode": config.agent.router.mode,
-                        "lan_cidr": config.agent.router.lan_cidr
+                        "lan_cidr": config.agent.router.lan_cidr,
+                        "pending_confirm": pending
                     }))
                 }
                 Err(_) => {
                     HttpResponse::Ok().json(serde_json::json!({
                         "mode": mode_str,
-                        "lan_cidr": json.get("lan_cidr").and_then(|v| v.as_str())
+                        "lan_cidr": json.get("lan_cidr").and_then(|v| v.as_str()),
+                        "pending_confirm": pending
                     }))
                 }
             }
         }
         Err(e) => {
-            // Check if it's a PeersExist error and return appropriate status code
-            let status = if e.to_string().contains("peers are configured") {
-                actix_web::http::StatusCode::FORBIDDEN
-            } else {
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+            // Invalid timeout, another change pending, peers exist, or a failed switch
+            let status = match e {
+                ConfirmError::InvalidTimeout(_) => actix_web::http::StatusCode::BAD_REQUEST,
+                ConfirmError::AlreadyPending(_) => actix_web::http::StatusCode::CONFLICT,
+                ConfirmError::ModeError(super::mode::ModeError::PeersExist) => actix_web::http::StatusCode::FORBIDDEN,
+                ConfirmError::ModeError(super::mode::ModeError::InvalidCidr(_)) => actix_web::http::StatusCode::BAD_REQUEST,
+                _ => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             };
             
             HttpResponse::build(status).json(serde_json::json!({
@@ -195,6 +237,40 @@ pub async fn toggle_mode(_req: HttpRequest, body: actix_web::web::Bytes) -> Http
     }
 }
 
+// Keep the change waiting for confirmation (optionally only the one with the given id)
+pub async fn confirm_change(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+    use super::confirm::{confirm, ConfirmError};
+
+    #[derive(serde::Deserialize, Default)]
+    struct ConfirmRequest {
+        id: Option<Uuid>,
+    }
+    let request: ConfirmRequest = if body.is_empty() {
+        ConfirmRequest::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Invalid JSON: {}", e)
+                }));
+            }
+        }
+    };
+
+    match confirm(request.id) {
+        Ok(change) => HttpResponse::Ok().json(serde_json::json!({
+            "confirmed": change
+        })),
+        Err(e @ ConfirmError::NothingPending) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+    }
+}
+
 // Get mode switch restrictions status
 pub async fn can_switch_mode(_req: HttpRequest) -> HttpResponse {
     use crate::mode::mode::can_switch_mode;
@@ -222,8 +298,9 @@ pub async fn can_switch_mode(_req: HttpRequest) -> HttpResponse {
 }
 
 // Update peer route status (active/backup for overlapping routes)
-// Currently supports setting exit node for default route (0.0.0.0/0)
-pub async fn update_peer_route_status(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+// 0.0.0.0/0 sets the exit node, any other prefix selects the active peer among the peers advertising it
+// dry_run only plans an exit node change
+pub async fn update_peer_route_status(_req: HttpRequest, body: actix_web::web::Bytes, dry_run: bool) -> HttpResponse {
     // Parse request body
     let body_str = match String::from_utf8(body.to_vec()) {
         Ok(s) => s,
@@ -303,17 +380,34 @@ pub async fn update_peer_route_status(_req: HttpRequest, body: actix_web::web::B
             }
         };
         
+        if dry_run {
+            let plan = conf::util::get_config()
+                .map_err(|e| super::routing_pbr::PolicyRoutingError::PersistenceError(format!("Failed to load config: {}", e)))
+                .and_then(|config| super::routing_pbr::plan_set_exit_node(&peer_uuid, &config.network));
+            return match plan {
+                Ok(plan) => HttpResponse::Ok().json(plan),
+                Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": format!("Failed to plan exit node change: {}", e)
+                })),
+            };
+        }
+        
         // Set exit node (load config if needed - API call doesn't hold lock)
+        let previous_exit = super::routing_pbr::get_exit_node().ok().flatten();
         match super::routing_pbr::set_exit_node(&peer_uuid, None) {
             Ok(_) => {
                 log::info!("Successfully set peer {} as exit node", active_peer_id);
+                events::record(EventKind::ManualSwitch, Initiator::Api, EventDetails {
+                    old_peer: previous_exit,
+                    new_peer: Some(peer_uuid),
+                    reason: Some("exit node".to_string()),
+                    ..Default::default()
+                });
                 
-                // Bug 4 fix: Clear primary_exit_node on manual gateway switch
-                // This prevents automatic fail-back to a stale primary after user manually changes gateway
-                if let Err(e) = super::routing_pbr::set_primary_exit_node(None) {
-                    log::warn!("Failed to clear primary exit node after manual switch: {}", e);
-                } else {
-                    log::debug!("Cleared primary exit node after manual gateway switch");
+                // Bug 4 fix: Release the active gateway group on manual gateway switch
+                // This prevents automatic fail-back to a stale tier after user manually changes gateway
+                if let Err(e) = super::routing_pbr::release_gateway_group() {
+                    log::warn!("Failed to release gateway group after manual switch: {}", e);
                 }
                 
                 HttpResponse::Ok().json(serde_json::json!({
@@ -328,15 +422,33 @@ pub async fn update_peer_route_status(_req: HttpRequest, body: actix_web::web::B
                 }))
             }
         }
+    } else if dry_run {
+        HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "dry_run is only supported for the default route (exit node)"
+        }))
     } else {
-        // For other prefixes, use the generic function (future implementation)
+        // Overlapping prefix advertised by several peers: active/backup arbitration
+        let previous_active = super::persist::load_mode_state().ok().flatten()
+            .and_then(|state| state.prefix_active_backup.get(prefix)
+                .and_then(|prefix_state| Uuid::parse_str(&prefix_state.active_peer_id).ok()));
         match super::routing_pbr::set_active_peer_for_prefix(prefix, active_peer_id, &backup_peer_ids) {
             Ok(_) => {
+                events::record(EventKind::ManualSwitch, Initiator::Api, EventDetails {
+                    old_peer: previous_active,
+                    new_peer: Uuid::parse_str(active_peer_id).ok(),
+                    reason: Some(format!("prefix {}", prefix)),
+                    ..Default::default()
+                });
                 HttpResponse::Ok().json(serde_json::json!({
                     "success": true,
                     "message": format!("Set peer {} as active for prefix {}", active_peer_id, prefix)
                 }))
             }
+            Err(e @ super::routing_pbr::PolicyRoutingError::PrefixError(_)) => {
+                HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": e.to_string()
+                }))
+            }
             Err(e) => {
                 log::error!("Failed to set active peer: {}", e);
                 HttpResponse::InternalServerError().json(serde_json::json!({
@@ -347,6 +459,51 @@ pub async fn update_peer_route_status(_req: HttpRequest, body: actix_web::web::B
     }
 }
 
+// Get active/backup status of overlapping prefixes (prefixes advertised by more than one peer)
+pub async fn get_peer_route_status(_req: HttpRequest) -> HttpResponse {
+    use crate::mode::persist::load_mode_state;
+    use crate::mode::routing_pbr::{get_overlapping_prefixes, get_peer_health};
+    
+    let config = match conf::util::get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }));
+        }
+    };
+    
+    if config.agent.router.mode.as_str() != "router" {
+        return HttpResponse::Ok().json(serde_json::json!({
+            "prefixes": []
+        }));
+    }
+    
+    let state = load_mode_state().unwrap_or(None);
+    let prefixes: Vec<serde_json::Value> = get_overlapping_prefixes(&config.network).iter().map(|(prefix, peers)| {
+        let prefix_state = state.as_ref().and_then(|s| s.prefix_active_backup.get(&prefix.to_string()));
+        let peers_json: Vec<serde_json::Value> = peers.iter().map(|peer_id| {
+            let health = get_peer_health(peer_id);
+            serde_json::json!({
+                "peer_id": peer_id.to_string(),
+                "is_online": health.as_ref().map(|h| h.is_online),
+                "degraded_reason": health.as_ref().and_then(|h| h.degraded_reason.clone()),
+                "latency_ms": health.as_ref().and_then(|h| h.latency_ms),
+            })
+        }).collect();
+        serde_json::json!({
+            "prefix": prefix.to_string(),
+            "active_peer_id": prefix_state.map(|ps| ps.active_peer_id.clone()),
+            "backup_peer_ids": prefix_state.map(|ps| ps.backup_peer_ids.clone()).unwrap_or_default(),
+            "peers": peers_json,
+        })
+    }).collect();
+    
+    HttpResponse::Ok().json(serde_json::json!({
+        "prefixes": prefixes
+    }))
+}
+
 // Peer control actions: stop, start, reconnect
 pub async fn peer_control(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
     use crate::helpers::shell_cmd;
@@ -408,7 +565,9 @@ pub async fn peer_control(_req: HttpRequest, body: actix_web::web::Bytes) -> Htt
         }
     };
     
-    let wg_interface = &config.network.name;
+    // A pinned exit node runs on its own interface, where it always holds 0.0.0.0/0
+    let wg_interface = &super::routing_pbr::peer_interface(&config.network, &peer_uuid);
+    let pinned = *wg_interface != config.network.name;
     
     // Check if this peer is the active exit node (include default route if so)
     let is_exit_node = match super::routing_pbr::get_exit_node() {
@@ -417,7 +576,7 @@ pub async fn peer_control(_req: HttpRequest, body: actix_web::web::Bytes) -> Htt
     };
     
     // Get WireGuard parameters from conf.yml using helper function
-    let wg_params = match get_peer_wg_params(&config.network, &peer_uuid, is_exit_node) {
+    let wg_params = match get_peer_wg_params(&config.network, &peer_uuid, is_exit_node || pinned) {
         Some(p) => p,
         None => {
             return HttpResponse::NotFound().json(serde_json::json!({
@@ -569,7 +728,7 @@ pub async fn peer_control(_req: HttpRequest, body: actix_web::web::Bytes) -> Htt
 
 // Get exit node information (current exit node and peers with default routes)
 pub async fn get_exit_node_info(_req: HttpRequest) -> HttpResponse {
-    use crate::mode::routing_pbr::{get_exit_node, get_peers_with_default_route, get_exit_node_health};
+    use crate::mode::routing_pbr::{get_exit_node, get_peers_with_default_route, get_exit_node_health, ExitNodeHealth};
     
     // Get current config to check mode and get network info
     let config = match conf::util::get_config() {
@@ -612,34 +771,29 @@ pub async fn get_exit_node_info(_req: HttpRequest) -> HttpResponse {
     // Get health status for exit nodes
     let wg_interface = &config.network.name;
     let health_status = get_exit_node_health(&config.network, wg_interface);
-    let health_json: Vec<serde_json::Value> = health_status.iter().map(|h| {
-        serde_json::json!({
-            "peer_id": h.peer_id.to_string(),
-            "packet_loss_percent": h.packet_loss_percent,
-            "jitter_ms": h.jitter_ms,
-            "is_online": h.is_online,
-            "last_handshake": h.last_handshake,
-            "first_handshake": h.first_handshake,
-            "latency_ms": h.latency_ms,
-            "transfer_rx": h.transfer_rx,
-            "transfer_tx": h.transfer_tx,
-            "endpoint": h.endpoint
-        })
-    }).collect();
+    let health_json: Vec<serde_json::Value> = health_status.iter().map(ExitNodeHealth::to_json).collect();
     
     // Get auto-failover status
     let auto_failover = super::routing_pbr::get_auto_failover().unwrap_or(false);
     
+    // The local uplink can be selected like an exit node but is not a peer
+    let local_breakout = serde_json::json!({
+        "peer_id": super::routing_pbr::LOCAL_BREAKOUT_EXIT_ID.to_string(),
+        "name": super::routing_pbr::LOCAL_BREAKOUT_NAME,
+        "fallback": super::routing_pbr::get_local_breakout().unwrap_or(false)
+    });
+    
     HttpResponse::Ok().json(serde_json::json!({
         "exit_node": exit_node,
         "peers_with_default_route": peers_with_default_str,
         "health_status": health_json,
-        "auto_failover": auto_failover
+        "auto_failover": auto_failover,
+        "local_breakout": local_breakout
     }))
 }
 
-/// Toggle LAN access for a specific peer
-pub async fn set_peer_lan_access(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+/// Toggle LAN access for a specific peer (or only plan it with dry_run)
+pub async fn set_peer_lan_access(_req: HttpRequest, body: actix_web::web::Bytes, dry_run: bool) -> HttpResponse {
     use crate::mode::routing_pbr;
     
     #[derive(serde::Deserialize)]
@@ -698,6 +852,15 @@ pub async fn set_peer_lan_access(_req: HttpRequest, body: actix_web::web::Bytes)
         }));
     }
     
+    if dry_run {
+        return match routing_pbr::plan_set_peer_lan_access(&peer_id, request.has_lan_access, &config.network) {
+            Ok(plan) => HttpResponse::Ok().json(plan),
+            Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to plan LAN access change: {}", e)
+            })),
+        };
+    }
+    
     // Update the LAN access setting
     match routing_pbr::set_peer_lan_access(&peer_id, request.has_lan_access, &config.network) {
         Ok(new_state) => {
@@ -706,6 +869,11 @@ pub async fn set_peer_lan_access(_req: HttpRequest, body: actix_web::web::Bytes)
                 .unwrap_or_else(|| request.peer_id.clone());
             
             log::info!("Updated LAN access for peer {} ({}): {}", peer_name, request.peer_id, new_state);
+            events::record(EventKind::LanAccess, Initiator::Api, EventDetails {
+                peer_id: Some(peer_id),
+                reason: Some(if new_state { "allowed" } else { "denied" }.to_string()),
+                ..Default::default()
+            });
             
             HttpResponse::Ok().json(serde_json::json!({
                 "success": true,
@@ -847,3 +1015,546 @@ pub async fn set_auto_failover(_req: HttpRequest, body: actix_web::web::Bytes) -
     }
 }
 
+/// Get whether Smart Gateway may fall back to the local uplink (direct / local breakout)
+pub async fn get_local_breakout(_req: HttpRequest) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    match routing_pbr::get_local_breakout() {
+        Ok(fallback) => {
+            HttpResponse::Ok().json(serde_json::json!({
+                "peer_id": routing_pbr::LOCAL_BREAKOUT_EXIT_ID.to_string(),
+                "name": routing_pbr::LOCAL_BREAKOUT_NAME,
+                "fallback": fallback
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to get local breakout status: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to get local breakout status: {}", e)
+            }))
+        }
+    }
+}
+
+/// Allow or disallow Smart Gateway to fall back to the local uplink when no exit node is healthy
+pub async fn set_local_breakout(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    #[derive(serde::Deserialize)]
+    struct LocalBreakoutRequest {
+        fallback: bool,
+    }
+    
+    let request: LocalBreakoutRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid request body: {}", e)
+            }));
+        }
+    };
+    
+    // Get current config to check mode
+    let config = match conf::util::get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }));
+        }
+    };
+    
+    // Only allow in router mode
+    if config.agent.router.mode.as_str() != "router" {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Local breakout is only available in Router Mode"
+        }));
+    }
+    
+    match routing_pbr::set_local_breakout(request.fallback) {
+        Ok(_) => {
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "fallback": request.fallback,
+                "message": format!("Local breakout fallback {}", if request.fallback { "enabled" } else { "disabled" })
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to set local breakout fallback: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to set local breakout fallback: {}", e)
+            }))
+        }
+    }
+}
+
+/// Get the health monitor and failover tuning (agent.router.failover in conf.yml)
+pub async fn get_failover_tuning(_req: HttpRequest) -> HttpResponse {
+    match conf::util::get_config() {
+        Ok(config) => HttpResponse::Ok().json(&config.agent.router.failover),
+        Err(e) => {
+            log::error!("Failed to load config: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }))
+        }
+    }
+}
+
+/// Replace the health monitor and failover tuning, including per exit node overrides
+/// The health monitor picks the new values up on its next round
+pub async fn set_failover_tuning(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+    use wg_quickrs_lib::types::config::AgentRouterFailover;
+    use wg_quickrs_lib::validation::agent::validate_router_failover;
+    
+    let failover: AgentRouterFailover = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid request body: {}", e)
+            }));
+        }
+    };
+    
+    if let Err(e) = validate_router_failover(&failover) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("Invalid failover tuning: {}", e)
+        }));
+    }
+    
+    let mut config = match conf::util::get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }));
+        }
+    };
+    
+    config.agent.router.failover = failover;
+    match conf::util::set_config(&mut config) {
+        Ok(_) => {
+            log::info!("Updated failover tuning");
+            HttpResponse::Ok().json(&config.agent.router.failover)
+        }
+        Err(e) => {
+            log::error!("Failed to save failover tuning: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to save failover tuning: {}", e)
+            }))
+        }
+    }
+}
+
+/// Get active-active exit mode (load balancing) status and exit node weights
+pub async fn get_load_balance(_req: HttpRequest) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    match routing_pbr::get_load_balance() {
+        Ok((enabled, weights)) => {
+            HttpResponse::Ok().json(serde_json::json!({
+                "enabled": enabled,
+                "weights": weights,
+                "max_weight": routing_pbr::MAX_EXIT_NODE_WEIGHT
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to get load balancing status: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to get load balancing status: {}", e)
+            }))
+        }
+    }
+}
+
+/// Set active-active exit mode (load balancing) status and optionally the exit node weights
+pub async fn set_load_balance(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    #[derive(serde::Deserialize)]
+    struct LoadBalanceRequest {
+        enabled: bool,
+        #[serde(default)]
+        weights: Option<std::collections::HashMap<String, u32>>,
+    }
+    
+    let request: LoadBalanceRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid request body: {}", e)
+            }));
+        }
+    };
+    
+    // Get current config to check mode
+    let config = match conf::util::get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }));
+        }
+    };
+    
+    // Only allow in router mode
+    if config.agent.router.mode.as_str() != "router" {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Load balancing is only available in Router Mode"
+        }));
+    }
+    
+    match routing_pbr::set_load_balance(request.enabled, request.weights, Some(&config.network)) {
+        Ok(_) => {
+            let (enabled, weights) = routing_pbr::get_load_balance().unwrap_or((request.enabled, Default::default()));
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "enabled": enabled,
+                "weights": weights,
+                "message": format!("Load balancing {}", if enabled { "enabled" } else { "disabled" })
+            }))
+        }
+        Err(e @ routing_pbr::PolicyRoutingError::LoadBalanceError(_)) => {
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to set load balancing: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to set load balancing: {}", e)
+            }))
+        }
+    }
+}
+
+/// Get the LAN clients pinned to an exit node of their own
+pub async fn get_client_exits(_req: HttpRequest) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    match routing_pbr::get_client_exits() {
+        Ok(client_exits) => {
+            HttpResponse::Ok().json(serde_json::json!({
+                "client_exits": client_exits,
+                "max_client_exits": routing_pbr::MAX_CLIENT_EXITS
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to get client exit nodes: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to get client exit nodes: {}", e)
+            }))
+        }
+    }
+}
+
+/// Replace the LAN clients pinned to an exit node of their own (an empty list unpins everyone)
+pub async fn set_client_exits(_req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    #[derive(serde::Deserialize)]
+    struct ClientExitsRequest {
+        client_exits: Vec<crate::mode::persist::ClientExit>,
+    }
+    
+    let request: ClientExitsRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid request body: {}", e)
+            }));
+        }
+    };
+    
+    // Get current config to check mode
+    let config = match conf::util::get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }));
+        }
+    };
+    
+    // Only allow in router mode
+    if config.agent.router.mode.as_str() != "router" {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Client exit nodes are only available in Router Mode"
+        }));
+    }
+    
+    match routing_pbr::set_client_exits(&request.client_exits, Some(&config.network)) {
+        Ok(client_exits) => {
+            HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "client_exits": client_exits,
+                "message": format!("{} client(s) pinned to exit nodes", client_exits.len())
+            }))
+        }
+        Err(e @ routing_pbr::PolicyRoutingError::ClientExitError(_)) => {
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to set client exit nodes: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to set client exit nodes: {}", e)
+            }))
+        }
+    }
+}
+
+/// Get routed domains with their exit node and the addresses they currently resolve to
+pub async fn get_domain_routes(_req: HttpRequest) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    match routing_pbr::get_domain_routes() {
+        Ok(routes) => {
+            let mut domains: Vec<&String> = routes.keys().collect();
+            domains.sort();
+            let routes: Vec<serde_json::Value> = domains.into_iter().map(|domain| serde_json::json!({
+                "domain": domain,
+                "peer_id": routes[domain],
+                "addresses": routing_pbr::get_domain_route_addresses(domain)
+            })).collect();
+            HttpResponse::Ok().json(serde_json::json!({
+                "routes": routes
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to get domain routes: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to get domain routes: {}", e)
+            }))
+        }
+    }
+}
+
+/// Replace the routed domains (domain -> exit node peer ID)
+pub async fn set_domain_routes(req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    #[derive(serde::Deserialize)]
+    struct DomainRoutesRequest {
+        routes: std::collections::HashMap<String, String>,
+    }
+    
+    let request: DomainRoutesRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid request body: {}", e)
+            }));
+        }
+    };
+    
+    // Get current config to check mode
+    let config = match conf::util::get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }));
+        }
+    };
+    
+    // Only allow in router mode
+    if config.agent.router.mode.as_str() != "router" {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Domain routing is only available in Router Mode"
+        }));
+    }
+    
+    match routing_pbr::set_domain_routes(request.routes, Some(&config.network)) {
+        Ok(_) => get_domain_routes(req).await,
+        Err(e @ routing_pbr::PolicyRoutingError::DomainRouteError(_)) => {
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to set domain routes: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to set domain routes: {}", e)
+            }))
+        }
+    }
+}
+
+/// Get gateway groups with their tiers, the active group and any pending fail-back
+pub async fn get_gateway_groups(_req: HttpRequest) -> HttpResponse {
+    use crate::mode::routing_pbr;
+    
+    match routing_pbr::get_gateway_groups() {
+        Ok((groups, active, failback)) => HttpResponse::Ok().json(serde_json::json!({
+            "groups": groups,
+            "active": active,
+            "failback": failback
+        })),
+        Err(e) => {
+            log::error!("Failed to get gateway groups: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to get gateway groups: {}", e)
+            }))
+        }
+    }
+}
+
+/// Replace the gateway groups (name -> ordered tiers of exit node peer IDs) and choose the active one
+pub async fn set_gateway_groups(req: HttpRequest, body: actix_web::web::Bytes) -> HttpResponse {
+    use crate::mode::persist::GatewayGroup;
+    use crate::mode::routing_pbr;
+    
+    #[derive(serde::Deserialize)]
+    struct GatewayGroupsRequest {
+        groups: std::collections::BTreeMap<String, GatewayGroup>,
+        #[serde(default)]
+        active: Option<String>,
+    }
+    
+    let request: GatewayGroupsRequest = match serde_json::from_slice(&body) {
+        Ok(r) => r,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid request body: {}", e)
+            }));
+        }
+    };
+    
+    // Get current config to check mode
+    let config = match conf::util::get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to load config: {}", e)
+            }));
+        }
+    };
+    
+    // Only allow in router mode
+    if config.agent.router.mode.as_str() != "router" {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Gateway groups are only available in Router Mode"
+        }));
+    }
+    
+    match routing_pbr::set_gateway_groups(request.groups, request.active, &config.network) {
+        Ok(_) => get_gateway_groups(req).await,
+        Err(e @ routing_pbr::PolicyRoutingError::GatewayGroupError(_)) => {
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to set gateway groups: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to set gateway groups: {}", e)
+            }))
+        }
+    }
+}
+
+/// Get the reconciler's last drift report (null before the first pass or outside Router Mode)
+pub async fn get_drift(_req: HttpRequest) -> HttpResponse {
+    use crate::mode::reconcile;
+    
+    HttpResponse::Ok().json(serde_json::json!({
+        "interval_secs": reconcile::RECONCILE_INTERVAL_SECS,
+        "report": reconcile::last_report()
+    }))
+}
+
+/// Get the stored health history of one exit node (the last hour unless from/to are given)
+pub async fn get_health_history(_req: HttpRequest, query: crate::web::api::HealthHistoryQuery) -> HttpResponse {
+    use crate::mode::history::{self, HistoryError};
+    
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let to = query.to.unwrap_or(now);
+    let from = query.from.unwrap_or(to.saturating_sub(3600));
+    
+    match history::query(&query.peer, from, to, query.step) {
+        Ok((step, points)) => HttpResponse::Ok().json(serde_json::json!({
+            "peer_id": query.peer,
+            "from": from,
+            "to": to,
+            "step": step,
+            "points": points
+        })),
+        Err(e @ (HistoryError::InvalidStep(_) | HistoryError::InvalidRange(_, _))) => {
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to read health history: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to read health history: {}", e)
+            }))
+        }
+    }
+}
+
+/// Get journal events (newest first), filtered by kind, peer and time, paged with `before`
+pub async fn get_events(_req: HttpRequest, query: crate::web::api::EventsQuery) -> HttpResponse {
+    use crate::mode::events::EventFilter;
+    
+    let mut kinds = Vec::new();
+    for name in query.kind.iter().flat_map(|kinds| kinds.split(',')).map(str::trim).filter(|name| !name.is_empty()) {
+        match EventKind::parse(name) {
+            Some(kind) => kinds.push(kind),
+            None => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Unknown event kind: {}", name)
+                }));
+            }
+        }
+    }
+    let filter = EventFilter {
+        kinds,
+        peer: query.peer,
+        since: query.since,
+        until: query.until,
+        before: query.before,
+    };
+    let limit = query.limit.clamp(1, 500);
+    
+    match events::query(&filter, limit) {
+        Ok(events) => {
+            // A full page may have more events behind it
+            let next_before = (events.len() == limit).then(|| events.last().map(|event| event.id)).flatten();
+            HttpResponse::Ok().json(serde_json::json!({
+                "events": events,
+                "next_before": next_before
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to read events: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to read events: {}", e)
+            }))
+        }
+    }
+}
+
+/// Send a test notification to every configured sink (once, without retries) and report each result
+pub async fn test_notifications(_req: HttpRequest) -> HttpResponse {
+    let results = match tokio::task::spawn_blocking(crate::mode::notify::send_test).await {
+        Ok(results) => results,
+        Err(e) => {
+            log::error!("Failed to send test notifications: {}", e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to send test notifications: {}", e)
+            }));
+        }
+    };
+    let results: Vec<_> = results.into_iter().map(|(sink, result)| serde_json::json!({
+        "sink": sink,
+        "ok": result.is_ok(),
+        "error": result.err().map(|e| e.to_string()),
+    })).collect();
+    HttpResponse::Ok().json(serde_json::json!({ "results": results }))
+}
@@ -2,13 +2,17 @@ use crate::conf;
 use crate::wireguard;
 use crate::mode::ui_mode;
 use crate::web::init;
+use crate::web::metrics::MetricsSnapshot;
+use crate::web::stream;
 use actix_web::{HttpRequest, HttpResponse, Responder, get, patch, post, web};
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use once_cell::sync::Lazy;
 use rand::{RngCore, rng};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 use std::time::{SystemTime, UNIX_EPOCH};
+use wg_quickrs_lib::types::config::MetricsAuth;
 use wg_quickrs_lib::types::misc::VERSION_BUILD_INFO;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -83,12 +87,35 @@ async fn get_mode(req: HttpRequest) -> impl Responder {
     ui_mode::get_mode(req).await
 }
 
+// ?dry_run=true returns the change plan instead of applying the change (see mode/change_plan.rs)
+#[derive(serde::Deserialize)]
+pub(crate) struct DryRunQuery {
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+}
+
+// ?confirm_timeout=<secs> rolls the change back unless POST /api/mode/confirm follows in time (see mode/confirm.rs)
+#[derive(serde::Deserialize)]
+pub(crate) struct ModeToggleQuery {
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+    pub(crate) confirm_timeout: Option<u64>,
+}
+
 #[patch("/api/mode/toggle")]
-async fn patch_mode_toggle(req: HttpRequest, body: web::Bytes) -> impl Responder {
+async fn patch_mode_toggle(req: HttpRequest, query: web::Query<ModeToggleQuery>, body: web::Bytes) -> impl Responder {
     if let Err(e) = enforce_auth(req.clone()) {
         return e;
     }
-    ui_mode::toggle_mode(req, body).await
+    ui_mode::toggle_mode(req, body, query.dry_run, query.confirm_timeout).await
+}
+
+#[post("/api/mode/confirm")]
+async fn post_mode_confirm(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::confirm_change(req, body).await
 }
 
 #[get("/api/mode/can-switch")]
@@ -100,11 +127,19 @@ async fn get_mode_can_switch(req: HttpRequest) -> impl Responder {
 }
 
 #[patch("/api/mode/peer-route-status")]
-async fn patch_peer_route_status(req: HttpRequest, body: web::Bytes) -> impl Responder {
+async fn patch_peer_route_status(req: HttpRequest, query: web::Query<DryRunQuery>, body: web::Bytes) -> impl Responder {
     if let Err(e) = enforce_auth(req.clone()) {
         return e;
     }
-    ui_mode::update_peer_route_status(req, body).await
+    ui_mode::update_peer_route_status(req, body, query.dry_run).await
+}
+
+#[get("/api/mode/peer-route-status")]
+async fn get_peer_route_status(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_peer_route_status(req).await
 }
 
 #[get("/api/mode/exit-node")]
@@ -124,11 +159,11 @@ async fn post_peer_control(req: HttpRequest, body: web::Bytes) -> impl Responder
 }
 
 #[patch("/api/peer/lan-access")]
-async fn patch_peer_lan_access(req: HttpRequest, body: web::Bytes) -> impl Responder {
+async fn patch_peer_lan_access(req: HttpRequest, query: web::Query<DryRunQuery>, body: web::Bytes) -> impl Responder {
     if let Err(e) = enforce_auth(req.clone()) {
         return e;
     }
-    ui_mode::set_peer_lan_access(req, body).await
+    ui_mode::set_peer_lan_access(req, body, query.dry_run).await
 }
 
 #[get("/api/peer/lan-access")]
@@ -155,6 +190,263 @@ pub async fn post_auto_failover(req: HttpRequest, body: web::Bytes) -> impl Resp
     ui_mode::set_auto_failover(req, body).await
 }
 
+#[get("/api/router-mode/local-breakout")]
+pub async fn get_local_breakout(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_local_breakout(req).await
+}
+
+#[post("/api/router-mode/local-breakout")]
+pub async fn post_local_breakout(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::set_local_breakout(req, body).await
+}
+
+#[get("/api/router-mode/failover")]
+pub async fn get_failover_tuning(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_failover_tuning(req).await
+}
+
+#[post("/api/router-mode/failover")]
+pub async fn post_failover_tuning(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::set_failover_tuning(req, body).await
+}
+
+#[get("/api/router-mode/load-balance")]
+pub async fn get_load_balance(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_load_balance(req).await
+}
+
+#[post("/api/router-mode/load-balance")]
+pub async fn post_load_balance(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::set_load_balance(req, body).await
+}
+
+#[get("/api/router-mode/client-exits")]
+pub async fn get_client_exits(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_client_exits(req).await
+}
+
+#[post("/api/router-mode/client-exits")]
+pub async fn post_client_exits(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::set_client_exits(req, body).await
+}
+
+#[get("/api/router-mode/domain-routes")]
+pub async fn get_domain_routes(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_domain_routes(req).await
+}
+
+#[post("/api/router-mode/domain-routes")]
+pub async fn post_domain_routes(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::set_domain_routes(req, body).await
+}
+
+#[get("/api/router-mode/gateway-groups")]
+pub async fn get_gateway_groups(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_gateway_groups(req).await
+}
+
+#[post("/api/router-mode/gateway-groups")]
+pub async fn post_gateway_groups(req: HttpRequest, body: web::Bytes) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::set_gateway_groups(req, body).await
+}
+
+#[get("/api/router-mode/drift")]
+pub async fn get_drift(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_drift(req).await
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct HealthHistoryQuery {
+    pub(crate) peer: Uuid,
+    pub(crate) from: Option<u64>,
+    pub(crate) to: Option<u64>,
+    pub(crate) step: Option<u64>,
+}
+
+#[get("/api/health/history")]
+pub async fn get_health_history(req: HttpRequest, query: web::Query<HealthHistoryQuery>) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_health_history(req, query.into_inner()).await
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct EventsQuery {
+    pub(crate) kind: Option<String>, // comma-separated
+    pub(crate) peer: Option<Uuid>,
+    pub(crate) since: Option<u64>,
+    pub(crate) until: Option<u64>,
+    pub(crate) before: Option<u64>,
+    #[serde(default = "default_events_limit")]
+    pub(crate) limit: usize,
+}
+
+fn default_events_limit() -> usize {
+    50
+}
+
+#[get("/api/events")]
+pub async fn get_events(req: HttpRequest, query: web::Query<EventsQuery>) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::get_events(req, query.into_inner()).await
+}
+
+#[post("/api/notifications/test")]
+pub async fn post_notifications_test(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    ui_mode::test_notifications(req).await
+}
+
+// Live dashboard updates as server-sent events (see web/stream.rs)
+#[get("/api/stream")]
+pub async fn get_stream(req: HttpRequest) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        // Compress would buffer the events
+        .insert_header(actix_web::http::header::ContentEncoding::Identity)
+        .streaming(stream::subscribe(stream::current_state()))
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct TelemetryQuery {
+    pub(crate) after: Option<u64>, // the cursor of the previous response
+    pub(crate) since: Option<i64>, // Unix milliseconds
+    pub(crate) limit: Option<usize>,
+}
+
+// Telemetry samples without side effects on the sampler or other consumers (see wireguard/telemetry.rs)
+#[get("/api/telemetry")]
+pub async fn get_telemetry(req: HttpRequest, query: web::Query<TelemetryQuery>) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    let since = match query.since.map(chrono::DateTime::from_timestamp_millis) {
+        Some(None) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Invalid since timestamp"
+            }));
+        }
+        since => since.flatten().map(|since| since.naive_utc()),
+    };
+    HttpResponse::Ok().json(wireguard::telemetry::query(query.after, since, query.limit))
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct UsageQuery {
+    pub(crate) peer: Option<Uuid>,
+}
+
+// Traffic totals and quota status per peer (see wireguard/usage.rs)
+#[get("/api/usage")]
+pub async fn get_usage(req: HttpRequest, query: web::Query<UsageQuery>) -> impl Responder {
+    if let Err(e) = enforce_auth(req.clone()) {
+        return e;
+    }
+    let config = match conf::util::get_config() {
+        Ok(config) => config,
+        Err(_) => {
+            return HttpResponse::InternalServerError().body("Unable to get config");
+        }
+    };
+    match wireguard::usage::report(&config.network, query.peer) {
+        Ok(peers) => HttpResponse::Ok().json(serde_json::json!({ "peers": peers })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to read usage totals: {}", e)
+        })),
+    }
+}
+
+// Prometheus scrape target (agent.web.metrics)
+#[get("/metrics")]
+pub async fn get_metrics(req: HttpRequest) -> impl Responder {
+    let metrics = match conf::util::get_config() {
+        Ok(config) => config.agent.web.metrics,
+        Err(_) => {
+            return HttpResponse::InternalServerError().body("Unable to get config");
+        }
+    };
+    if !metrics.enabled {
+        return HttpResponse::NotFound().body("Metrics are disabled");
+    }
+    match metrics.auth {
+        MetricsAuth::Api => {
+            if let Err(e) = enforce_auth(req.clone()) {
+                return e;
+            }
+        }
+        MetricsAuth::Token => {
+            let bearer = req.headers().get("Authorization")
+                .and_then(|header| header.to_str().ok())
+                .and_then(|header| header.strip_prefix("Bearer "));
+            let authorized = bearer.zip(metrics.token.as_deref()).is_some_and(|(bearer, token)| {
+                aws_lc_rs::constant_time::verify_slices_are_equal(bearer.as_bytes(), token.as_bytes()).is_ok()
+            });
+            if !authorized {
+                return HttpResponse::Unauthorized()
+                    .content_type("text/plain; charset=utf-8")
+                    .body("Authorization header missing or invalid");
+            }
+        }
+        MetricsAuth::None => {}
+    }
+
+    match web::block(|| MetricsSnapshot::collect().render()).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4; charset=utf-8")
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to collect metrics: {}", e)),
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub(crate) struct LogsQuery {
     #[serde(default = "default_log_lines")]
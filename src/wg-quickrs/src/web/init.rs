@@ -156,14 +156,14 @@ pub async fn post_init(_req: HttpRequest, body: web::Bytes) -> impl Responder {
         agent_web_http_port: init_data.agent_web_http_port,
         agent_web_https_enabled: init_data.agent_web_https_enabled,
         agent_web_https_port: init_data.agent_web_https_port,
-        agent_web_https_tls_cert: init_data.agent_web_https_tls_cert.map(|s| PathBuf::from(s)),
-        agent_web_https_tls_key: init_data.agent_web_https_tls_key.map(|s| PathBuf::from(s)),
+        agent_web_https_tls_cert: init_data.agent_web_https_tls_cert.map(PathBuf::from),
+        agent_web_https_tls_key: init_data.agent_web_https_tls_key.map(PathBuf::from),
         agent_web_password_enabled: init_data.agent_web_password_enabled,
         agent_web_password: init_data.agent_web_password,
         agent_vpn_enabled: init_data.agent_vpn_enabled,
         agent_vpn_port: init_data.agent_vpn_port,
         agent_firewall_enabled: init_data.agent_firewall_enabled,
-        agent_firewall_utility: init_data.agent_firewall_utility.map(|s| PathBuf::from(s)),
+        agent_firewall_utility: init_data.agent_firewall_utility.map(PathBuf::from),
         agent_firewall_gateway: init_data.agent_firewall_gateway,
         agent_peer_name: init_data.agent_peer_name,
         agent_peer_vpn_internal_address: init_data.agent_peer_vpn_internal_address.and_then(|s| s.parse::<Ipv4Addr>().ok()),
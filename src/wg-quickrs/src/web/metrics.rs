@@ -0,0 +1,256 @@
+// Prometheus /metrics: tunnel, peer and exit node health in the text exposition format
+//
+// Counters (bytes, events) start over when the agent restarts, which Prometheus' rate() handles.
+// Exit node metrics cover the peers with a default route (and the local breakout while it is monitored).
+
+use crate::conf;
+use crate::mode::events::{self, EventCounters, EventKind};
+use crate::mode::mode::SystemMode;
+use crate::mode::persist::load_mode_state;
+use crate::mode::routing_pbr::{self, ExitNodeHealth, LOCAL_BREAKOUT_EXIT_ID, LOCAL_BREAKOUT_NAME};
+use crate::wireguard::cmd::{show_dump, status_tunnel};
+use crate::wireguard::telemetry;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use uuid::Uuid;
+use wg_quickrs_lib::types::misc::WireGuardStatus;
+
+// Traffic and handshake of the tunnel to one peer
+#[derive(Debug, Clone, Default)]
+pub struct PeerMetrics {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub last_handshake: u64, // Unix seconds, 0 = never
+}
+
+// A metric's value for one peer or exit node (None: no sample)
+type PeerValue = fn(&PeerMetrics) -> Option<f64>;
+type HealthValue = fn(&ExitNodeHealth) -> Option<f64>;
+
+// Everything one scrape reports
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub now: u64,
+    pub tunnel_up: bool,
+    pub router_mode: bool,
+    pub auto_failover: bool,
+    pub load_balance: bool,
+    pub config_digest: String,
+    pub names: BTreeMap<Uuid, String>,
+    pub peers: BTreeMap<Uuid, PeerMetrics>,
+    pub exit_nodes: Vec<ExitNodeHealth>,
+    pub exit_node: Option<Uuid>,
+    pub events: EventCounters,
+}
+
+impl MetricsSnapshot {
+    pub fn collect() -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut snapshot = MetricsSnapshot {
+            now,
+            tunnel_up: status_tunnel().is_ok_and(|status| status == WireGuardStatus::UP),
+            config_digest: conf::util::get_network_digest().unwrap_or_default(),
+            exit_node: routing_pbr::get_exit_node().ok().flatten(),
+            events: events::counters(),
+            ..Default::default()
+        };
+        if let Ok(Some(state)) = load_mode_state() {
+            snapshot.router_mode = state.last_mode == SystemMode::Router;
+            snapshot.auto_failover = state.auto_failover;
+            snapshot.load_balance = state.load_balance;
+        }
+
+        let Ok(config) = conf::util::get_config() else {
+            return snapshot;
+        };
+        let this_peer = config.network.this_peer;
+        snapshot.names = config.network.peers.iter().map(|(id, peer)| (*id, peer.name.clone())).collect();
+        snapshot.names.insert(LOCAL_BREAKOUT_EXIT_ID, LOCAL_BREAKOUT_NAME.to_string());
+        snapshot.exit_nodes = routing_pbr::get_exit_node_health(&config.network, &config.network.name);
+        if snapshot.tunnel_up {
+            // The sampler's latest sample when it is current, so scrapes do not run `wg show` again
+            let dump = match telemetry::latest(telemetry::interval() * 2) {
+                Some(sample) => Ok(sample.datum),
+                None => show_dump(&config),
+            };
+            match dump {
+                Ok(dump) => {
+                    for (connection_id, datum) in dump {
+                        // a → b is what this peer sends when it is side a
+                        let (peer_id, tx_bytes, rx_bytes) = if connection_id.a == this_peer {
+                            (connection_id.b, datum.transfer_a_to_b, datum.transfer_b_to_a)
+                        } else {
+                            (connection_id.a, datum.transfer_b_to_a, datum.transfer_a_to_b)
+                        };
+                        snapshot.peers.insert(peer_id, PeerMetrics { rx_bytes, tx_bytes, last_handshake: datum.latest_handshake_at });
+                    }
+                }
+                Err(e) => log::warn!("Failed to read WireGuard peers for metrics: {}", e),
+            }
+        }
+        snapshot
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = Exposition::default();
+        let name = |id: &Uuid| self.names.get(id).cloned().unwrap_or_default();
+
+        out.family("wg_quickrs_tunnel_up", "gauge", "Whether the WireGuard tunnel is up");
+        out.sample("wg_quickrs_tunnel_up", &[], flag(self.tunnel_up));
+        out.family("wg_quickrs_router_mode", "gauge", "1 in Router Mode, 0 in Host Mode");
+        out.sample("wg_quickrs_router_mode", &[], flag(self.router_mode));
+        out.family("wg_quickrs_smart_gateway_enabled", "gauge", "Whether automatic failover (Smart Gateway) is on");
+        out.sample("wg_quickrs_smart_gateway_enabled", &[], flag(self.auto_failover));
+        out.family("wg_quickrs_load_balance_enabled", "gauge", "Whether active-active load balancing is on");
+        out.sample("wg_quickrs_load_balance_enabled", &[], flag(self.load_balance));
+        out.family("wg_quickrs_config_info", "gauge", "Digest of the network configuration");
+        out.sample("wg_quickrs_config_info", &[("digest", &self.config_digest)], 1.0);
+
+        let families: [(&str, &str, &str, PeerValue); 3] = [
+            ("wg_quickrs_peer_receive_bytes_total", "counter", "Bytes received from the peer", |p| Some(p.rx_bytes as f64)),
+            ("wg_quickrs_peer_transmit_bytes_total", "counter", "Bytes sent to the peer", |p| Some(p.tx_bytes as f64)),
+            ("wg_quickrs_peer_last_handshake_timestamp_seconds", "gauge", "Time of the latest handshake with the peer",
+                |p| (p.last_handshake > 0).then_some(p.last_handshake as f64)),
+        ];
+        for (metric, kind, help, value) in families {
+            out.family(metric, kind, help);
+            for (id, peer) in &self.peers {
+                if let Some(value) = value(peer) {
+                    out.sample(metric, &[("peer_id", &id.to_string()), ("name", &name(id))], value);
+                }
+            }
+        }
+        out.family("wg_quickrs_peer_handshake_age_seconds", "gauge", "Seconds since the latest handshake with the peer");
+        for (id, peer) in self.peers.iter().filter(|(_, peer)| peer.last_handshake > 0) {
+            let age = self.now.saturating_sub(peer.last_handshake) as f64;
+            out.sample("wg_quickrs_peer_handshake_age_seconds", &[("peer_id", &id.to_string()), ("name", &name(id))], age);
+        }
+
+        let families: [(&str, &str, HealthValue); 6] = [
+            ("wg_quickrs_exit_node_online", "Whether the exit node answers the health monitor", |h| Some(flag(h.is_online))),
+            ("wg_quickrs_exit_node_healthy", "Whether the exit node is online and within the SLA thresholds", |h| Some(flag(h.is_healthy()))),
+            ("wg_quickrs_exit_node_latency_seconds", "Average probe round trip time", |h| h.latency_ms.map(|ms| ms as f64 / 1000.0)),
+            ("wg_quickrs_exit_node_packet_loss_ratio", "Lost probes over the ping history (0-1)", |h| h.packet_loss_percent.map(|p| p / 100.0)),
+            ("wg_quickrs_exit_node_jitter_seconds", "Probe round trip time variation", |h| h.jitter_ms.map(|ms| ms as f64 / 1000.0)),
+            ("wg_quickrs_exit_node_internet_reachable", "Whether the internet probes through the exit node succeed",
+                |h| h.internet_reachable.map(flag)),
+        ];
+        for (metric, help, value) in families {
+            out.family(metric, "gauge", help);
+            for health in &self.exit_nodes {
+                if let Some(value) = value(health) {
+                    out.sample(metric, &[("peer_id", &health.peer_id.to_string()), ("name", &name(&health.peer_id))], value);
+                }
+            }
+        }
+        out.family("wg_quickrs_exit_node_active", "gauge", "1 for the current exit node");
+        for health in &self.exit_nodes {
+            let active = self.exit_node == Some(health.peer_id);
+            out.sample("wg_quickrs_exit_node_active", &[("peer_id", &health.peer_id.to_string()), ("name", &name(&health.peer_id))], flag(active));
+        }
+
+        out.family("wg_quickrs_events_total", "counter", "Journaled events since the agent started");
+        for kind in EventKind::ALL {
+            let count = self.events.by_kind.get(&kind).copied().unwrap_or(0);
+            out.sample("wg_quickrs_events_total", &[("kind", &kind.name())], count as f64);
+        }
+        out.family("wg_quickrs_exit_node_failovers_total", "counter", "Automatic failovers away from the peer since the agent started");
+        let failovers: BTreeMap<_, _> = self.events.failovers_from.iter().collect();
+        for (id, count) in failovers {
+            out.sample("wg_quickrs_exit_node_failovers_total", &[("peer_id", &id.to_string()), ("name", &name(id))], *count as f64);
+        }
+        out.0
+    }
+}
+
+// Text exposition format writer
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, metric: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", metric, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", metric, kind);
+    }
+
+    fn sample(&mut self, metric: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(metric);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn flag(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let (site_a, site_b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let health = |peer_id, is_online, latency_ms| ExitNodeHealth {
+            peer_id,
+            is_online,
+            last_handshake: None,
+            first_handshake: None,
+            latency_ms,
+            packet_loss_percent: Some(if is_online { 0.0 } else { 100.0 }),
+            jitter_ms: None,
+            transfer_rx: 0,
+            transfer_tx: 0,
+            endpoint: None,
+            degraded_reason: None,
+            internet_reachable: None,
+        };
+        let mut snapshot = MetricsSnapshot {
+            now: 1000,
+            tunnel_up: true,
+            router_mode: true,
+            config_digest: "abc".to_string(),
+            names: BTreeMap::from([(site_a, "site \"a\"".to_string()), (site_b, "site-b".to_string())]),
+            peers: BTreeMap::from([(site_a, PeerMetrics { rx_bytes: 2048, tx_bytes: 512, last_handshake: 880 })]),
+            exit_nodes: vec![health(site_a, false, None), health(site_b, true, Some(25))],
+            exit_node: Some(site_b),
+            ..Default::default()
+        };
+        snapshot.events.by_kind.insert(EventKind::Failover, 2);
+        snapshot.events.failovers_from.insert(site_a, 2);
+
+        let text = snapshot.render();
+        let a = format!("peer_id=\"{}\",name=\"site \\\"a\\\"\"", site_a);
+        let b = format!("peer_id=\"{}\",name=\"site-b\"", site_b);
+        for line in [
+            "wg_quickrs_tunnel_up 1".to_string(),
+            "wg_quickrs_config_info{digest=\"abc\"} 1".to_string(),
+            "# TYPE wg_quickrs_peer_receive_bytes_total counter".to_string(),
+            format!("wg_quickrs_peer_receive_bytes_total{{{}}} 2048", a),
+            format!("wg_quickrs_peer_handshake_age_seconds{{{}}} 120", a),
+            format!("wg_quickrs_exit_node_online{{{}}} 0", a),
+            format!("wg_quickrs_exit_node_packet_loss_ratio{{{}}} 1", a),
+            format!("wg_quickrs_exit_node_latency_seconds{{{}}} 0.025", b),
+            format!("wg_quickrs_exit_node_active{{{}}} 1", b),
+            "wg_quickrs_events_total{kind=\"failover\"} 2".to_string(),
+            "wg_quickrs_events_total{kind=\"online\"} 0".to_string(),
+            format!("wg_quickrs_exit_node_failovers_total{{{}}} 2", a),
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+        // No latency sample for an exit node without a measurement
+        assert!(!text.contains(&format!("wg_quickrs_exit_node_latency_seconds{{{}}}", a)));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod api;
 pub mod app;
 pub mod server;
+pub mod stream;
 pub mod init;
+pub mod metrics;
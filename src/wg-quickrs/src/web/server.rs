@@ -80,6 +80,7 @@ pub(crate) async fn run_web_server_init_mode() -> std::io::Result<()> {
                     enabled: false,
                     hash: String::new(),
                 },
+                metrics: wg_quickrs_lib::types::config::AgentWebMetrics::default(),
             },
             vpn: wg_quickrs_lib::types::config::AgentVpn {
                 enabled: false,
@@ -91,6 +92,9 @@ pub(crate) async fn run_web_server_init_mode() -> std::io::Result<()> {
                 gateway: String::new(),
             },
             router: wg_quickrs_lib::types::config::AgentRouter::default(),
+            notifications: wg_quickrs_lib::types::config::AgentNotifications::default(),
+            telemetry: wg_quickrs_lib::types::config::AgentTelemetry::default(),
+            usage: wg_quickrs_lib::types::config::AgentUsage::default(),
         },
         network: wg_quickrs_lib::types::network::Network {
             name: String::new(),
@@ -139,14 +143,36 @@ async fn run_web_server_with_config(config: &Config, init_mode: bool) -> std::io
             .service(api::post_wireguard_status)
                         .service(api::get_mode)
                         .service(api::patch_mode_toggle)
+                        .service(api::post_mode_confirm)
                         .service(api::get_mode_can_switch)
                         .service(api::patch_peer_route_status)
+                        .service(api::get_peer_route_status)
                         .service(api::get_exit_node_info)
                         .service(api::post_peer_control)
                         .service(api::patch_peer_lan_access)
                         .service(api::get_peer_lan_access)
                         .service(api::get_auto_failover)
                         .service(api::post_auto_failover)
+                        .service(api::get_local_breakout)
+                        .service(api::post_local_breakout)
+                        .service(api::get_failover_tuning)
+                        .service(api::post_failover_tuning)
+                        .service(api::get_load_balance)
+                        .service(api::post_load_balance)
+                        .service(api::get_client_exits)
+                        .service(api::post_client_exits)
+                        .service(api::get_domain_routes)
+                        .service(api::post_domain_routes)
+                        .service(api::get_gateway_groups)
+                        .service(api::post_gateway_groups)
+                        .service(api::get_drift)
+                        .service(api::get_health_history)
+                        .service(api::get_events)
+                        .service(api::get_stream)
+                        .service(api::get_telemetry)
+                        .service(api::get_usage)
+                        .service(api::post_notifications_test)
+                        .service(api::get_metrics)
                         .service(api::get_system_logs)
                 } else {
                     app
@@ -233,14 +259,36 @@ async fn run_web_server_with_config(config: &Config, init_mode: bool) -> std::io
                             .service(api::post_wireguard_status)
                             .service(api::get_mode)
                             .service(api::patch_mode_toggle)
+                            .service(api::post_mode_confirm)
                             .service(api::get_mode_can_switch)
                             .service(api::patch_peer_route_status)
+                            .service(api::get_peer_route_status)
                             .service(api::get_exit_node_info)
                             .service(api::post_peer_control)
                             .service(api::patch_peer_lan_access)
                             .service(api::get_peer_lan_access)
                             .service(api::get_auto_failover)
                             .service(api::post_auto_failover)
+                            .service(api::get_local_breakout)
+                            .service(api::post_local_breakout)
+                            .service(api::get_failover_tuning)
+                            .service(api::post_failover_tuning)
+                            .service(api::get_load_balance)
+                            .service(api::post_load_balance)
+                            .service(api::get_client_exits)
+                            .service(api::post_client_exits)
+                            .service(api::get_domain_routes)
+                            .service(api::post_domain_routes)
+                            .service(api::get_gateway_groups)
+                            .service(api::post_gateway_groups)
+                            .service(api::get_drift)
+                            .service(api::get_health_history)
+                            .service(api::get_events)
+                            .service(api::get_stream)
+                            .service(api::get_telemetry)
+                            .service(api::get_usage)
+                            .service(api::post_notifications_test)
+                            .service(api::get_metrics)
                             .service(api::get_system_logs)
                     } else {
                         app
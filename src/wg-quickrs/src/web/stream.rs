@@ -0,0 +1,145 @@
+// Live updates for dashboards: GET /api/stream (server-sent events)
+//
+// Instead of polling /api/network/summary and /api/mode/exit-node, a client keeps one response open
+// and receives each update once, as `event: <type>` with a JSON `data:` line:
+// - status: the WireGuard tunnel went up or down
+// - digest: the network configuration changed (fetch /api/network/summary again)
+// - telemetry: new transfer/handshake samples (the latest samples first, then each new one)
+// - health: an exit node's health monitor result (same fields as in /api/mode/exit-node)
+// - event: a journal event (failover, fail-back, manual switch, ...; see /api/events)
+//
+// Telemetry is sampled while anyone is subscribed (or always, see wireguard/telemetry.rs).
+// A client that falls too far behind skips the missed updates and gets a `resync` event.
+
+use crate::mode::events::Event;
+use actix_web::web::Bytes;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use wg_quickrs_lib::types::api::{Telemetry, TelemetryData};
+use wg_quickrs_lib::types::misc::WireGuardStatus;
+
+const CHANNEL_CAPACITY: usize = 256;
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamMessage {
+    Status { status: WireGuardStatus },
+    Digest { digest: String },
+    Telemetry { max_len: u8, data: Vec<TelemetryData> },
+    Health { health: serde_json::Value },
+    Event { event: Event },
+    Resync { missed: u64 },
+}
+
+impl StreamMessage {
+    fn name(&self) -> &'static str {
+        match self {
+            StreamMessage::Status { .. } => "status",
+            StreamMessage::Digest { .. } => "digest",
+            StreamMessage::Telemetry { .. } => "telemetry",
+            StreamMessage::Health { .. } => "health",
+            StreamMessage::Event { .. } => "event",
+            StreamMessage::Resync { .. } => "resync",
+        }
+    }
+
+    // One server-sent event
+    pub fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.name(), serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+impl From<Telemetry> for StreamMessage {
+    fn from(telemetry: Telemetry) -> Self {
+        StreamMessage::Telemetry { max_len: telemetry.max_len, data: telemetry.data }
+    }
+}
+
+static CHANNEL: Lazy<broadcast::Sender<StreamMessage>> = Lazy::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+// Whether a dashboard is listening (publishers skip work nobody would see)
+pub fn has_subscribers() -> bool {
+    CHANNEL.receiver_count() > 0
+}
+
+pub fn publish(message: StreamMessage) {
+    // Fails only when nobody is subscribed
+    let _ = CHANNEL.send(message);
+}
+
+// What a new subscriber starts from
+pub fn current_state() -> Vec<StreamMessage> {
+    let mut state = Vec::new();
+    if let Ok(status) = crate::wireguard::cmd::status_tunnel() {
+        state.push(StreamMessage::Status { status });
+    }
+    if let Ok(digest) = crate::conf::util::get_network_digest() {
+        state.push(StreamMessage::Digest { digest });
+    }
+    state.push(crate::wireguard::telemetry::recent().into());
+    if let Ok(config) = crate::conf::util::get_config() {
+        for health in crate::mode::routing_pbr::get_exit_node_health(&config.network, &config.network.name) {
+            state.push(StreamMessage::Health { health: health.to_json() });
+        }
+    }
+    state
+}
+
+// Server-sent events for one client: `initial` (the current state), then every published update
+pub fn subscribe(initial: Vec<StreamMessage>) -> impl futures_util::Stream<Item = Result<Bytes, actix_web::Error>> {
+    let receiver = CHANNEL.subscribe();
+    let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+    keep_alive.reset();
+    let initial: VecDeque<StreamMessage> = initial.into();
+    futures_util::stream::unfold((receiver, initial, keep_alive), |(mut receiver, mut initial, mut keep_alive)| async move {
+        if let Some(message) = initial.pop_front() {
+            return Some((Ok(Bytes::from(message.to_sse())), (receiver, initial, keep_alive)));
+        }
+        let chunk = tokio::select! {
+            received = receiver.recv() => match received {
+                Ok(message) => message.to_sse(),
+                Err(RecvError::Lagged(missed)) => StreamMessage::Resync { missed }.to_sse(),
+                Err(RecvError::Closed) => return None,
+            },
+            // A comment line keeps proxies from closing an idle stream
+            _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+        };
+        keep_alive.reset();
+        Some((Ok(Bytes::from(chunk)), (receiver, initial, keep_alive)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    #[tokio::test]
+    async fn test_stream_delivers_initial_state_then_updates() {
+        let stream = subscribe(vec![StreamMessage::Digest { digest: "abc".to_string() }]);
+        let mut stream = Box::pin(stream);
+        assert!(has_subscribers());
+
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first, Bytes::from("event: digest\ndata: {\"type\":\"digest\",\"digest\":\"abc\"}\n\n"));
+
+        // (other tests may publish events concurrently)
+        publish(StreamMessage::Status { status: WireGuardStatus::UP });
+        let mut second = stream.next().await.unwrap().unwrap();
+        while !second.starts_with(b"event: status\n") {
+            second = stream.next().await.unwrap().unwrap();
+        }
+        assert_eq!(second, Bytes::from("event: status\ndata: {\"type\":\"status\",\"status\":\"up\"}\n\n"));
+
+        // A client that cannot keep up is told to fetch the full state again
+        for _ in 0..CHANNEL_CAPACITY + 10 {
+            publish(StreamMessage::Digest { digest: "def".to_string() });
+        }
+        let lagged = stream.next().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&lagged).starts_with("event: resync\n"));
+    }
+}
@@ -2,47 +2,19 @@ use crate::{conf, mode};
 use once_cell::sync::Lazy;
 use wg_quickrs_lib::helpers::get_peer_wg_config;
 use wg_quickrs_lib::types::config::{Config};
-use wg_quickrs_lib::types::api::{Telemetry, TelemetryData, TelemetryDatum};
+use wg_quickrs_lib::types::api::TelemetryDatum;
 use wg_quickrs_lib::types::misc::{WireGuardStatus};
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-use std::time::{SystemTime, UNIX_EPOCH};
-use chrono::Utc;
+use std::sync::RwLock;
 use tempfile::NamedTempFile;
 use thiserror::Error;
 use tokio::signal::unix::{signal, SignalKind};
 use wg_quickrs_lib::types::network::ConnectionId;
 use crate::helpers::{shell_cmd, ShellError};
-use crate::wireguard::wg_quick;
-
-const TELEMETRY_CAPACITY: usize = 21;
-const TELEMETRY_INTERVAL: u64 = 1000;
-type TelemetryType = Lazy<Arc<RwLock<VecDeque<TelemetryData>>>>;
-static TELEMETRY: TelemetryType =
-    Lazy::new(|| Arc::new(RwLock::new(VecDeque::with_capacity(TELEMETRY_CAPACITY))));
-
-static LAST_TELEMETRY_QUERY_TS: Lazy<Arc<RwLock<u64>>> = Lazy::new(|| Arc::new(RwLock::new(0)));
-
-fn update_timestamp(ts: &Arc<RwLock<u64>>) {
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let mut ts = ts.write().unwrap();
-    *ts = now;
-}
-
-fn get_since_timestamp(ts: &Arc<RwLock<u64>>) -> u64 {
-    let start = *ts.read().unwrap();
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    now.saturating_sub(start) * 1000
-}
+use crate::wireguard::{telemetry, usage, wg_quick};
+use crate::web::stream::{self, StreamMessage};
 
 #[derive(Error, Debug)]
 pub enum WireGuardCommandError {
@@ -71,13 +43,12 @@ pub(crate) async fn run_vpn_server(
         // Set status to DOWN when VPN is disabled (not UNKNOWN)
         *WG_STATUS
             .write()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to set status: {}", e)))? =
+            .map_err(|e| std::io::Error::other(format!("Failed to set status: {}", e)))? =
             WireGuardStatus::DOWN;
         return Ok(());
     }
-    let mut tunnel_manager = WG_TUNNEL_MANAGER.write().unwrap();
-    tunnel_manager.config = Some(config.clone());
-    drop(tunnel_manager);
+    WG_TUNNEL_MANAGER.write().unwrap().config = Some(config.clone());
+    telemetry::configure(&config.agent.telemetry);
 
     Box::pin(async move {
         let _ = disable_tunnel();
@@ -89,7 +60,7 @@ pub(crate) async fn run_vpn_server(
 
         let mut signal_terminate = signal(SignalKind::terminate()).unwrap();
         let mut signal_interrupt = signal(SignalKind::interrupt()).unwrap();
-        let mut ticker = tokio::time::interval(Duration::from_millis(TELEMETRY_INTERVAL));
+        let mut ticker = tokio::time::interval(telemetry::interval());
 
         tokio::select! {
             _ = async {
@@ -102,69 +73,43 @@ pub(crate) async fn run_vpn_server(
             _ = signal_interrupt.recv() => log::info!("Received SIGINT"),
         }
 
+        mode::runtime::flush();
         let _ = disable_tunnel();
         Ok(())
     })
         .await
 }
 
+// Last tunnel status sent to live dashboards
+static LAST_STREAMED_STATUS: RwLock<Option<WireGuardStatus>> = RwLock::new(None);
+
 fn run_loop() {
-    match WG_STATUS.read() {
-        Ok(status) => {
-            if status.clone() != WireGuardStatus::UP {
-                return;
-            }
-        }
+    let status = match WG_STATUS.read() {
+        Ok(status) => status.clone(),
         Err(e) => {
             log::error!("{}", WireGuardCommandError::MutexLockFailed(e.to_string()));
             return;
         }
-    }
-
-    if get_since_timestamp(&LAST_TELEMETRY_QUERY_TS)
-        > TELEMETRY_INTERVAL * TELEMETRY_CAPACITY as u64
-    {
-        return;
-    }
-
-    let config = match conf::util::get_config() {
-        Ok(config) => config,
-        Err(e) => {
-            log::error!("{e}");
-            return;
-        }
     };
-
-    match show_dump(&config) {
-        Ok(telemetry) => {
-            let mut buf = TELEMETRY.write().unwrap();
-            if buf.len() == TELEMETRY_CAPACITY {
-                buf.pop_front();
-            }
-            buf.push_back(TelemetryData {
-                datum: telemetry,
-                timestamp: Utc::now().naive_utc(),
-            });
+    if stream::has_subscribers() {
+        let mut last_streamed = LAST_STREAMED_STATUS.write().unwrap();
+        if last_streamed.as_ref() != Some(&status) {
+            *last_streamed = Some(status.clone());
+            stream::publish(StreamMessage::Status { status: status.clone() });
         }
-        Err(e) => log::error!("Failed to get telemetry data => {}", e),
     }
-}
-
-pub(crate) fn get_telemetry() -> Result<Option<Telemetry>, WireGuardCommandError> {
-    if get_since_timestamp(&LAST_TELEMETRY_QUERY_TS)
-        > TELEMETRY_INTERVAL * TELEMETRY_CAPACITY as u64
-    {
-        *TELEMETRY.write().unwrap() = VecDeque::with_capacity(TELEMETRY_CAPACITY);
-    }
-    update_timestamp(&LAST_TELEMETRY_QUERY_TS);
-
-    match TELEMETRY.read() {
-        Ok(buf) => Ok(Some(Telemetry {
-            max_len: TELEMETRY_CAPACITY as u8,
-            data: buf.iter().cloned().collect(),
-        })),
-        Err(e) => Err(WireGuardCommandError::MutexLockFailed(e.to_string())),
+    if status != WireGuardStatus::UP {
+        return;
     }
+
+    telemetry::tick(|| {
+        let config = conf::util::get_config().map_err(|e| e.to_string())?;
+        show_dump(&config).map_err(|e| e.to_string())
+    });
+    usage::tick(|config| match telemetry::latest(telemetry::interval() * 2) {
+        Some(sample) => Ok(sample.datum),
+        None => show_dump(config),
+    });
 }
 
 pub(crate) fn status_tunnel() -> Result<WireGuardStatus, WireGuardCommandError> {
@@ -174,55 +119,82 @@ pub(crate) fn status_tunnel() -> Result<WireGuardStatus, WireGuardCommandError>
     Ok(wg_status.clone())
 }
 
-fn show_dump(config: &Config) -> Result<BTreeMap<ConnectionId, TelemetryDatum>, WireGuardCommandError> {
+pub(crate) fn show_dump(config: &Config) -> Result<BTreeMap<ConnectionId, TelemetryDatum>, WireGuardCommandError> {
     let tunnel_manager = WG_TUNNEL_MANAGER
         .read()
         .map_err(|e| WireGuardCommandError::MutexLockFailed(e.to_string()))?;
 
     let real_interface = tunnel_manager.real_interface.as_ref().ok_or(WireGuardCommandError::InterfaceMissing)?;
 
-    let output = shell_cmd(&["wg", "show", real_interface, "dump"])?;
-    let mut telemetry = BTreeMap::<ConnectionId, TelemetryDatum>::new();
-
-    let dump = String::from_utf8_lossy(&output.stdout);
-    for line in dump.trim().lines().skip(1) {
-        let parts: Vec<&str> = line.split('\t').collect();
-        if parts.len() < 8 {
-            continue;
+    // Pinned exit nodes run on interfaces of their own
+    let mut interfaces = vec![real_interface.clone()];
+    if let Ok(Some(state)) = mode::persist::load_mode_state()
+        && state.last_mode == mode::mode::SystemMode::Router {
+            interfaces.extend(mode::routing_pbr::pinned_exit_interfaces(&state).into_values());
         }
-        let public_key = parts[0];
 
-        for (peer_id, peer_details) in config.network.peers.clone() {
-            if wg_quickrs_lib::helpers::wg_public_key_from_private_key(&peer_details.private_key).to_base64() != public_key
-            {
+    let mut telemetry = BTreeMap::<ConnectionId, TelemetryDatum>::new();
+    for interface in &interfaces {
+        let output = if interface == real_interface {
+            shell_cmd(&["wg", "show", interface, "dump"])?
+        } else {
+            match shell_cmd(&["wg", "show", interface, "dump"]) {
+                Ok(output) => output,
+                Err(_) => continue,
+            }
+        };
+
+        let dump = String::from_utf8_lossy(&output.stdout);
+        for line in dump.trim().lines().skip(1) {
+            let parts: Vec<&str> = line.split('\t').collect();
+            if parts.len() < 8 {
                 continue;
             }
+            let public_key = parts[0];
 
-            let transfer_rx = parts[5].parse::<u64>().unwrap_or(0);
-            let transfer_tx = parts[6].parse::<u64>().unwrap_or(0);
-            let connection_id =
-                wg_quickrs_lib::helpers::get_connection_id(config.network.this_peer, peer_id);
+            for (peer_id, peer_details) in config.network.peers.clone() {
+                if wg_quickrs_lib::helpers::wg_public_key_from_private_key(&peer_details.private_key).to_base64() != public_key
+                {
+                    continue;
+                }
 
-            let (transfer_a_to_b, transfer_b_to_a) = if connection_id.a == config.network.this_peer {
-                (transfer_tx, transfer_rx)
-            } else {
-                (transfer_rx, transfer_tx)
-            };
-
-            telemetry.insert(
-                connection_id.clone(),
-                TelemetryDatum {
-                    latest_handshake_at: parts[4].parse::<u64>().unwrap_or(0),
-                    transfer_a_to_b,
-                    transfer_b_to_a,
-                },
-            );
-            break;
+                let transfer_rx = parts[5].parse::<u64>().unwrap_or(0);
+                let transfer_tx = parts[6].parse::<u64>().unwrap_or(0);
+                let connection_id =
+                    wg_quickrs_lib::helpers::get_connection_id(config.network.this_peer, peer_id);
+
+                let (transfer_a_to_b, transfer_b_to_a) = if connection_id.a == config.network.this_peer {
+                    (transfer_tx, transfer_rx)
+                } else {
+                    (transfer_rx, transfer_tx)
+                };
+
+                telemetry.insert(
+                    connection_id.clone(),
+                    TelemetryDatum {
+                        latest_handshake_at: parts[4].parse::<u64>().unwrap_or(0),
+                        transfer_a_to_b,
+                        transfer_b_to_a,
+                    },
+                );
+                break;
+            }
         }
     }
     Ok(telemetry)
 }
 
+// Take a peer off the interface (it comes back with the next sync_conf)
+pub(crate) fn remove_peer(public_key: &str) -> Result<(), WireGuardCommandError> {
+    let tunnel_manager = WG_TUNNEL_MANAGER
+        .read()
+        .map_err(|e| WireGuardCommandError::MutexLockFailed(e.to_string()))?;
+
+    let real_interface = tunnel_manager.real_interface.as_ref().ok_or(WireGuardCommandError::InterfaceMissing)?;
+    shell_cmd(&["wg", "set", real_interface, "peer", public_key, "remove"])?;
+    Ok(())
+}
+
 pub(crate) fn sync_conf(config: &Config) -> Result<(), WireGuardCommandError> {
     let mut tunnel_manager = WG_TUNNEL_MANAGER
         .write()
@@ -230,7 +202,7 @@ pub(crate) fn sync_conf(config: &Config) -> Result<(), WireGuardCommandError> {
 
     tunnel_manager.config = Some(config.clone());
 
-    let wg_conf_stripped = get_peer_wg_config(&config.network, &config.network.this_peer, true)
+    let wg_conf_stripped = get_peer_wg_config(&mode::routing_pbr::main_interface_network(&config.network), &config.network.this_peer, true)
         .map_err(|e| WireGuardCommandError::MutexLockFailed(e.to_string()))?;
 
     // Debug: Log the generated config to help diagnose sync issues
@@ -256,8 +228,10 @@ pub(crate) fn sync_conf(config: &Config) -> Result<(), WireGuardCommandError> {
             log::info!("Successfully synced WireGuard configuration for interface: {}", interface_name);
             
             // Restore exit node's 0.0.0.0/0 after sync (since sync_conf filters it out)
-            if let Ok(Some(exit_node_id)) = mode::routing_pbr::get_exit_node() {
-                if let Some(exit_peer) = config.network.peers.get(&exit_node_id) {
+            // (a pinned exit node holds it on its own interface)
+            if let Ok(Some(exit_node_id)) = mode::routing_pbr::get_exit_node()
+                && mode::routing_pbr::peer_interface(&config.network, &exit_node_id) == config.network.name
+                && let Some(exit_peer) = config.network.peers.get(&exit_node_id) {
                     let public_key = wg_quickrs_lib::helpers::wg_public_key_from_private_key(&exit_peer.private_key);
                     let public_key_b64 = public_key.to_base64();
                     
@@ -299,6 +273,15 @@ pub(crate) fn sync_conf(config: &Config) -> Result<(), WireGuardCommandError> {
                         log::info!("Successfully restored 0.0.0.0/0 to exit node {} after sync", exit_node_id);
                     }
                 }
+            
+            // Pinned exit nodes follow the config on their own interfaces
+            if let Err(e) = mode::routing_pbr::sync_exit_interfaces(&config.network) {
+                log::warn!("Failed to sync exit node interfaces: {}", e);
+            }
+            
+            // syncconf also dropped the addresses added for domain routes
+            if let Err(e) = mode::routing_pbr::apply_domain_routes(&config.network) {
+                log::warn!("Failed to restore domain routes after sync: {}", e);
             }
             
             Ok(())
@@ -326,7 +309,7 @@ pub(crate) fn disable_tunnel() -> Result<(), WireGuardCommandError> {
             .map_err(|e| WireGuardCommandError::MutexLockFailed(e.to_string()))? =
             WireGuardStatus::DOWN;
 
-        *TELEMETRY.write().unwrap() = VecDeque::with_capacity(TELEMETRY_CAPACITY);
+        telemetry::clear();
 
         Ok(())
 }
@@ -360,11 +343,10 @@ pub(crate) fn enable_tunnel() -> Result<(), WireGuardCommandError> {
     } else if interface_exists {
         // Interface exists even though start failed - might be from previous run
         // Ensure tunnel_manager has the interface name set (interface_exists() should have set it, but ensure it's there)
-        if tunnel_manager.real_interface.is_none() {
-            if let Some(ref cfg) = config {
+        if tunnel_manager.real_interface.is_none()
+            && let Some(ref cfg) = config {
                 tunnel_manager.real_interface = Some(cfg.network.name.clone());
             }
-        }
         
         // Sync the configuration to ensure it matches the current config
         // This is critical to prevent stale configuration from persisting
@@ -387,7 +369,7 @@ pub(crate) fn enable_tunnel() -> Result<(), WireGuardCommandError> {
         log::warn!("Tunnel start reported error, but interface exists. Continuing...");
     } else {
         // Start failed and interface doesn't exist - return the error
-        return start_result.map_err(|e| WireGuardCommandError::TunnelError(e));
+        return start_result.map_err(WireGuardCommandError::TunnelError);
     }
     
     // After the interface is up (or exists), restore peer routes if we're in Router Mode
@@ -406,8 +388,9 @@ pub(crate) fn enable_tunnel() -> Result<(), WireGuardCommandError> {
             // Restore exit node's 0.0.0.0/0 if exit node exists
             if let Some(ref cfg) = config {
                 let interface_name = &cfg.network.name;
-                if let Ok(Some(exit_node_id)) = mode::routing_pbr::get_exit_node() {
-                    if let Some(exit_peer) = cfg.network.peers.get(&exit_node_id) {
+                if let Ok(Some(exit_node_id)) = mode::routing_pbr::get_exit_node()
+                    && mode::routing_pbr::peer_interface(&cfg.network, &exit_node_id) == *interface_name
+                    && let Some(exit_peer) = cfg.network.peers.get(&exit_node_id) {
                         let public_key = wg_quickrs_lib::helpers::wg_public_key_from_private_key(&exit_peer.private_key);
                         let public_key_b64 = public_key.to_base64();
                         
@@ -449,7 +432,6 @@ pub(crate) fn enable_tunnel() -> Result<(), WireGuardCommandError> {
                             log::info!("Successfully restored 0.0.0.0/0 to exit node {} on startup", exit_node_id);
                         }
                     }
-                }
             }
         } else {
             log::debug!("Not in Router Mode. Skipping peer route restoration.");
@@ -462,6 +444,6 @@ pub(crate) fn enable_tunnel() -> Result<(), WireGuardCommandError> {
     if interface_exists {
     Ok(())
     } else {
-        start_result.map_err(|e| WireGuardCommandError::TunnelError(e))
+        start_result.map_err(WireGuardCommandError::TunnelError)
     }
 }
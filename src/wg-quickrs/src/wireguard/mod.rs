@@ -1,5 +1,7 @@
 pub(crate) mod cmd;
 pub(crate) mod respond;
+pub(crate) mod telemetry;
+pub(crate) mod usage;
 pub(crate) mod wg_quick;
 mod wg_quick_darwin;
 mod wg_quick_linux;
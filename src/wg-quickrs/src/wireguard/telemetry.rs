@@ -0,0 +1,212 @@
+// Telemetry sampler: WireGuard transfer and handshake samples shared by every consumer
+//
+// The network summary (web UI graphs), GET /api/telemetry, the live stream and /metrics all read one
+// window of samples, and reading never changes it. Each sample has an increasing sequence number, so a
+// consumer keeps its own cursor ("samples after 1234") or asks for the samples since a time.
+// - agent.telemetry.always_on (default): a sample every interval_ms while the tunnel is up
+// - otherwise samples are only taken while the web UI polled within the last UI_WINDOW intervals or a
+//   live dashboard is subscribed; when sampling resumes the old samples are dropped, so no rate is
+//   computed across the gap
+
+use crate::web::stream::{self, StreamMessage};
+use chrono::{NaiveDateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use wg_quickrs_lib::types::api::{Telemetry, TelemetryData, TelemetryDatum};
+use wg_quickrs_lib::types::config::AgentTelemetry;
+use wg_quickrs_lib::types::network::ConnectionId;
+
+// Samples in the network summary and at the start of the live stream (the dashboard graphs)
+pub const UI_WINDOW: usize = 21;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub data: TelemetryData,
+}
+
+// The newest `capacity` samples
+pub struct TelemetryStore {
+    samples: VecDeque<Sample>,
+    next_seq: u64,
+    capacity: usize,
+    paused: bool, // nobody wanted samples at the last tick (on-demand sampling)
+}
+
+impl TelemetryStore {
+    pub fn new(capacity: usize) -> Self {
+        TelemetryStore { samples: VecDeque::with_capacity(capacity), next_seq: 1, capacity, paused: false }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.samples.len() > capacity {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, data: TelemetryData) -> Sample {
+        let sample = Sample { seq: self.next_seq, data };
+        self.next_seq += 1;
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample.clone());
+        sample
+    }
+
+    // Sequence numbers keep counting, so cursors stay valid
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn recent(&self, count: usize) -> Vec<TelemetryData> {
+        let skip = self.samples.len().saturating_sub(count);
+        self.samples.iter().skip(skip).map(|sample| sample.data.clone()).collect()
+    }
+
+    // Samples after the `after` cursor and taken at or after `since`, oldest first, at most `limit`
+    pub fn query(&self, after: Option<u64>, since: Option<NaiveDateTime>, limit: usize) -> Vec<Sample> {
+        let matching: Vec<&Sample> = self.samples.iter()
+            .filter(|sample| after.is_none_or(|after| sample.seq > after))
+            .filter(|sample| since.is_none_or(|since| sample.data.timestamp >= since))
+            .collect();
+        let skip = matching.len().saturating_sub(limit);
+        matching.into_iter().skip(skip).cloned().collect()
+    }
+
+    pub fn last(&self) -> Option<&Sample> {
+        self.samples.back()
+    }
+}
+
+static SETTINGS: Lazy<RwLock<AgentTelemetry>> = Lazy::new(|| RwLock::new(AgentTelemetry::default()));
+static STORE: Lazy<RwLock<TelemetryStore>> = Lazy::new(|| RwLock::new(TelemetryStore::new(AgentTelemetry::default().window)));
+// When the web UI last fetched the network summary (keeps on-demand sampling going)
+static LAST_DEMAND: Mutex<Option<Instant>> = Mutex::new(None);
+
+// Apply agent.telemetry (at startup)
+pub fn configure(settings: &AgentTelemetry) {
+    STORE.write().unwrap().set_capacity(settings.window);
+    *SETTINGS.write().unwrap() = settings.clone();
+}
+
+pub fn interval() -> Duration {
+    Duration::from_millis(SETTINGS.read().unwrap().interval_ms)
+}
+
+pub fn note_demand() {
+    *LAST_DEMAND.lock().unwrap() = Some(Instant::now());
+}
+
+fn wanted() -> bool {
+    let settings = SETTINGS.read().unwrap();
+    let demand_window = Duration::from_millis(settings.interval_ms) * UI_WINDOW as u32;
+    settings.always_on
+        || stream::has_subscribers()
+        || LAST_DEMAND.lock().unwrap().is_some_and(|last| last.elapsed() <= demand_window)
+}
+
+// Take a sample if anyone wants one (each tick while the tunnel is up); `dump` reads the tunnel's peers
+pub fn tick<E: std::fmt::Display>(dump: impl FnOnce() -> Result<BTreeMap<ConnectionId, TelemetryDatum>, E>) {
+    if !wanted() {
+        STORE.write().unwrap().paused = true;
+        return;
+    }
+    match dump() {
+        Ok(datum) => {
+            let data = TelemetryData { datum, timestamp: Utc::now().naive_utc() };
+            let sample = {
+                let mut store = STORE.write().unwrap();
+                if store.paused {
+                    store.paused = false;
+                    store.clear();
+                }
+                store.push(data)
+            };
+            stream::publish(StreamMessage::Telemetry { max_len: UI_WINDOW as u8, data: vec![sample.data] });
+        }
+        Err(e) => log::error!("Failed to get telemetry data => {}", e),
+    }
+}
+
+// The tunnel went down
+pub fn clear() {
+    STORE.write().unwrap().clear();
+}
+
+// The newest UI_WINDOW samples
+pub fn recent() -> Telemetry {
+    Telemetry {
+        max_len: UI_WINDOW as u8,
+        data: STORE.read().unwrap().recent(UI_WINDOW),
+    }
+}
+
+// The newest sample if it was taken within `max_age`
+pub fn latest(max_age: Duration) -> Option<TelemetryData> {
+    let store = STORE.read().unwrap();
+    let sample = store.last()?;
+    let age = Utc::now().naive_utc() - sample.data.timestamp;
+    (age.to_std().unwrap_or_default() <= max_age).then(|| sample.data.clone())
+}
+
+#[derive(Serialize, Debug)]
+pub struct TelemetryPage {
+    pub interval_ms: u64,
+    pub window: usize,
+    pub cursor: Option<u64>, // pass as `after` to get only newer samples
+    pub data: Vec<Sample>,
+}
+
+pub fn query(after: Option<u64>, since: Option<NaiveDateTime>, limit: Option<usize>) -> TelemetryPage {
+    let settings = SETTINGS.read().unwrap().clone();
+    let store = STORE.read().unwrap();
+    let data = store.query(after, since, limit.unwrap_or(settings.window));
+    TelemetryPage {
+        interval_ms: settings.interval_ms,
+        window: settings.window,
+        cursor: data.last().map(|sample| sample.seq).or(after).or(store.last().map(|sample| sample.seq)),
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(ts: i64) -> TelemetryData {
+        TelemetryData { datum: BTreeMap::new(), timestamp: chrono::DateTime::from_timestamp(ts, 0).unwrap().naive_utc() }
+    }
+
+    #[test]
+    fn test_store_cursors_and_window() {
+        let mut store = TelemetryStore::new(5);
+        for ts in 0..8 {
+            store.push(data(ts));
+        }
+        // Only the newest 5 are kept; sequence numbers keep counting
+        let all = store.query(None, None, 100);
+        assert_eq!(all.iter().map(|s| s.seq).collect::<Vec<_>>(), vec![4, 5, 6, 7, 8]);
+        assert_eq!(store.recent(2), vec![data(6), data(7)]);
+
+        // Two consumers with their own cursors do not affect each other or the window
+        let first = store.query(Some(6), None, 100);
+        assert_eq!(first.iter().map(|s| s.seq).collect::<Vec<_>>(), vec![7, 8]);
+        store.push(data(8));
+        let second = store.query(Some(4), None, 2);
+        assert_eq!(second.iter().map(|s| s.seq).collect::<Vec<_>>(), vec![8, 9]);
+        assert_eq!(store.query(Some(8), None, 100).len(), 1);
+        assert_eq!(store.query(None, Some(data(7).timestamp), 100).len(), 2);
+
+        // Shrinking the window drops the oldest; clearing keeps the cursor position
+        store.set_capacity(3);
+        assert_eq!(store.query(None, None, 100).first().unwrap().seq, 7);
+        store.clear();
+        assert_eq!(store.push(data(9)).seq, 10);
+    }
+}
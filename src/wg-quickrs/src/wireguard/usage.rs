@@ -0,0 +1,592 @@
+// Usage accounting: traffic per peer and hour, day and month (UTC) that survives restarts
+//
+// WireGuard's transfer counters start over whenever the interface (or a peer on it) is recreated.
+// Every ACCOUNTING_INTERVAL the counters of each peer are compared with the previous reading and the
+// difference is added to the current hour, day and month; a counter that went down has started over,
+// so all of it is new traffic. Readings and totals are kept in usage.json in the config folder, written
+// every SAVE_INTERVAL and on quota changes, so restarting the agent loses nothing as long as the
+// interface is not recreated in between.
+//
+// Quotas (agent.usage.quotas) are checked after every round:
+// - going over one journals a quota_exceeded event; block_exit also makes the peer count as degraded
+//   for failover, disconnect removes it from the interface (again after any sync_conf)
+// - when the day or month is over, or the quota was raised, a quota_reset event follows and a
+//   disconnected peer is added back
+// Exit nodes are peers, so their totals are the traffic through them (the local breakout is not counted).
+
+use crate::WG_QUICKRS_CONFIG_FOLDER;
+use crate::conf;
+use crate::mode::events::{self, EventDetails, EventKind, Initiator};
+use crate::mode::routing_pbr;
+use crate::wireguard::cmd::{self, WireGuardCommandError};
+use chrono::{DateTime, Datelike};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use uuid::Uuid;
+use wg_quickrs_lib::helpers::wg_public_key_from_private_key;
+use wg_quickrs_lib::types::api::TelemetryDatum;
+use wg_quickrs_lib::types::config::{Config, QuotaAction, UsageQuota};
+use wg_quickrs_lib::types::network::{ConnectionId, Network};
+
+const USAGE_FILE: &str = "usage.json";
+const USAGE_TEMP_FILE: &str = "usage.json.tmp";
+const ACCOUNTING_INTERVAL: Duration = Duration::from_secs(10);
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+const HOURS_KEPT: usize = 48;
+const DAYS_KEPT: usize = 62;
+const MONTHS_KEPT: usize = 24;
+
+#[derive(Error, Debug)]
+pub enum UsageError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Config folder not initialized")]
+    NoConfigFolder,
+}
+
+// Traffic with a peer in one hour, day or month
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageBucket {
+    pub start: u64,    // Unix seconds
+    pub rx_bytes: u64, // Received from the peer
+    pub tx_bytes: u64, // Sent to the peer
+}
+
+impl UsageBucket {
+    pub fn total(&self) -> u64 {
+        self.rx_bytes + self.tx_bytes
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PeerUsage {
+    #[serde(default)]
+    pub hourly: Vec<UsageBucket>, // oldest first, like daily and monthly
+    #[serde(default)]
+    pub daily: Vec<UsageBucket>,
+    #[serde(default)]
+    pub monthly: Vec<UsageBucket>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counters: Option<(u64, u64)>, // interface counters (rx, tx) at the last reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub over_quota: Option<String>, // which quota is exceeded, from quota_exceeded until quota_reset
+}
+
+// A quota state change found by UsageLedger::check_quotas
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuotaChange {
+    Exceeded(Uuid, String),
+    Reset(Uuid, String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UsageLedger {
+    #[serde(default)]
+    pub peers: BTreeMap<Uuid, PeerUsage>,
+}
+
+fn hour_start(ts: u64) -> u64 {
+    ts - ts % 3600
+}
+
+fn day_start(ts: u64) -> u64 {
+    ts - ts % 86400
+}
+
+fn month_start(ts: u64) -> u64 {
+    let date = DateTime::from_timestamp(ts as i64, 0).unwrap_or_default().date_naive();
+    let first = date.with_day(1).unwrap_or(date);
+    first.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc().timestamp() as u64
+}
+
+// Traffic since the previous reading; a counter below the previous one started over (new interface or peer)
+fn counter_delta(previous: Option<(u64, u64)>, rx: u64, tx: u64) -> (u64, u64) {
+    match previous {
+        Some((prev_rx, prev_tx)) if rx >= prev_rx && tx >= prev_tx => (rx - prev_rx, tx - prev_tx),
+        _ => (rx, tx),
+    }
+}
+
+fn add_to(buckets: &mut Vec<UsageBucket>, start: u64, rx: u64, tx: u64, keep: usize) {
+    match buckets.last_mut() {
+        Some(last) if last.start == start => {
+            last.rx_bytes += rx;
+            last.tx_bytes += tx;
+        }
+        _ => buckets.push(UsageBucket { start, rx_bytes: rx, tx_bytes: tx }),
+    }
+    if buckets.len() > keep {
+        buckets.drain(..buckets.len() - keep);
+    }
+}
+
+// The bucket starting at `start`, empty if there was no traffic in it yet
+fn current(buckets: &[UsageBucket], start: u64) -> UsageBucket {
+    buckets.iter().rev()
+        .find(|bucket| bucket.start == start)
+        .copied()
+        .unwrap_or(UsageBucket { start, ..Default::default() })
+}
+
+// 1.5 GB, 820 MB, ... (decimal units, like metered plans)
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+impl PeerUsage {
+    pub fn hour(&self, now: u64) -> UsageBucket {
+        current(&self.hourly, hour_start(now))
+    }
+
+    pub fn day(&self, now: u64) -> UsageBucket {
+        current(&self.daily, day_start(now))
+    }
+
+    pub fn month(&self, now: u64) -> UsageBucket {
+        current(&self.monthly, month_start(now))
+    }
+
+    // Which limit of the quota the current day or month is over (None within the quota)
+    pub fn quota_overrun(&self, quota: &UsageQuota, now: u64) -> Option<String> {
+        let (day, month) = (self.day(now).total(), self.month(now).total());
+        if let Some(limit) = quota.daily_bytes.filter(|limit| day >= *limit) {
+            return Some(format!("daily quota exceeded ({} of {})", format_bytes(day), format_bytes(limit)));
+        }
+        if let Some(limit) = quota.monthly_bytes.filter(|limit| month >= *limit) {
+            return Some(format!("monthly quota exceeded ({} of {})", format_bytes(month), format_bytes(limit)));
+        }
+        None
+    }
+}
+
+impl UsageLedger {
+    // Add the traffic since the previous readings (rx, tx per peer) taken at `now`
+    pub fn account(&mut self, readings: &BTreeMap<Uuid, (u64, u64)>, now: u64) {
+        for (peer_id, (rx, tx)) in readings {
+            let usage = self.peers.entry(*peer_id).or_default();
+            let (rx_delta, tx_delta) = counter_delta(usage.counters, *rx, *tx);
+            usage.counters = Some((*rx, *tx));
+            if rx_delta == 0 && tx_delta == 0 {
+                continue;
+            }
+            add_to(&mut usage.hourly, hour_start(now), rx_delta, tx_delta, HOURS_KEPT);
+            add_to(&mut usage.daily, day_start(now), rx_delta, tx_delta, DAYS_KEPT);
+            add_to(&mut usage.monthly, month_start(now), rx_delta, tx_delta, MONTHS_KEPT);
+        }
+    }
+
+    // Compare every peer with its quota; peers without a quota are never over it
+    pub fn check_quotas(&mut self, quotas: &BTreeMap<Uuid, UsageQuota>, now: u64) -> Vec<QuotaChange> {
+        let mut changes = Vec::new();
+        for (peer_id, usage) in self.peers.iter_mut() {
+            let overrun = quotas.get(peer_id).and_then(|quota| usage.quota_overrun(quota, now));
+            match (usage.over_quota.is_some(), overrun) {
+                (false, Some(reason)) => {
+                    changes.push(QuotaChange::Exceeded(*peer_id, reason.clone()));
+                    usage.over_quota = Some(reason);
+                }
+                (true, None) => {
+                    usage.over_quota = None;
+                    let reason = format!("{} today, {} this month",
+                                         format_bytes(usage.day(now).total()), format_bytes(usage.month(now).total()));
+                    changes.push(QuotaChange::Reset(*peer_id, reason));
+                }
+                (true, Some(reason)) => usage.over_quota = Some(reason),
+                (false, None) => {}
+            }
+        }
+        changes
+    }
+}
+
+// Counters (rx, tx) of each peer connected to this one, from the router's side
+fn peer_readings(this_peer: Uuid, dump: &BTreeMap<ConnectionId, TelemetryDatum>) -> BTreeMap<Uuid, (u64, u64)> {
+    dump.iter()
+        .filter_map(|(connection_id, datum)| {
+            if connection_id.a == this_peer {
+                Some((connection_id.b, (datum.transfer_b_to_a, datum.transfer_a_to_b)))
+            } else if connection_id.b == this_peer {
+                Some((connection_id.a, (datum.transfer_a_to_b, datum.transfer_b_to_a)))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn get_usage_file_path() -> Result<PathBuf, UsageError> {
+    let config_folder = WG_QUICKRS_CONFIG_FOLDER.get().ok_or(UsageError::NoConfigFolder)?;
+    Ok(config_folder.join(USAGE_FILE))
+}
+
+// An unreadable file is logged and replaced, so accounting starts over instead of stopping
+fn load_ledger() -> Result<UsageLedger, UsageError> {
+    let file_path = get_usage_file_path()?;
+    let contents = match fs::read_to_string(&file_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(UsageLedger::default()),
+        Err(e) => return Err(e.into()),
+    };
+    match serde_json::from_str(&contents) {
+        Ok(ledger) => Ok(ledger),
+        Err(e) => {
+            log::warn!("Usage file {:?} is corrupted ({}). Starting over.", file_path, e);
+            Ok(UsageLedger::default())
+        }
+    }
+}
+
+// Atomic write (temp file and rename), like router_mode_state.json
+fn save_ledger(ledger: &UsageLedger) -> Result<(), UsageError> {
+    let file_path = get_usage_file_path()?;
+    let temp_path = file_path.with_file_name(USAGE_TEMP_FILE);
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(serde_json::to_string(ledger)?.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, &file_path)?;
+    Ok(())
+}
+
+struct Accounting {
+    ledger: Option<UsageLedger>, // loaded on the first round
+    last_round: Option<Instant>,
+    last_save: Option<Instant>,
+}
+
+static ACCOUNTING: Mutex<Accounting> = Mutex::new(Accounting { ledger: None, last_round: None, last_save: None });
+// agent.usage.quotas from conf.yml (refreshed every round)
+static QUOTAS: Lazy<RwLock<BTreeMap<Uuid, UsageQuota>>> = Lazy::new(|| RwLock::new(BTreeMap::new()));
+// Peers over a quota whose action keeps them from being an exit node, with the reason
+static BLOCKED_EXITS: Lazy<RwLock<HashMap<Uuid, String>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Why a peer may not be used as an exit node right now (None when it may)
+pub fn exit_blocked(peer_id: &Uuid) -> Option<String> {
+    BLOCKED_EXITS.read().unwrap().get(peer_id).cloned()
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// What a round asks of the WireGuard interface, carried out once ACCOUNTING is released
+#[derive(Debug, Default, PartialEq)]
+struct Enforcement {
+    resync: bool,                      // sync_conf, which adds back the peers within their quota again
+    disconnect: Vec<(String, String)>, // Peers to remove for being over a disconnect quota (name, public key)
+}
+
+impl Enforcement {
+    fn apply(&self, config: &Config) {
+        if self.resync && let Err(e) = cmd::sync_conf(config) {
+            log::error!("Failed to reconnect peers within their quota again: {}", e);
+        }
+        for (name, public_key) in &self.disconnect {
+            match cmd::remove_peer(public_key) {
+                Ok(()) => log::info!("Disconnected peer {} until it is within its quota again", name),
+                Err(e) => log::error!("Failed to disconnect peer {} over its quota: {}", name, e),
+            }
+        }
+    }
+}
+
+// Account and enforce quotas every ACCOUNTING_INTERVAL (called each tick while the tunnel is up)
+pub fn tick(dump: impl FnOnce(&Config) -> Result<BTreeMap<ConnectionId, TelemetryDatum>, WireGuardCommandError>) {
+    {
+        let mut accounting = ACCOUNTING.lock().unwrap();
+        if accounting.last_round.is_some_and(|last| last.elapsed() < ACCOUNTING_INTERVAL) {
+            return;
+        }
+        accounting.last_round = Some(Instant::now());
+    }
+
+    match conf::util::reload_usage() {
+        Ok(Some(usage)) => *QUOTAS.write().unwrap() = usage.quotas,
+        Ok(None) => {}
+        Err(e) => log::warn!("Ignoring usage quotas in conf.yml: {}", e),
+    }
+    let config = match conf::util::get_config() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{e}");
+            return;
+        }
+    };
+    let quotas = QUOTAS.read().unwrap().clone();
+    if let Some(enforcement) = round(&config.network, &quotas, || dump(&config), now_secs()) {
+        enforcement.apply(&config);
+    }
+}
+
+// One round: read the counters, add them up and check the quotas
+// ACCOUNTING is only held while the ledger changes, never across a wg command (report() waits on it)
+fn round(
+    network: &Network,
+    quotas: &BTreeMap<Uuid, UsageQuota>,
+    dump: impl FnOnce() -> Result<BTreeMap<ConnectionId, TelemetryDatum>, WireGuardCommandError>,
+    now: u64,
+) -> Option<Enforcement> {
+    let readings = match dump() {
+        Ok(dump) => peer_readings(network.this_peer, &dump),
+        Err(e) => {
+            log::error!("Failed to read transfer counters for usage accounting => {}", e);
+            return None;
+        }
+    };
+
+    let (changes, over_quota) = {
+        let mut accounting = ACCOUNTING.lock().unwrap();
+        if accounting.ledger.is_none() {
+            accounting.ledger = Some(load_ledger().unwrap_or_else(|e| {
+                log::warn!("Failed to load usage totals: {}", e);
+                UsageLedger::default()
+            }));
+        }
+        let ledger = accounting.ledger.as_mut().unwrap();
+        ledger.account(&readings, now);
+        ledger.peers.retain(|peer_id, _| network.peers.contains_key(peer_id));
+        let changes = ledger.check_quotas(quotas, now);
+
+        let over_quota: Vec<(Uuid, QuotaAction, String)> = ledger.peers.iter()
+            .filter_map(|(peer_id, usage)| {
+                let action = quotas.get(peer_id).map_or(QuotaAction::Warn, |quota| quota.action);
+                usage.over_quota.clone().map(|reason| (*peer_id, action, reason))
+            })
+            .collect();
+
+        if !changes.is_empty() || accounting.last_save.is_none_or(|last| last.elapsed() >= SAVE_INTERVAL) {
+            accounting.last_save = Some(Instant::now());
+            if let Err(e) = save_ledger(accounting.ledger.as_ref().unwrap()) {
+                log::warn!("Failed to save usage totals: {}", e);
+            }
+        }
+        (changes, over_quota)
+    };
+
+    *BLOCKED_EXITS.write().unwrap() = over_quota.iter()
+        .filter(|(_, action, _)| *action != QuotaAction::Warn)
+        .map(|(peer_id, _, reason)| (*peer_id, reason.clone()))
+        .collect();
+
+    for change in &changes {
+        let (kind, peer_id, reason) = match change {
+            QuotaChange::Exceeded(peer_id, reason) => (EventKind::QuotaExceeded, peer_id, reason),
+            QuotaChange::Reset(peer_id, reason) => (EventKind::QuotaReset, peer_id, reason),
+        };
+        let name = network.peers.get(peer_id).map_or_else(|| peer_id.to_string(), |peer| peer.name.clone());
+        match kind {
+            EventKind::QuotaExceeded => log::warn!("Peer {} is over its quota: {}", name, reason),
+            _ => log::info!("Peer {} is within its quota again ({})", name, reason),
+        }
+        events::record(kind, Initiator::Monitor, EventDetails {
+            peer_id: Some(*peer_id),
+            reason: Some(reason.clone()),
+            ..Default::default()
+        });
+    }
+
+    // A disconnected peer that is within its quota again comes back with the configuration,
+    // which also brings back the peers still over theirs: those are removed again
+    let resync = changes.iter().any(|change| matches!(change, QuotaChange::Reset(peer_id, _) if !readings.contains_key(peer_id)));
+    let disconnect = over_quota.iter()
+        .filter(|(peer_id, action, _)| *action == QuotaAction::Disconnect && (resync || readings.contains_key(peer_id)))
+        .filter_map(|(peer_id, _, _)| network.peers.get(peer_id))
+        .map(|peer| (peer.name.clone(), wg_public_key_from_private_key(&peer.private_key).to_base64()))
+        .collect();
+    Some(Enforcement { resync, disconnect })
+}
+
+#[derive(Serialize, Debug)]
+pub struct PeerUsageReport {
+    pub peer_id: Uuid,
+    pub name: String,
+    pub exit_node: bool,
+    pub hour: UsageBucket,
+    pub day: UsageBucket,
+    pub month: UsageBucket,
+    pub quota: Option<UsageQuota>,
+    pub over_quota: Option<String>,
+    pub hourly: Vec<UsageBucket>,
+    pub daily: Vec<UsageBucket>,
+    pub monthly: Vec<UsageBucket>,
+}
+
+// Totals of every peer in the network (GET /api/usage), or of one
+pub fn report(network: &Network, peer: Option<Uuid>) -> Result<Vec<PeerUsageReport>, UsageError> {
+    let mut accounting = ACCOUNTING.lock().unwrap();
+    if accounting.ledger.is_none() {
+        accounting.ledger = Some(load_ledger()?);
+    }
+    let ledger = accounting.ledger.as_ref().unwrap();
+    let quotas = QUOTAS.read().unwrap();
+    let exit_nodes = routing_pbr::get_peers_with_default_route(network);
+    let now = now_secs();
+    Ok(network.peers.iter()
+        .filter(|(peer_id, _)| **peer_id != network.this_peer && peer.is_none_or(|peer| peer == **peer_id))
+        .map(|(peer_id, details)| {
+            let usage = ledger.peers.get(peer_id).cloned().unwrap_or_default();
+            PeerUsageReport {
+                peer_id: *peer_id,
+                name: details.name.clone(),
+                exit_node: exit_nodes.contains(peer_id),
+                hour: usage.hour(now),
+                day: usage.day(now),
+                month: usage.month(now),
+                quota: quotas.get(peer_id).cloned(),
+                over_quota: usage.over_quota,
+                hourly: usage.hourly,
+                daily: usage.daily,
+                monthly: usage.monthly,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wg_quickrs_lib::types::network::Peer;
+
+    #[test]
+    fn test_usage_survives_counter_resets() {
+        let peer_id = Uuid::new_v4();
+        let start = 1_767_222_000; // 2025-12-31 23:00 UTC
+        let mut ledger = UsageLedger::default();
+        let readings = |rx: u64, tx: u64| BTreeMap::from([(peer_id, (rx, tx))]);
+
+        ledger.account(&readings(100, 10), start);
+        ledger.account(&readings(300, 40), start + 60);
+        // The interface was recreated: the counters started over
+        ledger.account(&readings(50, 5), start + 120);
+        let usage = &ledger.peers[&peer_id];
+        assert_eq!(usage.hour(start + 120), UsageBucket { start, rx_bytes: 350, tx_bytes: 45 });
+
+        // A new hour, day and month
+        ledger.account(&readings(150, 5), start + 3600);
+        let usage = &ledger.peers[&peer_id];
+        assert_eq!(usage.hourly.len(), 2);
+        assert_eq!(usage.day(start + 3600).total(), 100);
+        assert_eq!(usage.month(start + 3600).start, 1_767_225_600);
+        assert_eq!(usage.monthly.iter().map(UsageBucket::total).collect::<Vec<_>>(), vec![395, 100]);
+
+        // The ledger is what usage.json holds
+        let json = serde_json::to_string(&ledger).unwrap();
+        assert_eq!(serde_json::from_str::<UsageLedger>(&json).unwrap(), ledger);
+    }
+
+    #[test]
+    fn test_quota_exceeded_and_reset() {
+        let peer_id = Uuid::new_v4();
+        let start = 1_767_225_600; // 2026-01-01 00:00 UTC
+        let mut ledger = UsageLedger::default();
+        let quotas = BTreeMap::from([(peer_id, UsageQuota { daily_bytes: Some(1_000_000), ..Default::default() })]);
+
+        ledger.account(&BTreeMap::from([(peer_id, (600_000, 0))]), start);
+        assert!(ledger.check_quotas(&quotas, start).is_empty());
+        ledger.account(&BTreeMap::from([(peer_id, (900_000, 200_000))]), start + 10);
+        assert_eq!(ledger.check_quotas(&quotas, start + 10),
+                   vec![QuotaChange::Exceeded(peer_id, "daily quota exceeded (1.1 MB of 1.0 MB)".to_string())]);
+        // Reported once
+        assert!(ledger.check_quotas(&quotas, start + 20).is_empty());
+        // The next day starts within the quota
+        let changes = ledger.check_quotas(&quotas, start + 86400);
+        assert!(matches!(&changes[..], [QuotaChange::Reset(id, _)] if *id == peer_id));
+        assert_eq!(ledger.peers[&peer_id].over_quota, None);
+
+        assert_eq!(format_bytes(999), "999 B");
+        assert_eq!(format_bytes(20_000_000_000), "20.0 GB");
+    }
+
+    fn generate_peer(name: &str) -> Peer {
+        Peer {
+            name: name.to_string(),
+            address: std::net::Ipv4Addr::UNSPECIFIED,
+            endpoint: Default::default(),
+            kind: Default::default(),
+            icon: Default::default(),
+            dns: Default::default(),
+            mtu: Default::default(),
+            scripts: Default::default(),
+            private_key: Default::default(),
+            created_at: Default::default(),
+            updated_at: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_disconnect_over_quota_and_reconnect_on_reset() {
+        let (this_peer, limited, unlimited) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let network = Network {
+            name: "wg0".to_string(),
+            subnet: "10.0.34.0/24".parse().unwrap(),
+            this_peer,
+            peers: BTreeMap::from([
+                (this_peer, generate_peer("router")),
+                (limited, generate_peer("limited")),
+                (unlimited, generate_peer("unlimited")),
+            ]),
+            connections: Default::default(),
+            defaults: Default::default(),
+            reservations: Default::default(),
+            updated_at: Default::default(),
+        };
+        let quotas = BTreeMap::from([(limited, UsageQuota {
+            daily_bytes: Some(1_000_000),
+            action: QuotaAction::Disconnect,
+            ..Default::default()
+        })]);
+        let public_key = wg_public_key_from_private_key(&network.peers[&limited].private_key).to_base64();
+        ACCOUNTING.lock().unwrap().ledger = Some(UsageLedger::default());
+        // `wg show dump` as the interface would give it: (peer, bytes received from it)
+        let dump = |received: Vec<(Uuid, u64)>| move || Ok(received.into_iter()
+            .map(|(peer_id, rx)| (ConnectionId { a: this_peer, b: peer_id }, TelemetryDatum {
+                latest_handshake_at: 0,
+                transfer_a_to_b: 0,
+                transfer_b_to_a: rx,
+            }))
+            .collect());
+        let start = 1_767_225_600; // 2026-01-01 00:00 UTC
+
+        assert_eq!(round(&network, &quotas, dump(vec![(limited, 500_000), (unlimited, 500_000)]), start), Some(Enforcement::default()));
+        // Over the quota: removed from the interface and kept from being an exit node
+        let enforcement = round(&network, &quotas, dump(vec![(limited, 1_200_000), (unlimited, 5_000_000)]), start + 10).unwrap();
+        assert_eq!(enforcement, Enforcement { resync: false, disconnect: vec![("limited".to_string(), public_key)] });
+        assert!(exit_blocked(&limited).is_some());
+        assert!(exit_blocked(&unlimited).is_none());
+        // Gone from the dump: nothing to do until the quota resets
+        assert_eq!(round(&network, &quotas, dump(vec![(unlimited, 5_000_000)]), start + 20), Some(Enforcement::default()));
+        // The next day it is within its quota again and comes back with the configuration
+        let enforcement = round(&network, &quotas, dump(vec![(unlimited, 5_000_000)]), start + 86400).unwrap();
+        assert_eq!(enforcement, Enforcement { resync: true, disconnect: Vec::new() });
+        assert!(exit_blocked(&limited).is_none());
+
+        // No counters, no round
+        let failed = || Err(WireGuardCommandError::InterfaceMissing);
+        assert_eq!(round(&network, &quotas, failed, start + 86410), None);
+        assert!(ACCOUNTING.try_lock().is_ok());
+    }
+}
@@ -113,7 +113,7 @@ impl TunnelManager {
     pub fn start_tunnel(&mut self) -> TunnelResult<()> {
         let config = self.config
             .clone()
-            .ok_or_else(|| TunnelError::ConfigNotInitialized())?;
+            .ok_or(TunnelError::ConfigNotInitialized())?;
 
         if self.interface_exists()? {
             return Err(TunnelError::InterfaceExists(self.interface_name()));
@@ -156,7 +156,7 @@ impl TunnelManager {
     pub fn stop_tunnel(&mut self) -> TunnelResult<()> {
         let _ = self.config
             .clone()
-            .ok_or_else(|| TunnelError::ConfigNotInitialized())?;
+            .ok_or(TunnelError::ConfigNotInitialized())?;
 
         if !self.interface_exists()? {
             log::debug!("Interface already deleted, skipping cleanup");
@@ -294,7 +294,7 @@ impl TunnelManager {
         let iface = self.real_interface.as_ref().unwrap();
         let config = self.config.as_ref().unwrap();
 
-        let wg_config = wg_quickrs_lib::helpers::get_peer_wg_config(&config.network, &config.network.this_peer, true)?;
+        let wg_config = wg_quickrs_lib::helpers::get_peer_wg_config(&crate::mode::routing_pbr::main_interface_network(&config.network), &config.network.this_peer, true)?;
 
         let mut temp_file = NamedTempFile::new()?;
         writeln!(temp_file, "{}", wg_config)?;
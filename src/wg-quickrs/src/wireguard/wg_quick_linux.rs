@@ -63,24 +63,21 @@ fn calculate_mtu(iface: &str) -> TunnelResult<u16> {
         let output = shell_cmd(&["ip", "route", "get", &endpoint])?;
         let output_str = String::from_utf8_lossy(&output.stdout);
 
-        if let Some(mtu) = extract_mtu(&output_str, &mtu_regex, &dev_regex) {
-            if mtu < min_mtu {
+        if let Some(mtu) = extract_mtu(&output_str, &mtu_regex, &dev_regex)
+            && mtu < min_mtu {
                 min_mtu = mtu;
             }
-        }
     }
 
-    if min_mtu == u16::MAX {
-        if let Ok(default_output) = shell_cmd(&["ip", "route", "show", "default"]) {
+    if min_mtu == u16::MAX
+        && let Ok(default_output) = shell_cmd(&["ip", "route", "show", "default"]) {
             let default_output_str = String::from_utf8_lossy(&default_output.stdout);
 
-            if let Some(mtu) = extract_mtu(&default_output_str, &mtu_regex, &dev_regex) {
-                if mtu < min_mtu {
+            if let Some(mtu) = extract_mtu(&default_output_str, &mtu_regex, &dev_regex)
+                && mtu < min_mtu {
                     min_mtu = mtu;
                 }
-            }
         }
-    }
 
     if !(min_mtu > 80 && min_mtu < u16::MAX) {
         min_mtu = 1500;
@@ -91,22 +88,20 @@ fn calculate_mtu(iface: &str) -> TunnelResult<u16> {
 
 fn extract_mtu(output: &str, mtu_regex: &Regex, dev_regex: &Regex) -> Option<u16> {
     // Try to extract MTU directly from output
-    if let Some(caps) = mtu_regex.captures(output) {
-        if let Ok(mtu) = caps[1].parse::<u16>() {
+    if let Some(caps) = mtu_regex.captures(output)
+        && let Ok(mtu) = caps[1].parse::<u16>() {
             return Some(mtu);
         }
-    }
 
     // If not found, try to get device name and query it
     if let Some(caps) = dev_regex.captures(output) {
         let device = &caps[1];
         if let Ok(link_output) = shell_cmd(&["ip", "link", "show", "dev", device]) {
             let link_str = String::from_utf8_lossy(&link_output.stdout);
-            if let Some(caps) = mtu_regex.captures(&link_str) {
-                if let Ok(mtu) = caps[1].parse::<u16>() {
+            if let Some(caps) = mtu_regex.captures(&link_str)
+                && let Ok(mtu) = caps[1].parse::<u16>() {
                     return Some(mtu);
                 }
-            }
         }
     }
 
@@ -156,7 +151,7 @@ fn resolvconf_iface_prefix() -> String {
     String::new()
 }
 
-pub fn set_dns(dns_servers: &Vec<Ipv4Addr>, interface: &str, dns_manager: &mut DnsManager) -> TunnelResult<()> {
+pub fn set_dns(dns_servers: &[Ipv4Addr], interface: &str, dns_manager: &mut DnsManager) -> TunnelResult<()> {
     dns_manager.have_set_dns = false;
     if dns_servers.is_empty() {
         return Ok(());
@@ -169,7 +164,7 @@ pub fn set_dns(dns_servers: &Vec<Ipv4Addr>, interface: &str, dns_manager: &mut D
 
     log::debug!("[+] resolvconf -a {}{} -m 0 -x", resolvconf_iface_prefix(), interface);
     let mut child = Command::new("resolvconf")
-        .args(&["-a", &format!("{}{}", resolvconf_iface_prefix(), interface), "-m", "0", "-x"])
+        .args(["-a", &format!("{}{}", resolvconf_iface_prefix(), interface), "-m", "0", "-x"])
         .stdin(std::process::Stdio::piped())
         .spawn()?;
 
@@ -194,9 +189,9 @@ pub fn add_route(iface: &str, interface_name: &str, cidr: &str, endpoint_router:
         let is_ipv6 = cidr.contains(':');
         let proto = if is_ipv6 { "-6" } else { "-4" };
 
-        let check = shell_cmd(&["ip", proto, "route", "show", "dev", iface, "match", &cidr])?;
+        let check = shell_cmd(&["ip", proto, "route", "show", "dev", iface, "match", cidr])?;
         if check.stdout.is_empty() {
-            shell_cmd(&["ip", proto, "route", "add", &cidr, "dev", iface])?;
+            shell_cmd(&["ip", proto, "route", "add", cidr, "dev", iface])?;
         }
     }
 
@@ -338,7 +333,7 @@ pub fn add_default_route(interface: &str, cidr: &str) -> TunnelResult<()> {
     if nft_exists {
         execute_nft_command(&nftcmd)?;
     } else {
-        execute_iptables_command(&iptables, &restore)?;
+        execute_iptables_command(iptables, &restore)?;
     }
 
     Ok(())